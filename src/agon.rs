@@ -0,0 +1,122 @@
+use wasmparser::ValType;
+
+use crate::asm::{self, imm, Inst, Reg16::*};
+use crate::compile::Provided;
+
+/// Upper byte of the address MOS loads executables at, 0x040000, which
+/// MBASE is set to for the program to run there in Z80 mode.
+const SEGMENT: u8 = 0x04;
+
+/// Offset of the header MOS looks for in an executable.
+const HEADER_OFFSET: usize = 0x40;
+
+/// RAM above the program's segment left to programs, below what MOS keeps.
+const RAM_START: usize = 0x050000;
+const RAM_END: usize = 0x0B0000;
+
+/// Returns whether the `size` bytes of linear memory at `addr` are in the
+/// RAM left to the program outside its segment.
+pub fn is_ram(addr: usize, size: usize) -> bool {
+    RAM_START <= addr && addr + size <= RAM_END
+}
+
+/// Emits the code MOS runs in ADL mode at the load address, the header
+/// after it, and the start of the program proper, which sets up its own
+/// stack below `stack_top`.
+pub fn emit_setup(code: &mut Vec<Inst>, stack_top: u16) {
+    code.extend(asm::parse(
+        &ENTRY.replace("{segment}", &SEGMENT.to_string()),
+    ));
+    let padding = HEADER_OFFSET - asm::size(code);
+    for row in (0..padding).step_by(16) {
+        code.push(Inst::Db(vec![0.into(); (padding - row).min(16)]));
+    }
+    // The version of the header, and that the entry is in ADL mode.
+    let header = b"MOS\x00\x01".iter().map(|byte| i64::from(*byte).into());
+    code.push(Inst::Db(header.collect()));
+    code.push(Inst::Label("agon_main".into()));
+    code.push(Inst::Ld(SP.into(), imm(i64::from(stack_top))));
+}
+
+/// Routines imported from `console`, calling the MOS routines they stand
+/// for.
+pub const IMPORTS: &[Provided] = &[
+    Provided {
+        name: "putchar",
+        params: &[ValType::I32],
+        results: &[],
+        body: PUTCHAR,
+    },
+    Provided {
+        name: "getchar",
+        params: &[],
+        results: &[ValType::I32],
+        body: GETCHAR,
+    },
+];
+
+/// Saves what MOS needs back, points MBASE at the program's segment and
+/// calls the program in Z80 mode, returning 0 in `HL` once it returns with
+/// `RET.L`.
+///
+/// These instructions are encoded in ADL mode as they are in Z80 mode. The
+/// program's stack leaves MOS's alone, since Z80 mode has its own stack
+/// pointer.
+const ENTRY: &str = "\
+agon_entry:
+  PUSH IX
+  PUSH IY
+  LD A,MB
+  PUSH AF
+  LD A,{segment}
+  LD MB,A
+  CALL.IS agon_main
+  POP AF
+  LD MB,A
+  POP IY
+  POP IX
+  OR A
+  SBC HL,HL
+  RET
+";
+
+// MOS keeps the registers it doesn't return in.
+
+/// Writes the character in the low byte of the argument with `RST 0x10`.
+const PUTCHAR: &str = "
+  LD A,E
+  RST.LIL 0x10
+";
+
+/// Reads a character with `mos_getkey`, which waits for a key.
+const GETCHAR: &str = "
+  XOR A
+  RST.LIL 0x08
+";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile::{Config, Format, Platform, Target};
+    use crate::loader;
+
+    #[test]
+    fn entry_calls_past_header() {
+        let wasm = wat::parse_str(r#"(module (func (export "entry")))"#).unwrap();
+        let config = Config {
+            target: Target::Ez80,
+            platform: Platform::Agon,
+            format: Format::Bin,
+            ..Config::default()
+        };
+        let mut out = vec![];
+        loader::load(&wasm)
+            .unwrap()
+            .compile(&config, &mut out)
+            .unwrap();
+        let main = HEADER_OFFSET + 5;
+        assert_eq!(&out[HEADER_OFFSET..main], b"MOS\x00\x01");
+        let call = [0x49, 0xCD, main as u8, 0];
+        assert!(out[..HEADER_OFFSET].windows(4).any(|bytes| bytes == call));
+    }
+}
@@ -0,0 +1,886 @@
+use std::fmt::{self, Display, Formatter};
+use std::io::Write;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Reg8 {
+    A,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Reg16 {
+    BC,
+    DE,
+    HL,
+    SP,
+    AF,
+    IX,
+    IY,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Cond {
+    Nz,
+    Z,
+    Nc,
+    C,
+    Po,
+    Pe,
+    P,
+    M,
+}
+
+impl Cond {
+    pub fn negate(self) -> Cond {
+        match self {
+            Cond::Nz => Cond::Z,
+            Cond::Z => Cond::Nz,
+            Cond::Nc => Cond::C,
+            Cond::C => Cond::Nc,
+            Cond::Po => Cond::Pe,
+            Cond::Pe => Cond::Po,
+            Cond::P => Cond::M,
+            Cond::M => Cond::P,
+        }
+    }
+}
+
+/// A number, a symbol, or a symbol plus an offset.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Expr {
+    pub symbol: Option<String>,
+    pub offset: i64,
+}
+
+impl Expr {
+    pub fn sym(symbol: impl Into<String>) -> Expr {
+        Expr {
+            symbol: Some(symbol.into()),
+            offset: 0,
+        }
+    }
+
+    pub fn plus(mut self, offset: i64) -> Expr {
+        self.offset += offset;
+        self
+    }
+
+    pub fn value(&self) -> Option<i64> {
+        match self.symbol {
+            None => Some(self.offset),
+            Some(_) => None,
+        }
+    }
+}
+
+impl From<i64> for Expr {
+    fn from(offset: i64) -> Self {
+        Expr {
+            symbol: None,
+            offset,
+        }
+    }
+}
+
+impl From<&str> for Expr {
+    fn from(symbol: &str) -> Self {
+        Expr::sym(symbol)
+    }
+}
+
+impl From<String> for Expr {
+    fn from(symbol: String) -> Self {
+        Expr::sym(symbol)
+    }
+}
+
+impl Display for Expr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.symbol {
+            Some(symbol) if self.offset > 0 => write!(f, "{}+{}", symbol, self.offset),
+            Some(symbol) if self.offset < 0 => write!(f, "{}{}", symbol, self.offset),
+            Some(symbol) => write!(f, "{}", symbol),
+            None if self.offset >= 0x1000 => write!(f, "{:#06X}", self.offset),
+            None => write!(f, "{}", self.offset),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Operand {
+    Reg8(Reg8),
+    Reg16(Reg16),
+    Imm(Expr),
+    /// Memory at an absolute address, `(nn)`.
+    Mem(Expr),
+    /// Memory addressed by a register pair, `(HL)`.
+    Ind(Reg16),
+    /// Memory addressed by an index register, `(IX+d)`.
+    Idx(Reg16, i16),
+    /// Memory addressed by `HL`, which is incremented after the access,
+    /// `(HL+)` on the SM83.
+    HlInc,
+    /// Memory addressed by `HL`, which is decremented after the access,
+    /// `(HL-)` on the SM83.
+    HlDec,
+    /// `SP` plus a signed offset, as loaded into `HL` on the SM83.
+    SpOffset(i8),
+    /// An operand taken 24 bits wide by the eZ80 in Z80 mode: a pair with
+    /// its upper byte, memory at the 24-bit address in an index register
+    /// plus displacement, or memory at a 24-bit absolute address. Only `LD`
+    /// takes these, with the [`Suffix`] they call for.
+    Long(Box<Operand>),
+}
+
+impl Operand {
+    /// The operand as the instruction encodes it, without its suffix.
+    pub fn short(&self) -> &Operand {
+        match self {
+            Operand::Long(operand) => operand,
+            operand => operand,
+        }
+    }
+}
+
+impl From<Reg8> for Operand {
+    fn from(reg: Reg8) -> Self {
+        Operand::Reg8(reg)
+    }
+}
+
+impl From<Reg16> for Operand {
+    fn from(reg: Reg16) -> Self {
+        Operand::Reg16(reg)
+    }
+}
+
+pub fn imm(expr: impl Into<Expr>) -> Operand {
+    Operand::Imm(expr.into())
+}
+
+pub fn mem(expr: impl Into<Expr>) -> Operand {
+    Operand::Mem(expr.into())
+}
+
+pub fn ind(reg: Reg16) -> Operand {
+    Operand::Ind(reg)
+}
+
+pub fn idx(reg: Reg16, offset: usize) -> Operand {
+    Operand::Idx(reg, offset as i16)
+}
+
+pub fn long(operand: Operand) -> Operand {
+    Operand::Long(Box::new(operand))
+}
+
+/// Suffix of an eZ80 instruction run in Z80 mode, making its data (`L`) or
+/// its immediate address (`IL`) 24 bits wide.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Suffix {
+    Lis,
+    Sil,
+    Lil,
+}
+
+/// Returns the suffix `inst` takes for its long operands, if it has any.
+///
+/// A 24-bit absolute address takes `IL`, and the data stays 16 bits wide if
+/// it goes to or from a pair. Anything else long is data.
+pub fn suffix(inst: &Inst) -> Option<Suffix> {
+    let Inst::Ld(dst, src) = inst else {
+        return None;
+    };
+    match (dst, src) {
+        (Operand::Long(long), other) | (other, Operand::Long(long)) => {
+            Some(match (&**long, other) {
+                (Operand::Mem(_), Operand::Reg16(_)) => Suffix::Sil,
+                (Operand::Mem(_), _) => Suffix::Lil,
+                _ => Suffix::Lis,
+            })
+        }
+        _ => None,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Alu {
+    Add,
+    Adc,
+    Sub,
+    Sbc,
+    And,
+    Xor,
+    Or,
+    Cp,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Shift {
+    Rlc,
+    Rrc,
+    Rl,
+    Rr,
+    Sla,
+    Sra,
+    Srl,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BitOp {
+    Bit,
+    Res,
+    Set,
+}
+
+/// Instructions without operands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Plain {
+    Nop,
+    Halt,
+    Di,
+    Ei,
+    Exx,
+    Rlca,
+    Rrca,
+    Rla,
+    Rra,
+    Cpl,
+    Neg,
+    Scf,
+    Ccf,
+    Ldi,
+    Ldd,
+    Ldir,
+    Lddr,
+}
+
+/// A line of assembly: an instruction, a label, a directive or a comment.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Inst {
+    Label(String),
+    Comment(String),
+    Equ(String, Expr),
+    /// Places the code from here at an address, which only the first
+    /// instruction may do.
+    Org(u16),
+    Db(Vec<Expr>),
+    Dw(Vec<Expr>),
+    Ld(Operand, Operand),
+    /// `LD` between `A` and the SM83's high page, at 0xFF00 and above, with
+    /// the full address in the memory operand.
+    Ldh(Operand, Operand),
+    Push(Reg16),
+    Pop(Reg16),
+    Ex(Operand, Operand),
+    /// `ADD`, `ADC` and `SBC` with a 16-bit destination.
+    Alu16(Alu, Reg16, Reg16),
+    /// Arithmetic and logic on `A`.
+    Alu(Alu, Operand),
+    Inc(Operand),
+    Dec(Operand),
+    Shift(Shift, Operand),
+    Bit(BitOp, u8, Operand),
+    Plain(Plain),
+    Jp(Option<Cond>, Expr),
+    /// `JP (HL)`, `JP (IX)` or `JP (IY)`.
+    JpInd(Reg16),
+    Jr(Option<Cond>, Expr),
+    Djnz(Expr),
+    Call(Option<Cond>, Expr),
+    Ret(Option<Cond>),
+    Rst(u8),
+    /// `MLT rr` of the eZ80, multiplying the two halves of a pair into it.
+    Mlt(Reg16),
+    /// `LEA rr,IX+d` of the eZ80, loading an index register plus a
+    /// displacement into a pair.
+    Lea(Reg16, Reg16, i8),
+    /// `LD MB,A` of the eZ80 in ADL mode, setting MBASE, the upper byte of
+    /// the addresses of Z80 mode.
+    LdMbA,
+    /// `LD A,MB` of the eZ80, reading MBASE.
+    LdAMb,
+    /// `CALL.IS nn` of the eZ80 in ADL mode, calling Z80 mode code at `nn` in
+    /// the segment MBASE selects.
+    CallIs(Expr),
+    /// `RET.L` of the eZ80 in Z80 mode, returning to ADL mode code that
+    /// called with `CALL.IS`.
+    RetL,
+    /// `RST.LIL n` of the eZ80 in Z80 mode, calling the ADL mode code at `n`.
+    RstLil(u8),
+    /// `OUT0 (n),r` of the Z180, writing a register to an internal I/O port.
+    Out0(u8, Reg8),
+    /// `MULUW HL,rr` of the R800, multiplying `HL` by a pair into `DE:HL`.
+    Muluw(Reg16),
+}
+
+fn is_index(reg: Reg16) -> bool {
+    matches!(reg, Reg16::IX | Reg16::IY)
+}
+
+impl Inst {
+    /// Size in bytes of the encoded instruction or data.
+    pub fn size(&self) -> usize {
+        // Index registers take a prefix byte, and indexed memory a
+        // displacement byte too.
+        let prefix = |operand: &Operand| match operand {
+            Operand::Reg16(reg) if is_index(*reg) => 1,
+            Operand::Idx(..) => 2,
+            _ => 0,
+        };
+        // A long operand takes the suffix byte, and a 24-bit address a third
+        // byte.
+        let long = |operand: &Operand| match operand {
+            Operand::Long(long) => 1 + matches!(**long, Operand::Mem(_)) as usize,
+            _ => 0,
+        };
+        match self {
+            Inst::Label(_) | Inst::Comment(_) | Inst::Equ(..) | Inst::Org(_) => 0,
+            Inst::Db(values) => values.len(),
+            Inst::Dw(values) => values.len() * 2,
+            Inst::Ld(dst, src) => {
+                let (long, dst, src) = (long(dst) + long(src), dst.short(), src.short());
+                let base = match (dst, src) {
+                    (Operand::Reg16(_), Operand::Imm(_)) => 3,
+                    (_, Operand::SpOffset(_)) => 2,
+                    (Operand::Reg16(Reg16::SP), Operand::Reg16(_)) => 1,
+                    (Operand::Reg16(Reg16::HL | Reg16::IX | Reg16::IY), Operand::Mem(_))
+                    | (Operand::Mem(_), Operand::Reg16(Reg16::HL | Reg16::IX | Reg16::IY))
+                    | (Operand::Reg8(Reg8::A), Operand::Mem(_))
+                    | (Operand::Mem(_), Operand::Reg8(Reg8::A)) => 3,
+                    // The other pairs take an ED prefix.
+                    (Operand::Reg16(_), Operand::Mem(_)) | (Operand::Mem(_), Operand::Reg16(_)) => {
+                        4
+                    }
+                    (_, Operand::Imm(_)) => 2,
+                    _ => 1,
+                };
+                base + prefix(dst) + prefix(src) + long
+            }
+            Inst::Ldh(..) => 2,
+            Inst::Push(reg) | Inst::Pop(reg) => 1 + is_index(*reg) as usize,
+            Inst::Ex(a, b) => 1 + prefix(a) + prefix(b),
+            Inst::Alu16(alu, dst, _) => match alu {
+                Alu::Add => 1 + is_index(*dst) as usize,
+                _ => 2,
+            },
+            Inst::Alu(_, src) => match src {
+                Operand::Imm(_) => 2,
+                src => 1 + prefix(src),
+            },
+            Inst::Inc(operand) | Inst::Dec(operand) => 1 + prefix(operand),
+            Inst::Shift(_, operand) | Inst::Bit(_, _, operand) => 2 + prefix(operand),
+            Inst::Plain(plain) => match plain {
+                Plain::Neg | Plain::Ldi | Plain::Ldd | Plain::Ldir | Plain::Lddr => 2,
+                _ => 1,
+            },
+            Inst::Jp(..) | Inst::Call(..) => 3,
+            Inst::JpInd(reg) => 1 + is_index(*reg) as usize,
+            Inst::Jr(..) | Inst::Djnz(_) => 2,
+            Inst::Ret(_) | Inst::Rst(_) => 1,
+            Inst::Mlt(_) | Inst::Muluw(_) => 2,
+            Inst::Lea(..) | Inst::Out0(..) => 3,
+            Inst::LdMbA | Inst::LdAMb | Inst::RetL | Inst::RstLil(_) => 2,
+            Inst::CallIs(_) => 4,
+        }
+    }
+}
+
+/// Total size in bytes of `code`.
+pub fn size(code: &[Inst]) -> usize {
+    code.iter().map(Inst::size).sum()
+}
+
+/// Assembler syntax to emit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Syntax {
+    /// Zilog mnemonics, as accepted by most Z80 assemblers.
+    #[default]
+    Zilog,
+    /// The dialect of the ASxxxx assemblers shipped with SDCC.
+    Asxxxx,
+}
+
+struct Syntaxed<'a, T>(&'a T, Syntax);
+
+impl Display for Syntaxed<'_, Operand> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let Syntaxed(operand, syntax) = *self;
+        match (operand, syntax) {
+            (Operand::Reg8(reg), Syntax::Zilog) => write!(f, "{:?}", reg),
+            (Operand::Reg8(reg), Syntax::Asxxxx) => {
+                write!(f, "{}", format!("{:?}", reg).to_lowercase())
+            }
+            (Operand::Reg16(reg), _) => write!(f, "{}", reg_name(*reg, syntax)),
+            (Operand::Imm(expr), Syntax::Zilog) => write!(f, "{}", expr),
+            (Operand::Imm(expr), Syntax::Asxxxx) => write!(f, "#{}", expr),
+            (Operand::Mem(expr), _) => write!(f, "({})", expr),
+            (Operand::Ind(reg), _) => write!(f, "({})", reg_name(*reg, syntax)),
+            (Operand::Idx(reg, offset), Syntax::Zilog) if *offset < 0 => {
+                write!(f, "({}{})", reg_name(*reg, syntax), offset)
+            }
+            (Operand::Idx(reg, offset), Syntax::Zilog) => {
+                write!(f, "({}+{})", reg_name(*reg, syntax), offset)
+            }
+            (Operand::Idx(reg, offset), Syntax::Asxxxx) => {
+                write!(f, "{}({})", offset, reg_name(*reg, syntax))
+            }
+            (Operand::HlInc, _) => write!(f, "({}+)", reg_name(Reg16::HL, syntax)),
+            (Operand::HlDec, _) => write!(f, "({}-)", reg_name(Reg16::HL, syntax)),
+            (Operand::SpOffset(offset), Syntax::Zilog) => write!(f, "SP+{}", offset),
+            (Operand::SpOffset(offset), Syntax::Asxxxx) => write!(f, "#{}", offset),
+            (Operand::Long(operand), _) => write!(f, "{}", Syntaxed(&**operand, syntax)),
+        }
+    }
+}
+
+fn reg_name(reg: Reg16, syntax: Syntax) -> String {
+    let name = match reg {
+        Reg16::BC => "BC",
+        Reg16::DE => "DE",
+        Reg16::HL => "HL",
+        Reg16::SP => "SP",
+        Reg16::AF => "AF",
+        Reg16::IX => "IX",
+        Reg16::IY => "IY",
+    };
+    match syntax {
+        Syntax::Zilog => name.to_string(),
+        Syntax::Asxxxx => name.to_lowercase(),
+    }
+}
+
+fn mb(syntax: Syntax) -> String {
+    match syntax {
+        Syntax::Zilog => "MB".to_string(),
+        Syntax::Asxxxx => "mb".to_string(),
+    }
+}
+
+fn cond_name(cond: Cond, syntax: Syntax) -> String {
+    let name = match cond {
+        Cond::Nz => "NZ",
+        Cond::Z => "Z",
+        Cond::Nc => "NC",
+        Cond::C => "C",
+        Cond::Po => "PO",
+        Cond::Pe => "PE",
+        Cond::P => "P",
+        Cond::M => "M",
+    };
+    match syntax {
+        Syntax::Zilog => name.to_string(),
+        Syntax::Asxxxx => name.to_lowercase(),
+    }
+}
+
+fn mnemonic(inst: &Inst) -> String {
+    match inst {
+        Inst::Ld(..) | Inst::LdMbA | Inst::LdAMb => "LD".to_string(),
+        Inst::Ldh(..) => "LDH".to_string(),
+        Inst::Push(_) => "PUSH".to_string(),
+        Inst::Pop(_) => "POP".to_string(),
+        Inst::Ex(..) => "EX".to_string(),
+        Inst::Alu16(alu, ..) | Inst::Alu(alu, _) => format!("{:?}", alu).to_uppercase(),
+        Inst::Inc(_) => "INC".to_string(),
+        Inst::Dec(_) => "DEC".to_string(),
+        Inst::Shift(shift, _) => format!("{:?}", shift).to_uppercase(),
+        Inst::Bit(op, ..) => format!("{:?}", op).to_uppercase(),
+        Inst::Plain(plain) => format!("{:?}", plain).to_uppercase(),
+        Inst::Jp(..) | Inst::JpInd(_) => "JP".to_string(),
+        Inst::Jr(..) => "JR".to_string(),
+        Inst::Djnz(_) => "DJNZ".to_string(),
+        Inst::Call(..) => "CALL".to_string(),
+        Inst::Ret(_) => "RET".to_string(),
+        Inst::Rst(_) => "RST".to_string(),
+        Inst::Mlt(_) => "MLT".to_string(),
+        Inst::Lea(..) => "LEA".to_string(),
+        Inst::CallIs(_) => "CALL.IS".to_string(),
+        Inst::RetL => "RET.L".to_string(),
+        Inst::RstLil(_) => "RST.LIL".to_string(),
+        Inst::Out0(..) => "OUT0".to_string(),
+        Inst::Muluw(_) => "MULUW".to_string(),
+        Inst::Label(_)
+        | Inst::Comment(_)
+        | Inst::Equ(..)
+        | Inst::Org(_)
+        | Inst::Db(_)
+        | Inst::Dw(_) => {
+            unreachable!()
+        }
+    }
+}
+
+/// Writes `code` as assembly text in `syntax`.
+pub fn emit(code: &[Inst], syntax: Syntax, out: &mut Vec<u8>) {
+    for inst in code {
+        emit_inst(inst, syntax, out);
+    }
+}
+
+fn emit_inst(inst: &Inst, syntax: Syntax, out: &mut Vec<u8>) {
+    let operand = |operand: &Operand| Syntaxed(operand, syntax).to_string();
+    let target = |expr: &Expr| expr.to_string();
+    let operands = match inst {
+        Inst::Label(name) => {
+            writeln!(out, "{}:", name).unwrap();
+            return;
+        }
+        Inst::Comment(text) => {
+            writeln!(out, "  ; {}", text).unwrap();
+            return;
+        }
+        Inst::Equ(name, expr) => {
+            match syntax {
+                Syntax::Zilog => writeln!(out, "{}: EQU {}", name, expr).unwrap(),
+                Syntax::Asxxxx => writeln!(out, "{} = {}", name, expr).unwrap(),
+            }
+            return;
+        }
+        Inst::Org(addr) => {
+            match syntax {
+                Syntax::Zilog => writeln!(out, "  ORG {:#X}", addr).unwrap(),
+                // `.org` is only allowed in an absolute area.
+                Syntax::Asxxxx => {
+                    writeln!(out, "  .area _CODE (ABS)").unwrap();
+                    writeln!(out, "  .org {:#X}", addr).unwrap();
+                }
+            }
+            return;
+        }
+        Inst::Db(values) | Inst::Dw(values) => {
+            let directive = match (inst, syntax) {
+                (Inst::Db(_), Syntax::Zilog) => "DB",
+                (Inst::Db(_), Syntax::Asxxxx) => ".db",
+                (_, Syntax::Zilog) => "DW",
+                (_, Syntax::Asxxxx) => ".dw",
+            };
+            let values: Vec<String> = values.iter().map(target).collect();
+            writeln!(out, "  {} {}", directive, values.join(",")).unwrap();
+            return;
+        }
+        Inst::Ld(_, src @ Operand::SpOffset(_)) if syntax == Syntax::Asxxxx => {
+            vec![reg_name(Reg16::SP, syntax), operand(src)]
+        }
+        Inst::Ld(dst, src) | Inst::Ldh(dst, src) | Inst::Ex(dst, src) => {
+            vec![operand(dst), operand(src)]
+        }
+        Inst::Push(reg) | Inst::Pop(reg) | Inst::JpInd(reg) | Inst::Mlt(reg) => {
+            let reg = reg_name(*reg, syntax);
+            match inst {
+                Inst::JpInd(_) => vec![format!("({})", reg)],
+                _ => vec![reg],
+            }
+        }
+        Inst::Alu16(_, dst, src) => vec![reg_name(*dst, syntax), reg_name(*src, syntax)],
+        Inst::Alu(alu, src) => match (alu, syntax) {
+            // ASxxxx spells out the accumulator for every ALU operation.
+            (_, Syntax::Asxxxx) => vec!["a".to_string(), operand(src)],
+            (Alu::Add | Alu::Adc | Alu::Sbc, Syntax::Zilog) => {
+                vec!["A".to_string(), operand(src)]
+            }
+            (_, Syntax::Zilog) => vec![operand(src)],
+        },
+        Inst::Inc(operand_) | Inst::Dec(operand_) | Inst::Shift(_, operand_) => {
+            vec![operand(operand_)]
+        }
+        Inst::Bit(_, bit, operand_) => vec![bit.to_string(), operand(operand_)],
+        Inst::Plain(_) | Inst::RetL => vec![],
+        Inst::LdMbA => vec![mb(syntax), operand(&Reg8::A.into())],
+        Inst::LdAMb => vec![operand(&Reg8::A.into()), mb(syntax)],
+        Inst::Jp(cond, expr) | Inst::Jr(cond, expr) | Inst::Call(cond, expr) => {
+            let mut operands = vec![];
+            if let Some(cond) = cond {
+                operands.push(cond_name(*cond, syntax));
+            }
+            operands.push(target(expr));
+            operands
+        }
+        Inst::Djnz(expr) | Inst::CallIs(expr) => vec![target(expr)],
+        Inst::Ret(cond) => cond.iter().map(|cond| cond_name(*cond, syntax)).collect(),
+        Inst::Rst(vector) | Inst::RstLil(vector) => vec![format!("{:#04X}", vector)],
+        Inst::Out0(port, reg) => vec![format!("({:#04X})", port), operand(&(*reg).into())],
+        Inst::Muluw(reg) => vec![reg_name(Reg16::HL, syntax), reg_name(*reg, syntax)],
+        Inst::Lea(dst, src, offset) => {
+            let src = reg_name(*src, syntax);
+            let src = if *offset < 0 {
+                format!("{}{}", src, offset)
+            } else {
+                format!("{}+{}", src, offset)
+            };
+            vec![reg_name(*dst, syntax), src]
+        }
+    };
+    let mut mnemonic = match (inst, syntax) {
+        // ASxxxx writes `LD HL,SP+n` as `LDHL SP,#n`.
+        (Inst::Ld(_, Operand::SpOffset(_)), Syntax::Asxxxx) => "LDHL".to_string(),
+        _ => mnemonic(inst),
+    };
+    if let Some(suffix) = suffix(inst) {
+        mnemonic = format!("{}.{:?}", mnemonic, suffix).to_uppercase();
+    }
+    if syntax == Syntax::Asxxxx {
+        mnemonic = mnemonic.to_lowercase();
+    }
+    let operands = operands.join(",");
+    if operands.is_empty() {
+        writeln!(out, "  {}", mnemonic).unwrap();
+    } else {
+        writeln!(out, "  {} {}", mnemonic, operands).unwrap();
+    }
+}
+
+/// Parses assembly in the Zilog syntax emitted by [`emit`], as used for the
+/// hand-written runtime routines.
+pub fn parse(text: &str) -> Vec<Inst> {
+    text.lines()
+        .filter_map(|line| {
+            let line = line.split(';').next().unwrap().trim_end();
+            if line.is_empty() {
+                return None;
+            }
+            if !line.starts_with(' ') {
+                let (label, rest) = line.split_once(':').unwrap();
+                let rest = rest.trim();
+                if let Some(value) = rest.strip_prefix("EQU ") {
+                    return Some(Inst::Equ(label.to_string(), parse_expr(value)));
+                }
+                assert!(rest.is_empty(), "unexpected text after label: {}", line);
+                return Some(Inst::Label(label.to_string()));
+            }
+            Some(parse_inst(line.trim()))
+        })
+        .collect()
+}
+
+fn parse_inst(line: &str) -> Inst {
+    let (mnemonic, operands) = line.split_once(' ').unwrap_or((line, ""));
+    let operands: Vec<&str> = if operands.is_empty() {
+        vec![]
+    } else {
+        operands.split(',').map(str::trim).collect()
+    };
+    let cond = |name: &str| parse_cond(name);
+    let plain = |plain| Inst::Plain(plain);
+    match (mnemonic, operands.as_slice()) {
+        ("DB", values) => Inst::Db(values.iter().map(|value| parse_expr(value)).collect()),
+        ("DW", values) => Inst::Dw(values.iter().map(|value| parse_expr(value)).collect()),
+        ("LD", ["MB", "A"]) => Inst::LdMbA,
+        ("LD", ["A", "MB"]) => Inst::LdAMb,
+        ("LD", [dst, src]) => Inst::Ld(parse_operand(dst), parse_operand(src)),
+        ("LD.LIS" | "LD.SIL" | "LD.LIL", [dst, src]) => parse_long_ld(mnemonic, dst, src),
+        ("LDH", [dst, src]) => Inst::Ldh(parse_operand(dst), parse_operand(src)),
+        ("PUSH", [reg]) => Inst::Push(parse_reg16(reg).unwrap()),
+        ("POP", [reg]) => Inst::Pop(parse_reg16(reg).unwrap()),
+        ("EX", [a, b]) => Inst::Ex(parse_operand(a), parse_operand(b)),
+        ("ADD" | "ADC" | "SBC", [dst, src]) if parse_reg16(dst).is_some() => Inst::Alu16(
+            parse_alu(mnemonic),
+            parse_reg16(dst).unwrap(),
+            parse_reg16(src).unwrap(),
+        ),
+        ("ADD" | "ADC" | "SBC", ["A", src]) => Inst::Alu(parse_alu(mnemonic), parse_operand(src)),
+        ("ADD" | "ADC" | "SUB" | "SBC" | "AND" | "XOR" | "OR" | "CP", [src]) => {
+            Inst::Alu(parse_alu(mnemonic), parse_operand(src))
+        }
+        ("INC", [operand]) => Inst::Inc(parse_operand(operand)),
+        ("DEC", [operand]) => Inst::Dec(parse_operand(operand)),
+        ("RLC", [operand]) => Inst::Shift(Shift::Rlc, parse_operand(operand)),
+        ("RRC", [operand]) => Inst::Shift(Shift::Rrc, parse_operand(operand)),
+        ("RL", [operand]) => Inst::Shift(Shift::Rl, parse_operand(operand)),
+        ("RR", [operand]) => Inst::Shift(Shift::Rr, parse_operand(operand)),
+        ("SLA", [operand]) => Inst::Shift(Shift::Sla, parse_operand(operand)),
+        ("SRA", [operand]) => Inst::Shift(Shift::Sra, parse_operand(operand)),
+        ("SRL", [operand]) => Inst::Shift(Shift::Srl, parse_operand(operand)),
+        ("BIT", [bit, operand]) => {
+            Inst::Bit(BitOp::Bit, bit.parse().unwrap(), parse_operand(operand))
+        }
+        ("RES", [bit, operand]) => {
+            Inst::Bit(BitOp::Res, bit.parse().unwrap(), parse_operand(operand))
+        }
+        ("SET", [bit, operand]) => {
+            Inst::Bit(BitOp::Set, bit.parse().unwrap(), parse_operand(operand))
+        }
+        ("NOP", []) => plain(Plain::Nop),
+        ("HALT", []) => plain(Plain::Halt),
+        ("DI", []) => plain(Plain::Di),
+        ("EI", []) => plain(Plain::Ei),
+        ("EXX", []) => plain(Plain::Exx),
+        ("RLCA", []) => plain(Plain::Rlca),
+        ("RRCA", []) => plain(Plain::Rrca),
+        ("RLA", []) => plain(Plain::Rla),
+        ("RRA", []) => plain(Plain::Rra),
+        ("CPL", []) => plain(Plain::Cpl),
+        ("NEG", []) => plain(Plain::Neg),
+        ("SCF", []) => plain(Plain::Scf),
+        ("CCF", []) => plain(Plain::Ccf),
+        ("LDI", []) => plain(Plain::Ldi),
+        ("LDD", []) => plain(Plain::Ldd),
+        ("LDIR", []) => plain(Plain::Ldir),
+        ("LDDR", []) => plain(Plain::Lddr),
+        ("JP", [target]) if target.starts_with('(') => {
+            Inst::JpInd(parse_reg16(&target[1..target.len() - 1]).unwrap())
+        }
+        ("JP", [target]) => Inst::Jp(None, parse_expr(target)),
+        ("JP", [c, target]) => Inst::Jp(Some(cond(c)), parse_expr(target)),
+        ("JR", [target]) => Inst::Jr(None, parse_expr(target)),
+        ("JR", [c, target]) => Inst::Jr(Some(cond(c)), parse_expr(target)),
+        ("DJNZ", [target]) => Inst::Djnz(parse_expr(target)),
+        ("CALL", [target]) => Inst::Call(None, parse_expr(target)),
+        ("CALL", [c, target]) => Inst::Call(Some(cond(c)), parse_expr(target)),
+        ("RET", []) => Inst::Ret(None),
+        ("RET", [c]) => Inst::Ret(Some(cond(c))),
+        ("RST", [vector]) => Inst::Rst(parse_expr(vector).value().unwrap() as u8),
+        ("MLT", [reg]) => Inst::Mlt(parse_reg16(reg).unwrap()),
+        ("LEA", [dst, src]) => match parse_operand(&format!("({})", src)) {
+            Operand::Idx(reg, offset) => Inst::Lea(parse_reg16(dst).unwrap(), reg, offset as i8),
+            _ => panic!("cannot parse instruction: {}", line),
+        },
+        ("CALL.IS", [target]) => Inst::CallIs(parse_expr(target)),
+        ("RET.L", []) => Inst::RetL,
+        ("RST.LIL", [vector]) => Inst::RstLil(parse_expr(vector).value().unwrap() as u8),
+        ("OUT0", [port, reg]) => Inst::Out0(
+            parse_expr(&port[1..port.len() - 1]).value().unwrap() as u8,
+            parse_reg8(reg).unwrap(),
+        ),
+        ("MULUW", ["HL", reg]) => Inst::Muluw(parse_reg16(reg).unwrap()),
+        _ => panic!("cannot parse instruction: {}", line),
+    }
+}
+
+fn parse_alu(mnemonic: &str) -> Alu {
+    match mnemonic {
+        "ADD" => Alu::Add,
+        "ADC" => Alu::Adc,
+        "SUB" => Alu::Sub,
+        "SBC" => Alu::Sbc,
+        "AND" => Alu::And,
+        "XOR" => Alu::Xor,
+        "OR" => Alu::Or,
+        _ => Alu::Cp,
+    }
+}
+
+fn parse_cond(name: &str) -> Cond {
+    match name {
+        "NZ" => Cond::Nz,
+        "Z" => Cond::Z,
+        "NC" => Cond::Nc,
+        "C" => Cond::C,
+        "PO" => Cond::Po,
+        "PE" => Cond::Pe,
+        "P" => Cond::P,
+        "M" => Cond::M,
+        _ => panic!("unknown condition {}", name),
+    }
+}
+
+fn parse_reg8(name: &str) -> Option<Reg8> {
+    Some(match name {
+        "A" => Reg8::A,
+        "B" => Reg8::B,
+        "C" => Reg8::C,
+        "D" => Reg8::D,
+        "E" => Reg8::E,
+        "H" => Reg8::H,
+        "L" => Reg8::L,
+        _ => return None,
+    })
+}
+
+fn parse_reg16(name: &str) -> Option<Reg16> {
+    Some(match name {
+        "BC" => Reg16::BC,
+        "DE" => Reg16::DE,
+        "HL" => Reg16::HL,
+        "SP" => Reg16::SP,
+        "AF" => Reg16::AF,
+        "IX" => Reg16::IX,
+        "IY" => Reg16::IY,
+        _ => return None,
+    })
+}
+
+/// Parses an `LD` with a suffix, taking the operand the suffix is for as
+/// long the way [`suffix`] does: the address for `SIL` and `LIL`, and for
+/// `LIS` the indexed memory, or else the pair.
+fn parse_long_ld(mnemonic: &str, dst: &str, src: &str) -> Inst {
+    let (dst, src) = (parse_operand(dst), parse_operand(src));
+    let is_long = |operand: &Operand, other: &Operand| match (mnemonic, operand) {
+        ("LD.LIS", Operand::Idx(..)) => true,
+        ("LD.LIS", Operand::Reg16(_)) => !matches!(other, Operand::Idx(..)),
+        ("LD.LIS", _) => false,
+        (_, operand) => matches!(operand, Operand::Mem(_)),
+    };
+    if is_long(&dst, &src) {
+        Inst::Ld(long(dst), src)
+    } else {
+        Inst::Ld(dst, long(src))
+    }
+}
+
+fn parse_operand(text: &str) -> Operand {
+    if let Some(reg) = parse_reg8(text) {
+        return Operand::Reg8(reg);
+    }
+    if let Some(reg) = parse_reg16(text) {
+        return Operand::Reg16(reg);
+    }
+    match text {
+        "(HL+)" => return Operand::HlInc,
+        "(HL-)" => return Operand::HlDec,
+        _ => {}
+    }
+    if let Some(offset) = text.strip_prefix("SP+") {
+        return Operand::SpOffset(parse_number(offset).unwrap() as i8);
+    }
+    if let Some(inner) = text
+        .strip_prefix('(')
+        .and_then(|text| text.strip_suffix(')'))
+    {
+        if let Some(reg) = parse_reg16(inner) {
+            return match reg {
+                Reg16::IX | Reg16::IY => Operand::Idx(reg, 0),
+                _ => Operand::Ind(reg),
+            };
+        }
+        if let Some(reg) =
+            parse_reg16(&inner[..2]).filter(|reg| matches!(reg, Reg16::IX | Reg16::IY))
+        {
+            let offset = parse_expr(&inner[2..]).value().unwrap();
+            return Operand::Idx(reg, offset as i16);
+        }
+        return Operand::Mem(parse_expr(inner));
+    }
+    Operand::Imm(parse_expr(text))
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    match text.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+fn parse_expr(text: &str) -> Expr {
+    let text = text.trim();
+    if let Some(value) = parse_number(text) {
+        return Expr::from(value);
+    }
+    // An optional leading minus belongs to the number, not an operator.
+    match text[1..].find(['+', '-']) {
+        Some(pos) => {
+            let (symbol, offset) = text.split_at(pos + 1);
+            let offset = match offset.strip_prefix('+') {
+                Some(offset) => parse_number(offset).unwrap(),
+                None => -parse_number(&offset[1..]).unwrap(),
+            };
+            Expr::sym(symbol).plus(offset)
+        }
+        None => Expr::sym(text),
+    }
+}
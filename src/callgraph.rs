@@ -0,0 +1,28 @@
+use crate::ir::{Function, Inst};
+
+/// Returns the functions `func` calls directly.
+pub fn callees(func: &Function) -> Vec<usize> {
+    let calls = func.body.iter().filter_map(|inst| match inst {
+        Inst::Call { func, .. } => Some(*func as usize),
+        _ => None,
+    });
+    calls.collect()
+}
+
+/// Returns for each function whether it can be called, directly or through
+/// others, from the `roots`.
+///
+/// Calls through tables aren't followed, so every function in a table has to
+/// be among the roots.
+pub fn reachable(funcs: &[Function], roots: impl IntoIterator<Item = usize>) -> Vec<bool> {
+    let mut reached = vec![false; funcs.len()];
+    let mut pending: Vec<usize> = roots.into_iter().collect();
+    while let Some(index) = pending.pop() {
+        if reached[index] {
+            continue;
+        }
+        reached[index] = true;
+        pending.extend(callees(&funcs[index]));
+    }
+    reached
+}
@@ -1,537 +1,1910 @@
 use std::fmt::{self, Display, Formatter};
-use std::io::Write;
 
-use wasmparser::{BlockType, FuncType, FunctionBody, Operator};
+use anyhow::{bail, Result};
+use wasmparser::{FuncType, FunctionBody, ValType};
+
+use crate::agon;
+use crate::asm::{self, idx, imm, ind, mem, Expr, Inst, Plain, Reg16::*, Reg8::*, Syntax};
+use crate::callgraph;
+use crate::countdown;
+use crate::cpm;
+use crate::encode;
+use crate::ez80;
+use crate::i8080;
+use crate::inline;
+use crate::ir::{self, BinaryOp, CompareOp, UnaryOp};
+use crate::msx;
+use crate::narrow::{self, Narrow};
+use crate::peephole;
+use crate::regalloc::{self, high, low, Cached, Regs};
+use crate::relax;
+use crate::runtime::{self, Helper, Runtime};
+use crate::sm83;
+use crate::spectrum;
+use crate::trap::{self, Trap};
+use crate::z180;
 
 pub struct FunctionDef<'a> {
     pub func_type: FuncType,
-    pub body: FunctionBody<'a>,
+    pub code: Code<'a>,
+}
+
+pub enum Code<'a> {
+    Body(FunctionBody<'a>),
+    /// An imported function, which the platform provides.
+    Import(Import),
+}
+
+/// Module and name a function is imported under.
+#[derive(Clone, Debug)]
+pub struct Import {
+    pub module: String,
+    pub name: String,
+}
+
+impl std::fmt::Display for Import {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.module, self.name)
+    }
+}
+
+/// A routine a platform provides for functions imported from `console`
+/// under `name`, with the type they must be imported with.
+///
+/// Its body finds the character an i32 argument holds in `E` and leaves the
+/// one an i32 result holds in `A`, and returns by running off its end.
+pub struct Provided {
+    pub name: &'static str,
+    pub params: &'static [ValType],
+    pub results: &'static [ValType],
+    pub body: &'static str,
+}
+
+/// A function table, with the function index stored in each slot.
+pub struct Table {
+    pub elements: Vec<Option<u32>>,
+}
+
+/// An active data segment, copied to its place in linear memory before the
+/// program starts.
+pub struct Data<'a> {
+    pub offset: usize,
+    pub bytes: &'a [u8],
 }
 
 pub struct Module<'a> {
     pub entry: usize,
+    /// Function run before the entry, as Wasm runs it on instantiation.
+    pub start: Option<usize>,
+    /// Exported functions, the entry among them.
+    pub exports: Vec<usize>,
+    pub types: Vec<FuncType>,
     pub functions: Vec<FunctionDef<'a>>,
+    pub tables: Vec<Table>,
+    /// Number of globals, which are kept after the trap record.
+    pub globals: usize,
+    pub data: Vec<Data<'a>>,
 }
 
-impl<'a> Module<'a> {
-    pub fn compile(&self, out: &mut Vec<u8>) {
-        let mut labeler = Labeler::new();
-        writeln!(out, "LD SP,0xFFE8").unwrap();
-        /*
-        writeln!(out, "LD IX,8096").unwrap();
-        writeln!(out, "LD (0xFFF2),IX").unwrap();
-        writeln!(out, "LD (0xFFF6),IX").unwrap();
-        writeln!(out, "LD (0xFFFA),IX").unwrap();
-        writeln!(out, "LD IX,0").unwrap();
-        writeln!(out, "LD (0xFFF0),IX").unwrap();
-        writeln!(out, "LD (0xFFF4),IX").unwrap();
-        writeln!(out, "LD (0xFFF8),IX").unwrap();
-        */
-
-        let def = &self.functions[self.entry];
-        let num_locals = def.body.get_locals_reader().unwrap().get_count();
-        let params = def.func_type.params();
-        let results = def.func_type.results();
-        writeln!(out, "  ; call").unwrap();
-        writeln!(out, "  LD BC,0").unwrap();
-        for _ in 0..num_locals {
-            writeln!(out, "  PUSH BC").unwrap();
-            writeln!(out, "  PUSH BC").unwrap();
-        }
-        writeln!(out, "  PUSH IY").unwrap();
-        writeln!(out, "  CALL func_{}", self.entry).unwrap();
-        if results.len() > 0 {
-            writeln!(out, "  POP DE").unwrap();
-            writeln!(out, "  POP BC").unwrap();
-        }
-        writeln!(out, "  POP IY").unwrap();
-        for _ in 0..(params.len() + num_locals as usize) {
-            writeln!(out, "  POP BC").unwrap();
-            writeln!(out, "  POP BC").unwrap();
-        }
-        if results.len() > 0 {
-            writeln!(out, "  PUSH BC").unwrap();
-            writeln!(out, "  PUSH DE").unwrap();
-        }
-
-        writeln!(out, "HALT").unwrap();
-        for (index, func) in self.functions.iter().enumerate() {
-            writeln!(out, "func_{}:", index).unwrap();
-            self.compile_function(out, &mut labeler, func);
-        }
-    }
-
-    fn compile_function(&self, out: &mut Vec<u8>, labeler: &mut Labeler, def: &FunctionDef) {
-        assert!(def.func_type.results().len() <= 1);
-        let params = def.func_type.params();
-        let num_locals: usize = def
-            .body
-            .get_locals_reader()
-            .unwrap()
-            .into_iter()
-            .map(|result| result.unwrap())
-            .map(|(amt, _ty)| amt as usize)
-            .sum();
-        let has_result = def.func_type.results().len() == 1;
-        let operators = def.body.get_operators_reader().unwrap();
-        let mut label_stack: Vec<Label> = vec![];
-        let mut end_stack: Vec<Option<Label>> = vec![];
-        writeln!(out, "  LD IY,0").unwrap();
-        writeln!(out, "  ADD IY,SP").unwrap();
-        for op in operators {
-            let op = op.unwrap();
-            match op {
-                Operator::LocalGet { local_index } => {
-                    let d = (num_locals + params.len()) * 4 - local_index as usize * 4;
-                    writeln!(out, "  ; local.get {}", local_index).unwrap();
-                    writeln!(out, "  LD E,(IY+{})", d + 2).unwrap();
-                    writeln!(out, "  LD D,(IY+{})", d + 3).unwrap();
-                    writeln!(out, "  PUSH DE").unwrap();
-                    writeln!(out, "  LD E,(IY+{})", d + 0).unwrap();
-                    writeln!(out, "  LD D,(IY+{})", d + 1).unwrap();
-                    writeln!(out, "  PUSH DE").unwrap();
-                }
-                Operator::LocalSet { local_index } => {
-                    let d = (num_locals + params.len()) * 4 - local_index as usize * 4;
-                    writeln!(out, "  ; local.set {}", local_index).unwrap();
-                    writeln!(out, "  POP DE").unwrap();
-                    writeln!(out, "  LD (IY+{}),E", d + 0).unwrap();
-                    writeln!(out, "  LD (IY+{}),D", d + 1).unwrap();
-                    writeln!(out, "  POP DE").unwrap();
-                    writeln!(out, "  LD (IY+{}),E", d + 2).unwrap();
-                    writeln!(out, "  LD (IY+{}),D", d + 3).unwrap();
-                }
-                Operator::LocalTee { local_index } => {
-                    let d = (num_locals + params.len()) * 4 - local_index as usize * 4;
-                    writeln!(out, "  ; local.tee {}", local_index).unwrap();
-                    writeln!(out, "  LD IX,0").unwrap();
-                    writeln!(out, "  ADD IX,SP").unwrap();
-                    writeln!(out, "  LD A,(IX+0)").unwrap();
-                    writeln!(out, "  LD (IY+{}),A", d + 0).unwrap();
-                    writeln!(out, "  LD A,(IX+1)").unwrap();
-                    writeln!(out, "  LD (IY+{}),A", d + 1).unwrap();
-                    writeln!(out, "  LD A,(IX+2)").unwrap();
-                    writeln!(out, "  LD (IY+{}),A", d + 2).unwrap();
-                    writeln!(out, "  LD A,(IX+3)").unwrap();
-                    writeln!(out, "  LD (IY+{}),A", d + 3).unwrap();
-                }
-                Operator::GlobalGet { global_index } => {
-                    let addr = 0xFFF8 - global_index * 4;
-                    writeln!(out, "  ; global.get {}", global_index).unwrap();
-                    writeln!(out, "  LD IX,{addr}").unwrap();
-                    writeln!(out, "  LD E,(IX+{})", 2).unwrap();
-                    writeln!(out, "  LD D,(IX+{})", 3).unwrap();
-                    writeln!(out, "  PUSH DE").unwrap();
-                    writeln!(out, "  LD E,(IX+{})", 0).unwrap();
-                    writeln!(out, "  LD D,(IX+{})", 1).unwrap();
-                    writeln!(out, "  PUSH DE").unwrap();
-                }
-                Operator::GlobalSet { global_index } => {
-                    let addr = 0xFFF8 - global_index * 4;
-                    writeln!(out, "  ; global.set {}", global_index).unwrap();
-                    writeln!(out, "  LD IX,{addr}").unwrap();
-                    writeln!(out, "  POP DE").unwrap();
-                    writeln!(out, "  LD (IX+{}),E", 0).unwrap();
-                    writeln!(out, "  LD (IX+{}),D", 1).unwrap();
-                    writeln!(out, "  POP DE").unwrap();
-                    writeln!(out, "  LD (IX+{}),E", 2).unwrap();
-                    writeln!(out, "  LD (IX+{}),D", 3).unwrap();
-                }
-                Operator::I32Const { value } => {
-                    let lower = value as u16;
-                    let upper = (value >> 16) as u16;
-                    writeln!(out, "  ; i32.const").unwrap();
-                    writeln!(out, "  LD DE,{lower}").unwrap();
-                    writeln!(out, "  PUSH DE").unwrap();
-                    writeln!(out, "  LD DE,{upper}").unwrap();
-                    writeln!(out, "  PUSH DE").unwrap();
-                }
-                Operator::I32Store8 { memarg } => {
-                    let offset = memarg.offset;
-                    writeln!(out, "  ; i32.store8").unwrap();
-                    writeln!(out, "  POP DE").unwrap();
-                    writeln!(out, "  POP DE").unwrap();
-                    writeln!(out, "  POP IX").unwrap();
-                    writeln!(out, "  POP IX").unwrap();
-                    writeln!(out, "  LD BC,{offset}").unwrap();
-                    writeln!(out, "  ADD IX,BC").unwrap();
-                    writeln!(out, "  LD (IX+0),E").unwrap();
-                }
-                Operator::I32Store { memarg } => {
-                    let offset = memarg.offset;
-                    writeln!(out, "  ; i32.store").unwrap();
-                    writeln!(out, "  POP DE").unwrap();
-                    writeln!(out, "  POP HL").unwrap();
-                    writeln!(out, "  POP IX").unwrap();
-                    writeln!(out, "  POP IX").unwrap();
-                    writeln!(out, "  LD BC,{offset}").unwrap();
-                    writeln!(out, "  ADD IX,BC").unwrap();
-                    writeln!(out, "  LD (IX+0),L").unwrap();
-                    writeln!(out, "  LD (IX+1),H").unwrap();
-                    writeln!(out, "  LD (IX+2),E").unwrap();
-                    writeln!(out, "  LD (IX+3),D").unwrap();
-                }
-                Operator::I32Load { memarg } => {
-                    let offset = memarg.offset;
-                    writeln!(out, "  ; i32.load").unwrap();
-                    writeln!(out, "  POP IX").unwrap();
-                    writeln!(out, "  POP IX").unwrap();
-                    writeln!(out, "  LD BC,{offset}").unwrap();
-                    writeln!(out, "  ADD IX,BC").unwrap();
-                    writeln!(out, "  LD C,(IX+0)").unwrap();
-                    writeln!(out, "  LD B,(IX+1)").unwrap();
-                    writeln!(out, "  PUSH BC").unwrap();
-                    writeln!(out, "  LD C,(IX+2)").unwrap();
-                    writeln!(out, "  LD B,(IX+3)").unwrap();
-                    writeln!(out, "  PUSH BC").unwrap();
-                }
-                Operator::I32Load8U { memarg } => {
-                    let offset = memarg.offset;
-                    writeln!(out, "  ; i32.load8_u").unwrap();
-                    writeln!(out, "  POP IX").unwrap();
-                    writeln!(out, "  POP IX").unwrap();
-                    writeln!(out, "  LD BC,{offset}").unwrap();
-                    writeln!(out, "  ADD IX,BC").unwrap();
-                    writeln!(out, "  LD E,(IX+0)").unwrap();
-                    writeln!(out, "  LD D,0").unwrap();
-                    writeln!(out, "  PUSH DE").unwrap();
-                    writeln!(out, "  LD E,0").unwrap();
-                    writeln!(out, "  PUSH DE").unwrap();
-                }
-                Operator::I32Eqz => {
-                    let zero = labeler.next();
-                    let nonzero = labeler.next();
-                    writeln!(out, "  ; i32.eqz").unwrap();
-                    writeln!(out, "  POP DE").unwrap();
-                    writeln!(out, "  LD A,D").unwrap();
-                    writeln!(out, "  OR E").unwrap();
-                    writeln!(out, "  POP DE").unwrap();
-                    writeln!(out, "  OR D").unwrap();
-                    writeln!(out, "  OR E").unwrap();
-                    writeln!(out, "  JR Z,{zero}").unwrap();
-                    writeln!(out, "  LD DE,0").unwrap();
-                    writeln!(out, "  PUSH DE").unwrap();
-                    writeln!(out, "  JR {nonzero}").unwrap();
-                    writeln!(out, "{zero}:").unwrap();
-                    writeln!(out, "  LD DE,1").unwrap();
-                    writeln!(out, "  PUSH DE").unwrap();
-                    writeln!(out, "{nonzero}:").unwrap();
-                    writeln!(out, "  LD E,0").unwrap();
-                    writeln!(out, "  PUSH DE").unwrap();
-                }
-                Operator::I32Add => {
-                    writeln!(out, "  ; i32.add").unwrap();
-                    writeln!(out, "  POP DE").unwrap();
-                    writeln!(out, "  POP BC").unwrap();
-                    writeln!(out, "  POP IX").unwrap();
-                    writeln!(out, "  POP HL").unwrap();
-
-                    writeln!(out, "  AND A").unwrap();
-                    writeln!(out, "  ADD HL,BC").unwrap();
-                    writeln!(out, "  PUSH HL").unwrap();
-
-                    writeln!(out, "  EX DE,HL").unwrap();
-                    writeln!(out, "  PUSH IX").unwrap();
-                    writeln!(out, "  POP DE").unwrap();
-                    writeln!(out, "  ADC HL,DE").unwrap();
-                    writeln!(out, "  PUSH HL").unwrap();
-                }
-                Operator::I32Sub => {
-                    writeln!(out, "  ; i32.sub").unwrap();
-                    writeln!(out, "  POP IX").unwrap();
-                    writeln!(out, "  POP HL").unwrap();
-                    writeln!(out, "  POP DE").unwrap();
-                    writeln!(out, "  POP BC").unwrap();
-
-                    writeln!(out, "  AND A").unwrap();
-                    writeln!(out, "  SBC HL,BC").unwrap();
-                    writeln!(out, "  PUSH HL").unwrap();
-                    writeln!(out, "  PUSH IX").unwrap();
-                    writeln!(out, "  POP HL").unwrap();
-                    writeln!(out, "  SBC HL,DE").unwrap();
-                    writeln!(out, "  PUSH HL").unwrap();
-                }
-                Operator::I32And => {
-                    writeln!(out, "  ; i32.and").unwrap();
-                    writeln!(out, "  POP DE").unwrap();
-                    writeln!(out, "  POP BC").unwrap();
-                    writeln!(out, "  POP IX").unwrap();
-                    writeln!(out, "  POP HL").unwrap();
-
-                    writeln!(out, "  LD A,L").unwrap();
-                    writeln!(out, "  AND C").unwrap();
-                    writeln!(out, "  LD L,A").unwrap();
-                    writeln!(out, "  LD A,H").unwrap();
-                    writeln!(out, "  AND B").unwrap();
-                    writeln!(out, "  LD H,A").unwrap();
-                    writeln!(out, "  PUSH HL").unwrap();
-
-                    writeln!(out, "  PUSH IX").unwrap();
-                    writeln!(out, "  POP HL").unwrap();
-                    writeln!(out, "  LD A,L").unwrap();
-                    writeln!(out, "  AND E").unwrap();
-                    writeln!(out, "  LD L,A").unwrap();
-                    writeln!(out, "  LD A,H").unwrap();
-                    writeln!(out, "  AND D").unwrap();
-                    writeln!(out, "  LD H,A").unwrap();
-                    writeln!(out, "  PUSH HL").unwrap();
-                }
-                Operator::I32GtU => {
-                    let gt = labeler.next();
-                    let after = labeler.next();
+#[derive(Default)]
+pub struct Config {
+    /// Label jumped to after a trap is recorded, instead of halting.
+    pub trap_handler: Option<String>,
+    /// Whether runtime routines use lookup tables, trading size for speed.
+    pub lookup_tables: bool,
+    /// Assembler syntax of the output.
+    pub syntax: Syntax,
+    /// Processor the code runs on.
+    pub target: Target,
+    /// System the code runs on.
+    pub platform: Platform,
+    /// Form of the output.
+    pub format: Format,
+    /// Index register functions address their frames with.
+    pub frame_pointer: FramePointer,
+    /// Optimizations to run.
+    pub passes: Passes,
+    /// Functions compiled even if unreachable from the exports, the start
+    /// function and the tables.
+    pub keep: Vec<u32>,
+    /// How the Z180's MMU is set up.
+    pub mmu: z180::Mmu,
+}
 
-                    writeln!(out, "  ; i32.gt_u").unwrap();
-                    writeln!(out, "  POP IX").unwrap();
-                    writeln!(out, "  POP HL").unwrap();
-                    writeln!(out, "  POP DE").unwrap();
-                    writeln!(out, "  POP BC").unwrap();
-
-                    writeln!(out, "  AND A").unwrap();
-                    writeln!(out, "  SBC HL,BC").unwrap();
-                    writeln!(out, "  LD B,H").unwrap();
-                    writeln!(out, "  LD C,L").unwrap();
-                    writeln!(out, "  PUSH IX").unwrap();
-                    writeln!(out, "  POP HL").unwrap();
-                    writeln!(out, "  SBC HL,DE").unwrap();
-
-                    writeln!(out, "  JR C,{gt}").unwrap();
-                    writeln!(out, "  LD HL,0").unwrap();
-                    writeln!(out, "  PUSH HL").unwrap();
-                    writeln!(out, "  JR {after}").unwrap();
-                    writeln!(out, "{gt}:").unwrap();
-                    writeln!(out, "  LD HL,1").unwrap();
-                    writeln!(out, "  PUSH HL").unwrap();
-                    writeln!(out, "{after}:").unwrap();
-                    writeln!(out, "  LD HL,0").unwrap();
-                    writeln!(out, "  PUSH HL").unwrap();
-                }
-                Operator::I32GtS => {
-                    let gt = labeler.next();
-                    let after = labeler.next();
+/// Processor to generate code for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Target {
+    #[default]
+    Z80,
+    /// The Intel 8080 and 8085, running Z80 code rewritten into the
+    /// instructions they share, with the rest done by routines.
+    #[value(name = "8080")]
+    I8080,
+    /// The Game Boy's Sharp SM83, running Z80 code rewritten like for the
+    /// 8080. The stack is at the top of work RAM and linear memory addresses
+    /// are used as they are, so data belongs in work RAM from 0xC000.
+    Sm83,
+    /// The eZ80 in Z80 mode, with code, stack and globals in the 64 KiB
+    /// segment MBASE selects and i32 values in 16-bit pairs as on the Z80.
+    /// Linear memory is addressed with 24 bits, reaching all 16 MiB through
+    /// long loads and stores, and multiplication uses `MLT`.
+    Ez80,
+    /// The Z180, multiplying with `MLT`. Linear memory is addressed with 20
+    /// bits, reaching all 1 MiB through a window of the MMU's bank area,
+    /// which code must end below, at 0xD000 unless configured otherwise. The
+    /// stack, scratch buffer and globals are in common area 1 above it.
+    Z180,
+    /// The R800 of the MSX turbo R, multiplying with `MULUW`.
+    R800,
+}
 
-                    writeln!(out, "  ; i32.gt_u").unwrap();
-                    writeln!(out, "  POP IX").unwrap();
-                    writeln!(out, "  POP HL").unwrap();
-                    writeln!(out, "  POP DE").unwrap();
-                    writeln!(out, "  POP BC").unwrap();
-
-                    writeln!(out, "  AND A").unwrap();
-                    writeln!(out, "  SBC HL,BC").unwrap();
-                    writeln!(out, "  LD B,H").unwrap();
-                    writeln!(out, "  LD C,L").unwrap();
-                    writeln!(out, "  PUSH IX").unwrap();
-                    writeln!(out, "  POP HL").unwrap();
-                    writeln!(out, "  SBC HL,DE").unwrap();
-
-                    writeln!(out, "  JR C,{gt}").unwrap();
-                    writeln!(out, "  LD HL,0").unwrap();
-                    writeln!(out, "  PUSH HL").unwrap();
-                    writeln!(out, "  JR {after}").unwrap();
-                    writeln!(out, "{gt}:").unwrap();
-                    writeln!(out, "  LD HL,1").unwrap();
-                    writeln!(out, "  PUSH HL").unwrap();
-                    writeln!(out, "{after}:").unwrap();
-                    writeln!(out, "  LD HL,0").unwrap();
-                    writeln!(out, "  PUSH HL").unwrap();
-                }
-                Operator::I32LtU => {
-                    let gt = labeler.next();
-                    let after = labeler.next();
+impl Target {
+    /// Bytes of an address into linear memory.
+    fn address_size(self) -> usize {
+        match self {
+            Target::Ez80 | Target::Z180 => 3,
+            _ => 2,
+        }
+    }
 
-                    writeln!(out, "  ; i32.lt_u").unwrap();
-                    writeln!(out, "  POP DE").unwrap();
-                    writeln!(out, "  POP BC").unwrap();
-                    writeln!(out, "  POP IX").unwrap();
-                    writeln!(out, "  POP HL").unwrap();
-
-                    writeln!(out, "  AND A").unwrap();
-                    writeln!(out, "  SBC HL,BC").unwrap();
-                    writeln!(out, "  LD B,H").unwrap();
-                    writeln!(out, "  LD C,L").unwrap();
-                    writeln!(out, "  PUSH IX").unwrap();
-                    writeln!(out, "  POP HL").unwrap();
-                    writeln!(out, "  SBC HL,DE").unwrap();
-
-                    writeln!(out, "  JR C,{gt}").unwrap();
-                    writeln!(out, "  LD HL,0").unwrap();
-                    writeln!(out, "  PUSH HL").unwrap();
-                    writeln!(out, "  JR {after}").unwrap();
-                    writeln!(out, "{gt}:").unwrap();
-                    writeln!(out, "  LD HL,1").unwrap();
-                    writeln!(out, "  PUSH HL").unwrap();
-                    writeln!(out, "{after}:").unwrap();
-                    writeln!(out, "  LD HL,0").unwrap();
-                    writeln!(out, "  PUSH HL").unwrap();
-                }
-                Operator::I32GeU => {
-                    let gt = labeler.next();
-                    let after = labeler.next();
+    /// Bytes of linear memory the processor reaches.
+    fn memory_size(self) -> usize {
+        match self {
+            Target::Ez80 => 1 << 24,
+            Target::Z180 => 1 << 20,
+            _ => 1 << 16,
+        }
+    }
+}
 
-                    writeln!(out, "  ; i32.lt_u").unwrap();
-                    writeln!(out, "  POP DE").unwrap();
-                    writeln!(out, "  POP BC").unwrap();
-                    writeln!(out, "  POP IX").unwrap();
-                    writeln!(out, "  POP HL").unwrap();
-
-                    writeln!(out, "  AND A").unwrap();
-                    writeln!(out, "  SBC HL,BC").unwrap();
-                    writeln!(out, "  LD B,H").unwrap();
-                    writeln!(out, "  LD C,L").unwrap();
-                    writeln!(out, "  PUSH IX").unwrap();
-                    writeln!(out, "  POP HL").unwrap();
-                    writeln!(out, "  SBC HL,DE").unwrap();
-
-                    writeln!(out, "  JR C,{gt}").unwrap();
-                    writeln!(out, "  LD HL,1").unwrap();
-                    writeln!(out, "  PUSH HL").unwrap();
-                    writeln!(out, "  JR {after}").unwrap();
-                    writeln!(out, "{gt}:").unwrap();
-                    writeln!(out, "  LD HL,0").unwrap();
-                    writeln!(out, "  PUSH HL").unwrap();
-                    writeln!(out, "{after}:").unwrap();
-                    writeln!(out, "  LD HL,0").unwrap();
-                    writeln!(out, "  PUSH HL").unwrap();
-                }
-                Operator::I32Ne => {
-                    let ne = labeler.next();
-                    let after = labeler.next();
-                    writeln!(out, "  ; i32.ne").unwrap();
-                    writeln!(out, "  POP IX").unwrap();
-                    writeln!(out, "  POP HL").unwrap();
-                    writeln!(out, "  POP DE").unwrap();
-                    writeln!(out, "  POP BC").unwrap();
-
-                    writeln!(out, "  AND A").unwrap();
-                    writeln!(out, "  SBC HL,BC").unwrap();
-                    writeln!(out, "  JR NZ,{ne}").unwrap();
-
-                    writeln!(out, "  LD B,H").unwrap();
-                    writeln!(out, "  LD C,L").unwrap();
-                    writeln!(out, "  PUSH IX").unwrap();
-                    writeln!(out, "  POP HL").unwrap();
-                    writeln!(out, "  SBC HL,DE").unwrap();
-
-                    writeln!(out, "  JR NZ,{ne}").unwrap();
-                    writeln!(out, "  LD HL,0").unwrap();
-                    writeln!(out, "  PUSH HL").unwrap();
-                    writeln!(out, "  JR {after}").unwrap();
-                    writeln!(out, "{ne}:").unwrap();
-                    writeln!(out, "  LD HL,1").unwrap();
-                    writeln!(out, "  PUSH HL").unwrap();
-                    writeln!(out, "{after}:").unwrap();
-                    writeln!(out, "  LD HL,0").unwrap();
-                    writeln!(out, "  PUSH HL").unwrap();
-                }
-                Operator::Select => {
+/// System the program runs on, deciding where it is loaded, how it starts
+/// and exits, and what its imports do.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Platform {
+    /// The program alone in memory: loaded at 0 with the stack, scratch
+    /// buffer, trap record and up to 12 globals at the top, halting when
+    /// done, and with a console at 0xFFFD to 0xFFFF instead of imports.
+    #[default]
+    Bare,
+    /// A CP/M `.COM` program, loaded at 0x100 with the stack below the BDOS
+    /// and returning to CP/M when done. The `console` imports `putchar`,
+    /// `getchar` and `print` call BDOS functions 2, 1 and 9. Linear memory
+    /// addresses are used as they are, so data belongs in the TPA above the
+    /// program.
+    Cpm,
+    /// A ZX Spectrum program, loaded at 0x8000 by a BASIC loader and
+    /// returning to BASIC when done. IX is the frame pointer whatever is
+    /// chosen, since the ROM's interrupt handler relies on IY, and the
+    /// `console` import `putchar` prints with `RST 0x10`. Linear memory
+    /// addresses are used as they are, so data belongs above the program.
+    Spectrum,
+    /// An MSX cartridge, started by the BIOS from page 1 with the data
+    /// block in the RAM of page 3 at 0xC000, and stopping when done. The
+    /// `console` imports `putchar` and `getchar` call `CHPUT` and `CHGET`.
+    /// Linear memory addresses are used as they are, so data belongs in
+    /// page 3 above the data block.
+    Msx,
+    /// An Agon Light MOS executable for the eZ80, loaded at 0x040000 and
+    /// entered in ADL mode, which switches to Z80 mode with MBASE at 0x04
+    /// for the program and back to return to MOS when done. The stack,
+    /// scratch buffer, trap record and up to 12 globals are at the top of
+    /// the program's segment, and the `console` imports `putchar` and
+    /// `getchar` call MOS. Linear memory addresses are used as they are, so
+    /// data belongs in the RAM above the program's segment, from 0x050000 to
+    /// 0x0B0000.
+    Agon,
+}
+
+impl Platform {
+    /// Returns whether the system can have `target` for a processor. The
+    /// Z180's MMU and the SM83's memory map are only set up bare.
+    pub fn runs(self, target: Target) -> bool {
+        match self {
+            Platform::Bare => true,
+            Platform::Cpm => !matches!(target, Target::Z180 | Target::Sm83),
+            Platform::Spectrum => target == Target::Z80,
+            Platform::Msx => matches!(target, Target::Z80 | Target::R800),
+            Platform::Agon => target == Target::Ez80,
+        }
+    }
+
+    /// Address the program is loaded at.
+    fn origin(self) -> u16 {
+        match self {
+            Platform::Bare | Platform::Agon => 0,
+            Platform::Cpm => 0x100,
+            Platform::Spectrum => spectrum::ORIGIN,
+            Platform::Msx => msx::CODE_ADDR,
+        }
+    }
+
+    /// Returns whether data segments can be copied to the `size` bytes of
+    /// linear memory at `addr` for `config`, for a program whose image ends
+    /// at `end`. The data is copied at startup, so it must leave the image
+    /// alone, and be in RAM the system leaves to the program.
+    fn holds_data(self, config: &Config, end: usize, addr: usize, size: usize) -> bool {
+        match self {
+            Platform::Bare if config.target == Target::Sm83 => {
+                0xC000 <= addr && addr + size <= usize::from(sm83::STACK_TOP)
+            }
+            Platform::Bare => {
+                let (floor, top) = bare_stack(config);
+                end <= addr && (addr + size <= floor || top <= addr)
+            }
+            // Below the program on the Spectrum are the screen, the system
+            // variables and BASIC.
+            Platform::Cpm | Platform::Spectrum => end <= addr,
+            Platform::Msx => msx::is_ram(addr, size),
+            Platform::Agon => agon::is_ram(addr, size),
+        }
+    }
+
+    /// Describes where [`Platform::holds_data`] allows data.
+    fn data_place(self, config: &Config, end: usize) -> String {
+        match self {
+            Platform::Bare if config.target == Target::Sm83 => {
+                "in work RAM, from 0xC000 to 0xE000".into()
+            }
+            Platform::Bare => {
+                let (floor, top) = bare_stack(config);
+                format!(
+                    "above the program, which ends at 0x{end:04X}, and outside the stack and runtime data from 0x{floor:04X} to 0x{top:04X}"
+                )
+            }
+            Platform::Cpm | Platform::Spectrum => {
+                format!("above the program, which ends at 0x{end:04X}")
+            }
+            Platform::Msx => "in page 3, between the data block and the system work area".into(),
+            Platform::Agon => "in the RAM from 0x050000 to 0x0B0000".into(),
+        }
+    }
+
+    /// Address of the scratch buffer, which the trap record and the globals
+    /// follow and the 8080's registers in memory precede.
+    fn scratch(self) -> Expr {
+        match self {
+            Platform::Bare | Platform::Agon => i64::from(runtime::SCRATCH_ADDR).into(),
+            Platform::Cpm => Expr::sym("rt_data").plus(cpm::SCRATCH_OFFSET),
+            Platform::Spectrum => Expr::sym("rt_data"),
+            Platform::Msx => i64::from(msx::DATA_ADDR).into(),
+        }
+    }
+
+    /// Number of globals there is room for after the trap record.
+    fn max_globals(self) -> usize {
+        match self {
+            Platform::Bare | Platform::Agon => runtime::MAX_GLOBALS,
+            Platform::Cpm => cpm::MAX_GLOBALS,
+            Platform::Spectrum => spectrum::MAX_GLOBALS,
+            Platform::Msx => msx::MAX_GLOBALS,
+        }
+    }
+
+    /// Instruction ending the program.
+    fn exit(self) -> Inst {
+        match self {
+            Platform::Bare => Inst::Plain(Plain::Halt),
+            Platform::Cpm => Inst::Jp(None, 0.into()),
+            Platform::Spectrum => Inst::Jp(None, Expr::sym("spectrum_exit")),
+            Platform::Msx => Inst::Jp(None, Expr::sym("msx_exit")),
+            Platform::Agon => Inst::RetL,
+        }
+    }
+
+    /// Returns the frame pointer to use when `chosen` is asked for.
+    fn frame_pointer(self, chosen: FramePointer) -> FramePointer {
+        match self {
+            Platform::Spectrum => FramePointer::Ix,
+            _ => chosen,
+        }
+    }
+
+    /// Emits the code setting up the stack and whatever else the program
+    /// needs, with `stack_top` the top of the stack when it is the program's
+    /// own.
+    fn emit_setup(self, code: &mut Vec<Inst>, stack_top: u16) {
+        match self {
+            Platform::Bare => code.push(Inst::Ld(SP.into(), imm(i64::from(stack_top)))),
+            Platform::Cpm => cpm::emit_setup(code),
+            Platform::Spectrum => spectrum::emit_setup(code),
+            Platform::Msx => {}
+            Platform::Agon => agon::emit_setup(code, stack_top),
+        }
+    }
+
+    /// Emits what goes after the code: the data block of a program sharing
+    /// memory with a system, and the routines the platform needs.
+    fn emit_data(self, code: &mut Vec<Inst>) {
+        match self {
+            Platform::Bare | Platform::Agon => {}
+            Platform::Cpm => cpm::emit_data(code),
+            Platform::Spectrum => spectrum::emit_data(code),
+            Platform::Msx => msx::emit_routine(code),
+        }
+    }
+
+    /// Emits the body of the function imported as `import` with type `ty`.
+    fn emit_import(self, code: &mut Vec<Inst>, import: &Import, ty: &FuncType) -> Result<()> {
+        let (provided, place): (&[Provided], _) = match self {
+            Platform::Bare => (&[], "without a platform"),
+            Platform::Cpm => (cpm::IMPORTS, "on CP/M"),
+            Platform::Spectrum => (spectrum::IMPORTS, "on the ZX Spectrum"),
+            Platform::Msx => (msx::IMPORTS, "on the MSX"),
+            Platform::Agon => (agon::IMPORTS, "on the Agon Light"),
+        };
+        let routine = (provided.iter())
+            .find(|routine| import.module == "console" && import.name == routine.name);
+        let Some(routine) = routine else {
+            bail!("{import} isn't provided {place}");
+        };
+        if ty.params() != routine.params || ty.results() != routine.results {
+            bail!("{import} is imported with the wrong type");
+        }
+        // The argument is taken from below the return address, and the
+        // result put there.
+        if !routine.params.is_empty() {
+            code.push(Inst::Pop(HL));
+            code.push(Inst::Pop(DE));
+            code.push(Inst::Pop(DE));
+            code.push(Inst::Push(HL));
+        }
+        code.extend(asm::parse(routine.body));
+        if routine.results.is_empty() {
+            code.push(Inst::Ret(None));
+        } else {
+            code.push(Inst::Pop(HL));
+            code.push(Inst::Ld(E.into(), A.into()));
+            code.push(Inst::Ld(D.into(), imm(0)));
+            code.push(Inst::Push(DE));
+            code.push(Inst::Ld(E.into(), D.into()));
+            code.push(Inst::Push(DE));
+            code.push(Inst::JpInd(HL));
+        }
+        Ok(())
+    }
+}
+
+/// Form of the output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// Assembly text.
+    #[default]
+    Asm,
+    /// Machine code starting at the platform's load address, which for CP/M
+    /// is a `.COM` file and for the Agon Light a MOS executable.
+    Bin,
+    /// A Game Boy ROM image with the code after the header, for the SM83.
+    Gb,
+    /// A ZX Spectrum tape image with a BASIC loader in front of the code.
+    Tap,
+    /// An MSX cartridge ROM image of 16 or 32 KiB, as the code needs.
+    Rom,
+}
+
+impl Format {
+    /// Returns whether output of this form can be made for `target` on
+    /// `platform`.
+    pub fn fits(self, target: Target, platform: Platform) -> bool {
+        match self {
+            Format::Asm | Format::Bin => true,
+            Format::Gb => target == Target::Sm83,
+            Format::Tap => platform == Platform::Spectrum,
+            Format::Rom => platform == Platform::Msx,
+        }
+    }
+}
+
+/// Index register used as frame pointer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum FramePointer {
+    #[default]
+    Iy,
+    /// Leaves IY untouched, for systems whose interrupt handlers rely on it,
+    /// like the ZX Spectrum ROM.
+    Ix,
+}
+
+impl FramePointer {
+    fn reg(self) -> asm::Reg16 {
+        match self {
+            FramePointer::Iy => IY,
+            FramePointer::Ix => IX,
+        }
+    }
+}
+
+/// Optimization level, choosing which passes run.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OptLevel {
+    /// Compiles each instruction on its own, as written.
+    #[value(name = "0")]
+    O0,
+    /// Keeps operands in registers, folds constants and runs the peephole
+    /// pass.
+    #[value(name = "1")]
+    O1,
+    /// Also inlines small functions.
+    #[default]
+    #[value(name = "2")]
+    O2,
+    /// Also inlines larger functions and expands the runtime routines for
+    /// bit counting, copying and multiplication where they are used, for
+    /// speed. Saturating truncation, division, and multiplication with `MLT`
+    /// on the eZ80 and Z180, stay calls.
+    #[value(name = "3")]
+    O3,
+    /// Like 2, but only inlines the smallest functions, for size.
+    #[value(name = "s")]
+    Os,
+}
+
+impl OptLevel {
+    pub fn passes(self) -> Passes {
+        let optimize = self != OptLevel::O0;
+        Passes {
+            peephole: optimize,
+            fold: optimize,
+            registers: optimize,
+            inline_threshold: match self {
+                OptLevel::O0 | OptLevel::O1 => 0,
+                OptLevel::O2 => 5,
+                OptLevel::O3 => 8,
+                OptLevel::Os => 4,
+            },
+            inline_helpers: self == OptLevel::O3,
+        }
+    }
+}
+
+/// Optimizations to run, as chosen by an [`OptLevel`].
+#[derive(Clone, Copy, Debug)]
+pub struct Passes {
+    /// Whether the peephole pass rewrites the generated code.
+    pub peephole: bool,
+    /// Whether operations on constants are evaluated at compile time.
+    pub fold: bool,
+    /// Whether operands stay in registers from one instruction to the next,
+    /// instead of going back on the stack after each.
+    pub registers: bool,
+    /// Cost in IR instructions up to which called functions are inlined, or
+    /// 0 to inline none.
+    pub inline_threshold: usize,
+    /// Whether runtime routines that make no calls of their own and never
+    /// return conditionally are expanded where they are used instead of
+    /// called.
+    pub inline_helpers: bool,
+}
+
+impl Default for Passes {
+    fn default() -> Self {
+        OptLevel::default().passes()
+    }
+}
+
+/// Code size in bytes before the peephole pass and of the final code, which
+/// relaxing jumps or lowering to the 8080 or SM83 can also make larger.
+pub struct Stats {
+    pub before: usize,
+    pub after: usize,
+}
+
+impl<'a> Module<'a> {
+    pub fn compile(&self, config: &Config, out: &mut Vec<u8>) -> Result<Stats> {
+        let mut code = vec![];
+        let mut labeler = Labeler::new();
+        let passes = config.passes;
+        let mut runtime = Runtime::new(config.lookup_tables, passes.inline_helpers, config.target);
+        let platform = config.platform;
+        let scratch = platform.scratch();
+        if self.globals > platform.max_globals() {
+            bail!(
+                "the module has {} globals, but there is only room for {} on {:?}",
+                self.globals,
+                platform.max_globals(),
+                platform
+            );
+        }
+        let functions = self.functions.len();
+        if let Some(func) = config.keep.iter().find(|func| **func as usize >= functions) {
+            bail!("function {func} to keep is out of range, the module has {functions} functions");
+        }
+        if platform.origin() != 0 {
+            code.push(Inst::Org(platform.origin()));
+        }
+        trap::emit_symbols(&mut code, &scratch);
+        runtime::emit_symbols(&mut code, &scratch);
+        let stack_top = match config.target {
+            Target::Z80 | Target::Ez80 | Target::R800 => runtime::SCRATCH_ADDR,
+            Target::Z180 => {
+                z180::emit_setup(&mut code, config.mmu);
+                runtime::SCRATCH_ADDR
+            }
+            Target::I8080 => {
+                i8080::emit_symbols(&mut code, &scratch.clone().plus(-16));
+                i8080::STATE_ADDR
+            }
+            Target::Sm83 => {
+                sm83::emit_symbols(&mut code);
+                sm83::STACK_TOP
+            }
+        };
+        platform.emit_setup(&mut code, stack_top);
+        trap::emit_clear(&mut code);
+        for (index, segment) in self.data.iter().enumerate() {
+            let end = segment.offset + segment.bytes.len();
+            if end > config.target.memory_size() {
+                bail!(
+                    "data segment {index} ends at 0x{end:X}, past the {} KiB {:?} reaches",
+                    config.target.memory_size() >> 10,
+                    config.target
+                );
+            }
+            // Copies are split where the eZ80's `IX` would wrap around and
+            // where the Z180's bank window has to move.
+            let page = match config.target {
+                Target::Z180 => 0x1000,
+                _ => 0x10000,
+            };
+            let mut addr = segment.offset;
+            while addr < end {
+                let next = end.min((addr / page + 1) * page);
+                match config.target {
+                    Target::Ez80 => ez80::emit_copy_dest(&mut code, addr),
+                    Target::Z180 if !config.mmu.is_common(addr as i64, next - addr) => {
+                        z180::emit_map(&mut code, addr)
+                    }
+                    _ => code.push(Inst::Ld(DE.into(), imm(addr as i64))),
+                }
+                let source =
+                    Expr::sym(format!("data_{index}")).plus((addr - segment.offset) as i64);
+                code.push(Inst::Ld(HL.into(), imm(source)));
+                code.push(Inst::Ld(BC.into(), imm((next - addr) as i64)));
+                runtime.call(&mut code, Helper::MemCopy);
+                addr = next;
+            }
+        }
+        if let Some(start) = self.start {
+            code.push(Inst::Call(None, format!("func_{}", start).into()));
+        }
+        code.push(Inst::Call(None, format!("func_{}", self.entry).into()));
+        code.push(platform.exit());
+        trap::emit_routine(&mut code, config.trap_handler.as_deref(), platform.exit());
+        code.push(Inst::Label("call_hl".into()));
+        code.push(Inst::JpInd(HL));
+        let mut funcs: Vec<_> = (0..self.functions.len())
+            .map(|index| ir::build(self, index))
+            .collect::<Result<_>>()?;
+        inline::inline(&mut funcs, passes.inline_threshold);
+        // Only functions that can be called are compiled, and with them only
+        // the runtime routines they use.
+        let tables = self.tables.iter().flat_map(|table| table.elements.iter());
+        let roots = (self.exports.iter().copied())
+            .chain(self.start)
+            .chain(tables.flatten().map(|func| *func as usize))
+            .chain(config.keep.iter().map(|func| *func as usize));
+        let reachable = callgraph::reachable(&funcs, roots);
+        let fp = platform.frame_pointer(config.frame_pointer).reg();
+        for func in funcs.iter().filter(|func| reachable[func.index]) {
+            code.push(Inst::Label(format!("func_{}", func.index)));
+            if let Some(import) = &func.import {
+                platform.emit_import(&mut code, import, &func.ty)?;
+                continue;
+            }
+            self.compile_function(
+                &mut code,
+                &mut labeler,
+                &mut runtime,
+                fp,
+                passes,
+                config.target,
+                platform,
+                config.mmu,
+                func,
+            )?;
+        }
+        runtime.emit(&mut code);
+        if config.target == Target::Z180 {
+            z180::emit_routine(&mut code, config.mmu);
+        }
+        for (index, table) in self.tables.iter().enumerate() {
+            code.push(Inst::Label(format!("table_{}", index)));
+            for element in &table.elements {
+                let entry = match element {
+                    Some(func) => {
+                        let def = &self.functions[*func as usize];
+                        let type_id = self.type_id(&def.func_type);
+                        vec![format!("func_{func}").into(), (type_id as i64).into()]
+                    }
+                    None => vec![0.into(), 0.into()],
+                };
+                code.push(Inst::Dw(entry));
+            }
+        }
+        for (index, segment) in self.data.iter().enumerate() {
+            code.push(Inst::Label(format!("data_{index}")));
+            for row in segment.bytes.chunks(16) {
+                code.push(Inst::Db(
+                    row.iter().map(|byte| i64::from(*byte).into()).collect(),
+                ));
+            }
+        }
+        platform.emit_data(&mut code);
+        let before = asm::size(&code);
+        if passes.peephole {
+            peephole::optimize(&mut code);
+        }
+        match config.target {
+            Target::Z80 | Target::Z180 | Target::R800 => relax::relax(&mut code),
+            Target::Ez80 => {
+                ez80::optimize(&mut code);
+                relax::relax(&mut code);
+            }
+            Target::I8080 => i8080::lower(&mut code)?,
+            Target::Sm83 => {
+                sm83::lower(&mut code)?;
+                relax::shorten_jumps(&mut code);
+            }
+        }
+        let after = asm::size(&code);
+        let end = usize::from(platform.origin()) + after;
+        for (index, segment) in self.data.iter().enumerate() {
+            let (addr, size) = (segment.offset, segment.bytes.len());
+            if size > 0 && !platform.holds_data(config, end, addr, size) {
+                bail!(
+                    "data segment {index} at 0x{addr:04X} must be {} on {platform:?}",
+                    platform.data_place(config, end)
+                );
+            }
+        }
+        if config.target == Target::Z180 && after > usize::from(config.mmu.bank_addr()) {
+            bail!(
+                "the code takes {after} bytes, running into the Z180 bank area at 0x{:04X}",
+                config.mmu.bank_addr()
+            );
+        }
+        match config.format {
+            Format::Asm => asm::emit(&code, config.syntax, out),
+            Format::Bin => out.extend(encode::assemble(&code, platform.origin(), config.target)),
+            Format::Gb => out.extend(sm83::rom(&code)?),
+            Format::Tap => out.extend(spectrum::tap(&code)?),
+            Format::Rom => out.extend(msx::rom(&code, config.target)?),
+        }
+        Ok(Stats { before, after })
+    }
+
+    /// Returns the index of the first type structurally equal to `typ`, which
+    /// `call_indirect` compares against the type recorded in the table.
+    fn type_id(&self, typ: &FuncType) -> usize {
+        self.types.iter().position(|t| t == typ).unwrap()
+    }
+
+    /// Lowers `func` to code for `target` on `platform` keeping operands on
+    /// the stack, with the topmost ones cached in registers, addressing its
+    /// frame with `fp`.
+    #[allow(clippy::too_many_arguments)]
+    fn compile_function(
+        &self,
+        code: &mut Vec<Inst>,
+        labeler: &mut Labeler,
+        runtime: &mut Runtime,
+        fp: asm::Reg16,
+        passes: Passes,
+        target: Target,
+        platform: Platform,
+        mmu: z180::Mmu,
+        func: &ir::Function,
+    ) -> Result<()> {
+        let num_params = func.ty.params().len();
+        // Locals are laid out from fp+4 up, the last one lowest, followed by
+        // the parameters in the same order.
+        let mut offsets = vec![0; func.locals.len()];
+        let mut offset = 4;
+        for (local, ty) in func.locals.iter().enumerate().rev() {
+            offsets[local] = offset;
+            offset += value_size(*ty);
+        }
+        let frame_size = offset;
+        if frame_size > MAX_FRAME_SIZE {
+            bail!(
+                "function {} has a {frame_size}-byte frame, but its frame pointer only reaches {MAX_FRAME_SIZE} bytes",
+                func.index
+            );
+        }
+        let result_size = stack_size(func.ty.results());
+        let labels: Vec<Label> = (0..func.labels).map(|_| labeler.next()).collect();
+        let label = |label: ir::Label| labels[label.0];
+        // The caller pushes the arguments and calls, and the callee builds the
+        // rest of its frame: the locals, the caller's fp and the return
+        // address, in that order below the arguments. A function without
+        // parameters or locals has nothing to address and builds none.
+        if has_frame(func) {
+            code.push(Inst::Pop(HL));
+            let locals_size = stack_size(&func.locals[num_params..]);
+            let zero = func.unset_reads()[num_params..].contains(&true);
+            emit_reserve(code, locals_size, zero);
+            code.push(Inst::Push(fp));
+            code.push(Inst::Push(HL));
+            code.push(Inst::Ld(fp.into(), imm(0)));
+            code.push(Inst::Alu16(asm::Alu::Add, fp, SP));
+        }
+        // The topmost operands are kept in registers where an instruction can
+        // use them there, and spilled to the stack before any other.
+        // Code there only computes and tests as many bytes as needed.
+        let mut regs = Regs::default();
+        let narrow = narrow::analyze(func, target.address_size());
+        // Loops counting down from a small constant keep the count in `B`,
+        // on processors with `DJNZ`.
+        let countdowns = match target {
+            Target::I8080 | Target::Sm83 => vec![],
+            _ if passes.registers => countdown::find(func),
+            _ => vec![],
+        };
+        for (index, (inst, narrow)) in func.body.iter().zip(narrow).enumerate() {
+            if !matches!(inst, ir::Inst::Label(_)) {
+                code.push(Inst::Comment(inst.to_string()));
+            }
+            if let Some(countdown) = countdowns.iter().find(|c| c.set + 1 == index) {
+                regs.flush(code);
+                code.push(Inst::Ld(B.into(), imm(i64::from(countdown.count))));
+                regs.set_counting(true);
+            }
+            if let Some(countdown) =
+                (countdowns.iter()).find(|c| (c.decrement..=c.branch).contains(&index))
+            {
+                // The local is only written once the loop is left, at zero.
+                // Its high bytes are zero from the start.
+                if index == countdown.branch {
+                    let ir::Inst::Branch { target, .. } = inst else {
+                        unreachable!();
+                    };
+                    regs.flush(code);
+                    code.push(Inst::Dec(B.into()));
+                    code.push(Inst::Jp(Some(asm::Cond::Nz), label(*target).into()));
+                    let low = word_offsets(offsets[countdown.local], 4).next().unwrap();
+                    code.push(Inst::Ld(idx(fp, low), B.into()));
+                    regs.set_counting(false);
+                }
+                continue;
+            }
+            let compiled = self.compile_in_registers(
+                code,
+                labeler,
+                &mut regs,
+                fp,
+                passes.fold,
+                target,
+                platform,
+                mmu,
+                func,
+                &offsets,
+                &labels,
+                inst,
+                narrow,
+            );
+            if compiled {
+                if !passes.registers {
+                    regs.flush(code);
+                }
+                continue;
+            }
+            regs.flush(code);
+            match inst {
+                ir::Inst::Drop { ty, .. } => {
+                    for _ in 0..value_size(*ty) / 2 {
+                        code.push(Inst::Pop(DE));
+                    }
+                }
+                ir::Inst::LocalGet { local, .. } => {
+                    let d = offsets[*local];
+                    let size = value_size(func.locals[*local]);
+                    for word in word_offsets(d, size) {
+                        code.push(Inst::Ld(E.into(), idx(fp, word)));
+                        code.push(Inst::Ld(D.into(), idx(fp, word + 1)));
+                        code.push(Inst::Push(DE));
+                    }
+                }
+                ir::Inst::LocalSet { local, .. } => {
+                    let d = offsets[*local];
+                    let size = value_size(func.locals[*local]);
+                    for word in word_offsets(d, size).rev() {
+                        code.push(Inst::Pop(DE));
+                        code.push(Inst::Ld(idx(fp, word), E.into()));
+                        code.push(Inst::Ld(idx(fp, word + 1), D.into()));
+                    }
+                }
+                ir::Inst::LocalTee { local, .. } => {
+                    let d = offsets[*local];
+                    code.push(Inst::Ld(HL.into(), imm(0)));
+                    code.push(Inst::Alu16(asm::Alu::Add, HL, SP));
+                    for i in 0..value_size(func.locals[*local]) {
+                        if i > 0 {
+                            code.push(Inst::Inc(HL.into()));
+                        }
+                        code.push(Inst::Ld(A.into(), ind(HL)));
+                        code.push(Inst::Ld(idx(fp, d + i), A.into()));
+                    }
+                }
+                ir::Inst::Const { ty, value, .. } => {
+                    for word in 0..value_size(*ty) / 2 {
+                        let word = (*value >> (word * 16)) as u16;
+                        code.push(Inst::Ld(DE.into(), imm(i64::from(word))));
+                        code.push(Inst::Push(DE));
+                    }
+                }
+                ir::Inst::Unary {
+                    op: UnaryOp::Eqz, ..
+                } => {
+                    let zero = labeler.next();
+                    let nonzero = labeler.next();
+                    emit_test(code);
+                    code.push(Inst::Jr(Some(asm::Cond::Z), zero.into()));
+                    code.push(Inst::Ld(DE.into(), imm(0)));
+                    code.push(Inst::Push(DE));
+                    code.push(Inst::Jr(None, nonzero.into()));
+                    code.push(Inst::Label(zero.to_string()));
+                    code.push(Inst::Ld(DE.into(), imm(1)));
+                    code.push(Inst::Push(DE));
+                    code.push(Inst::Label(nonzero.to_string()));
+                    code.push(Inst::Ld(E.into(), imm(0)));
+                    code.push(Inst::Push(DE));
+                }
+                ir::Inst::Unary {
+                    op: UnaryOp::Extend8S,
+                    ty: ValType::I32,
+                    ..
+                } => {
+                    code.push(Inst::Pop(DE));
+                    code.push(Inst::Pop(HL));
+                    emit_sign(code, L);
+                    code.push(Inst::Ld(H.into(), A.into()));
+                    code.push(Inst::Ld(D.into(), A.into()));
+                    code.push(Inst::Ld(E.into(), A.into()));
+                    code.push(Inst::Push(HL));
+                    code.push(Inst::Push(DE));
+                }
+                ir::Inst::Unary {
+                    op: UnaryOp::Extend16S,
+                    ty: ValType::I32,
+                    ..
+                } => {
+                    code.push(Inst::Pop(DE));
+                    code.push(Inst::Pop(HL));
+                    emit_sign(code, H);
+                    code.push(Inst::Ld(D.into(), A.into()));
+                    code.push(Inst::Ld(E.into(), A.into()));
+                    code.push(Inst::Push(HL));
+                    code.push(Inst::Push(DE));
+                }
+                ir::Inst::Unary {
+                    op: op @ (UnaryOp::Extend8S | UnaryOp::Extend16S | UnaryOp::Extend32S),
+                    ..
+                } => {
+                    code.push(Inst::Pop(DE));
+                    code.push(Inst::Pop(DE));
+                    code.push(Inst::Pop(DE));
+                    let sign_words = if *op == UnaryOp::Extend32S {
+                        emit_sign(code, D);
+                        code.push(Inst::Push(DE));
+                        2
+                    } else {
+                        code.push(Inst::Pop(HL));
+                        if *op == UnaryOp::Extend8S {
+                            emit_sign(code, L);
+                            code.push(Inst::Ld(H.into(), A.into()));
+                        } else {
+                            emit_sign(code, H);
+                        }
+                        code.push(Inst::Push(HL));
+                        3
+                    };
+                    code.push(Inst::Ld(D.into(), A.into()));
+                    code.push(Inst::Ld(E.into(), A.into()));
+                    for _ in 0..sign_words {
+                        code.push(Inst::Push(DE));
+                    }
+                }
+                ir::Inst::Unary {
+                    op: op @ (UnaryOp::Clz | UnaryOp::Ctz | UnaryOp::Popcnt),
+                    ty,
+                    ..
+                } => {
+                    let helper = match op {
+                        UnaryOp::Clz => Helper::Clz,
+                        UnaryOp::Ctz => Helper::Ctz,
+                        _ => Helper::Popcnt,
+                    };
+                    let size = value_size(*ty);
+                    runtime::emit_pop_to_scratch(code, size);
+                    code.push(Inst::Ld(B.into(), imm(size as i64)));
+                    runtime.call(code, helper);
+                    runtime::emit_push_a(code, size);
+                }
+                ir::Inst::Unary {
+                    op: op @ (UnaryOp::TruncSatS | UnaryOp::TruncSatU),
+                    ty: from,
+                    result: to,
+                    ..
+                } => {
+                    let mut flags = 0;
+                    if *from == ValType::F64 {
+                        flags |= runtime::TRUNC_SAT_F64;
+                    }
+                    if *to == ValType::I64 {
+                        flags |= runtime::TRUNC_SAT_I64;
+                    }
+                    if *op == UnaryOp::TruncSatS {
+                        flags |= runtime::TRUNC_SAT_SIGNED;
+                    }
+                    runtime::emit_pop_to_scratch(code, value_size(*from));
+                    code.push(Inst::Ld(A.into(), imm(i64::from(flags))));
+                    runtime.call(code, Helper::TruncSat);
+                    runtime::emit_push_from_scratch(code, value_size(*to));
+                }
+                ir::Inst::Binary {
+                    op: BinaryOp::Mul, ..
+                } => {
+                    runtime::emit_pop_to_scratch(code, 8);
+                    runtime.call(code, Helper::Mul);
+                    runtime::emit_push_from_scratch(code, 4);
+                }
+                ir::Inst::Binary {
+                    op: op @ (BinaryOp::DivS | BinaryOp::DivU | BinaryOp::RemS | BinaryOp::RemU),
+                    ..
+                } => {
+                    let mut flags = 0;
+                    if matches!(op, BinaryOp::DivS | BinaryOp::RemS) {
+                        flags |= runtime::DIV_SIGNED;
+                    }
+                    if matches!(op, BinaryOp::RemS | BinaryOp::RemU) {
+                        flags |= runtime::DIV_REM;
+                    }
+                    runtime::emit_pop_to_scratch(code, 8);
+                    code.push(Inst::Ld(A.into(), imm(i64::from(flags))));
+                    runtime.call(code, Helper::Div);
+                    let ok = labeler.next();
+                    code.push(Inst::Alu(asm::Alu::Or, A.into()));
+                    code.push(Inst::Jr(Some(asm::Cond::Z), ok.into()));
+                    trap::emit_raise_in_a(code, func.index);
+                    code.push(Inst::Label(ok.to_string()));
+                    runtime::emit_push_from_scratch(code, 4);
+                }
+                ir::Inst::Select { .. } => {
                     let zero = labeler.next();
                     let after = labeler.next();
-                    writeln!(out, "  ; select").unwrap();
-                    writeln!(out, "  POP DE").unwrap();
-                    writeln!(out, "  LD A,D").unwrap();
-                    writeln!(out, "  OR E").unwrap();
-                    writeln!(out, "  POP DE").unwrap();
-                    writeln!(out, "  OR D").unwrap();
-                    writeln!(out, "  OR E").unwrap();
-
-                    writeln!(out, "  JR Z,{zero}").unwrap();
-                    writeln!(out, "  POP DE").unwrap();
-                    writeln!(out, "  POP DE").unwrap();
-                    writeln!(out, "  POP DE").unwrap();
-                    writeln!(out, "  POP BC").unwrap();
-                    writeln!(out, "  JR {after}").unwrap();
-                    writeln!(out, "{zero}:").unwrap();
-                    writeln!(out, "  POP DE").unwrap();
-                    writeln!(out, "  POP BC").unwrap();
-                    writeln!(out, "  POP IX").unwrap();
-                    writeln!(out, "  POP IX").unwrap();
-                    writeln!(out, "{after}:").unwrap();
-                    writeln!(out, "  PUSH BC").unwrap();
-                    writeln!(out, "  PUSH DE").unwrap();
-                }
-                Operator::Br { relative_depth } => {
-                    let label = label_stack[label_stack.len() - relative_depth as usize - 1];
-                    writeln!(out, "  ; br").unwrap();
-                    writeln!(out, "  JP {label}").unwrap();
-                }
-                Operator::BrIf { relative_depth } => {
-                    let label = label_stack[label_stack.len() - relative_depth as usize - 1];
-                    writeln!(out, "  ; br_if").unwrap();
-                    writeln!(out, "  POP DE").unwrap();
-                    writeln!(out, "  LD A,D").unwrap();
-                    writeln!(out, "  OR E").unwrap();
-                    writeln!(out, "  POP DE").unwrap();
-                    writeln!(out, "  OR D").unwrap();
-                    writeln!(out, "  OR E").unwrap();
-                    writeln!(out, "  JP NZ,{label}").unwrap();
-                }
-                Operator::Loop { blockty } => {
-                    assert_eq!(blockty, BlockType::Empty);
-                    let label = labeler.next();
-                    label_stack.push(label.clone());
-                    writeln!(out, "{label}: ; loop").unwrap();
-                    end_stack.push(None);
-                }
-                Operator::Block { blockty } => {
-                    assert_eq!(blockty, BlockType::Empty);
-                    let label = labeler.next();
-                    label_stack.push(label.clone());
-                    end_stack.push(Some(label));
-                }
-                Operator::Call { function_index } => {
-                    let def = &self.functions[function_index as usize];
-                    let num_locals = def.body.get_locals_reader().unwrap().get_count();
-                    let params = def.func_type.params();
-                    let results = def.func_type.results();
-                    writeln!(out, "  ; call").unwrap();
-                    writeln!(out, "  LD BC,0").unwrap();
-                    for _ in 0..num_locals {
-                        writeln!(out, "  PUSH BC").unwrap();
-                        writeln!(out, "  PUSH BC").unwrap();
+                    emit_test(code);
+                    code.push(Inst::Jr(Some(asm::Cond::Z), zero.into()));
+                    code.push(Inst::Pop(DE));
+                    code.push(Inst::Pop(DE));
+                    code.push(Inst::Pop(DE));
+                    code.push(Inst::Pop(BC));
+                    code.push(Inst::Jr(None, after.into()));
+                    code.push(Inst::Label(zero.to_string()));
+                    code.push(Inst::Pop(DE));
+                    code.push(Inst::Pop(BC));
+                    code.push(Inst::Pop(HL));
+                    code.push(Inst::Pop(HL));
+                    code.push(Inst::Label(after.to_string()));
+                    code.push(Inst::Push(BC));
+                    code.push(Inst::Push(DE));
+                }
+                ir::Inst::Trap(trap) => trap::emit_raise(code, *trap, func.index),
+                ir::Inst::Label(l) => code.push(Inst::Label(label(*l).to_string())),
+                ir::Inst::Branch {
+                    target,
+                    cond,
+                    unwind,
+                } => {
+                    let cond = match cond {
+                        ir::Cond::Always => None,
+                        ir::Cond::NonZero(_) => Some(asm::Cond::Nz),
+                        ir::Cond::Zero(_) => Some(asm::Cond::Z),
+                        ir::Cond::Compare(..) => unreachable!("compared in registers"),
+                    };
+                    if cond.is_some() {
+                        emit_test(code);
+                    }
+                    emit_jump(code, labeler, func, &labels, *target, cond, unwind);
+                }
+                ir::Inst::Call {
+                    func: callee,
+                    tail: false,
+                    ..
+                } => {
+                    code.push(Inst::Call(None, format!("func_{}", callee).into()));
+                }
+                // The arguments take the place of the caller's, which has
+                // the same results to return, and the callee returns to the
+                // caller's caller.
+                ir::Inst::Call {
+                    func: callee,
+                    ty,
+                    tail: true,
+                    ..
+                } => {
+                    let exit = Exit::Jump(format!("func_{}", callee).into());
+                    emit_return(code, fp, frame_size, stack_size(ty.params()), exit);
+                }
+                ir::Inst::CallIndirect {
+                    table, ty, tail, ..
+                } => {
+                    let size = self.tables[*table as usize].elements.len();
+                    let type_id = self.type_id(ty);
+                    let undefined = labeler.next();
+                    let mismatch = labeler.next();
+                    let after = labeler.next();
+                    code.push(Inst::Pop(DE));
+                    code.push(Inst::Pop(HL));
+                    code.push(Inst::Ld(A.into(), D.into()));
+                    code.push(Inst::Alu(asm::Alu::Or, E.into()));
+                    code.push(Inst::Jr(Some(asm::Cond::Nz), undefined.into()));
+                    code.push(Inst::Ld(DE.into(), imm(size as i64)));
+                    code.push(Inst::Alu(asm::Alu::And, A.into()));
+                    code.push(Inst::Alu16(asm::Alu::Sbc, HL, DE));
+                    code.push(Inst::Alu16(asm::Alu::Add, HL, DE));
+                    code.push(Inst::Jr(Some(asm::Cond::Nc), undefined.into()));
+                    code.push(Inst::Alu16(asm::Alu::Add, HL, HL));
+                    code.push(Inst::Alu16(asm::Alu::Add, HL, HL));
+                    code.push(Inst::Ld(DE.into(), imm(format!("table_{table}"))));
+                    code.push(Inst::Alu16(asm::Alu::Add, HL, DE));
+                    code.push(Inst::Ld(E.into(), ind(HL)));
+                    code.push(Inst::Inc(HL.into()));
+                    code.push(Inst::Ld(D.into(), ind(HL)));
+                    code.push(Inst::Inc(HL.into()));
+                    code.push(Inst::Ld(A.into(), D.into()));
+                    code.push(Inst::Alu(asm::Alu::Or, E.into()));
+                    code.push(Inst::Jr(Some(asm::Cond::Z), undefined.into()));
+                    code.push(Inst::Ld(A.into(), ind(HL)));
+                    code.push(Inst::Inc(HL.into()));
+                    code.push(Inst::Ld(H.into(), ind(HL)));
+                    code.push(Inst::Ld(L.into(), A.into()));
+                    code.push(Inst::Ld(BC.into(), imm(type_id as i64)));
+                    code.push(Inst::Alu(asm::Alu::And, A.into()));
+                    code.push(Inst::Alu16(asm::Alu::Sbc, HL, BC));
+                    code.push(Inst::Jr(Some(asm::Cond::Nz), mismatch.into()));
+                    if *tail {
+                        // The callee's address is moved along with the
+                        // arguments, below them.
+                        code.push(Inst::Push(DE));
+                        let size = stack_size(ty.params()) + 2;
+                        emit_return(code, fp, frame_size, size, Exit::Indirect);
+                    } else {
+                        code.push(Inst::Ex(DE.into(), HL.into()));
+                        code.push(Inst::Call(None, "call_hl".into()));
+                        code.push(Inst::Jr(None, after.into()));
                     }
-                    writeln!(out, "  PUSH IY").unwrap();
-                    writeln!(out, "  CALL func_{}", function_index).unwrap();
-                    if results.len() > 0 {
-                        writeln!(out, "  POP DE").unwrap();
-                        writeln!(out, "  POP BC").unwrap();
+                    code.push(Inst::Label(undefined.to_string()));
+                    trap::emit_raise(code, Trap::UndefinedElement, func.index);
+                    code.push(Inst::Label(mismatch.to_string()));
+                    trap::emit_raise(code, Trap::IndirectCallTypeMismatch, func.index);
+                    code.push(Inst::Label(after.to_string()));
+                }
+                inst => bail!("unsupported operator {inst} in function {}", func.index),
+            }
+        }
+        match has_frame(func) {
+            true => emit_return(code, fp, frame_size, result_size, Exit::Return),
+            false => emit_frameless_return(code, result_size),
+        }
+        Ok(())
+    }
+
+    /// Compiles `inst` operating on operands in registers, returning false
+    /// if it has to be compiled with its operands on the stack instead.
+    /// Operations on constants are evaluated here if `fold`.
+    #[allow(clippy::too_many_arguments)]
+    fn compile_in_registers(
+        &self,
+        code: &mut Vec<Inst>,
+        labeler: &mut Labeler,
+        regs: &mut Regs,
+        fp: asm::Reg16,
+        fold: bool,
+        target: Target,
+        platform: Platform,
+        mmu: z180::Mmu,
+        func: &ir::Function,
+        offsets: &[usize],
+        labels: &[Label],
+        inst: &ir::Inst,
+        narrow: Narrow,
+    ) -> bool {
+        let Narrow { demand, width } = narrow;
+        // Only 4-byte values are cached.
+        let cached = |ty: &ValType| value_size(*ty) == 4;
+        // Linear memory is outside the eZ80's segment, and accessed long.
+        let linear = |operand| match target {
+            Target::Ez80 => asm::long(operand),
+            _ => operand,
+        };
+        let emit_index = match target {
+            Target::Ez80 => ez80::emit_index,
+            Target::Z180 => z180::emit_index,
+            _ => emit_index,
+        };
+        // The Z180 only accesses its common areas directly, and the rest of
+        // linear memory through the bank window.
+        let direct = |base: i32, offset: u64, size: usize| {
+            target != Target::Z180 || mmu.is_common(constant_addr(base, offset, target), size)
+        };
+        // Locals store their high word first, like the stack.
+        let local = |local: usize| move |byte: usize| idx(fp, offsets[local] + (byte ^ 2));
+        match inst {
+            ir::Inst::Const { ty, value, .. } if cached(ty) => {
+                regs.push_const(*value as i32);
+            }
+            ir::Inst::LocalGet { local: l, .. } if cached(&func.locals[*l]) => {
+                let pair = regs.push(code);
+                regalloc::emit_load(code, pair, demand, local(*l));
+            }
+            ir::Inst::LocalSet { local: l, .. } if cached(&func.locals[*l]) => {
+                regs.fill(code, 1);
+                match regs.pop() {
+                    Cached::Pair(pair) => regalloc::emit_store(code, pair, local(*l)),
+                    Cached::Const(value) => regalloc::emit_store_const(code, value, local(*l)),
+                }
+            }
+            ir::Inst::LocalTee { local: l, .. } if cached(&func.locals[*l]) => {
+                regs.fill(code, 1);
+                match regs.get(0) {
+                    Cached::Pair(pair) => regalloc::emit_store(code, pair, local(*l)),
+                    Cached::Const(value) => regalloc::emit_store_const(code, value, local(*l)),
+                }
+            }
+            ir::Inst::GlobalGet { global, .. } => {
+                let pair = regs.push(code);
+                let addr = global_addr(platform, *global);
+                regalloc::each_needed_bank(code, demand, |code, bank| {
+                    let word = addr.clone().plus(2 - bank as i64 * 2);
+                    code.push(Inst::Ld(pair.into(), mem(word)));
+                });
+            }
+            ir::Inst::GlobalSet { global, .. } => {
+                regs.fill(code, 1);
+                let pair = regs.pair(code, 0);
+                regs.pop();
+                let addr = global_addr(platform, *global);
+                regalloc::each_bank(code, |code, bank| {
+                    let word = addr.clone().plus(2 - bank as i64 * 2);
+                    code.push(Inst::Ld(mem(word), pair.into()));
+                });
+            }
+            ir::Inst::Drop { ty, .. } if cached(ty) && !regs.is_empty() => {
+                regs.pop();
+            }
+            ir::Inst::Load {
+                ty,
+                size: size @ (1 | 4),
+                signed: false,
+                offset,
+                ..
+            } if cached(ty) => {
+                regs.fill(code, 1);
+                // A constant address is accessed directly.
+                if let Some(base) = regs
+                    .constant(0)
+                    .filter(|&base| direct(base, *offset, *size))
+                {
+                    regs.pop();
+                    let addr = |byte| constant_addr(base, *offset + byte, target);
+                    let pair = regs.push(code);
+                    if *size == 4 {
+                        regalloc::each_needed_bank(code, demand, |code, bank| {
+                            let word = addr(bank as u64 * 2);
+                            code.push(Inst::Ld(pair.into(), linear(mem(word))));
+                        });
+                    } else {
+                        code.push(Inst::Ld(A.into(), linear(mem(addr(0)))));
+                        code.push(Inst::Ld(low(pair).into(), A.into()));
+                        emit_zero_upper(code, pair, demand);
                     }
-                    writeln!(out, "  POP IY").unwrap();
-                    for _ in 0..(params.len() + num_locals as usize) {
-                        writeln!(out, "  POP BC").unwrap();
-                        writeln!(out, "  POP BC").unwrap();
+                    return true;
+                }
+                let pair = regs.pair(code, 0);
+                let saved = save_frame_pointer(code, fp);
+                let disp = emit_index(code, pair, *offset, *size);
+                if *size == 4 {
+                    regalloc::emit_load(code, pair, demand, |byte| linear(idx(IX, disp + byte)));
+                } else {
+                    code.push(Inst::Ld(low(pair).into(), linear(idx(IX, disp))));
+                }
+                if saved {
+                    code.push(Inst::Pop(IX));
+                }
+                if *size == 1 {
+                    emit_zero_upper(code, pair, demand);
+                }
+            }
+            ir::Inst::Store {
+                ty,
+                size: size @ (1 | 4),
+                offset,
+                ..
+            } if cached(ty) => {
+                regs.fill(code, 2);
+                if let Some(base) = regs
+                    .constant(1)
+                    .filter(|&base| direct(base, *offset, *size))
+                {
+                    let addr = |byte| constant_addr(base, *offset + byte, target);
+                    if *size == 4 {
+                        let value = regs.pair(code, 0);
+                        regs.pop();
+                        regs.pop();
+                        regalloc::each_bank(code, |code, bank| {
+                            let word = addr(bank as u64 * 2);
+                            code.push(Inst::Ld(linear(mem(word)), value.into()));
+                        });
+                    } else {
+                        let value = match regs.pop() {
+                            Cached::Pair(pair) => low(pair).into(),
+                            Cached::Const(value) => imm(i64::from(value as u8)),
+                        };
+                        regs.pop();
+                        code.push(Inst::Ld(A.into(), value));
+                        code.push(Inst::Ld(linear(mem(addr(0))), A.into()));
                     }
-                    if results.len() > 0 {
-                        writeln!(out, "  PUSH BC").unwrap();
-                        writeln!(out, "  PUSH DE").unwrap();
+                    return true;
+                }
+                let addr = regs.pair(code, 1);
+                let value = regs.pop();
+                regs.pop();
+                let saved = save_frame_pointer(code, fp);
+                let disp = emit_index(code, addr, *offset, *size);
+                let at = |byte| linear(idx(IX, disp + byte));
+                match (value, size) {
+                    (Cached::Pair(value), 4) => regalloc::emit_store(code, value, at),
+                    (Cached::Pair(value), _) => code.push(Inst::Ld(at(0), low(value).into())),
+                    (Cached::Const(value), 4) => regalloc::emit_store_const(code, value, at),
+                    (Cached::Const(value), _) => {
+                        code.push(Inst::Ld(at(0), imm(i64::from(value as u8))));
                     }
                 }
-                Operator::Return => {
-                    if has_result {
-                        writeln!(out, "  POP DE").unwrap();
-                        writeln!(out, "  POP BC").unwrap();
-                        writeln!(out, "  POP HL").unwrap();
-                        writeln!(out, "  PUSH BC").unwrap();
-                        writeln!(out, "  PUSH DE").unwrap();
-                        writeln!(out, "  PUSH HL").unwrap();
+                if saved {
+                    code.push(Inst::Pop(IX));
+                }
+            }
+            // Products and quotients of operands that aren't both constants
+            // are computed by a routine, which takes them on the stack, as
+            // are quotients that trap.
+            ir::Inst::Binary { op, .. }
+                if op.calls_routine() && (!fold || folded(*op, regs).is_none()) =>
+            {
+                return false;
+            }
+            ir::Inst::Binary { op, ty, .. } if cached(ty) => {
+                regs.fill(code, 2);
+                if let (true, Some(value)) = (fold, folded(*op, regs)) {
+                    regs.pop();
+                    regs.pop();
+                    regs.push_const(value);
+                    return true;
+                }
+                // The other operations are commutative, so a constant can be
+                // moved to the right.
+                if *op != BinaryOp::Sub && regs.constant(1).is_some() {
+                    regs.swap();
+                }
+                let alu = match op {
+                    BinaryOp::And => asm::Alu::And,
+                    BinaryOp::Or => asm::Alu::Or,
+                    _ => asm::Alu::Xor,
+                };
+                match (op, regs.constant(0)) {
+                    (BinaryOp::And, Some(-1))
+                    | (BinaryOp::Add | BinaryOp::Sub | BinaryOp::Or | BinaryOp::Xor, Some(0))
+                        if fold =>
+                    {
+                        regs.pop();
+                    }
+                    (BinaryOp::And | BinaryOp::Or | BinaryOp::Xor, Some(value)) => {
+                        regs.pop();
+                        let lhs = regs.pair(code, 0);
+                        regalloc::each_needed_bank(code, demand, |code, bank| {
+                            let bytes = [(0, low(lhs)), (1, high(lhs))];
+                            for (byte, reg) in bytes.into_iter().take(demand) {
+                                let value = (value >> (bank * 16 + byte * 8)) as u8;
+                                emit_alu_imm(code, alu, reg, value);
+                            }
+                        });
+                    }
+                    _ => {
+                        regs.pair(code, 0);
+                        if matches!(op, BinaryOp::Add | BinaryOp::Sub) {
+                            regs.move_to_hl(code, 1);
+                        }
+                        let rhs = regs.pair(code, 0);
+                        regs.pop();
+                        let lhs = regs.pair(code, 0);
+                        if *op == BinaryOp::Sub {
+                            code.push(Inst::Alu(asm::Alu::And, A.into()));
+                        }
+                        regalloc::each_needed_bank(code, demand, |code, bank| match op {
+                            BinaryOp::Add if bank == 0 => {
+                                code.push(Inst::Alu16(asm::Alu::Add, HL, rhs))
+                            }
+                            BinaryOp::Add => code.push(Inst::Alu16(asm::Alu::Adc, HL, rhs)),
+                            BinaryOp::Sub => code.push(Inst::Alu16(asm::Alu::Sbc, HL, rhs)),
+                            _ => {
+                                let bytes = [(low(lhs), low(rhs)), (high(lhs), high(rhs))];
+                                for (dst, src) in bytes.into_iter().take(demand) {
+                                    code.push(Inst::Ld(A.into(), dst.into()));
+                                    code.push(Inst::Alu(alu, src.into()));
+                                    code.push(Inst::Ld(dst.into(), A.into()));
+                                }
+                            }
+                        });
                     }
-                    writeln!(out, "  RET").unwrap();
                 }
-                Operator::End => {
-                    if let Some(block_label) = end_stack.pop().flatten() {
-                        writeln!(out, "{block_label}: ; block").unwrap();
+            }
+            ir::Inst::Compare { op, ty, .. } if cached(ty) => {
+                regs.fill(code, 2);
+                if let (true, Some(lhs), Some(rhs)) = (fold, regs.constant(1), regs.constant(0)) {
+                    regs.pop();
+                    regs.pop();
+                    regs.push_const(op.eval(lhs, rhs) as i32);
+                    return true;
+                }
+                // Move the result into the carry.
+                match emit_compare_regs(code, labeler, regs, *op, width) {
+                    // Borrows only when `A` is zero.
+                    asm::Cond::Z => code.push(Inst::Alu(asm::Alu::Sub, imm(1))),
+                    // Carries unless `A` is zero.
+                    asm::Cond::Nz => code.push(Inst::Alu(asm::Alu::Add, imm(0xFF))),
+                    asm::Cond::Nc => code.push(Inst::Plain(Plain::Ccf)),
+                    _ => {}
+                }
+                let pair = regs.push(code);
+                emit_carry(code, pair, demand);
+            }
+            ir::Inst::Unary {
+                op: UnaryOp::Eqz, ..
+            } => {
+                regs.fill(code, 1);
+                if let (true, Some(value)) = (fold, regs.constant(0)) {
+                    regs.pop();
+                    regs.push_const((value == 0) as i32);
+                    return true;
+                }
+                let pair = regs.pair(code, 0);
+                emit_test_pair(code, pair, width);
+                // Borrows only when the value is zero.
+                code.push(Inst::Alu(asm::Alu::Sub, imm(1)));
+                emit_carry(code, pair, demand);
+            }
+            ir::Inst::Branch {
+                target,
+                cond: cond @ (ir::Cond::NonZero(_) | ir::Cond::Zero(_)),
+                unwind,
+            } => {
+                regs.fill(code, 1);
+                regs.spill(code, 1);
+                if !fold {
+                    regs.pair(code, 0);
+                }
+                let pair = match regs.pop() {
+                    Cached::Pair(pair) => pair,
+                    // A constant condition makes the branch always or never
+                    // taken.
+                    Cached::Const(value) => {
+                        let taken = match cond {
+                            ir::Cond::NonZero(_) => value != 0,
+                            _ => value == 0,
+                        };
+                        if taken {
+                            emit_jump(code, labeler, func, labels, *target, None, unwind);
+                        }
+                        return true;
+                    }
+                };
+                emit_test_pair(code, pair, width);
+                code.push(Inst::Plain(Plain::Exx));
+                let cond = match cond {
+                    ir::Cond::NonZero(_) => asm::Cond::Nz,
+                    _ => asm::Cond::Z,
+                };
+                emit_jump(code, labeler, func, labels, *target, Some(cond), unwind);
+            }
+            ir::Inst::Branch {
+                target,
+                cond: ir::Cond::Compare(op, ..),
+                unwind,
+            } => {
+                regs.fill(code, 2);
+                regs.spill(code, 2);
+                if let (true, Some(lhs), Some(rhs)) = (fold, regs.constant(1), regs.constant(0)) {
+                    regs.pop();
+                    regs.pop();
+                    if op.eval(lhs, rhs) {
+                        emit_jump(code, labeler, func, labels, *target, None, unwind);
                     }
+                    return true;
                 }
-                op => unimplemented!("operator {:?} not implemented", op),
+                let cond = emit_compare_regs(code, labeler, regs, *op, width);
+                code.push(Inst::Plain(Plain::Exx));
+                emit_jump(code, labeler, func, labels, *target, Some(cond), unwind);
+            }
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// Returns the offsets of the words of a `size`-byte value stored at `d` in
+/// the order they are pushed, least significant first.
+///
+/// Values are stored as they lie on the stack, with the most significant word
+/// lowest.
+fn word_offsets(d: usize, size: usize) -> impl DoubleEndedIterator<Item = usize> {
+    (0..size / 2).map(move |word| d + size - 2 - word * 2)
+}
+
+/// Offset from the scratch buffer of the globals, which follow the trap
+/// record with 4 bytes each.
+pub const GLOBALS_OFFSET: usize = 12;
+
+/// Returns the address of `global` on `platform`.
+fn global_addr(platform: Platform, global: u32) -> Expr {
+    let offset = GLOBALS_OFFSET as i64 + i64::from(global) * 4;
+    platform.scratch().plus(offset)
+}
+
+/// Emits the jump of a branch, taken under `cond` if there is one.
+fn emit_jump(
+    code: &mut Vec<Inst>,
+    labeler: &mut Labeler,
+    func: &ir::Function,
+    labels: &[Label],
+    target: ir::Label,
+    cond: Option<asm::Cond>,
+    unwind: &ir::Unwind,
+) {
+    // The epilogue of a function with a frame drops whatever is left above
+    // the results, so returning needs no unwinding.
+    let unwind = match target == func.return_label && has_frame(func) {
+        true => (0, 0),
+        false => (stack_size(&unwind.keep), stack_size(&unwind.discard)),
+    };
+    let target = labels[target.0];
+    let Some(cond) = cond else {
+        emit_unwind(code, unwind);
+        code.push(Inst::Jp(None, target.into()));
+        return;
+    };
+    if unwind.1 > 0 {
+        let skip = labeler.next();
+        code.push(Inst::Jp(Some(cond.negate()), skip.into()));
+        emit_unwind(code, unwind);
+        code.push(Inst::Jp(None, target.into()));
+        code.push(Inst::Label(skip.to_string()));
+    } else {
+        code.push(Inst::Jp(Some(cond), target.into()));
+    }
+}
+
+/// Emits an i32 comparison of the two topmost cached operands, popping them,
+/// and returns the condition under which it holds. The code ends in the
+/// shadow bank.
+///
+/// Equality leaves the bytes of the difference ORed together in `A` for the
+/// Z flag to test, and orderings leave the borrow, or the sign corrected for
+/// overflow, in the carry. Operands known to fit in `width` bytes of 2 or
+/// less are compared in 16 bits, where they are never negative.
+fn emit_compare_regs(
+    code: &mut Vec<Inst>,
+    labeler: &mut Labeler,
+    regs: &mut Regs,
+    op: CompareOp,
+    width: usize,
+) -> asm::Cond {
+    // Equality with zero only needs the value tested.
+    if matches!(op, CompareOp::Eq | CompareOp::Ne) && regs.constant(0) == Some(0) {
+        regs.pop();
+        let pair = regs.pair(code, 0);
+        regs.pop();
+        emit_test_pair(code, pair, width);
+        return match op {
+            CompareOp::Eq => asm::Cond::Z,
+            _ => asm::Cond::Nz,
+        };
+    }
+    regs.pair(code, 0);
+    regs.pair(code, 1);
+    // Subtract the right operand from the left, or the other way around, so
+    // that the borrow is set when the left one is less or greater.
+    let (minuend, cond) = match op {
+        CompareOp::Eq => (1, asm::Cond::Z),
+        CompareOp::Ne => (1, asm::Cond::Nz),
+        CompareOp::LtS | CompareOp::LtU => (1, asm::Cond::C),
+        CompareOp::GeS | CompareOp::GeU => (1, asm::Cond::Nc),
+        CompareOp::GtS | CompareOp::GtU => (0, asm::Cond::C),
+        CompareOp::LeS | CompareOp::LeU => (0, asm::Cond::Nc),
+    };
+    regs.move_to_hl(code, minuend);
+    let subtrahend = regs.pair(code, 1 - minuend);
+    regs.pop();
+    regs.pop();
+    code.push(Inst::Alu(asm::Alu::And, A.into()));
+    code.push(Inst::Alu16(asm::Alu::Sbc, HL, subtrahend));
+    if width <= 2 {
+        if matches!(op, CompareOp::Eq | CompareOp::Ne) {
+            code.push(Inst::Ld(A.into(), H.into()));
+            code.push(Inst::Alu(asm::Alu::Or, L.into()));
+        }
+        code.push(Inst::Plain(Plain::Exx));
+        return cond;
+    }
+    match op {
+        CompareOp::Eq | CompareOp::Ne => {
+            code.push(Inst::Ld(A.into(), H.into()));
+            code.push(Inst::Alu(asm::Alu::Or, L.into()));
+            code.push(Inst::Plain(Plain::Exx));
+            code.push(Inst::Alu16(asm::Alu::Sbc, HL, subtrahend));
+            code.push(Inst::Alu(asm::Alu::Or, H.into()));
+            code.push(Inst::Alu(asm::Alu::Or, L.into()));
+        }
+        CompareOp::LtS | CompareOp::GtS | CompareOp::LeS | CompareOp::GeS => {
+            let no_overflow = labeler.next();
+            code.push(Inst::Plain(Plain::Exx));
+            code.push(Inst::Alu16(asm::Alu::Sbc, HL, subtrahend));
+            code.push(Inst::Ld(A.into(), H.into()));
+            code.push(Inst::Jp(Some(asm::Cond::Po), no_overflow.into()));
+            code.push(Inst::Plain(Plain::Cpl));
+            code.push(Inst::Label(no_overflow.to_string()));
+            code.push(Inst::Plain(Plain::Rla));
+        }
+        _ => {
+            code.push(Inst::Plain(Plain::Exx));
+            code.push(Inst::Alu16(asm::Alu::Sbc, HL, subtrahend));
+        }
+    }
+    cond
+}
+
+/// Emits code ORing the bytes of the i32 in `pair` into `A`, setting the Z
+/// flag if it is zero. Only the `width` low bytes can be nonzero, and the
+/// others are left out. The code ends in the shadow bank.
+fn emit_test_pair(code: &mut Vec<Inst>, pair: asm::Reg16, width: usize) {
+    if width == 1 {
+        code.push(Inst::Ld(A.into(), low(pair).into()));
+        code.push(Inst::Alu(asm::Alu::Or, A.into()));
+        code.push(Inst::Plain(Plain::Exx));
+        return;
+    }
+    code.push(Inst::Ld(A.into(), high(pair).into()));
+    code.push(Inst::Alu(asm::Alu::Or, low(pair).into()));
+    code.push(Inst::Plain(Plain::Exx));
+    if width > 2 {
+        code.push(Inst::Alu(asm::Alu::Or, high(pair).into()));
+        code.push(Inst::Alu(asm::Alu::Or, low(pair).into()));
+    }
+}
+
+/// Emits code setting the `demand` low bytes of the i32 in `pair` to the
+/// carry flag, starting in the shadow bank.
+fn emit_carry(code: &mut Vec<Inst>, pair: asm::Reg16, demand: usize) {
+    if demand > 2 {
+        code.push(Inst::Ld(pair.into(), imm(0)));
+    }
+    code.push(Inst::Plain(Plain::Exx));
+    if demand > 1 {
+        code.push(Inst::Ld(pair.into(), imm(0)));
+    } else {
+        code.push(Inst::Ld(low(pair).into(), imm(0)));
+    }
+    code.push(Inst::Shift(asm::Shift::Rl, low(pair).into()));
+}
+
+/// Returns the result of `op` on the two operands on top of `regs` if both
+/// are constants and it doesn't trap.
+fn folded(op: BinaryOp, regs: &Regs) -> Option<i32> {
+    op.eval(regs.constant(1)?, regs.constant(0)?)
+}
+
+/// Returns the address a constant `base` plus `offset` accesses on `target`,
+/// wrapping around like the sum computed at run time.
+fn constant_addr(base: i32, offset: u64, target: Target) -> i64 {
+    let sum = i64::from(base as u32) + offset as i64;
+    sum & ((1 << (target.address_size() * 8)) - 1)
+}
+
+/// Emits `reg = reg <alu> value`, for `AND`, `OR` and `XOR`, leaving out
+/// what doesn't change `reg`.
+fn emit_alu_imm(code: &mut Vec<Inst>, alu: asm::Alu, reg: asm::Reg8, value: u8) {
+    match (alu, value) {
+        (asm::Alu::And, 0xFF) | (asm::Alu::Or | asm::Alu::Xor, 0) => {}
+        (asm::Alu::And, 0) | (asm::Alu::Or, 0xFF) => {
+            code.push(Inst::Ld(reg.into(), imm(i64::from(value))));
+        }
+        _ => {
+            code.push(Inst::Ld(A.into(), reg.into()));
+            if (alu, value) == (asm::Alu::Xor, 0xFF) {
+                code.push(Inst::Plain(Plain::Cpl));
+            } else {
+                code.push(Inst::Alu(alu, imm(i64::from(value))));
             }
+            code.push(Inst::Ld(reg.into(), A.into()));
         }
-        if has_result {
-            writeln!(out, "  POP DE").unwrap();
-            writeln!(out, "  POP BC").unwrap();
-            writeln!(out, "  POP HL").unwrap();
-            writeln!(out, "  PUSH BC").unwrap();
-            writeln!(out, "  PUSH DE").unwrap();
-            writeln!(out, "  PUSH HL").unwrap();
+    }
+}
+
+/// Emits code clearing the bytes of the i32 in `pair` above the lowest, as
+/// far as the `demand` low bytes go.
+fn emit_zero_upper(code: &mut Vec<Inst>, pair: asm::Reg16, demand: usize) {
+    if demand > 1 {
+        code.push(Inst::Ld(high(pair).into(), imm(0)));
+    }
+    if demand > 2 {
+        code.push(Inst::Plain(Plain::Exx));
+        code.push(Inst::Ld(pair.into(), imm(0)));
+        code.push(Inst::Plain(Plain::Exx));
+    }
+}
+
+/// Emits code saving the frame pointer `fp` on the stack if it is IX, which
+/// `emit_index` is about to overwrite, returning whether it did.
+fn save_frame_pointer(code: &mut Vec<Inst>, fp: asm::Reg16) -> bool {
+    if fp != IX {
+        return false;
+    }
+    code.push(Inst::Push(IX));
+    true
+}
+
+/// Returns whether IX pointing at an address can reach the `size` bytes at
+/// `offset` from it with its displacement.
+pub fn in_reach(offset: u64, size: usize) -> bool {
+    offset as usize + size <= 128
+}
+
+/// Emits code pointing IX at the 16-bit address in `pair` plus `offset`,
+/// returning the displacement the `size` bytes there are accessed with.
+pub fn emit_index(code: &mut Vec<Inst>, pair: asm::Reg16, offset: u64, size: usize) -> usize {
+    if in_reach(offset, size) {
+        code.push(Inst::Push(pair));
+        code.push(Inst::Pop(IX));
+        return offset as usize;
+    }
+    code.push(Inst::Ld(IX.into(), imm((offset & 0xFFFF) as i64)));
+    // IX can't be added to HL directly.
+    if pair == HL {
+        code.push(Inst::Ex(DE.into(), HL.into()));
+        code.push(Inst::Alu16(asm::Alu::Add, IX, DE));
+        code.push(Inst::Ex(DE.into(), HL.into()));
+    } else {
+        code.push(Inst::Alu16(asm::Alu::Add, IX, pair));
+    }
+    0
+}
+
+/// Emits code popping the i32 on top of the stack and setting the Z flag if
+/// it is zero.
+fn emit_test(code: &mut Vec<Inst>) {
+    code.push(Inst::Pop(DE));
+    code.push(Inst::Ld(A.into(), D.into()));
+    code.push(Inst::Alu(asm::Alu::Or, E.into()));
+    code.push(Inst::Pop(DE));
+    code.push(Inst::Alu(asm::Alu::Or, D.into()));
+    code.push(Inst::Alu(asm::Alu::Or, E.into()));
+}
+
+/// Emits code filling `A` with the sign bit of `reg`.
+fn emit_sign(code: &mut Vec<Inst>, reg: asm::Reg8) {
+    code.push(Inst::Ld(A.into(), reg.into()));
+    code.push(Inst::Plain(Plain::Rla));
+    code.push(Inst::Alu(asm::Alu::Sbc, A.into()));
+}
+
+/// Emits code keeping the top `keep` bytes of the operand stack and dropping
+/// the `discard` bytes below them.
+fn emit_unwind(code: &mut Vec<Inst>, (keep, discard): (usize, usize)) {
+    if discard == 0 {
+        return;
+    }
+    match keep {
+        0 => {}
+        4 => {
+            code.push(Inst::Pop(DE));
+            code.push(Inst::Pop(BC));
+        }
+        _ => {
+            code.push(Inst::Ld(HL.into(), imm((keep + discard - 1) as i64)));
+            code.push(Inst::Alu16(asm::Alu::Add, HL, SP));
+            code.push(Inst::Ex(DE.into(), HL.into()));
+            code.push(Inst::Ld(HL.into(), imm((keep - 1) as i64)));
+            code.push(Inst::Alu16(asm::Alu::Add, HL, SP));
+            code.push(Inst::Ld(BC.into(), imm(keep as i64)));
+            code.push(Inst::Plain(Plain::Lddr));
         }
-        writeln!(out, "  RET").unwrap();
+    }
+    code.push(Inst::Ld(HL.into(), imm(discard as i64)));
+    code.push(Inst::Alu16(asm::Alu::Add, HL, SP));
+    code.push(Inst::Ld(SP.into(), HL.into()));
+    if keep == 4 {
+        code.push(Inst::Push(BC));
+        code.push(Inst::Push(DE));
+    }
+}
+
+/// Size in bytes of a value of type `ty` on the operand stack.
+fn value_size(ty: ValType) -> usize {
+    match ty {
+        ValType::I64 | ValType::F64 => 8,
+        ValType::V128 => 16,
+        _ => 4,
+    }
+}
+
+fn stack_size(types: &[ValType]) -> usize {
+    types.iter().copied().map(value_size).sum()
+}
+
+/// Largest frame whose every byte the frame pointer reaches with its signed
+/// 8-bit displacement.
+pub const MAX_FRAME_SIZE: usize = 128;
+
+/// Returns the size of the frame of a function with `locals`, parameters
+/// included: the locals, the caller's frame pointer and the return address.
+pub fn frame_size(locals: &[ValType]) -> usize {
+    4 + stack_size(locals)
+}
+
+/// Returns where in linear memory the stack, the scratch buffer, the globals
+/// and the console are on a bare system: from the stack's lowest address to
+/// the top of the 64 KiB the processor addresses. On the Z180 that is common
+/// area 1, wherever it is mapped in physical memory.
+fn bare_stack(config: &Config) -> (usize, usize) {
+    let base = match config.target {
+        Target::Z180 => usize::from(config.mmu.cbr) << 12,
+        _ => 0,
+    };
+    let floor = usize::from(runtime::SCRATCH_ADDR - runtime::STACK_SIZE);
+    (base + floor, base + 0x10000)
+}
+
+/// Where the epilogue of a function goes once its frame is released.
+enum Exit {
+    /// Back to the caller.
+    Return,
+    /// To a function called in its place, returning to the caller.
+    Jump(Expr),
+    /// To the function whose address was moved along with the values, below
+    /// them.
+    Indirect,
+}
+
+/// Emits the epilogue of a function whose frame (return address, caller's fp,
+/// locals and arguments) spans `frame_size` bytes from `fp`, leaving through
+/// `exit`.
+///
+/// The `size` bytes on top of the operand stack, the results or the
+/// arguments of a tail call, are moved to where the arguments began, and the
+/// frame is released with them, so that the caller finds the results in
+/// place of the arguments it pushed.
+fn emit_return(code: &mut Vec<Inst>, fp: asm::Reg16, frame_size: usize, size: usize, exit: Exit) {
+    if size == 0 {
+        code.push(Inst::Ld(SP.into(), fp.into()));
+        code.push(Inst::Pop(HL));
+        code.push(Inst::Pop(fp));
+        if frame_size > 4 {
+            code.push(Inst::Ex(DE.into(), HL.into()));
+            code.push(Inst::Ld(HL.into(), imm((frame_size - 4) as i64)));
+            code.push(Inst::Alu16(asm::Alu::Add, HL, SP));
+            code.push(Inst::Ld(SP.into(), HL.into()));
+            code.push(Inst::Ex(DE.into(), HL.into()));
+        }
+        emit_exit(code, exit);
+        return;
+    }
+    code.push(Inst::Ld(L.into(), idx(fp, 0)));
+    code.push(Inst::Ld(H.into(), idx(fp, 1)));
+    code.push(Inst::Push(HL));
+    code.push(Inst::Ld(L.into(), idx(fp, 2)));
+    code.push(Inst::Ld(H.into(), idx(fp, 3)));
+    code.push(Inst::Push(HL));
+    code.push(Inst::Push(fp));
+    code.push(Inst::Pop(HL));
+    code.push(Inst::Ld(DE.into(), imm((frame_size - 1) as i64)));
+    code.push(Inst::Alu16(asm::Alu::Add, HL, DE));
+    code.push(Inst::Ex(DE.into(), HL.into()));
+    code.push(Inst::Ld(HL.into(), imm((size + 3) as i64)));
+    code.push(Inst::Alu16(asm::Alu::Add, HL, SP));
+    code.push(Inst::Ld(BC.into(), imm(size as i64)));
+    code.push(Inst::Plain(Plain::Lddr));
+    code.push(Inst::Inc(DE.into()));
+    code.push(Inst::Pop(fp));
+    code.push(Inst::Pop(HL));
+    code.push(Inst::Ex(DE.into(), HL.into()));
+    code.push(Inst::Ld(SP.into(), HL.into()));
+    code.push(Inst::Ex(DE.into(), HL.into()));
+    emit_exit(code, exit);
+}
+
+/// Emits the jump to `exit` that ends an epilogue, with the return address in
+/// HL.
+fn emit_exit(code: &mut Vec<Inst>, exit: Exit) {
+    match exit {
+        Exit::Return => code.push(Inst::JpInd(HL)),
+        Exit::Jump(target) => {
+            code.push(Inst::Push(HL));
+            code.push(Inst::Jp(None, target));
+        }
+        Exit::Indirect => {
+            code.push(Inst::Ex(ind(SP), HL.into()));
+            code.push(Inst::JpInd(HL));
+        }
+    }
+}
+
+/// Emits the epilogue of a function without a frame, returning with the
+/// `result_size` bytes of results on top of the stack and only the return
+/// address below them.
+fn emit_frameless_return(code: &mut Vec<Inst>, result_size: usize) {
+    match result_size {
+        0 => code.push(Inst::Ret(None)),
+        4 => {
+            code.push(Inst::Pop(HL));
+            code.push(Inst::Pop(DE));
+            code.push(Inst::Ex(DE.into(), HL.into()));
+            code.push(Inst::Ex(ind(SP), HL.into()));
+            code.push(Inst::Push(DE));
+            code.push(Inst::JpInd(HL));
+        }
+        _ => {
+            // Move the results up over the return address.
+            code.push(Inst::Ld(HL.into(), imm(result_size as i64)));
+            code.push(Inst::Alu16(asm::Alu::Add, HL, SP));
+            code.push(Inst::Ld(E.into(), ind(HL)));
+            code.push(Inst::Inc(HL.into()));
+            code.push(Inst::Ld(D.into(), ind(HL)));
+            code.push(Inst::Push(DE));
+            code.push(Inst::Ld(D.into(), H.into()));
+            code.push(Inst::Ld(E.into(), L.into()));
+            code.push(Inst::Dec(HL.into()));
+            code.push(Inst::Dec(HL.into()));
+            code.push(Inst::Ld(BC.into(), imm(result_size as i64)));
+            code.push(Inst::Plain(Plain::Lddr));
+            code.push(Inst::Pop(HL));
+            code.push(Inst::Inc(SP.into()));
+            code.push(Inst::Inc(SP.into()));
+            code.push(Inst::JpInd(HL));
+        }
+    }
+}
+
+/// Returns whether `func` builds a frame, which it needs to address
+/// parameters and locals, or to release everything below the arguments of a
+/// tail call. So only functions without parameters or locals go without one,
+/// even if they make no calls: parameters are only addressed through the
+/// frame pointer.
+fn has_frame(func: &ir::Function) -> bool {
+    let tail_call = func.body.iter().any(|inst| {
+        matches!(
+            inst,
+            ir::Inst::Call { tail: true, .. } | ir::Inst::CallIndirect { tail: true, .. }
+        )
+    });
+    !func.locals.is_empty() || tail_call
+}
+
+/// Emits code reserving `size` bytes of stack for locals, with the return
+/// address in HL, zeroing them if `zero`.
+fn emit_reserve(code: &mut Vec<Inst>, size: usize, zero: bool) {
+    if size == 0 {
+        return;
+    }
+    if zero {
+        code.push(Inst::Ld(BC.into(), imm(0)));
+    } else if size / 2 > 7 {
+        // Moving SP takes 7 bytes, and pushing one per word.
+        code.push(Inst::Ex(DE.into(), HL.into()));
+        code.push(Inst::Ld(HL.into(), imm(-(size as i64))));
+        code.push(Inst::Alu16(asm::Alu::Add, HL, SP));
+        code.push(Inst::Ld(SP.into(), HL.into()));
+        code.push(Inst::Ex(DE.into(), HL.into()));
+        return;
+    }
+    for _ in 0..size / 2 {
+        code.push(Inst::Push(BC));
     }
 }
 
@@ -558,3 +1931,561 @@ impl Display for Label {
         write!(f, "label_{}", self.0)
     }
 }
+
+impl From<Label> for Expr {
+    fn from(label: Label) -> Self {
+        Expr::sym(label.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emu::{self, Machine};
+    use crate::loader;
+
+    /// Compiles a module with `bytes` of data at `offset`.
+    fn compile(target: Target, platform: Platform, offset: u32, bytes: usize) -> Result<Stats> {
+        let wasm = wat::parse_str(format!(
+            r#"(module (memory 256) (data (i32.const {offset}) "{}") (func (export "entry")))"#,
+            "x".repeat(bytes)
+        ))
+        .unwrap();
+        let config = Config {
+            target,
+            platform,
+            ..Config::default()
+        };
+        loader::load(&wasm)?.compile(&config, &mut vec![])
+    }
+
+    #[test]
+    fn data_stays_off_the_program() {
+        assert!(compile(Target::Z80, Platform::Bare, 0x10, 4).is_err());
+        assert!(compile(Target::Z80, Platform::Bare, 0x8000, 4).is_ok());
+        assert!(compile(Target::Z80, Platform::Bare, 0xFBBC, 4).is_ok());
+        assert!(compile(Target::Z80, Platform::Bare, 0xFBBD, 4).is_err());
+        assert!(compile(Target::Z80, Platform::Bare, 0xFF00, 4).is_err());
+        assert!(compile(Target::Z80, Platform::Bare, 0xFFC8, 2).is_err());
+        assert!(compile(Target::Z80, Platform::Bare, 0xFFFD, 1).is_err());
+        assert!(compile(Target::I8080, Platform::Bare, 0xFFB0, 4).is_err());
+        assert!(compile(Target::Ez80, Platform::Bare, 0xFFF0, 4).is_err());
+        assert!(compile(Target::Ez80, Platform::Bare, 0x10000, 4).is_ok());
+        assert!(compile(Target::Z80, Platform::Spectrum, 0x3FFE, 4).is_err());
+        assert!(compile(Target::Z80, Platform::Spectrum, 0x4000, 4).is_err());
+        assert!(compile(Target::Z80, Platform::Spectrum, 0x7FFC, 4).is_err());
+        assert!(compile(Target::Z80, Platform::Spectrum, 0x8010, 4).is_err());
+        assert!(compile(Target::Z80, Platform::Spectrum, 0xC000, 4).is_ok());
+        assert!(compile(Target::Z80, Platform::Msx, 0x8000, 4).is_err());
+        assert!(compile(Target::Z80, Platform::Msx, 0xD000, 4).is_ok());
+        assert!(compile(Target::Sm83, Platform::Bare, 0x8000, 4).is_err());
+        assert!(compile(Target::Sm83, Platform::Bare, 0xC000, 4).is_ok());
+        assert!(compile(Target::Ez80, Platform::Agon, 0x04F000, 4).is_err());
+        assert!(compile(Target::Ez80, Platform::Agon, 0x05FFFE, 4).is_ok());
+        assert!(compile(Target::Ez80, Platform::Agon, 0x0AFFFE, 4).is_err());
+    }
+
+    #[test]
+    fn data_stays_in_reach() {
+        assert!(compile(Target::Z80, Platform::Bare, 0xFFFE, 4).is_err());
+        assert!(compile(Target::Z180, Platform::Bare, 0xFFFE, 4).is_err());
+        assert!(compile(Target::Z180, Platform::Bare, 0x1FFFE, 4).is_ok());
+        assert!(compile(Target::Z180, Platform::Bare, 0xFFFFE, 4).is_err());
+        assert!(compile(Target::Ez80, Platform::Bare, 0xFFFFE, 4).is_ok());
+    }
+
+    /// Runs `op` on `arg` passed to a function, so that it isn't folded.
+    fn convert(op: &str, src: &str, dst: &str, arg: &str) -> Machine {
+        emu::run_wat(
+            &format!(
+                r#"(module
+                    (func $op (param {src}) (result {dst}) ({op} (local.get 0)))
+                    (func (export "entry") (result {dst})
+                      (call $op ({src}.const {arg}))))"#
+            ),
+            Config::default(),
+        )
+    }
+
+    #[test]
+    fn extends_sign_extend() {
+        for (op, arg, result) in [
+            ("i32.extend8_s", "0x80", -0x80),
+            ("i32.extend8_s", "0x17F", 0x7F),
+            ("i32.extend16_s", "0x18000", -0x8000),
+            ("i32.extend16_s", "0x7FFF", 0x7FFF),
+        ] {
+            assert_eq!(
+                convert(op, "i32", "i32", arg).top_i32(),
+                result,
+                "{op} {arg}"
+            );
+        }
+        for (op, arg, result) in [
+            ("i64.extend8_s", "0x80", -0x80),
+            ("i64.extend16_s", "0x1_0000_8000", -0x8000),
+            ("i64.extend32_s", "0x8000_0000", -0x8000_0000),
+            ("i64.extend32_s", "0x1_7FFF_FFFF", 0x7FFF_FFFF),
+        ] {
+            assert_eq!(
+                convert(op, "i64", "i64", arg).top_i64(),
+                result,
+                "{op} {arg}"
+            );
+        }
+    }
+
+    #[test]
+    fn trunc_sat_saturates() {
+        for src in ["f32", "f64"] {
+            for (op, arg, result) in [
+                ("i32.trunc_sat_{src}_s", "-7.9", -7),
+                ("i32.trunc_sat_{src}_s", "1e10", i32::MAX),
+                ("i32.trunc_sat_{src}_s", "nan", 0),
+                ("i32.trunc_sat_{src}_u", "3e9", 3_000_000_000_u32 as i32),
+                ("i32.trunc_sat_{src}_u", "-1.5", 0),
+            ] {
+                let op = op.replace("{src}", src);
+                assert_eq!(
+                    convert(&op, src, "i32", arg).top_i32(),
+                    result,
+                    "{op} {arg}"
+                );
+            }
+            for (op, arg, result) in [
+                ("i64.trunc_sat_{src}_s", "-5e9", -5_000_000_000),
+                ("i64.trunc_sat_{src}_s", "-1e30", i64::MIN),
+                ("i64.trunc_sat_{src}_u", "1e10", 10_000_000_000),
+                ("i64.trunc_sat_{src}_u", "1e30", -1),
+            ] {
+                let op = op.replace("{src}", src);
+                assert_eq!(
+                    convert(&op, src, "i64", arg).top_i64(),
+                    result,
+                    "{op} {arg}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn unsupported_operators_are_rejected() {
+        let wasm = wat::parse_str(
+            r#"(module (func (export "entry") (result i64)
+                (i64.add (i64.const 1) (i64.const 2))))"#,
+        )
+        .unwrap();
+        let Err(error) = loader::load(&wasm)
+            .unwrap()
+            .compile(&Config::default(), &mut vec![])
+        else {
+            panic!("i64.add compiled");
+        };
+        assert!(format!("{error:#}").contains("I64Add"), "{error:#}");
+    }
+
+    /// Runs `op` on `lhs` and `rhs` passed to a function, unoptimized and
+    /// with operands in registers.
+    fn binary(op: &str, lhs: i32, rhs: i32) -> [i32; 2] {
+        [OptLevel::O0, OptLevel::O2].map(|level| {
+            let config = Config {
+                passes: level.passes(),
+                ..Config::default()
+            };
+            emu::run_wat(
+                &format!(
+                    r#"(module
+                        (func $op (param i32 i32) (result i32)
+                          (i32.{op} (local.get 0) (local.get 1)))
+                        (func (export "entry") (result i32)
+                          (call $op (i32.const {lhs}) (i32.const {rhs}))))"#
+                ),
+                config,
+            )
+            .top_i32()
+        })
+    }
+
+    #[test]
+    fn sub_subtracts_the_second_operand() {
+        assert_eq!(binary("sub", 10, 3), [7; 2]);
+        assert_eq!(binary("sub", 3, 0x10000), [3 - 0x10000; 2]);
+    }
+
+    #[test]
+    fn comparisons_compare() {
+        let pairs = [(1, 2), (2, 1), (5, 5), (-1, 1), (1, -1), (0x10000, 1)];
+        for (op, expected) in [
+            ("eq", i32::eq as fn(&i32, &i32) -> bool),
+            ("ne", i32::ne),
+            ("lt_s", i32::lt),
+            ("gt_s", i32::gt),
+            ("le_s", i32::le),
+            ("ge_s", i32::ge),
+        ] {
+            for (lhs, rhs) in pairs {
+                let result = i32::from(expected(&lhs, &rhs));
+                assert_eq!(binary(op, lhs, rhs), [result; 2], "{op} {lhs} {rhs}");
+            }
+        }
+        for (op, expected) in [
+            ("lt_u", u32::lt as fn(&u32, &u32) -> bool),
+            ("gt_u", u32::gt),
+            ("le_u", u32::le),
+            ("ge_u", u32::ge),
+        ] {
+            for (lhs, rhs) in pairs {
+                let result = i32::from(expected(&(lhs as u32), &(rhs as u32)));
+                assert_eq!(binary(op, lhs, rhs), [result; 2], "{op} {lhs} {rhs}");
+            }
+        }
+    }
+
+    #[test]
+    fn or_and_xor_combine_bits() {
+        assert_eq!(binary("or", 0x1234_00F0, 0x0F), [0x1234_00FF; 2]);
+        assert_eq!(binary("xor", 0x1234_00FF, 0x1200_000F), [0x0034_00F0; 2]);
+    }
+
+    #[test]
+    fn i64_locals_keep_their_neighbours() {
+        let wat = r#"(module
+            (func $mix (param i32 i64 i32) (result i64)
+              (local i64 i32)
+              (local.set 3 (local.get 1))
+              (local.set 4 (i32.add (local.get 0) (local.get 2)))
+              (if (result i64) (i32.eq (local.get 4) (i32.const 0x30003))
+                (then (local.get 3))
+                (else (i64.const 0))))
+            (func (export "entry") (result i64)
+              (call $mix (i32.const 0x10001) (i64.const 0x1122_3344_5566_7788)
+                (i32.const 0x20002))))"#;
+        for level in [OptLevel::O0, OptLevel::O2] {
+            let config = Config {
+                passes: level.passes(),
+                ..Config::default()
+            };
+            assert_eq!(emu::run_wat(wat, config).top_i64(), 0x1122_3344_5566_7788);
+        }
+    }
+
+    /// Compiles a function with `locals` i32 locals, called twice.
+    fn compile_locals(locals: usize, format: Format) -> Result<Stats> {
+        let wasm = wat::parse_str(format!(
+            r#"(module
+                (func $f (local {})
+                  (local.set 0 (i32.const 1))
+                  (local.set {} (i32.const 2)))
+                (func (export "entry") (call $f) (call $f)))"#,
+            "i32 ".repeat(locals),
+            locals - 1
+        ))
+        .unwrap();
+        let config = Config {
+            format,
+            ..Config::default()
+        };
+        loader::load(&wasm)?.compile(&config, &mut vec![])
+    }
+
+    #[test]
+    fn frames_stay_in_reach() {
+        for format in [Format::Asm, Format::Bin] {
+            assert!(compile_locals(31, format).is_ok());
+            assert!(compile_locals(32, format).is_err());
+            assert!(compile_locals(40, format).is_err());
+        }
+    }
+
+    /// Compiles a module importing `name` with type `ty` on `platform`.
+    fn import(platform: Platform, name: &str, ty: &str) -> Result<Stats> {
+        let wasm = wat::parse_str(format!(
+            r#"(module
+                (import "console" "{name}" (func {ty}))
+                (func (export "entry") (call 0 (i32.const 65))))"#
+        ))
+        .unwrap();
+        let target = match platform {
+            Platform::Agon => Target::Ez80,
+            _ => Target::Z80,
+        };
+        let config = Config {
+            target,
+            platform,
+            ..Config::default()
+        };
+        loader::load(&wasm)?.compile(&config, &mut vec![])
+    }
+
+    #[test]
+    fn imports_are_checked() {
+        assert!(import(Platform::Bare, "putchar", "(param i32)").is_err());
+        for platform in [
+            Platform::Cpm,
+            Platform::Spectrum,
+            Platform::Msx,
+            Platform::Agon,
+        ] {
+            assert!(import(platform, "putchar", "(param i32)").is_ok());
+            assert!(import(platform, "putchar", "(param i32) (result i32)").is_err());
+            assert!(import(platform, "beep", "(param i32)").is_err());
+        }
+    }
+
+    #[test]
+    fn imports_take_and_return_i32s() {
+        let wat = r#"(module
+            (import "console" "putchar" (func (param i32)))
+            (import "console" "getchar" (func (result i32)))
+            (func (export "entry") (call 0 (call 1))))"#;
+        let config = || Config {
+            platform: Platform::Msx,
+            ..Config::default()
+        };
+        let putchar = emu::function_asm(wat, config(), 0).unwrap();
+        let take = ["POP HL", "POP DE", "POP DE", "PUSH HL", "LD A,E"];
+        assert!(putchar.starts_with(&take.map(String::from)), "{putchar:?}");
+        assert_eq!(putchar.last().unwrap(), "RET");
+        let getchar = emu::function_asm(wat, config(), 1).unwrap();
+        assert_eq!(getchar[0], "CALL 159");
+        assert_eq!(getchar.last().unwrap(), "JP (HL)");
+    }
+
+    #[test]
+    fn images_must_fit() {
+        let code = |size| vec![Inst::Db(vec![0.into(); size])];
+        assert!(spectrum::tap(&code(0x8000)).is_ok());
+        assert!(spectrum::tap(&code(0x8001)).is_err());
+        assert!(msx::rom(&code(0x7000), Target::Z80).is_ok());
+        assert!(msx::rom(&code(0x8000), Target::Z80).is_err());
+        assert!(sm83::rom(&code(0x7EB0)).is_ok());
+        assert!(sm83::rom(&code(0x7EB1)).is_err());
+    }
+
+    fn folding(fold: bool) -> Config {
+        Config {
+            passes: Passes {
+                fold,
+                ..OptLevel::O1.passes()
+            },
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn constants_fold() {
+        let wat = r#"(module (memory 1)
+            (func (export "entry") (result i32)
+              (i32.store8 (i32.const 16) (i32.add (i32.const 60) (i32.const 5)))
+              (i32.sub (i32.load8_u (i32.const 16)) (i32.sub (i32.const 3) (i32.const 10)))))"#;
+        let folded = emu::function_asm(wat, folding(true), 0).unwrap();
+        assert!(folded.starts_with(&["LD A,65".into(), "LD (16),A".into()]));
+        assert!(!folded.iter().any(|line| line.starts_with("ADD")));
+        let unfolded = emu::function_asm(wat, folding(false), 0).unwrap();
+        assert!(unfolded.contains(&"ADD HL,DE".into()));
+        assert!(unfolded.contains(&"LD (16),A".into()));
+        for fold in [true, false] {
+            assert_eq!(emu::run_wat(wat, folding(fold)).top_i32(), 72);
+        }
+    }
+
+    #[test]
+    fn folding_leaves_traps() {
+        let wat = r#"(module (func (export "entry") (result i32)
+            (i32.div_u (i32.const 1) (i32.const 0))))"#;
+        let machine = emu::run_wat(wat, folding(true));
+        let code = machine.byte(runtime::SCRATCH_ADDR + trap::TRAP_CODE_OFFSET as u16);
+        assert_eq!(code, Trap::IntegerDivideByZero.code());
+    }
+
+    /// Returns the code of `func`, called by the entry with 41 if it takes a
+    /// parameter, and the entry's result.
+    fn framing(func: &str, frame_pointer: FramePointer) -> (Vec<String>, i32) {
+        let arg = match func.contains("param") {
+            true => "(i32.const 41)",
+            false => "",
+        };
+        let wat = format!(
+            r#"(module {func}
+                (func (export "entry") (result i32) (call 0 {arg})))"#
+        );
+        let config = || Config {
+            frame_pointer,
+            passes: OptLevel::O1.passes(),
+            ..Config::default()
+        };
+        let code = emu::function_asm(&wat, config(), 0).unwrap();
+        (code, emu::run_wat(&wat, config()).top_i32())
+    }
+
+    #[test]
+    fn frames_are_elided() {
+        let (code, result) = framing("(func (result i32) (i32.const 42))", FramePointer::Iy);
+        assert!(!code.iter().any(|line| line.contains("IY")), "{code:?}");
+        assert_eq!(result, 42);
+        // A leaf function with a parameter still addresses it through a
+        // frame.
+        let identity = "(func (param i32) (result i32) (local.get 0))";
+        for (frame_pointer, fp) in [(FramePointer::Iy, "IY"), (FramePointer::Ix, "IX")] {
+            let (code, result) = framing(identity, frame_pointer);
+            let prologue = [
+                "POP HL".to_string(),
+                format!("PUSH {fp}"),
+                "PUSH HL".into(),
+                format!("LD {fp},0"),
+                format!("ADD {fp},SP"),
+            ];
+            assert!(code.starts_with(&prologue), "{code:?}");
+            assert_eq!(result, 41);
+        }
+    }
+
+    #[test]
+    fn locals_set_first_are_not_zeroed() {
+        let (code, result) = framing(
+            "(func (param i32) (result i32) (local i32)
+              (local.set 1 (local.get 0))
+              (local.get 1))",
+            FramePointer::Iy,
+        );
+        assert!(!code.contains(&"LD BC,0".into()), "{code:?}");
+        assert_eq!(result, 41);
+        let (code, result) = framing(
+            "(func (param i32) (result i32) (local i32)
+              (i32.add (local.get 1) (local.get 0)))",
+            FramePointer::Iy,
+        );
+        assert!(code.contains(&"LD BC,0".into()), "{code:?}");
+        assert_eq!(result, 41);
+    }
+
+    /// Returns the code of function 0 of a module with `extra` in it, which
+    /// only the table may call, compiled keeping `keep`.
+    fn unused(extra: &str, keep: Vec<u32>) -> Option<Vec<String>> {
+        let wat = format!(
+            r#"(module {extra}
+                (func (result i32) (i32.const 7))
+                (func (export "entry")))"#
+        );
+        let config = Config {
+            keep,
+            ..Config::default()
+        };
+        emu::function_asm(&wat, config, 0)
+    }
+
+    #[test]
+    fn only_reachable_functions_are_compiled() {
+        assert_eq!(unused("", vec![]), None);
+        let kept = unused("", vec![0]).unwrap();
+        assert!(kept.contains(&"LD HL,7".into()), "{kept:?}");
+        let table = "(table 1 funcref) (elem (i32.const 0) 0)";
+        assert!(unused(table, vec![]).is_some());
+    }
+
+    #[test]
+    fn kept_functions_must_exist() {
+        let wasm = wat::parse_str(r#"(module (func) (func (export "entry")))"#).unwrap();
+        let module = loader::load(&wasm).unwrap();
+        for (keep, ok) in [(1, true), (2, false), (u32::MAX, false)] {
+            let config = Config {
+                keep: vec![keep],
+                ..Config::default()
+            };
+            assert_eq!(module.compile(&config, &mut vec![]).is_ok(), ok, "{keep}");
+        }
+    }
+
+    /// Returns a module whose entry counts `depth` down to zero through
+    /// `call` of function 0 on itself, and returns 9.
+    fn countdown(call: &str, depth: u32) -> String {
+        format!(
+            r#"(module
+                (func (param i32) (result i32)
+                  (if (result i32) (i32.eqz (local.get 0))
+                    (then (i32.const 9))
+                    (else ({call} 0 (i32.sub (local.get 0) (i32.const 1))))))
+                (func (export "entry") (result i32) (call 0 (i32.const {depth}))))"#
+        )
+    }
+
+    #[test]
+    fn tail_calls_jump() {
+        let code = emu::function_asm(&countdown("return_call", 1), Config::default(), 0).unwrap();
+        let jump = |line: &String| line == "JP func_0" || line == "JR func_0";
+        assert!(code.iter().any(jump), "{code:?}");
+        assert!(!code.contains(&"CALL func_0".into()), "{code:?}");
+        let code = emu::function_asm(&countdown("call", 1), Config::default(), 0).unwrap();
+        assert!(code.contains(&"CALL func_0".into()), "{code:?}");
+        assert!(!code.iter().any(jump), "{code:?}");
+        for (call, depth) in [("return_call", 10000), ("call", 10)] {
+            let machine = emu::run_wat(&countdown(call, depth), Config::default());
+            assert_eq!(machine.top_i32(), 9, "{call} {depth}");
+        }
+    }
+
+    /// A module whose entry adds up four calls to function 0, which sums two
+    /// bytes and costs 5 to inline.
+    const SUMS: &str = r#"(module (memory 1)
+        (data (i32.const 0x8000) "\03\04")
+        (func (result i32)
+          (i32.add (i32.load8_u (i32.const 0x8000)) (i32.load8_u (i32.const 0x8001))))
+        (func (export "entry") (result i32)
+          (i32.add (i32.add (call 0) (call 0)) (i32.add (call 0) (call 0)))))"#;
+
+    /// Returns how often the entry of [`SUMS`] calls function 0 at `level`,
+    /// and the size of the binary.
+    fn sums(level: OptLevel) -> (usize, usize) {
+        let config = |format| Config {
+            format,
+            passes: level.passes(),
+            ..Config::default()
+        };
+        let code = emu::function_asm(SUMS, config(Format::Asm), 1).unwrap();
+        let calls = code.iter().filter(|line| *line == "CALL func_0").count();
+        let wasm = wat::parse_str(SUMS).unwrap();
+        let mut out = vec![];
+        let module = loader::load(&wasm).unwrap();
+        module.compile(&config(Format::Bin), &mut out).unwrap();
+        assert_eq!(emu::run_wat(SUMS, config(Format::Bin)).top_i32(), 28);
+        (calls, out.len())
+    }
+
+    #[test]
+    fn opt_levels_trade_size() {
+        let [o0, o1, o2, o3, os] = [
+            OptLevel::O0,
+            OptLevel::O1,
+            OptLevel::O2,
+            OptLevel::O3,
+            OptLevel::Os,
+        ]
+        .map(sums);
+        // Only O2 and O3 inline a call costing 5.
+        assert_eq!([o0.0, o1.0, o2.0, o3.0, os.0], [4, 4, 0, 0, 4]);
+        assert!(o1.1 < o0.1);
+        assert!(os.1 < o2.1);
+    }
+
+    #[test]
+    fn asxxxx_output_starts_an_absolute_area() {
+        let wasm = wat::parse_str(r#"(module (func (export "entry")))"#).unwrap();
+        let module = loader::load(&wasm).unwrap();
+        let header = |syntax| {
+            let config = Config {
+                platform: Platform::Cpm,
+                syntax,
+                ..Config::default()
+            };
+            let mut out = vec![];
+            module.compile(&config, &mut out).unwrap();
+            let text = String::from_utf8(out).unwrap();
+            text.lines().take(2).map(str::to_string).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            header(Syntax::Asxxxx),
+            ["  .area _CODE (ABS)", "  .org 0x100"]
+        );
+        assert_eq!(header(Syntax::Zilog)[0], "  ORG 0x100");
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::HashSet;
+
+use wasmparser::ValType;
+
+use crate::ir::{BinaryOp, Cond, Function, Inst, UnaryOp};
+
+/// A loop counting an i32 local down from a constant of at most 255 to
+/// zero, one per iteration, and leaving at zero:
+///
+/// ```text
+/// i32.const n  local.set i
+/// loop  ...  local.get i  i32.const 1  i32.sub  local.tee i  br_if 0  end
+/// ```
+///
+/// The count can then be kept in `B` and the loop closed with `DEC B` and a
+/// jump, which relaxing fuses into `DJNZ`. The body leaves the local alone
+/// and only does what leaves `BC` alone, and it only branches within itself
+/// or back to the top. So `B` holds the count throughout, and the local is
+/// only written, as zero, once the loop is left.
+pub struct Countdown {
+    pub local: usize,
+    pub count: u8,
+    /// Index of the `local.set` of the count, which the loop starts after.
+    pub set: usize,
+    /// Index of the `local.get` the decrement starts with.
+    pub decrement: usize,
+    /// Index of the branch back to the top.
+    pub branch: usize,
+}
+
+/// Returns the countdown loops of `func`, in order.
+pub fn find(func: &Function) -> Vec<Countdown> {
+    (1..func.body.len())
+        .filter_map(|set| countdown(func, set))
+        .collect()
+}
+
+fn countdown(func: &Function, set: usize) -> Option<Countdown> {
+    let body = &func.body;
+    let (
+        Inst::Const {
+            ty: ValType::I32,
+            value,
+            dst,
+        },
+        Inst::LocalSet { src, local },
+        Inst::Label(top),
+    ) = (&body[set - 1], &body[set], body.get(set + 1)?)
+    else {
+        return None;
+    };
+    let count = u8::try_from(*value).ok().filter(|count| *count > 0)?;
+    if dst != src || func.locals[*local] != ValType::I32 {
+        return None;
+    }
+    let start = set + 2;
+    let decrement = (start..body.len()).find(|&index| !safe(func, *local, &body[index]))?;
+    let [Inst::LocalGet {
+        dst: counter,
+        local: get,
+    }, Inst::Const {
+        ty: ValType::I32,
+        value: 1,
+        dst: one,
+    }, Inst::Binary {
+        op: BinaryOp::Sub,
+        ty: ValType::I32,
+        dst,
+        lhs,
+        rhs,
+    }, Inst::LocalTee { src, local: tee }, Inst::Branch {
+        target,
+        cond: Cond::NonZero(cond),
+        unwind,
+    }] = body.get(decrement..decrement + 5)?
+    else {
+        return None;
+    };
+    let slots = [dst, lhs, src, cond];
+    if get != local
+        || tee != local
+        || target != top
+        || !unwind.discard.is_empty()
+        || one.0 != counter.0 + 1
+        || *rhs != *one
+        || slots.iter().any(|slot| *slot != counter)
+    {
+        return None;
+    }
+    // Branches in the body may only go back to the top or stay in it.
+    let inner: HashSet<_> = (body[start..decrement].iter())
+        .filter_map(|inst| match inst {
+            Inst::Label(label) => Some(*label),
+            _ => None,
+        })
+        .collect();
+    let escapes = body[start..decrement].iter().any(|inst| match inst {
+        Inst::Branch { target, .. } => target != top && !inner.contains(target),
+        _ => false,
+    });
+    // A countdown inside would count in `B` too.
+    let nested = (start + 1..decrement).any(|set| countdown(func, set).is_some());
+    if escapes || nested {
+        return None;
+    }
+    Some(Countdown {
+        local: *local,
+        count,
+        set,
+        decrement,
+        branch: decrement + 4,
+    })
+}
+
+/// Returns whether `inst` can be in the body of a countdown loop over
+/// `local`: it leaves the local alone, and its code in registers leaves
+/// `BC` alone once no operand is cached there.
+fn safe(func: &Function, local: usize, inst: &Inst) -> bool {
+    match inst {
+        Inst::LocalGet { local: l, .. }
+        | Inst::LocalSet { local: l, .. }
+        | Inst::LocalTee { local: l, .. } => *l != local && func.locals[*l] == ValType::I32,
+        Inst::Binary { op, ty, .. } => {
+            *ty == ValType::I32
+                && matches!(
+                    op,
+                    BinaryOp::Add | BinaryOp::Sub | BinaryOp::And | BinaryOp::Or | BinaryOp::Xor
+                )
+        }
+        Inst::Const { ty, .. }
+        | Inst::Load { ty, .. }
+        | Inst::Store { ty, .. }
+        | Inst::Compare { ty, .. }
+        | Inst::Drop { ty, .. } => *ty == ValType::I32,
+        Inst::Unary { op, ty, .. } => *op == UnaryOp::Eqz && *ty == ValType::I32,
+        Inst::Branch { unwind, .. } => unwind.discard.is_empty(),
+        Inst::GlobalGet { .. } | Inst::GlobalSet { .. } | Inst::Label(_) | Inst::Trap(_) => true,
+        Inst::Select { .. } | Inst::Call { .. } | Inst::CallIndirect { .. } => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compile::{Config, OptLevel};
+    use crate::emu;
+    use crate::loader;
+
+    /// Returns the assembly of a module whose entry sums 3 `count` times in
+    /// a loop counting down local 0, with `body` in the loop too, and
+    /// returns the sum plus local 0.
+    fn counting(count: u32, body: &str, level: OptLevel) -> (String, i32) {
+        let wat = format!(
+            r#"(module
+                (memory 1)
+                (func $f)
+                (func (export "entry") (result i32) (local i32 i32 i32)
+                  (local.set 0 (i32.const {count}))
+                  (block
+                    (loop
+                      (local.set 1 (i32.add (local.get 1) (i32.const 3)))
+                      {body}
+                      (br_if 0 (local.tee 0 (i32.sub (local.get 0) (i32.const 1))))))
+                  (i32.add (local.get 1) (local.get 0))))"#
+        );
+        let config = Config {
+            passes: level.passes(),
+            ..Config::default()
+        };
+        let wasm = wat::parse_str(&wat).unwrap();
+        let mut out = vec![];
+        loader::load(&wasm)
+            .unwrap()
+            .compile(&config, &mut out)
+            .unwrap();
+        let result = emu::run_wat(&wat, config).top_i32();
+        (String::from_utf8(out).unwrap(), result)
+    }
+
+    #[test]
+    fn countdowns_close_with_djnz() {
+        for body in [
+            "",
+            "(i32.store (i32.const 16) (local.get 1))",
+            "(if (i32.eqz (local.get 1)) (then (unreachable)))",
+        ] {
+            let (asm, result) = counting(10, body, OptLevel::O2);
+            assert!(asm.contains("DJNZ"), "{body}\n{asm}");
+            assert_eq!(result, 30, "{body}");
+        }
+        let (asm, result) = counting(255, "", OptLevel::O2);
+        assert!(asm.contains("DJNZ"));
+        assert_eq!(result, 765);
+    }
+
+    #[test]
+    fn other_loops_keep_the_count_in_the_local() {
+        for (count, body, level) in [
+            (10, "", OptLevel::O0),
+            (256, "", OptLevel::O2),
+            (10, "(call $f)", OptLevel::O1),
+            (
+                10,
+                "(br_if 1 (i32.gt_u (local.get 1) (i32.const 1000)))",
+                OptLevel::O2,
+            ),
+            (10, "(local.set 0 (local.get 0))", OptLevel::O2),
+        ] {
+            let (asm, result) = counting(count, body, level);
+            assert!(!asm.contains("DJNZ"), "{count} {body}\n{asm}");
+            assert_eq!(result, 3 * count as i32, "{count} {body}");
+        }
+        // Only the inner of two nested countdowns keeps its count in `B`.
+        let inner = "(local.set 2 (i32.const 4))
+            (loop
+              (local.set 1 (i32.add (local.get 1) (i32.const 1)))
+              (br_if 0 (local.tee 2 (i32.sub (local.get 2) (i32.const 1)))))";
+        for level in [OptLevel::O1, OptLevel::O2, OptLevel::O3, OptLevel::Os] {
+            let (asm, result) = counting(10, inner, level);
+            assert_eq!(asm.matches("DJNZ").count(), 1, "{level:?}\n{asm}");
+            assert_eq!(result, 70, "{level:?}");
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use wasmparser::ValType;
+
+use crate::asm::{imm, mem, Inst, Reg16::*, Reg8::*};
+use crate::compile::{Provided, GLOBALS_OFFSET};
+
+/// Offset of the scratch buffer in the data block, after the 8080's
+/// registers in memory.
+pub const SCRATCH_OFFSET: i64 = 16;
+
+/// Number of globals there is room for in the data block.
+pub const MAX_GLOBALS: usize = 16;
+
+/// Size of the data block: the 8080's registers, the scratch buffer, the
+/// trap record and the globals.
+const DATA_SIZE: usize = SCRATCH_OFFSET as usize + GLOBALS_OFFSET + 4 * MAX_GLOBALS;
+
+/// Emits code starting the stack at the BDOS, below the serial number on
+/// the page of its entry, whose address the word at 6 holds. It grows down
+/// over the CCP, which CP/M reloads on exit.
+pub fn emit_setup(code: &mut Vec<Inst>) {
+    code.push(Inst::Ld(HL.into(), mem(6)));
+    code.push(Inst::Ld(L.into(), imm(0)));
+    code.push(Inst::Ld(SP.into(), HL.into()));
+}
+
+/// Emits the data block, which is part of the program since the top of
+/// memory belongs to CP/M.
+pub fn emit_data(code: &mut Vec<Inst>) {
+    code.push(Inst::Label("rt_data".into()));
+    for row in (0..DATA_SIZE).step_by(16) {
+        code.push(Inst::Db(vec![0.into(); (DATA_SIZE - row).min(16)]));
+    }
+}
+
+/// Routines imported from `console`, calling the BDOS functions they stand
+/// for.
+pub const IMPORTS: &[Provided] = &[
+    Provided {
+        name: "putchar",
+        params: &[ValType::I32],
+        results: &[],
+        body: PUTCHAR,
+    },
+    Provided {
+        name: "getchar",
+        params: &[],
+        results: &[ValType::I32],
+        body: GETCHAR,
+    },
+    Provided {
+        name: "print",
+        params: &[ValType::I32],
+        results: &[],
+        body: PRINT,
+    },
+];
+
+// The BDOS may change any register, and the index registers are saved
+// around it for BIOSes written for the Z80.
+
+/// Writes the character in the low byte of the argument with BDOS function 2.
+const PUTCHAR: &str = "
+  PUSH IX
+  PUSH IY
+  LD C,2
+  CALL 5
+  POP IY
+  POP IX
+";
+
+/// Reads a character with BDOS function 1, which echoes it.
+const GETCHAR: &str = "
+  PUSH IX
+  PUSH IY
+  LD C,1
+  CALL 5
+  POP IY
+  POP IX
+";
+
+/// Writes the string at the address in the argument, up to a `$`, with BDOS
+/// function 9.
+const PRINT: &str = "
+  PUSH IX
+  PUSH IY
+  LD C,9
+  CALL 5
+  POP IY
+  POP IX
+";
+
+#[cfg(test)]
+mod tests {
+    use crate::compile::{Config, Platform};
+    use crate::loader;
+
+    fn compile(offset: u32) -> anyhow::Result<Vec<u8>> {
+        let wasm = wat::parse_str(format!(
+            r#"(module (memory 1) (data (i32.const {offset}) "Hi$") (func (export "entry")))"#
+        ))
+        .unwrap();
+        let config = Config {
+            platform: Platform::Cpm,
+            ..Config::default()
+        };
+        let mut out = vec![];
+        loader::load(&wasm)?.compile(&config, &mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn data_over_program_is_rejected() {
+        assert!(compile(0x100).is_err());
+        assert!(compile(0x4000).is_ok());
+    }
+}
@@ -0,0 +1,778 @@
+//! A Z80 core for tests, running the machine code of a program compiled for
+//! the Z80 without a platform until it halts.
+//!
+//! It runs the documented instructions the compiler and the runtime routines
+//! use, with the flags they test exact, and doesn't count cycles or take
+//! interrupts.
+
+use crate::compile::{Config, Format};
+use crate::loader;
+
+/// Address of the console, which prints the bytes written to it.
+const CONSOLE_ADDR: u16 = 0xFFFF;
+
+/// Flag bits of `F`.
+const FLAG_C: u8 = 0x01;
+const FLAG_N: u8 = 0x02;
+const FLAG_PV: u8 = 0x04;
+const FLAG_H: u8 = 0x10;
+const FLAG_Z: u8 = 0x40;
+const FLAG_S: u8 = 0x80;
+
+/// Register `HL` stands for in an instruction, as its prefix selects.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Index {
+    Hl,
+    Ix,
+    Iy,
+}
+
+pub struct Machine {
+    pub mem: Vec<u8>,
+    /// Bytes written to the console, in order.
+    pub console: Vec<u8>,
+    /// `A`, `F`, `B`, `C`, `D`, `E`, `H` and `L`.
+    regs: [u8; 8],
+    /// The shadow registers, in the same order.
+    shadow: [u8; 8],
+    ix: u16,
+    iy: u16,
+    pub sp: u16,
+    pc: u16,
+    halted: bool,
+    /// Number of instructions run.
+    steps: usize,
+}
+
+const A: usize = 0;
+const F: usize = 1;
+
+/// Index into `regs` of the 8-bit register with the code `r` in an opcode,
+/// other than 6 for memory.
+fn reg_index(r: u8) -> usize {
+    match r {
+        7 => A,
+        r => 2 + r as usize,
+    }
+}
+
+fn parity(value: u8) -> bool {
+    value.count_ones().is_multiple_of(2)
+}
+
+/// Flags `S`, `Z` and `P/V` set from a result, with parity in `P/V`.
+fn szp(value: u8) -> u8 {
+    let mut flags = value & FLAG_S;
+    if value == 0 {
+        flags |= FLAG_Z;
+    }
+    if parity(value) {
+        flags |= FLAG_PV;
+    }
+    flags
+}
+
+impl Machine {
+    /// Loads `image` at address 0, to start running from there.
+    pub fn new(image: &[u8]) -> Machine {
+        let mut mem = vec![0; 0x10000];
+        mem[..image.len()].copy_from_slice(image);
+        Machine {
+            mem,
+            console: vec![],
+            regs: [0xFF; 8],
+            shadow: [0xFF; 8],
+            ix: 0xFFFF,
+            iy: 0xFFFF,
+            sp: 0xFFFF,
+            pc: 0,
+            halted: false,
+            steps: 0,
+        }
+    }
+
+    /// Loads `image` and runs it until it halts.
+    pub fn run(image: &[u8]) -> Machine {
+        let mut machine = Machine::new(image);
+        machine.run_until_halt();
+        machine
+    }
+
+    /// Runs until `HALT`, panicking if that takes too long.
+    pub fn run_until_halt(&mut self) {
+        while !self.halted {
+            assert!(
+                self.steps < 50_000_000,
+                "still running at 0x{:04X}",
+                self.pc
+            );
+            self.step();
+            self.steps += 1;
+        }
+    }
+
+    pub fn byte(&self, addr: u16) -> u8 {
+        self.mem[usize::from(addr)]
+    }
+
+    /// Returns the little-endian word at `addr`.
+    pub fn word(&self, addr: u16) -> u16 {
+        u16::from_le_bytes([self.byte(addr), self.byte(addr.wrapping_add(1))])
+    }
+
+    /// Returns the i32 on top of the operand stack, where the entry leaves
+    /// its result, with its high word lowest.
+    pub fn top_i32(&self) -> i32 {
+        let high = self.word(self.sp);
+        let low = self.word(self.sp.wrapping_add(2));
+        ((u32::from(high) << 16) | u32::from(low)) as i32
+    }
+
+    /// Returns the i64 on top of the operand stack, with its highest word
+    /// lowest.
+    pub fn top_i64(&self) -> i64 {
+        (0..4).fold(0, |value, word| {
+            let addr = self.sp.wrapping_add(word * 2);
+            (value << 16) | i64::from(self.word(addr))
+        })
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        if addr == CONSOLE_ADDR {
+            self.console.push(value);
+        }
+        self.mem[usize::from(addr)] = value;
+    }
+
+    fn write_word(&mut self, addr: u16, value: u16) {
+        let [low, high] = value.to_le_bytes();
+        self.write(addr, low);
+        self.write(addr.wrapping_add(1), high);
+    }
+
+    fn fetch(&mut self) -> u8 {
+        let value = self.byte(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        value
+    }
+
+    fn fetch_word(&mut self) -> u16 {
+        let low = self.fetch();
+        let high = self.fetch();
+        u16::from_le_bytes([low, high])
+    }
+
+    fn pair(&self, high: usize) -> u16 {
+        u16::from_be_bytes([self.regs[high], self.regs[high + 1]])
+    }
+
+    fn set_pair(&mut self, high: usize, value: u16) {
+        [self.regs[high], self.regs[high + 1]] = value.to_be_bytes();
+    }
+
+    fn bc(&self) -> u16 {
+        self.pair(2)
+    }
+
+    fn de(&self) -> u16 {
+        self.pair(4)
+    }
+
+    fn hl(&self) -> u16 {
+        self.pair(6)
+    }
+
+    fn flag(&self, flag: u8) -> bool {
+        self.regs[F] & flag != 0
+    }
+
+    /// Returns `HL`, or the index register standing for it.
+    fn indexed(&self, index: Index) -> u16 {
+        match index {
+            Index::Hl => self.hl(),
+            Index::Ix => self.ix,
+            Index::Iy => self.iy,
+        }
+    }
+
+    fn set_indexed(&mut self, index: Index, value: u16) {
+        match index {
+            Index::Hl => self.set_pair(6, value),
+            Index::Ix => self.ix = value,
+            Index::Iy => self.iy = value,
+        }
+    }
+
+    /// Returns the pair with the code `p` in opcodes taking `SP`.
+    fn rp(&self, p: u8, index: Index) -> u16 {
+        match p {
+            0 => self.bc(),
+            1 => self.de(),
+            2 => self.indexed(index),
+            _ => self.sp,
+        }
+    }
+
+    fn set_rp(&mut self, p: u8, index: Index, value: u16) {
+        match p {
+            0 => self.set_pair(2, value),
+            1 => self.set_pair(4, value),
+            2 => self.set_indexed(index, value),
+            _ => self.sp = value,
+        }
+    }
+
+    /// Returns the pair with the code `p` in `PUSH` and `POP`.
+    fn rp2(&self, p: u8, index: Index) -> u16 {
+        match p {
+            3 => self.pair(A),
+            p => self.rp(p, index),
+        }
+    }
+
+    fn set_rp2(&mut self, p: u8, index: Index, value: u16) {
+        match p {
+            3 => self.set_pair(A, value),
+            p => self.set_rp(p, index, value),
+        }
+    }
+
+    /// Returns the address of the memory operand, `(HL)` or `(IX+d)` with
+    /// the displacement fetched.
+    fn operand_addr(&mut self, index: Index) -> u16 {
+        match index {
+            Index::Hl => self.hl(),
+            _ => {
+                let displacement = self.fetch() as i8;
+                self.indexed(index).wrapping_add_signed(displacement.into())
+            }
+        }
+    }
+
+    /// Reads the 8-bit register with the code `r`, which is an index register
+    /// half where the prefix says so. Memory is read from `addr`.
+    fn get(&self, r: u8, index: Index, addr: u16) -> u8 {
+        match (r, index) {
+            (6, _) => self.byte(addr),
+            (4, Index::Ix) => (self.ix >> 8) as u8,
+            (5, Index::Ix) => self.ix as u8,
+            (4, Index::Iy) => (self.iy >> 8) as u8,
+            (5, Index::Iy) => self.iy as u8,
+            (r, _) => self.regs[reg_index(r)],
+        }
+    }
+
+    fn set(&mut self, r: u8, index: Index, addr: u16, value: u8) {
+        match (r, index) {
+            (6, _) => self.write(addr, value),
+            (4, Index::Ix) => self.ix = (self.ix & 0xFF) | u16::from(value) << 8,
+            (5, Index::Ix) => self.ix = (self.ix & 0xFF00) | u16::from(value),
+            (4, Index::Iy) => self.iy = (self.iy & 0xFF) | u16::from(value) << 8,
+            (5, Index::Iy) => self.iy = (self.iy & 0xFF00) | u16::from(value),
+            (r, _) => self.regs[reg_index(r)] = value,
+        }
+    }
+
+    fn push(&mut self, value: u16) {
+        self.sp = self.sp.wrapping_sub(2);
+        self.write_word(self.sp, value);
+    }
+
+    fn pop(&mut self) -> u16 {
+        let value = self.word(self.sp);
+        self.sp = self.sp.wrapping_add(2);
+        value
+    }
+
+    fn condition(&self, cc: u8) -> bool {
+        match cc {
+            0 => !self.flag(FLAG_Z),
+            1 => self.flag(FLAG_Z),
+            2 => !self.flag(FLAG_C),
+            3 => self.flag(FLAG_C),
+            4 => !self.flag(FLAG_PV),
+            5 => self.flag(FLAG_PV),
+            6 => !self.flag(FLAG_S),
+            _ => self.flag(FLAG_S),
+        }
+    }
+
+    fn jump_relative(&mut self, displacement: u8) {
+        self.pc = self.pc.wrapping_add_signed((displacement as i8).into());
+    }
+
+    fn step(&mut self) {
+        let mut op = self.fetch();
+        let mut index = Index::Hl;
+        loop {
+            match op {
+                0xDD => index = Index::Ix,
+                0xFD => index = Index::Iy,
+                _ => break,
+            }
+            op = self.fetch();
+        }
+        match op {
+            0xCB => self.cb(index),
+            0xED => self.ed(op),
+            _ => self.main(op, index),
+        }
+    }
+
+    fn main(&mut self, op: u8, index: Index) {
+        let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+        let (p, q) = (y >> 1, y & 1);
+        match (x, z) {
+            (0, 0) => match y {
+                0 => {}
+                1 => self.regs[..2].swap_with_slice(&mut self.shadow[..2]),
+                2 => {
+                    let displacement = self.fetch();
+                    self.regs[2] = self.regs[2].wrapping_sub(1);
+                    if self.regs[2] != 0 {
+                        self.jump_relative(displacement);
+                    }
+                }
+                3 => {
+                    let displacement = self.fetch();
+                    self.jump_relative(displacement);
+                }
+                _ => {
+                    let displacement = self.fetch();
+                    if self.condition(y - 4) {
+                        self.jump_relative(displacement);
+                    }
+                }
+            },
+            (0, 1) if q == 0 => {
+                let value = self.fetch_word();
+                self.set_rp(p, index, value);
+            }
+            (0, 1) => {
+                let (lhs, rhs) = (self.indexed(index), self.rp(p, index));
+                let sum = u32::from(lhs) + u32::from(rhs);
+                let half = (lhs & 0xFFF) + (rhs & 0xFFF) > 0xFFF;
+                let mut flags = self.regs[F] & (FLAG_S | FLAG_Z | FLAG_PV);
+                if half {
+                    flags |= FLAG_H;
+                }
+                if sum > 0xFFFF {
+                    flags |= FLAG_C;
+                }
+                self.regs[F] = flags;
+                self.set_indexed(index, sum as u16);
+            }
+            (0, 2) => match (q, p) {
+                (0, 0) => self.write(self.bc(), self.regs[A]),
+                (0, 1) => self.write(self.de(), self.regs[A]),
+                (0, 2) => {
+                    let addr = self.fetch_word();
+                    self.write_word(addr, self.indexed(index));
+                }
+                (0, _) => {
+                    let addr = self.fetch_word();
+                    self.write(addr, self.regs[A]);
+                }
+                (_, 0) => self.regs[A] = self.byte(self.bc()),
+                (_, 1) => self.regs[A] = self.byte(self.de()),
+                (_, 2) => {
+                    let addr = self.fetch_word();
+                    self.set_indexed(index, self.word(addr));
+                }
+                _ => {
+                    let addr = self.fetch_word();
+                    self.regs[A] = self.byte(addr);
+                }
+            },
+            (0, 3) => {
+                let value = self.rp(p, index);
+                let value = match q {
+                    0 => value.wrapping_add(1),
+                    _ => value.wrapping_sub(1),
+                };
+                self.set_rp(p, index, value);
+            }
+            (0, 4 | 5) => {
+                let addr = if y == 6 { self.operand_addr(index) } else { 0 };
+                let value = self.get(y, index, addr);
+                let result = self.inc_dec(value, z == 5);
+                self.set(y, index, addr, result);
+            }
+            (0, 6) => {
+                let addr = if y == 6 { self.operand_addr(index) } else { 0 };
+                let value = self.fetch();
+                self.set(y, index, addr, value);
+            }
+            (0, _) => self.accumulator_op(y),
+            (1, 6) if y == 6 => self.halted = true,
+            (1, _) => {
+                // With a memory operand, the other is a plain register.
+                let memory = y == 6 || z == 6;
+                let addr = if memory { self.operand_addr(index) } else { 0 };
+                let registers = if memory { Index::Hl } else { index };
+                let value = self.get(z, registers, addr);
+                self.set(y, registers, addr, value);
+            }
+            (2, _) => {
+                let addr = if z == 6 { self.operand_addr(index) } else { 0 };
+                let value = self.get(z, index, addr);
+                self.alu(y, value);
+            }
+            (3, 0) => {
+                if self.condition(y) {
+                    self.pc = self.pop();
+                }
+            }
+            (3, 1) if q == 0 => {
+                let value = self.pop();
+                self.set_rp2(p, index, value);
+            }
+            (3, 1) => match p {
+                0 => self.pc = self.pop(),
+                1 => {
+                    for reg in 2..8 {
+                        std::mem::swap(&mut self.regs[reg], &mut self.shadow[reg]);
+                    }
+                }
+                2 => self.pc = self.indexed(index),
+                _ => self.sp = self.indexed(index),
+            },
+            (3, 2) => {
+                let addr = self.fetch_word();
+                if self.condition(y) {
+                    self.pc = addr;
+                }
+            }
+            (3, 3) => match y {
+                0 => self.pc = self.fetch_word(),
+                4 => {
+                    let value = self.word(self.sp);
+                    self.write_word(self.sp, self.indexed(index));
+                    self.set_indexed(index, value);
+                }
+                5 => {
+                    let (de, hl) = (self.de(), self.hl());
+                    self.set_pair(4, hl);
+                    self.set_pair(6, de);
+                }
+                6 | 7 => {}
+                _ => panic!("unsupported opcode 0x{op:02X} at 0x{:04X}", self.pc - 1),
+            },
+            (3, 4) => {
+                let addr = self.fetch_word();
+                if self.condition(y) {
+                    self.push(self.pc);
+                    self.pc = addr;
+                }
+            }
+            (3, 5) if q == 0 => self.push(self.rp2(p, index)),
+            (3, 5) if p == 0 => {
+                let addr = self.fetch_word();
+                self.push(self.pc);
+                self.pc = addr;
+            }
+            (3, 6) => {
+                let value = self.fetch();
+                self.alu(y, value);
+            }
+            (3, 7) => {
+                self.push(self.pc);
+                self.pc = u16::from(y) * 8;
+            }
+            _ => panic!("unsupported opcode 0x{op:02X} at 0x{:04X}", self.pc - 1),
+        }
+    }
+
+    fn inc_dec(&mut self, value: u8, dec: bool) -> u8 {
+        let (result, overflow, half) = match dec {
+            false => (value.wrapping_add(1), value == 0x7F, value & 0xF == 0xF),
+            true => (value.wrapping_sub(1), value == 0x80, value & 0xF == 0),
+        };
+        let mut flags = self.regs[F] & FLAG_C | szp(result) & !FLAG_PV;
+        if overflow {
+            flags |= FLAG_PV;
+        }
+        if half {
+            flags |= FLAG_H;
+        }
+        if dec {
+            flags |= FLAG_N;
+        }
+        self.regs[F] = flags;
+        result
+    }
+
+    /// Runs the rotates of `A`, `DAA`, `CPL`, `SCF` and `CCF`.
+    fn accumulator_op(&mut self, y: u8) {
+        let a = self.regs[A];
+        let carry = self.flag(FLAG_C);
+        let kept = self.regs[F] & (FLAG_S | FLAG_Z | FLAG_PV);
+        let (result, carry_out) = match y {
+            0 => (a.rotate_left(1), a & 0x80 != 0),
+            1 => (a.rotate_right(1), a & 1 != 0),
+            2 => (a << 1 | carry as u8, a & 0x80 != 0),
+            3 => (a >> 1 | (carry as u8) << 7, a & 1 != 0),
+            4 => return self.daa(),
+            5 => {
+                self.regs[A] = !a;
+                self.regs[F] |= FLAG_H | FLAG_N;
+                return;
+            }
+            6 => (a, true),
+            _ => {
+                let half = if carry { FLAG_H } else { 0 };
+                self.regs[F] = kept | half | !carry as u8;
+                return;
+            }
+        };
+        self.regs[A] = result;
+        self.regs[F] = kept | carry_out as u8;
+    }
+
+    fn daa(&mut self) {
+        let a = self.regs[A];
+        let mut correction = 0;
+        let mut carry = self.flag(FLAG_C);
+        if self.flag(FLAG_H) || a & 0xF > 9 {
+            correction |= 0x06;
+        }
+        if carry || a > 0x99 {
+            correction |= 0x60;
+            carry = true;
+        }
+        let subtract = self.flag(FLAG_N);
+        let result = match subtract {
+            false => a.wrapping_add(correction),
+            true => a.wrapping_sub(correction),
+        };
+        let mut flags = szp(result) | self.regs[F] & FLAG_N | carry as u8;
+        if (a ^ result) & 0x10 != 0 {
+            flags |= FLAG_H;
+        }
+        self.regs[A] = result;
+        self.regs[F] = flags;
+    }
+
+    /// Runs the operation with the code `y` on `A` and `value`.
+    fn alu(&mut self, y: u8, value: u8) {
+        let a = self.regs[A];
+        let carry = self.flag(FLAG_C) as u8;
+        match y {
+            0 | 1 => {
+                let carry = if y == 1 { carry } else { 0 };
+                self.regs[A] = self.add8(a, value, carry);
+            }
+            2 | 3 | 7 => {
+                let carry = if y == 3 { carry } else { 0 };
+                let result = self.sub8(a, value, carry);
+                if y != 7 {
+                    self.regs[A] = result;
+                }
+            }
+            _ => {
+                let result = match y {
+                    4 => a & value,
+                    5 => a ^ value,
+                    _ => a | value,
+                };
+                let half = if y == 4 { FLAG_H } else { 0 };
+                self.regs[A] = result;
+                self.regs[F] = szp(result) | half;
+            }
+        }
+    }
+
+    fn add8(&mut self, a: u8, value: u8, carry: u8) -> u8 {
+        let sum = u16::from(a) + u16::from(value) + u16::from(carry);
+        let result = sum as u8;
+        let mut flags = szp(result) & !FLAG_PV;
+        if (a ^ value ^ 0x80) & (a ^ result) & 0x80 != 0 {
+            flags |= FLAG_PV;
+        }
+        if (a & 0xF) + (value & 0xF) + carry > 0xF {
+            flags |= FLAG_H;
+        }
+        if sum > 0xFF {
+            flags |= FLAG_C;
+        }
+        self.regs[F] = flags;
+        result
+    }
+
+    fn sub8(&mut self, a: u8, value: u8, carry: u8) -> u8 {
+        let difference = i16::from(a) - i16::from(value) - i16::from(carry);
+        let result = difference as u8;
+        let mut flags = szp(result) & !FLAG_PV | FLAG_N;
+        if (a ^ value) & (a ^ result) & 0x80 != 0 {
+            flags |= FLAG_PV;
+        }
+        if i16::from(a & 0xF) - i16::from(value & 0xF) - i16::from(carry) < 0 {
+            flags |= FLAG_H;
+        }
+        if difference < 0 {
+            flags |= FLAG_C;
+        }
+        self.regs[F] = flags;
+        result
+    }
+
+    /// Runs `ADC HL,rr` or `SBC HL,rr`, which set every flag from the 16-bit
+    /// result.
+    fn adc_sbc16(&mut self, rhs: u16, subtract: bool) {
+        let lhs = self.hl();
+        let carry = self.flag(FLAG_C) as i32;
+        let (result, overflow, carry_out) = match subtract {
+            false => {
+                let sum = i32::from(lhs) + i32::from(rhs) + carry;
+                let result = sum as u16;
+                let overflow = (lhs ^ rhs ^ 0x8000) & (lhs ^ result) & 0x8000 != 0;
+                (result, overflow, sum > 0xFFFF)
+            }
+            true => {
+                let difference = i32::from(lhs) - i32::from(rhs) - carry;
+                let result = difference as u16;
+                let overflow = (lhs ^ rhs) & (lhs ^ result) & 0x8000 != 0;
+                (result, overflow, difference < 0)
+            }
+        };
+        let mut flags = (result >> 8) as u8 & FLAG_S;
+        if result == 0 {
+            flags |= FLAG_Z;
+        }
+        if overflow {
+            flags |= FLAG_PV;
+        }
+        if subtract {
+            flags |= FLAG_N;
+        }
+        if carry_out {
+            flags |= FLAG_C;
+        }
+        self.regs[F] = flags;
+        self.set_pair(6, result);
+    }
+
+    fn cb(&mut self, index: Index) {
+        // An indexed operand's displacement comes before the opcode.
+        let addr = self.operand_addr(index);
+        let op = self.fetch();
+        let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+        let z = if index == Index::Hl { z } else { 6 };
+        let value = self.get(z, Index::Hl, addr);
+        let carry = self.flag(FLAG_C) as u8;
+        let result = match x {
+            0 => {
+                let (result, carry_out) = match y {
+                    0 => (value.rotate_left(1), value >> 7),
+                    1 => (value.rotate_right(1), value & 1),
+                    2 => (value << 1 | carry, value >> 7),
+                    3 => (value >> 1 | carry << 7, value & 1),
+                    4 => (value << 1, value >> 7),
+                    5 => (value >> 1 | value & 0x80, value & 1),
+                    6 => (value << 1 | 1, value >> 7),
+                    _ => (value >> 1, value & 1),
+                };
+                self.regs[F] = szp(result) | carry_out;
+                result
+            }
+            1 => {
+                let bit = value & (1 << y);
+                let mut flags = self.regs[F] & FLAG_C | FLAG_H | bit & FLAG_S;
+                if bit == 0 {
+                    flags |= FLAG_Z | FLAG_PV;
+                }
+                self.regs[F] = flags;
+                return;
+            }
+            2 => value & !(1 << y),
+            _ => value | 1 << y,
+        };
+        self.set(z, Index::Hl, addr, result);
+    }
+
+    fn ed(&mut self, prefix: u8) {
+        let op = self.fetch();
+        let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+        let (p, q) = (y >> 1, y & 1);
+        match (x, z) {
+            (1, 2) => {
+                let rhs = self.rp(p, Index::Hl);
+                self.adc_sbc16(rhs, q == 0);
+            }
+            (1, 3) => {
+                let addr = self.fetch_word();
+                match q {
+                    0 => self.write_word(addr, self.rp(p, Index::Hl)),
+                    _ => {
+                        let value = self.word(addr);
+                        self.set_rp(p, Index::Hl, value);
+                    }
+                }
+            }
+            (1, 4) => {
+                let a = self.regs[A];
+                self.regs[A] = self.sub8(0, a, 0);
+            }
+            (1, 5) => self.pc = self.pop(),
+            (2, 0) if y >= 4 => {
+                let step = if y & 1 == 0 { 1 } else { -1 };
+                loop {
+                    let value = self.byte(self.hl());
+                    self.write(self.de(), value);
+                    self.set_pair(6, self.hl().wrapping_add_signed(step));
+                    self.set_pair(4, self.de().wrapping_add_signed(step));
+                    self.set_pair(2, self.bc().wrapping_sub(1));
+                    // The repeating forms stop once BC runs out.
+                    if y < 6 || self.bc() == 0 {
+                        break;
+                    }
+                }
+                let mut flags = self.regs[F] & (FLAG_S | FLAG_Z | FLAG_C);
+                if self.bc() != 0 {
+                    flags |= FLAG_PV;
+                }
+                self.regs[F] = flags;
+            }
+            _ => panic!(
+                "unsupported opcode 0x{prefix:02X} 0x{op:02X} at 0x{:04X}",
+                self.pc - 2
+            ),
+        }
+    }
+}
+
+/// Compiles the module in `wat` for the Z80 alone in memory as `config` says
+/// otherwise, and runs it until it halts.
+pub fn run_wat(wat: &str, config: Config) -> Machine {
+    let wasm = wat::parse_str(wat).unwrap();
+    let config = Config {
+        format: Format::Bin,
+        ..config
+    };
+    let mut image = vec![];
+    let module = loader::load(&wasm).unwrap();
+    module.compile(&config, &mut image).unwrap();
+    Machine::run(&image)
+}
+
+/// Compiles `wat` with `config` and returns the code of function `index`:
+/// its instructions and labels, one per line, without comments. `None` if
+/// the function isn't compiled.
+pub fn function_asm(wat: &str, config: Config, index: usize) -> Option<Vec<String>> {
+    let wasm = wat::parse_str(wat).unwrap();
+    let mut out = vec![];
+    let module = loader::load(&wasm).unwrap();
+    module.compile(&config, &mut out).unwrap();
+    let asm = String::from_utf8(out).unwrap();
+    let mut lines = asm
+        .lines()
+        .skip_while(|line| *line != format!("func_{index}:"));
+    lines.next()?;
+    // Its code ends where a label other than its own starts.
+    let code = lines
+        .take_while(|line| line.starts_with(' ') || line.starts_with("label_"))
+        .map(str::trim)
+        .filter(|line| !line.starts_with(';'))
+        .map(String::from);
+    Some(code.collect())
+}
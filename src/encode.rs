@@ -0,0 +1,644 @@
+use std::collections::HashMap;
+
+use crate::asm::{self, Alu, BitOp, Cond, Expr, Inst, Operand, Plain, Reg16, Reg8, Shift, Suffix};
+use crate::compile::Target;
+
+/// Assembles `code` into machine code for `target`, placed at `origin`.
+///
+/// Every symbol the code refers to must be a label in it or defined by an
+/// `EQU` before its use. The 8080 shares the Z80's encoding of the
+/// instructions it has.
+pub fn assemble(code: &[Inst], origin: u16, target: Target) -> Vec<u8> {
+    let mut encoder = Encoder {
+        symbols: symbols(code, origin),
+        origin,
+        sm83: target == Target::Sm83,
+        ez80: target == Target::Ez80,
+        z180: target == Target::Z180,
+        r800: target == Target::R800,
+        out: Vec::with_capacity(crate::asm::size(code)),
+    };
+    for inst in code {
+        let start = encoder.out.len();
+        encoder.inst(inst);
+        assert_eq!(
+            encoder.out.len() - start,
+            inst.size(),
+            "{:?} encoded to an unexpected size",
+            inst
+        );
+    }
+    encoder.out
+}
+
+/// Returns the value of every label and `EQU` symbol in `code`.
+fn symbols(code: &[Inst], origin: u16) -> HashMap<String, i64> {
+    let mut symbols = HashMap::new();
+    let mut address = i64::from(origin);
+    for inst in code {
+        if let Inst::Label(name) = inst {
+            symbols.insert(name.clone(), address);
+        }
+        address += inst.size() as i64;
+    }
+    for inst in code {
+        if let Inst::Equ(name, expr) = inst {
+            let value = eval(&symbols, expr);
+            symbols.insert(name.clone(), value);
+        }
+    }
+    symbols
+}
+
+fn eval(symbols: &HashMap<String, i64>, expr: &Expr) -> i64 {
+    let base = match &expr.symbol {
+        Some(symbol) => match symbols.get(symbol) {
+            Some(value) => *value,
+            None => panic!("undefined symbol {}", symbol),
+        },
+        None => 0,
+    };
+    base + expr.offset
+}
+
+struct Encoder {
+    symbols: HashMap<String, i64>,
+    origin: u16,
+    sm83: bool,
+    ez80: bool,
+    z180: bool,
+    r800: bool,
+    out: Vec<u8>,
+}
+
+fn reg8(reg: Reg8) -> u8 {
+    match reg {
+        Reg8::B => 0,
+        Reg8::C => 1,
+        Reg8::D => 2,
+        Reg8::E => 3,
+        Reg8::H => 4,
+        Reg8::L => 5,
+        Reg8::A => 7,
+    }
+}
+
+/// Code of `reg` in instructions taking `BC`, `DE`, `HL` or `SP`, where the
+/// index registers take the place of `HL`.
+fn reg16(reg: Reg16) -> u8 {
+    match reg {
+        Reg16::BC => 0,
+        Reg16::DE => 1,
+        Reg16::HL | Reg16::IX | Reg16::IY => 2,
+        Reg16::SP => 3,
+        Reg16::AF => unreachable!(),
+    }
+}
+
+/// Code of `reg` in `PUSH` and `POP`, which take `AF` instead of `SP`.
+fn push_reg16(reg: Reg16) -> u8 {
+    match reg {
+        Reg16::AF => 3,
+        reg => reg16(reg),
+    }
+}
+
+fn cond(cond: Cond) -> u8 {
+    match cond {
+        Cond::Nz => 0,
+        Cond::Z => 1,
+        Cond::Nc => 2,
+        Cond::C => 3,
+        Cond::Po => 4,
+        Cond::Pe => 5,
+        Cond::P => 6,
+        Cond::M => 7,
+    }
+}
+
+fn alu(alu: Alu) -> u8 {
+    match alu {
+        Alu::Add => 0,
+        Alu::Adc => 1,
+        Alu::Sub => 2,
+        Alu::Sbc => 3,
+        Alu::And => 4,
+        Alu::Xor => 5,
+        Alu::Or => 6,
+        Alu::Cp => 7,
+    }
+}
+
+fn shift(shift: Shift) -> u8 {
+    match shift {
+        Shift::Rlc => 0,
+        Shift::Rrc => 1,
+        Shift::Rl => 2,
+        Shift::Rr => 3,
+        Shift::Sla => 4,
+        Shift::Sra => 5,
+        Shift::Srl => 7,
+    }
+}
+
+/// Code of an 8-bit operand: a register, or memory at `HL` or an index
+/// register plus displacement, which share the code of `(HL)`.
+fn operand8(operand: &Operand) -> Option<u8> {
+    match operand {
+        Operand::Reg8(reg) => Some(reg8(*reg)),
+        Operand::Ind(Reg16::HL) | Operand::Idx(..) => Some(6),
+        _ => None,
+    }
+}
+
+/// The index register and displacement among `operands`, if any.
+fn indexed<'a>(operands: impl IntoIterator<Item = &'a Operand>) -> Option<(Reg16, i16)> {
+    operands.into_iter().find_map(|operand| match operand {
+        Operand::Idx(reg, offset) => Some((*reg, *offset)),
+        _ => None,
+    })
+}
+
+impl Encoder {
+    fn value(&self, expr: &Expr) -> i64 {
+        eval(&self.symbols, expr)
+    }
+
+    fn byte(&mut self, expr: &Expr) {
+        let value = self.value(expr);
+        assert!(
+            (-128..=255).contains(&value),
+            "{} doesn't fit in a byte",
+            expr
+        );
+        self.out.push(value as u8);
+    }
+
+    fn word(&mut self, expr: &Expr) {
+        let value = self.value(expr);
+        assert!(
+            (-32768..=65535).contains(&value),
+            "{} doesn't fit in a word",
+            expr
+        );
+        self.out.extend((value as u16).to_le_bytes());
+    }
+
+    /// Emits the displacement of a relative jump to `target` from the end of
+    /// the 2-byte jump instruction.
+    fn relative(&mut self, target: &Expr) {
+        let end = i64::from(self.origin) + self.out.len() as i64 + 1;
+        let displacement = self.value(target) - end;
+        match i8::try_from(displacement) {
+            Ok(displacement) => self.out.push(displacement as u8),
+            Err(_) => panic!("relative jump to {} out of range", target),
+        }
+    }
+
+    fn z80_only(&self, inst: &Inst) {
+        assert!(!self.sm83, "{:?} is not an SM83 instruction", inst);
+    }
+
+    fn ez80_only(&self, inst: &Inst) {
+        assert!(self.ez80, "{:?} is an eZ80 instruction", inst);
+    }
+
+    fn z180_only(&self, inst: &Inst) {
+        assert!(self.z180, "{:?} is a Z180 instruction", inst);
+    }
+
+    fn r800_only(&self, inst: &Inst) {
+        assert!(self.r800, "{:?} is an R800 instruction", inst);
+    }
+
+    /// Emits the prefix selecting index register `reg` in place of `HL`.
+    fn prefix(&mut self, reg: Reg16) {
+        match reg {
+            Reg16::IX => self.out.push(0xDD),
+            Reg16::IY => self.out.push(0xFD),
+            _ => return,
+        }
+        assert!(!self.sm83, "the SM83 has no index registers");
+    }
+
+    /// Emits `opcode`, accessing `operands`, with the prefix and the
+    /// displacement of an indexed one around it.
+    fn indexed(&mut self, operands: &[&Operand], opcode: u8) {
+        let index = indexed(operands.iter().copied());
+        if let Some((reg, _)) = index {
+            self.prefix(reg);
+        }
+        self.out.push(opcode);
+        if let Some((_, offset)) = index {
+            self.displacement(offset);
+        }
+    }
+
+    fn displacement(&mut self, offset: i16) {
+        match i8::try_from(offset) {
+            Ok(offset) => self.out.push(offset as u8),
+            Err(_) => panic!("displacement {} out of range", offset),
+        }
+    }
+
+    /// Emits a `CB`-prefixed instruction on `operand`, where an indexed one
+    /// puts its displacement before the opcode.
+    fn cb(&mut self, operand: &Operand, opcode: u8) {
+        let code = operand8(operand).expect("not an 8-bit operand");
+        match operand {
+            Operand::Idx(reg, offset) => {
+                self.prefix(*reg);
+                self.out.push(0xCB);
+                self.displacement(*offset);
+            }
+            _ => self.out.push(0xCB),
+        }
+        self.out.push(opcode | code);
+    }
+
+    fn jump(&mut self, inst: &Inst, condition: Option<Cond>, base: u8, conditional: u8) {
+        match condition {
+            None => self.out.push(base),
+            Some(condition) => {
+                if self.sm83 && cond(condition) > 3 {
+                    panic!("{:?} tests a flag the SM83 lacks", inst);
+                }
+                self.out.push(conditional | cond(condition) << 3);
+            }
+        }
+    }
+
+    fn inst(&mut self, inst: &Inst) {
+        match inst {
+            Inst::Label(_) | Inst::Comment(_) | Inst::Equ(..) => {}
+            Inst::Org(addr) => assert_eq!(
+                self.out.len() as i64 + i64::from(self.origin),
+                i64::from(*addr),
+                "ORG is only supported at the origin"
+            ),
+            Inst::Db(values) => values.iter().for_each(|value| self.byte(value)),
+            Inst::Dw(values) => values.iter().for_each(|value| self.word(value)),
+            Inst::Ld(dst, src) => self.ld(inst, dst, src),
+            Inst::Ldh(dst, src) => {
+                let (opcode, addr) = match (dst, src) {
+                    (Operand::Mem(addr), Operand::Reg8(Reg8::A)) => (0xE0, addr),
+                    (Operand::Reg8(Reg8::A), Operand::Mem(addr)) => (0xF0, addr),
+                    _ => panic!("cannot encode {:?}", inst),
+                };
+                let value = self.value(addr);
+                assert!(
+                    (0xFF00..=0xFFFF).contains(&value),
+                    "{} is not in the high page",
+                    addr
+                );
+                self.out.extend([opcode, value as u8]);
+            }
+            Inst::Push(reg) => {
+                self.prefix(*reg);
+                self.out.push(0xC5 | push_reg16(*reg) << 4);
+            }
+            Inst::Pop(reg) => {
+                self.prefix(*reg);
+                self.out.push(0xC1 | push_reg16(*reg) << 4);
+            }
+            Inst::Ex(a, b) => {
+                self.z80_only(inst);
+                match (a, b) {
+                    (Operand::Reg16(Reg16::DE), Operand::Reg16(Reg16::HL)) => self.out.push(0xEB),
+                    (Operand::Ind(Reg16::SP), Operand::Reg16(reg)) => {
+                        self.prefix(*reg);
+                        self.out.push(0xE3);
+                    }
+                    _ => panic!("cannot encode {:?}", inst),
+                }
+            }
+            Inst::Alu16(Alu::Add, dst, src) => {
+                self.prefix(*dst);
+                self.out.push(0x09 | reg16(*src) << 4);
+            }
+            Inst::Alu16(alu @ (Alu::Adc | Alu::Sbc), Reg16::HL, src) => {
+                self.z80_only(inst);
+                let base = if *alu == Alu::Adc { 0x4A } else { 0x42 };
+                self.out.extend([0xED, base | reg16(*src) << 4]);
+            }
+            Inst::Alu16(..) => panic!("cannot encode {:?}", inst),
+            Inst::Alu(op, Operand::Imm(value)) => {
+                self.out.push(0xC6 | alu(*op) << 3);
+                self.byte(value);
+            }
+            Inst::Alu(op, src) => {
+                let code = operand8(src).expect("not an 8-bit operand");
+                self.indexed(&[src], 0x80 | alu(*op) << 3 | code);
+            }
+            Inst::Inc(Operand::Reg16(reg)) => {
+                self.prefix(*reg);
+                self.out.push(0x03 | reg16(*reg) << 4);
+            }
+            Inst::Dec(Operand::Reg16(reg)) => {
+                self.prefix(*reg);
+                self.out.push(0x0B | reg16(*reg) << 4);
+            }
+            Inst::Inc(operand) | Inst::Dec(operand) => {
+                let code = operand8(operand).expect("not an 8-bit operand");
+                let base = if matches!(inst, Inst::Inc(_)) {
+                    0x04
+                } else {
+                    0x05
+                };
+                self.indexed(&[operand], base | code << 3);
+            }
+            Inst::Shift(op, operand) => self.cb(operand, shift(*op) << 3),
+            Inst::Bit(op, bit, operand) => {
+                let base = match op {
+                    BitOp::Bit => 0x40,
+                    BitOp::Res => 0x80,
+                    BitOp::Set => 0xC0,
+                };
+                self.cb(operand, base | bit << 3);
+            }
+            Inst::Plain(plain) => {
+                let bytes: &[u8] = match plain {
+                    Plain::Nop => &[0x00],
+                    Plain::Halt => &[0x76],
+                    Plain::Di => &[0xF3],
+                    Plain::Ei => &[0xFB],
+                    Plain::Rlca => &[0x07],
+                    Plain::Rrca => &[0x0F],
+                    Plain::Rla => &[0x17],
+                    Plain::Rra => &[0x1F],
+                    Plain::Cpl => &[0x2F],
+                    Plain::Scf => &[0x37],
+                    Plain::Ccf => &[0x3F],
+                    Plain::Exx => &[0xD9],
+                    Plain::Neg => &[0xED, 0x44],
+                    Plain::Ldi => &[0xED, 0xA0],
+                    Plain::Ldd => &[0xED, 0xA8],
+                    Plain::Ldir => &[0xED, 0xB0],
+                    Plain::Lddr => &[0xED, 0xB8],
+                };
+                if bytes.len() > 1 || *plain == Plain::Exx {
+                    self.z80_only(inst);
+                }
+                self.out.extend(bytes);
+            }
+            Inst::Jp(condition, target) => {
+                self.jump(inst, *condition, 0xC3, 0xC2);
+                self.word(target);
+            }
+            Inst::JpInd(reg) => {
+                self.prefix(*reg);
+                self.out.push(0xE9);
+            }
+            Inst::Jr(condition, target) => {
+                if matches!(condition, Some(Cond::Po | Cond::Pe | Cond::P | Cond::M)) {
+                    panic!("cannot encode {:?}", inst);
+                }
+                self.jump(inst, *condition, 0x18, 0x20);
+                self.relative(target);
+            }
+            Inst::Djnz(target) => {
+                self.z80_only(inst);
+                self.out.push(0x10);
+                self.relative(target);
+            }
+            Inst::Call(condition, target) => {
+                self.jump(inst, *condition, 0xCD, 0xC4);
+                self.word(target);
+            }
+            Inst::Ret(condition) => self.jump(inst, *condition, 0xC9, 0xC0),
+            Inst::Rst(vector) => {
+                assert!(vector % 8 == 0 && *vector < 0x40, "bad RST vector");
+                self.out.push(0xC7 | vector);
+            }
+            Inst::Mlt(reg) => {
+                // The eZ80 took `MLT` over from the Z180.
+                if !self.z180 {
+                    self.ez80_only(inst);
+                }
+                self.out.extend([0xED, 0x4C | reg16(*reg) << 4]);
+            }
+            Inst::Lea(dst, src, offset) => {
+                self.ez80_only(inst);
+                let opcode = match (dst, src) {
+                    (Reg16::IX, Reg16::IX) => 0x32,
+                    (Reg16::IY, Reg16::IY) => 0x33,
+                    (Reg16::IX, Reg16::IY) => 0x54,
+                    (Reg16::IY, Reg16::IX) => 0x55,
+                    (Reg16::BC | Reg16::DE | Reg16::HL, Reg16::IX) => 0x02 | reg16(*dst) << 4,
+                    (Reg16::BC | Reg16::DE | Reg16::HL, Reg16::IY) => 0x03 | reg16(*dst) << 4,
+                    _ => panic!("cannot encode {:?}", inst),
+                };
+                self.out.extend([0xED, opcode, *offset as u8]);
+            }
+            Inst::LdMbA | Inst::LdAMb => {
+                self.ez80_only(inst);
+                let opcode = if *inst == Inst::LdMbA { 0x6D } else { 0x6E };
+                self.out.extend([0xED, opcode]);
+            }
+            // The suffixes switch modes: `LIS` from ADL to Z80 mode for the
+            // call, and back for the return, and `LIL` into ADL mode.
+            Inst::CallIs(target) => {
+                self.ez80_only(inst);
+                self.out.extend([0x49, 0xCD]);
+                self.word(target);
+            }
+            Inst::RetL => {
+                self.ez80_only(inst);
+                self.out.extend([0x49, 0xC9]);
+            }
+            Inst::RstLil(vector) => {
+                self.ez80_only(inst);
+                assert!(vector % 8 == 0 && *vector < 0x40, "bad RST vector");
+                self.out.extend([0x5B, 0xC7 | vector]);
+            }
+            Inst::Out0(port, reg) => {
+                self.z180_only(inst);
+                self.out.extend([0xED, 0x01 | reg8(*reg) << 3, *port]);
+            }
+            Inst::Muluw(reg @ (Reg16::BC | Reg16::SP)) => {
+                self.r800_only(inst);
+                self.out.extend([0xED, 0xC3 | reg16(*reg) << 4]);
+            }
+            Inst::Muluw(_) => panic!("cannot encode {:?}", inst),
+        }
+    }
+
+    /// Emits an `LD` with a long operand: the suffix, the instruction on the
+    /// operands as they are encoded, and the upper byte of a 24-bit address
+    /// after the 16 bits it ends with.
+    fn long_ld(&mut self, inst: &Inst, dst: &Operand, src: &Operand) {
+        self.ez80_only(inst);
+        self.out.push(match asm::suffix(inst).unwrap() {
+            Suffix::Lis => 0x49,
+            Suffix::Sil => 0x52,
+            Suffix::Lil => 0x5B,
+        });
+        let mut upper = None;
+        let mut short = |operand: &Operand| match operand {
+            Operand::Long(long) => match &**long {
+                Operand::Mem(addr) => {
+                    let value = self.value(addr);
+                    assert!(
+                        (0..0x1000000).contains(&value),
+                        "{} doesn't fit in 24 bits",
+                        addr
+                    );
+                    upper = Some((value >> 16) as u8);
+                    Operand::Mem((value & 0xFFFF).into())
+                }
+                operand => operand.clone(),
+            },
+            operand => operand.clone(),
+        };
+        let (dst, src) = (short(dst), short(src));
+        self.ld(inst, &dst, &src);
+        self.out.extend(upper);
+    }
+
+    fn ld(&mut self, inst: &Inst, dst: &Operand, src: &Operand) {
+        use Operand::{HlDec, HlInc, Imm, Ind, Mem, Reg16 as R16, Reg8 as R8, SpOffset};
+        match (dst, src) {
+            (Operand::Long(_), _) | (_, Operand::Long(_)) => self.long_ld(inst, dst, src),
+            (R8(Reg8::A), Ind(Reg16::BC)) => self.out.push(0x0A),
+            (R8(Reg8::A), Ind(Reg16::DE)) => self.out.push(0x1A),
+            (Ind(Reg16::BC), R8(Reg8::A)) => self.out.push(0x02),
+            (Ind(Reg16::DE), R8(Reg8::A)) => self.out.push(0x12),
+            (R8(Reg8::A), Mem(addr)) => {
+                self.out.push(if self.sm83 { 0xFA } else { 0x3A });
+                self.word(addr);
+            }
+            (Mem(addr), R8(Reg8::A)) => {
+                self.out.push(if self.sm83 { 0xEA } else { 0x32 });
+                self.word(addr);
+            }
+            (HlInc | HlDec, _) | (_, HlInc | HlDec) => {
+                assert!(self.sm83, "{:?} is an SM83 instruction", inst);
+                let opcode = match (dst, src) {
+                    (HlInc, R8(Reg8::A)) => 0x22,
+                    (R8(Reg8::A), HlInc) => 0x2A,
+                    (HlDec, R8(Reg8::A)) => 0x32,
+                    (R8(Reg8::A), HlDec) => 0x3A,
+                    _ => panic!("cannot encode {:?}", inst),
+                };
+                self.out.push(opcode);
+            }
+            (R16(Reg16::HL), SpOffset(offset)) => {
+                assert!(self.sm83, "{:?} is an SM83 instruction", inst);
+                self.out.extend([0xF8, *offset as u8]);
+            }
+            (R16(reg), Imm(value)) => {
+                self.prefix(*reg);
+                self.out.push(0x01 | reg16(*reg) << 4);
+                self.word(value);
+            }
+            (R16(Reg16::SP), R16(reg)) => {
+                self.prefix(*reg);
+                self.out.push(0xF9);
+            }
+            (R16(reg @ (Reg16::HL | Reg16::IX | Reg16::IY)), Mem(addr)) => {
+                self.z80_only(inst);
+                self.prefix(*reg);
+                self.out.push(0x2A);
+                self.word(addr);
+            }
+            (Mem(addr), R16(reg @ (Reg16::HL | Reg16::IX | Reg16::IY))) => {
+                self.z80_only(inst);
+                self.prefix(*reg);
+                self.out.push(0x22);
+                self.word(addr);
+            }
+            (R16(reg), Mem(addr)) => {
+                self.z80_only(inst);
+                self.out.extend([0xED, 0x4B | reg16(*reg) << 4]);
+                self.word(addr);
+            }
+            (Mem(addr), R16(reg)) => {
+                self.z80_only(inst);
+                self.out.extend([0xED, 0x43 | reg16(*reg) << 4]);
+                self.word(addr);
+            }
+            (dst, Imm(value)) => {
+                let code = operand8(dst).expect("not an 8-bit operand");
+                self.indexed(&[dst], 0x06 | code << 3);
+                self.byte(value);
+            }
+            (dst, src) => match (operand8(dst), operand8(src)) {
+                (Some(6), Some(6)) | (None, _) | (_, None) => panic!("cannot encode {:?}", inst),
+                (Some(d), Some(s)) => self.indexed(&[dst, src], 0x40 | d << 3 | s),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile::{Config, Format, OptLevel};
+    use crate::loader;
+
+    /// A module exercising much of what the compiler emits: arithmetic
+    /// through the runtime routines, memory, globals, indirect calls and
+    /// traps.
+    const MODULE: &str = r#"(module
+      (memory 1)
+      (global $g (mut i32) (i32.const 7))
+      (table 1 funcref)
+      (elem (i32.const 0) $scale)
+      (data (i32.const 0xC100) "\01\02\03\04")
+      (type $t (func (param i32) (result i32)))
+      (func $scale (param i32) (result i32)
+        (i32.div_s (i32.mul (local.get 0) (global.get $g)) (i32.const 3)))
+      (func (export "entry") (result i32)
+        (local $p i32)
+        (local.set $p (i32.const 0xC100))
+        (i32.store offset=8 (local.get $p) (i32.load (local.get $p)))
+        (global.set $g (i32.popcnt (i32.load8_u offset=2 (local.get $p))))
+        (if (i32.lt_s (global.get $g) (i32.const 0)) (then unreachable))
+        (call_indirect (type $t) (i32.rem_u (local.get $p) (i32.const 9)) (i32.const 0))))"#;
+
+    /// Checks that the assembly emitted for `target` assembles to the same
+    /// bytes as the binary output.
+    fn round_trip(target: Target) {
+        let wasm = wat::parse_str(MODULE).unwrap();
+        let module = loader::load(&wasm).unwrap();
+        let compile = |format| {
+            let config = Config {
+                target,
+                format,
+                passes: OptLevel::O3.passes(),
+                ..Config::default()
+            };
+            let mut out = vec![];
+            module.compile(&config, &mut out).unwrap();
+            out
+        };
+        let text = String::from_utf8(compile(Format::Asm)).unwrap();
+        let code = crate::asm::parse(&text);
+        assert_eq!(assemble(&code, 0, target), compile(Format::Bin));
+    }
+
+    #[test]
+    fn i8080_round_trip() {
+        round_trip(Target::I8080);
+    }
+
+    #[test]
+    fn sm83_round_trip() {
+        round_trip(Target::Sm83);
+    }
+
+    #[test]
+    fn ez80_round_trip() {
+        round_trip(Target::Ez80);
+    }
+
+    #[test]
+    fn z180_round_trip() {
+        round_trip(Target::Z180);
+    }
+
+    #[test]
+    fn r800_round_trip() {
+        round_trip(Target::R800);
+    }
+}
@@ -0,0 +1,82 @@
+use crate::asm::{imm, long, mem, Alu, Expr, Inst, Operand, Plain, Reg16, Reg16::*, Reg8::*};
+use crate::compile;
+use crate::regalloc::low;
+
+/// Emits code pointing all 24 bits of IX at the address in the low three
+/// bytes of the i32 in `pair` plus `offset`, returning the displacement the
+/// `size` bytes there are accessed with.
+///
+/// Code runs in Z80 mode, where IX is loaded 24 bits wide only from memory,
+/// so the address is put together in the scratch buffer. An offset out of
+/// reach of the displacement is added to it there.
+pub fn emit_index(code: &mut Vec<Inst>, pair: Reg16, offset: u64, size: usize) -> usize {
+    let scratch = |offset| mem(Expr::sym("rt_buf").plus(offset));
+    let near = compile::in_reach(offset, size);
+    let disp = match near {
+        true => {
+            code.push(Inst::Ld(scratch(0), pair.into()));
+            offset as usize
+        }
+        false => {
+            let disp = compile::emit_index(code, pair, offset, size);
+            code.push(Inst::Ld(scratch(0), IX.into()));
+            disp
+        }
+    };
+    code.push(Inst::Plain(Plain::Exx));
+    code.push(Inst::Ld(A.into(), low(pair).into()));
+    code.push(Inst::Plain(Plain::Exx));
+    if !near {
+        code.push(Inst::Alu(Alu::Adc, imm(((offset >> 16) & 0xFF) as i64)));
+    }
+    code.push(Inst::Ld(scratch(2), A.into()));
+    code.push(Inst::Ld(long(IX.into()), scratch(0)));
+    disp
+}
+
+/// Emits code storing the linear memory address `addr` in the scratch
+/// buffer, where `mem_copy` takes the 24-bit address it copies to.
+pub fn emit_copy_dest(code: &mut Vec<Inst>, addr: usize) {
+    let scratch = |offset| mem(Expr::sym("rt_buf").plus(offset));
+    code.push(Inst::Ld(HL.into(), imm((addr & 0xFFFF) as i64)));
+    code.push(Inst::Ld(scratch(0), HL.into()));
+    code.push(Inst::Ld(A.into(), imm((addr >> 16) as i64)));
+    code.push(Inst::Ld(scratch(2), A.into()));
+}
+
+/// Rewrites Z80 code into shorter eZ80 code: an index register plus a
+/// displacement moved into `DE` through `HL`, as function epilogues do,
+/// becomes `LEA`.
+pub fn optimize(code: &mut Vec<Inst>) {
+    let mut i = 0;
+    while i < code.len() {
+        if let Some(lea) = lea(&code[i..]) {
+            code.splice(i..i + 5, [lea]);
+        }
+        i += 1;
+    }
+}
+
+/// Matches `PUSH IX; POP HL; LD DE,d; ADD HL,DE; EX DE,HL` where `LD HL,n;
+/// ADD HL,SP` follows, which overwrites what `LEA DE,IX+d` would leave
+/// different: `HL` and the flags `ADD` sets.
+fn lea(code: &[Inst]) -> Option<Inst> {
+    let [Inst::Push(reg @ (IX | IY)), pop, Inst::Ld(de, Operand::Imm(offset)), rest @ ..] = code
+    else {
+        return None;
+    };
+    let [add, swap, Inst::Ld(hl, Operand::Imm(_)), add_sp, ..] = rest else {
+        return None;
+    };
+    let matched = *pop == Inst::Pop(HL)
+        && *de == DE.into()
+        && *add == Inst::Alu16(Alu::Add, HL, DE)
+        && *swap == Inst::Ex(DE.into(), HL.into())
+        && *hl == HL.into()
+        && *add_sp == Inst::Alu16(Alu::Add, HL, SP);
+    if !matched {
+        return None;
+    }
+    let offset = i8::try_from(offset.value()?).ok()?;
+    Some(Inst::Lea(DE, *reg, offset))
+}
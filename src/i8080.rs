@@ -0,0 +1,490 @@
+use anyhow::{bail, Result};
+
+use crate::asm::{
+    self, imm, ind, mem, Alu, BitOp, Cond, Expr, Inst, Operand, Plain, Reg16, Reg16::*, Reg8::*,
+    Shift,
+};
+use crate::regalloc::{high, low};
+use crate::runtime;
+
+/// Address of the 16 bytes holding what the 8080 lacks in registers: the
+/// index registers, the shadow bank and temporaries. The stack starts below
+/// them instead of at the scratch buffer.
+pub const STATE_ADDR: u16 = runtime::SCRATCH_ADDR - 16;
+
+/// Emits the symbols of the variables in the 16 bytes at `state`.
+pub fn emit_symbols(code: &mut Vec<Inst>, state: &Expr) {
+    let symbols = [
+        ("rt_ix", 0),
+        ("rt_iy", 2),
+        ("rt_shadow_bc", 4),
+        ("rt_shadow_de", 6),
+        ("rt_shadow_hl", 8),
+        ("rt_a", 10),
+        ("rt_hl", 12),
+    ];
+    for (name, offset) in symbols {
+        code.push(Inst::Equ(name.into(), state.clone().plus(offset)));
+    }
+}
+
+/// Routines the lowered code calls in place of Z80 instructions.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Routine {
+    Exx,
+    Carry16,
+    Lddr,
+    Index(Reg16),
+}
+
+impl Routine {
+    fn text(self) -> String {
+        match self {
+            Routine::Exx => EXX.to_string(),
+            Routine::Carry16 => CARRY16.to_string(),
+            Routine::Lddr => LDDR.to_string(),
+            Routine::Index(reg) => INDEX.replace("{reg}", &index_name(reg)),
+        }
+    }
+}
+
+pub fn index_name(reg: Reg16) -> String {
+    format!("{reg:?}").to_lowercase()
+}
+
+/// Variable holding the value of index register `reg`.
+pub fn index_var(reg: Reg16) -> Operand {
+    mem(Expr::sym(format!("rt_{}", index_name(reg))))
+}
+
+/// Rewrites `code` into the subset of instructions the Intel 8080 and 8085
+/// share with the Z80, appending the routines the rewritten code calls.
+///
+/// Z80 instructions are replaced one at a time by equivalent sequences,
+/// which leave the other registers and, unless noted, the flags as the
+/// instruction would. The index registers and the shadow bank live in
+/// memory, and indexed accesses go through `HL`. Instructions with no 8080
+/// equivalent, such as the block instructions other than `LDDR`, are
+/// rejected.
+pub fn lower(code: &mut Vec<Inst>) -> Result<()> {
+    let mut routines = vec![];
+    let mut lowered = Vec::with_capacity(code.len());
+    for inst in code.drain(..) {
+        lower_inst(&mut lowered, &mut routines, inst)?;
+    }
+    for routine in routines {
+        lowered.extend(asm::parse(&routine.text()));
+    }
+    *code = lowered;
+    Ok(())
+}
+
+fn lower_inst(code: &mut Vec<Inst>, routines: &mut Vec<Routine>, inst: Inst) -> Result<()> {
+    if let Some((reg, offset)) = indexed(&inst) {
+        return lower_indexed(code, routines, inst, reg, offset);
+    }
+    match inst {
+        Inst::Jr(cond, target) => code.push(Inst::Jp(cond, target)),
+        // Only the carry survives, which is all the loops carry over.
+        Inst::Djnz(target) => {
+            code.push(Inst::Dec(B.into()));
+            code.push(Inst::Jp(Some(Cond::Nz), target));
+        }
+        Inst::Plain(Plain::Exx) => call(code, routines, Routine::Exx, "rt_exx"),
+        Inst::Plain(Plain::Lddr) => call(code, routines, Routine::Lddr, "rt_lddr"),
+        Inst::Plain(Plain::Neg) => {
+            code.push(Inst::Ld(mem("rt_a"), A.into()));
+            code.push(Inst::Push(HL));
+            code.push(Inst::Ld(HL.into(), imm("rt_a")));
+            code.push(Inst::Alu(Alu::Xor, A.into()));
+            code.push(Inst::Alu(Alu::Sub, ind(HL)));
+            code.push(Inst::Pop(HL));
+        }
+        // The routines take the operand in `DE`.
+        Inst::Alu16(alu @ (Alu::Adc | Alu::Sbc), HL, src) => {
+            let label = match alu {
+                Alu::Adc => "rt_adc",
+                _ => "rt_sbc",
+            };
+            if src == DE {
+                call(code, routines, Routine::Carry16, label);
+                return Ok(());
+            }
+            code.push(Inst::Push(DE));
+            code.push(Inst::Ld(D.into(), high(src).into()));
+            code.push(Inst::Ld(E.into(), low(src).into()));
+            call(code, routines, Routine::Carry16, label);
+            code.push(Inst::Pop(DE));
+        }
+        Inst::Alu16(Alu::Add, reg @ (IX | IY), SP) => {
+            code.push(Inst::Push(HL));
+            code.push(Inst::Ld(HL.into(), imm(2)));
+            code.push(Inst::Alu16(Alu::Add, HL, SP));
+            code.push(Inst::Push(DE));
+            code.push(Inst::Ex(DE.into(), HL.into()));
+            code.push(Inst::Ld(HL.into(), index_var(reg)));
+            code.push(Inst::Alu16(Alu::Add, HL, DE));
+            code.push(Inst::Ld(index_var(reg), HL.into()));
+            code.push(Inst::Pop(DE));
+            code.push(Inst::Pop(HL));
+        }
+        Inst::Alu16(Alu::Add, reg @ (IX | IY), src @ (BC | DE)) => {
+            code.push(Inst::Push(HL));
+            code.push(Inst::Ld(HL.into(), index_var(reg)));
+            code.push(Inst::Alu16(Alu::Add, HL, src));
+            code.push(Inst::Ld(index_var(reg), HL.into()));
+            code.push(Inst::Pop(HL));
+        }
+        Inst::Push(reg @ (IX | IY)) => {
+            code.push(Inst::Push(HL));
+            code.push(Inst::Ld(HL.into(), index_var(reg)));
+            code.push(Inst::Ex(ind(SP), HL.into()));
+        }
+        Inst::Pop(reg @ (IX | IY)) => {
+            code.push(Inst::Ex(ind(SP), HL.into()));
+            code.push(Inst::Ld(index_var(reg), HL.into()));
+            code.push(Inst::Pop(HL));
+        }
+        Inst::Ld(Operand::Reg16(reg @ (IX | IY)), Operand::Imm(value)) => {
+            code.push(Inst::Push(HL));
+            code.push(Inst::Ld(HL.into(), Operand::Imm(value)));
+            code.push(Inst::Ld(index_var(reg), HL.into()));
+            code.push(Inst::Pop(HL));
+        }
+        Inst::Ld(Operand::Reg16(SP), Operand::Reg16(reg @ (IX | IY))) => {
+            code.push(Inst::Ld(mem("rt_hl"), HL.into()));
+            code.push(Inst::Ld(HL.into(), index_var(reg)));
+            code.push(Inst::Ld(SP.into(), HL.into()));
+            code.push(Inst::Ld(HL.into(), mem("rt_hl")));
+        }
+        // Only `HL` is loaded from and stored to an address directly.
+        Inst::Ld(Operand::Reg16(DE), src @ Operand::Mem(_)) => {
+            code.push(Inst::Push(HL));
+            code.push(Inst::Ld(HL.into(), src));
+            code.push(Inst::Ex(DE.into(), HL.into()));
+            code.push(Inst::Pop(HL));
+        }
+        Inst::Ld(Operand::Reg16(BC), src @ Operand::Mem(_)) => {
+            code.push(Inst::Push(HL));
+            code.push(Inst::Ld(HL.into(), src));
+            code.push(Inst::Ld(B.into(), H.into()));
+            code.push(Inst::Ld(C.into(), L.into()));
+            code.push(Inst::Pop(HL));
+        }
+        Inst::Ld(dst @ Operand::Mem(_), Operand::Reg16(DE)) => {
+            code.push(Inst::Ex(DE.into(), HL.into()));
+            code.push(Inst::Ld(dst, HL.into()));
+            code.push(Inst::Ex(DE.into(), HL.into()));
+        }
+        Inst::Ld(dst @ Operand::Mem(_), Operand::Reg16(BC)) => {
+            code.push(Inst::Push(HL));
+            code.push(Inst::Ld(H.into(), B.into()));
+            code.push(Inst::Ld(L.into(), C.into()));
+            code.push(Inst::Ld(dst, HL.into()));
+            code.push(Inst::Pop(HL));
+        }
+        Inst::Shift(shift, operand) => lower_shift(code, shift, operand),
+        // Leaves the carry clear, which is never tested after a `BIT`.
+        Inst::Bit(BitOp::Bit, bit, operand) => {
+            code.push(Inst::Ld(mem("rt_a"), A.into()));
+            if operand != A.into() {
+                code.push(Inst::Ld(A.into(), operand));
+            }
+            code.push(Inst::Alu(Alu::And, imm(1 << bit)));
+            code.push(Inst::Ld(A.into(), mem("rt_a")));
+        }
+        Inst::Bit(op, bit, operand) if operand != A.into() => {
+            code.push(Inst::Push(AF));
+            code.push(Inst::Ld(A.into(), operand.clone()));
+            match op {
+                BitOp::Set => code.push(Inst::Alu(Alu::Or, imm(1 << bit))),
+                _ => code.push(Inst::Alu(Alu::And, imm(!(1 << bit) & 0xFF))),
+            }
+            code.push(Inst::Ld(operand, A.into()));
+            code.push(Inst::Pop(AF));
+        }
+        inst @ (Inst::Plain(Plain::Ldi | Plain::Ldd | Plain::Ldir)
+        | Inst::Bit(..)
+        | Inst::Alu16(..)
+        | Inst::Ex(..)
+        | Inst::JpInd(IX | IY)
+        | Inst::Ld(Operand::Reg16(IX | IY | SP), _)
+        | Inst::Ld(_, Operand::Reg16(IX | IY | SP))) => {
+            if !is_8080(&inst) {
+                bail!("{inst:?} has no 8080 equivalent");
+            }
+            code.push(inst);
+        }
+        inst => code.push(inst),
+    }
+    Ok(())
+}
+
+fn call(code: &mut Vec<Inst>, routines: &mut Vec<Routine>, routine: Routine, label: &str) {
+    if !routines.contains(&routine) {
+        routines.push(routine);
+    }
+    code.push(Inst::Call(None, label.into()));
+}
+
+/// Whether `inst`, among the forms `lower_inst` doesn't rewrite, exists on
+/// the 8080.
+fn is_8080(inst: &Inst) -> bool {
+    matches!(
+        inst,
+        Inst::Alu16(Alu::Add, HL, _)
+            | Inst::Ex(Operand::Reg16(DE), Operand::Reg16(HL))
+            | Inst::Ex(Operand::Ind(SP), Operand::Reg16(HL))
+            | Inst::Ld(Operand::Reg16(SP), Operand::Reg16(HL) | Operand::Imm(_))
+    )
+}
+
+/// Returns the index register and displacement `inst` accesses memory at,
+/// if any.
+pub fn indexed(inst: &Inst) -> Option<(Reg16, i16)> {
+    operands(inst)
+        .into_iter()
+        .find_map(|operand| match operand {
+            Operand::Idx(reg, offset) => Some((*reg, *offset)),
+            _ => None,
+        })
+}
+
+/// Lowers `inst` accessing memory at index register `reg` plus `offset` to
+/// access it through `HL` instead, which the index routine points there.
+/// An instruction that also uses `H` or `L` uses `D` or `E` in their place,
+/// with `HL` swapped into `DE`.
+fn lower_indexed(
+    code: &mut Vec<Inst>,
+    routines: &mut Vec<Routine>,
+    inst: Inst,
+    reg: Reg16,
+    offset: i16,
+) -> Result<()> {
+    if !(0..=255).contains(&offset) {
+        bail!("displacement {offset} out of range");
+    }
+    let uses_hl = operands(&inst)
+        .iter()
+        .any(|operand| matches!(operand, Operand::Reg8(H | L)));
+    let inst = map_operands(inst, |operand| match operand {
+        Operand::Idx(..) => ind(HL),
+        Operand::Reg8(H) => D.into(),
+        Operand::Reg8(L) => E.into(),
+        operand => operand,
+    });
+    if uses_hl {
+        code.push(Inst::Push(DE));
+        code.push(Inst::Ex(DE.into(), HL.into()));
+    } else {
+        code.push(Inst::Push(HL));
+    }
+    let label = format!("rt_{}_index", index_name(reg));
+    call(code, routines, Routine::Index(reg), &label);
+    code.push(Inst::Db(vec![i64::from(offset).into()]));
+    lower_inst(code, routines, inst)?;
+    if uses_hl {
+        code.push(Inst::Ex(DE.into(), HL.into()));
+        code.push(Inst::Pop(DE));
+    } else {
+        code.push(Inst::Pop(HL));
+    }
+    Ok(())
+}
+
+pub fn operands(inst: &Inst) -> Vec<&Operand> {
+    match inst {
+        Inst::Ld(dst, src) => vec![dst, src],
+        Inst::Alu(_, operand)
+        | Inst::Inc(operand)
+        | Inst::Dec(operand)
+        | Inst::Shift(_, operand)
+        | Inst::Bit(_, _, operand) => vec![operand],
+        _ => vec![],
+    }
+}
+
+pub fn map_operands(inst: Inst, f: impl Fn(Operand) -> Operand) -> Inst {
+    match inst {
+        Inst::Ld(dst, src) => Inst::Ld(f(dst), f(src)),
+        Inst::Alu(alu, operand) => Inst::Alu(alu, f(operand)),
+        Inst::Inc(operand) => Inst::Inc(f(operand)),
+        Inst::Dec(operand) => Inst::Dec(f(operand)),
+        Inst::Shift(shift, operand) => Inst::Shift(shift, f(operand)),
+        Inst::Bit(op, bit, operand) => Inst::Bit(op, bit, f(operand)),
+        inst => inst,
+    }
+}
+
+/// Lowers a shift of a register or `(HL)` to the 8080's rotations of `A`,
+/// saving `A` in memory meanwhile.
+fn lower_shift(code: &mut Vec<Inst>, shift: Shift, operand: Operand) {
+    let in_a = operand == A.into();
+    if !in_a || shift == Shift::Sra {
+        code.push(Inst::Ld(mem("rt_a"), A.into()));
+    }
+    if !in_a {
+        code.push(Inst::Ld(A.into(), operand.clone()));
+    }
+    match shift {
+        Shift::Rlc => code.push(Inst::Plain(Plain::Rlca)),
+        Shift::Rrc => code.push(Inst::Plain(Plain::Rrca)),
+        Shift::Rl => code.push(Inst::Plain(Plain::Rla)),
+        Shift::Rr => code.push(Inst::Plain(Plain::Rra)),
+        Shift::Sla => code.push(Inst::Alu(Alu::Add, A.into())),
+        Shift::Srl => {
+            code.push(Inst::Alu(Alu::And, A.into()));
+            code.push(Inst::Plain(Plain::Rra));
+        }
+        // The sign is shifted into the carry, then back in at the top.
+        Shift::Sra => {
+            code.push(Inst::Plain(Plain::Rla));
+            match in_a {
+                true => code.push(Inst::Ld(A.into(), mem("rt_a"))),
+                false => code.push(Inst::Ld(A.into(), operand.clone())),
+            }
+            code.push(Inst::Plain(Plain::Rra));
+        }
+    }
+    // The rotations of `A` only set the carry, and the other flags follow
+    // the result, as after a Z80 shift.
+    if shift != Shift::Sla {
+        code.push(Inst::Inc(A.into()));
+        code.push(Inst::Dec(A.into()));
+    }
+    if !in_a {
+        code.push(Inst::Ld(operand, A.into()));
+        code.push(Inst::Ld(A.into(), mem("rt_a")));
+    }
+}
+
+/// Swaps `BC`, `DE` and `HL` with the shadow bank in memory, like `EXX`.
+const EXX: &str = "\
+rt_exx:
+  PUSH HL
+  LD HL,(rt_shadow_de)
+  EX DE,HL
+  LD (rt_shadow_de),HL
+  LD HL,(rt_shadow_bc)
+  PUSH HL
+  LD H,B
+  LD L,C
+  LD (rt_shadow_bc),HL
+  POP BC
+  LD HL,(rt_shadow_hl)
+  EX (SP),HL
+  LD (rt_shadow_hl),HL
+  POP HL
+  RET
+";
+
+/// `ADC HL,DE` and `SBC HL,DE`, setting the sign, zero, overflow and carry
+/// flags as the Z80 does. The flags are built in `E` and popped into `F`
+/// along with the caller's `A`, kept in `B`.
+const CARRY16: &str = "\
+rt_adc:
+  PUSH DE
+  PUSH BC
+  LD B,A
+  LD C,H
+  LD A,L
+  ADC A,E
+  LD L,A
+  LD A,H
+  ADC A,D
+  LD H,A
+  SBC A,A
+  LD E,A
+  LD A,C
+  XOR D
+  CPL
+  JP rt_carry16_flags
+rt_sbc:
+  PUSH DE
+  PUSH BC
+  LD B,A
+  LD C,H
+  LD A,L
+  SBC A,E
+  LD L,A
+  LD A,H
+  SBC A,D
+  LD H,A
+  SBC A,A
+  LD E,A
+  LD A,C
+  XOR D
+rt_carry16_flags:
+  LD D,A
+  LD A,C
+  XOR H
+  AND D
+  AND 0x80
+  RRCA
+  RRCA
+  RRCA
+  RRCA
+  RRCA
+  LD D,A
+  LD A,E
+  AND 1
+  OR D
+  LD D,A
+  LD A,H
+  AND 0x80
+  OR D
+  LD E,A
+  LD A,H
+  OR L
+  LD A,E
+  JP NZ,rt_carry16_nonzero
+  OR 0x40
+rt_carry16_nonzero:
+  LD E,A
+  LD D,B
+  PUSH DE
+  POP AF
+  POP BC
+  POP DE
+  RET
+";
+
+/// `LDDR`, keeping `A` and the flags.
+pub const LDDR: &str = "\
+rt_lddr:
+  PUSH AF
+rt_lddr_byte:
+  LD A,(HL)
+  LD (DE),A
+  DEC HL
+  DEC DE
+  DEC BC
+  LD A,B
+  OR C
+  JP NZ,rt_lddr_byte
+  POP AF
+  RET
+";
+
+/// Points `HL` at the index register's value plus the displacement byte
+/// following the call, which it returns past, keeping the other registers
+/// and the flags.
+const INDEX: &str = "\
+rt_{reg}_index:
+  POP HL
+  PUSH DE
+  PUSH AF
+  LD E,(HL)
+  LD D,0
+  INC HL
+  EX (SP),HL
+  PUSH HL
+  LD HL,(rt_{reg})
+  ADD HL,DE
+  POP AF
+  POP DE
+  EX (SP),HL
+  EX DE,HL
+  EX (SP),HL
+  RET
+";
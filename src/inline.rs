@@ -0,0 +1,359 @@
+use wasmparser::ValType;
+
+use crate::callgraph;
+use crate::compile::{frame_size, MAX_FRAME_SIZE};
+use crate::ir::{Cond, Function, Inst, Label, Slot};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Visit {
+    New,
+    Started,
+    Done,
+}
+
+/// Replaces calls to functions costing at most `threshold` with their
+/// bodies, leaving them all as calls if it is 0.
+///
+/// Functions are visited callees first along the call graph, so a callee has
+/// had its own calls inlined by the time it is measured. A call back into a
+/// function still being visited is recursive and stays a call, as does one
+/// whose locals would make the caller's frame larger than the frame pointer
+/// reaches.
+pub fn inline(funcs: &mut [Function], threshold: usize) {
+    if threshold == 0 {
+        return;
+    }
+    let mut visits = vec![Visit::New; funcs.len()];
+    for index in 0..funcs.len() {
+        visit(funcs, &mut visits, threshold, index);
+    }
+}
+
+fn visit(funcs: &mut [Function], visits: &mut [Visit], threshold: usize, index: usize) {
+    if visits[index] != Visit::New {
+        return;
+    }
+    visits[index] = Visit::Started;
+    for callee in callgraph::callees(&funcs[index]) {
+        visit(funcs, visits, threshold, callee);
+    }
+    let body = std::mem::take(&mut funcs[index].body);
+    // The locals of inlined bodies follow the caller's own, shared between
+    // the call sites since no two of them run at once.
+    let own = funcs[index].locals.len();
+    for inst in body {
+        match inst {
+            Inst::Call {
+                func,
+                args,
+                tail: false,
+                ..
+            } if visits[func as usize] == Visit::Done
+                && inlinable(&funcs[func as usize], threshold) =>
+            {
+                let callee = &funcs[func as usize];
+                let caller = &funcs[index];
+                let (map, added) = assign_locals(&caller.locals[own..], &callee.locals);
+                let mut locals = caller.locals.clone();
+                locals.extend(&added);
+                if frame_size(&locals) > MAX_FRAME_SIZE {
+                    funcs[index].body.push(inst);
+                    continue;
+                }
+                let body = expand(callee, caller, args, own, &map);
+                let labels = callee.labels;
+                let caller = &mut funcs[index];
+                caller.locals = locals;
+                caller.labels += labels;
+                caller.body.extend(body);
+            }
+            inst => funcs[index].body.push(inst),
+        }
+    }
+    visits[index] = Visit::Done;
+}
+
+/// Assigns each of `locals` a distinct one of the same type in `pool`, adding
+/// those missing to it, and returns the index in the pool of each and the
+/// types added.
+fn assign_locals(pool: &[ValType], locals: &[ValType]) -> (Vec<usize>, Vec<ValType>) {
+    let mut taken = vec![false; pool.len()];
+    let mut added = vec![];
+    let map = locals
+        .iter()
+        .map(|ty| {
+            let free = (0..pool.len()).find(|&local| !taken[local] && pool[local] == *ty);
+            match free {
+                Some(local) => {
+                    taken[local] = true;
+                    local
+                }
+                None => {
+                    added.push(*ty);
+                    pool.len() + added.len() - 1
+                }
+            }
+        })
+        .collect();
+    (map, added)
+}
+
+/// Cost of a parameter, which the inlined body moves into a local through
+/// the frame, in instructions.
+const PARAM_COST: usize = 3;
+
+/// Returns whether calls to `func` may be replaced with its body: if it isn't
+/// imported, costs at most `threshold`, can't trap, since a trap records the
+/// function it happened in, and makes no tail calls, which would return from
+/// the caller.
+fn inlinable(func: &Function, threshold: usize) -> bool {
+    if func.import.is_some() {
+        return false;
+    }
+    let excluded = func.body.iter().any(|inst| {
+        matches!(
+            inst,
+            Inst::Trap(_) | Inst::CallIndirect { .. } | Inst::Call { tail: true, .. }
+        )
+    });
+    !excluded && cost(func) <= threshold
+}
+
+/// Estimates the size of `func` inlined, counting the instructions that
+/// generate code and the parameters.
+fn cost(func: &Function) -> usize {
+    let labels = func
+        .body
+        .iter()
+        .filter(|inst| matches!(inst, Inst::Label(_)));
+    func.body.len() - labels.count() + func.ty.params().len() * PARAM_COST
+}
+
+/// Returns the body of `callee` for a call from `caller` with the arguments
+/// starting at `args`, with its labels numbered after the caller's and each
+/// of its locals the one `map` gives among those after the caller's first
+/// `own`.
+///
+/// The arguments are moved into the callee's parameters, and the locals it
+/// may read before setting are zeroed, since every call starts them out
+/// zero. Branches to the callee's return label then unwind to the results
+/// like any other, which the call leaves in place of the arguments.
+fn expand(
+    callee: &Function,
+    caller: &Function,
+    args: Slot,
+    own: usize,
+    map: &[usize],
+) -> Vec<Inst> {
+    let local = |local: usize| own + map[local];
+    let labels = caller.labels;
+    let num_params = callee.ty.params().len();
+    let mut body = vec![];
+    for param in (0..num_params).rev() {
+        body.push(Inst::LocalSet {
+            src: Slot(args.0 + param),
+            local: local(param),
+        });
+    }
+    let unset = callee.unset_reads();
+    for (index, ty) in callee.locals.iter().enumerate().skip(num_params) {
+        if unset[index] {
+            body.push(Inst::Const {
+                dst: args,
+                ty: *ty,
+                value: 0,
+            });
+            body.push(Inst::LocalSet {
+                src: args,
+                local: local(index),
+            });
+        }
+    }
+    let slot = |slot: Slot| Slot(args.0 + slot.0);
+    let label = |label: Label| Label(labels + label.0);
+    for inst in &callee.body {
+        let mut inst = inst.clone();
+        match &mut inst {
+            Inst::Const { dst, .. } | Inst::GlobalGet { dst, .. } => *dst = slot(*dst),
+            Inst::LocalGet { dst, local: l } => {
+                *dst = slot(*dst);
+                *l = local(*l);
+            }
+            Inst::LocalSet { src, local: l } | Inst::LocalTee { src, local: l } => {
+                *src = slot(*src);
+                *l = local(*l);
+            }
+            Inst::GlobalSet { src, .. } | Inst::Drop { src, .. } => *src = slot(*src),
+            Inst::Load { dst, addr, .. } => {
+                *dst = slot(*dst);
+                *addr = slot(*addr);
+            }
+            Inst::Store { addr, src, .. } => {
+                *addr = slot(*addr);
+                *src = slot(*src);
+            }
+            Inst::Unary { dst, src, .. } => {
+                *dst = slot(*dst);
+                *src = slot(*src);
+            }
+            Inst::Binary { dst, lhs, rhs, .. } | Inst::Compare { dst, lhs, rhs, .. } => {
+                *dst = slot(*dst);
+                *lhs = slot(*lhs);
+                *rhs = slot(*rhs);
+            }
+            Inst::Select {
+                dst,
+                if_true,
+                if_false,
+                cond,
+                ..
+            } => {
+                *dst = slot(*dst);
+                *if_true = slot(*if_true);
+                *if_false = slot(*if_false);
+                *cond = slot(*cond);
+            }
+            Inst::Call { args, .. } => *args = slot(*args),
+            Inst::CallIndirect { args, index, .. } => {
+                *args = slot(*args);
+                *index = slot(*index);
+            }
+            Inst::Label(target) => *target = label(*target),
+            Inst::Branch { target, cond, .. } => {
+                *target = label(*target);
+                match cond {
+                    Cond::Always => {}
+                    Cond::NonZero(test) | Cond::Zero(test) => *test = slot(*test),
+                    Cond::Compare(_, lhs, rhs) => {
+                        *lhs = slot(*lhs);
+                        *rhs = slot(*rhs);
+                    }
+                }
+            }
+            Inst::Trap(_) => {}
+        }
+        body.push(inst);
+    }
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile::{Config, Format, OptLevel};
+    use crate::{emu, ir, loader};
+
+    /// A module whose entry has `locals` i32 locals, and passes each through
+    /// a call to a function small enough to inline.
+    fn repeated_calls(locals: usize) -> Vec<u8> {
+        let calls: String = (0..locals)
+            .map(|local| format!("(local.set {local} (call $inc (local.get {local})))"))
+            .collect();
+        wat::parse_str(format!(
+            r#"(module
+                (func $inc (param i32) (result i32) (i32.add (local.get 0) (i32.const 1)))
+                (func (export "entry") (local {}) {calls}))"#,
+            "i32 ".repeat(locals)
+        ))
+        .unwrap()
+    }
+
+    fn inlined(wasm: &[u8], threshold: usize) -> Vec<Function> {
+        let module = loader::load(wasm).unwrap();
+        let mut funcs: Vec<_> = (0..module.functions.len())
+            .map(|index| ir::build(&module, index))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        inline(&mut funcs, threshold);
+        funcs
+    }
+
+    fn calls(func: &Function) -> usize {
+        let calls = func
+            .body
+            .iter()
+            .filter(|inst| matches!(inst, Inst::Call { .. }));
+        calls.count()
+    }
+
+    #[test]
+    fn call_sites_share_locals() {
+        let funcs = inlined(&repeated_calls(26), OptLevel::O3.passes().inline_threshold);
+        assert_eq!(calls(&funcs[1]), 0);
+        assert_eq!(funcs[1].locals.len(), 27);
+    }
+
+    #[test]
+    fn frame_stays_in_reach() {
+        let funcs = inlined(&repeated_calls(31), OptLevel::O3.passes().inline_threshold);
+        assert_eq!(calls(&funcs[1]), 31);
+        assert!(frame_size(&funcs[1].locals) <= MAX_FRAME_SIZE);
+    }
+
+    #[test]
+    fn many_call_sites_assemble() {
+        let wasm = repeated_calls(26);
+        let config = Config {
+            format: Format::Bin,
+            passes: OptLevel::O3.passes(),
+            ..Config::default()
+        };
+        loader::load(&wasm)
+            .unwrap()
+            .compile(&config, &mut vec![])
+            .unwrap();
+    }
+
+    /// Returns whether the entry of a module with `callee` as function 0 still
+    /// calls it at `level`, checking the entry returns `expected`.
+    fn calls_callee(callee: &str, level: OptLevel, expected: i32) -> bool {
+        let wat = format!(
+            r#"(module (memory 1)
+                (data (i32.const 0x8000) "\07")
+                {callee}
+                (func (export "entry") (result i32) (call 0 (i32.const 5))))"#
+        );
+        let config = || Config {
+            passes: level.passes(),
+            ..Config::default()
+        };
+        let code = emu::function_asm(&wat, config(), 1).unwrap();
+        assert_eq!(emu::run_wat(&wat, config()).top_i32(), expected);
+        code.contains(&"CALL func_0".into())
+    }
+
+    #[test]
+    fn small_calls_are_inlined() {
+        let getter = "(func (param i32) (result i32)
+            (i32.add (i32.load8_u (i32.const 0x8000)) (local.get 0)))";
+        assert!(!calls_callee(getter, OptLevel::O3, 12));
+        assert!(calls_callee(getter, OptLevel::O1, 12));
+    }
+
+    #[test]
+    fn other_calls_stay() {
+        let large = "(func (param i32) (result i32)
+            (local.set 0 (i32.add (local.get 0) (i32.const 1)))
+            (local.set 0 (i32.add (local.get 0) (i32.const 1)))
+            (i32.add (local.get 0) (i32.const 1)))";
+        assert!(calls_callee(large, OptLevel::O3, 8));
+        // However large the threshold, recursive and trapping calls stay.
+        for callee in [
+            "(func (param i32) (result i32)
+              (if (result i32) (local.get 0)
+                (then (call 0 (i32.sub (local.get 0) (i32.const 1))))
+                (else (i32.const 9))))",
+            "(func (param i32) (result i32)
+              (if (i32.eqz (local.get 0)) (then (unreachable)))
+              (local.get 0))",
+        ] {
+            let wasm = wat::parse_str(format!(
+                r#"(module {callee}
+                    (func (export "entry") (result i32) (call 0 (i32.const 5))))"#
+            ))
+            .unwrap();
+            let funcs = inlined(&wasm, usize::MAX);
+            assert_eq!(calls(&funcs[1]), 1, "{callee}");
+        }
+    }
+}
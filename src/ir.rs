@@ -0,0 +1,1129 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+
+use anyhow::{bail, Context, Result};
+use wasmparser::{BlockType, FuncType, Operator, ValType};
+
+use crate::compile::{Code, Import, Module};
+use crate::trap::Trap;
+
+/// A position on the operand stack, counted from the bottom of the function's
+/// operand stack.
+///
+/// Instructions read their operands from and write their results to the slots
+/// on top of the stack, so within straight-line code a slot holds a single
+/// value from the instruction defining it to the one consuming it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Slot(pub usize);
+
+/// A branch target, numbered within its function.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Label(pub usize);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnaryOp {
+    Eqz,
+    Clz,
+    Ctz,
+    Popcnt,
+    Extend8S,
+    Extend16S,
+    Extend32S,
+    TruncSatS,
+    TruncSatU,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    And,
+    Or,
+    Xor,
+    Mul,
+    DivS,
+    DivU,
+    RemS,
+    RemU,
+}
+
+impl BinaryOp {
+    /// Computes the operation on i32 constants, or returns `None` if it
+    /// traps.
+    pub fn eval(self, lhs: i32, rhs: i32) -> Option<i32> {
+        let (lhs_u, rhs_u) = (lhs as u32, rhs as u32);
+        match self {
+            BinaryOp::Add => Some(lhs.wrapping_add(rhs)),
+            BinaryOp::Sub => Some(lhs.wrapping_sub(rhs)),
+            BinaryOp::And => Some(lhs & rhs),
+            BinaryOp::Or => Some(lhs | rhs),
+            BinaryOp::Xor => Some(lhs ^ rhs),
+            BinaryOp::Mul => Some(lhs.wrapping_mul(rhs)),
+            BinaryOp::DivS => lhs.checked_div(rhs),
+            BinaryOp::DivU => lhs_u.checked_div(rhs_u).map(|value| value as i32),
+            // `INT_MIN % -1` is 0 in Wasm rather than an overflow.
+            BinaryOp::RemS => (rhs != 0).then(|| lhs.wrapping_rem(rhs)),
+            BinaryOp::RemU => lhs_u.checked_rem(rhs_u).map(|value| value as i32),
+        }
+    }
+
+    /// Whether the operation is computed by a runtime routine rather than
+    /// inline.
+    pub fn calls_routine(self) -> bool {
+        !matches!(
+            self,
+            BinaryOp::Add | BinaryOp::Sub | BinaryOp::And | BinaryOp::Or | BinaryOp::Xor
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    LtS,
+    LtU,
+    GtS,
+    GtU,
+    LeS,
+    LeU,
+    GeS,
+    GeU,
+}
+
+impl CompareOp {
+    /// Returns the comparison that holds exactly when this one doesn't.
+    pub fn negate(self) -> CompareOp {
+        match self {
+            CompareOp::Eq => CompareOp::Ne,
+            CompareOp::Ne => CompareOp::Eq,
+            CompareOp::LtS => CompareOp::GeS,
+            CompareOp::LtU => CompareOp::GeU,
+            CompareOp::GtS => CompareOp::LeS,
+            CompareOp::GtU => CompareOp::LeU,
+            CompareOp::LeS => CompareOp::GtS,
+            CompareOp::LeU => CompareOp::GtU,
+            CompareOp::GeS => CompareOp::LtS,
+            CompareOp::GeU => CompareOp::LtU,
+        }
+    }
+
+    /// Computes the comparison on i32 constants.
+    pub fn eval(self, lhs: i32, rhs: i32) -> bool {
+        let (lhs_u, rhs_u) = (lhs as u32, rhs as u32);
+        match self {
+            CompareOp::Eq => lhs == rhs,
+            CompareOp::Ne => lhs != rhs,
+            CompareOp::LtS => lhs < rhs,
+            CompareOp::LtU => lhs_u < rhs_u,
+            CompareOp::GtS => lhs > rhs,
+            CompareOp::GtU => lhs_u > rhs_u,
+            CompareOp::LeS => lhs <= rhs,
+            CompareOp::LeU => lhs_u <= rhs_u,
+            CompareOp::GeS => lhs >= rhs,
+            CompareOp::GeU => lhs_u >= rhs_u,
+        }
+    }
+}
+
+/// Condition under which a branch is taken.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cond {
+    Always,
+    /// The i32 in the slot, which the branch pops, is not zero.
+    NonZero(Slot),
+    /// The i32 in the slot, which the branch pops, is zero.
+    Zero(Slot),
+    /// The comparison of the i32s in the two slots, which the branch pops,
+    /// holds. Built from a comparison feeding a branch directly, so that
+    /// the branch can test the flags it sets.
+    Compare(CompareOp, Slot, Slot),
+}
+
+/// Operands a branch drops so that the stack matches its target: the values
+/// carried to the target are kept on top, and the ones below them discarded.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Unwind {
+    pub keep: Vec<ValType>,
+    pub discard: Vec<ValType>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Inst {
+    Const {
+        dst: Slot,
+        ty: ValType,
+        /// The bits of the constant, sign-extended from narrower types.
+        value: i64,
+    },
+    LocalGet {
+        dst: Slot,
+        local: usize,
+    },
+    LocalSet {
+        src: Slot,
+        local: usize,
+    },
+    /// Stores into a local like `LocalSet`, but leaves the value in place.
+    LocalTee {
+        src: Slot,
+        local: usize,
+    },
+    GlobalGet {
+        dst: Slot,
+        global: u32,
+    },
+    GlobalSet {
+        src: Slot,
+        global: u32,
+    },
+    /// Loads `size` bytes from linear memory, extending them to `ty`.
+    Load {
+        ty: ValType,
+        size: usize,
+        signed: bool,
+        dst: Slot,
+        addr: Slot,
+        offset: u64,
+    },
+    /// Stores the low `size` bytes of a `ty` value to linear memory.
+    Store {
+        ty: ValType,
+        size: usize,
+        addr: Slot,
+        src: Slot,
+        offset: u64,
+    },
+    /// An operation from a `ty` operand to a `result`.
+    Unary {
+        op: UnaryOp,
+        ty: ValType,
+        result: ValType,
+        dst: Slot,
+        src: Slot,
+    },
+    Binary {
+        op: BinaryOp,
+        ty: ValType,
+        dst: Slot,
+        lhs: Slot,
+        rhs: Slot,
+    },
+    /// Compares two `ty` operands, producing an i32 of 0 or 1.
+    Compare {
+        op: CompareOp,
+        ty: ValType,
+        dst: Slot,
+        lhs: Slot,
+        rhs: Slot,
+    },
+    Select {
+        ty: ValType,
+        dst: Slot,
+        if_true: Slot,
+        if_false: Slot,
+        cond: Slot,
+    },
+    Drop {
+        ty: ValType,
+        src: Slot,
+    },
+    /// Calls a function with the arguments starting at `args`, which are
+    /// replaced by its results.
+    ///
+    /// A tail call returns the results of the callee from the caller, and
+    /// the caller's frame is released before the callee runs.
+    Call {
+        func: u32,
+        ty: FuncType,
+        args: Slot,
+        tail: bool,
+    },
+    /// Calls the function in slot `index` of a table, like `Call`.
+    CallIndirect {
+        type_index: u32,
+        table: u32,
+        ty: FuncType,
+        args: Slot,
+        index: Slot,
+        tail: bool,
+    },
+    Label(Label),
+    Branch {
+        target: Label,
+        cond: Cond,
+        unwind: Unwind,
+    },
+    Trap(Trap),
+}
+
+impl Display for Inst {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let ty = |ty: &ValType| format!("{:?}", ty).to_lowercase();
+        let op = |op: &dyn fmt::Debug| {
+            // Spell operators the way the Wasm text format does, `LtS` as `lt_s`.
+            let name = format!("{:?}", op);
+            let mut out = String::new();
+            for (i, c) in name.chars().enumerate() {
+                if c.is_uppercase() && i > 0 {
+                    out.push('_');
+                }
+                out.push(c.to_ascii_lowercase());
+            }
+            out
+        };
+        match self {
+            Inst::Const { ty: t, value, .. } => write!(f, "{}.const {}", ty(t), value),
+            Inst::LocalGet { local, .. } => write!(f, "local.get {}", local),
+            Inst::LocalSet { local, .. } => write!(f, "local.set {}", local),
+            Inst::LocalTee { local, .. } => write!(f, "local.tee {}", local),
+            Inst::GlobalGet { global, .. } => write!(f, "global.get {}", global),
+            Inst::GlobalSet { global, .. } => write!(f, "global.set {}", global),
+            Inst::Load {
+                ty: t,
+                size,
+                signed,
+                ..
+            } => match (t, size) {
+                (ValType::I32, 4) | (ValType::I64, 8) => write!(f, "{}.load", ty(t)),
+                _ => {
+                    let sign = if *signed { "s" } else { "u" };
+                    write!(f, "{}.load{}_{}", ty(t), size * 8, sign)
+                }
+            },
+            Inst::Store { ty: t, size, .. } => match (t, size) {
+                (ValType::I32, 4) | (ValType::I64, 8) => write!(f, "{}.store", ty(t)),
+                _ => write!(f, "{}.store{}", ty(t), size * 8),
+            },
+            Inst::Unary {
+                op: o @ (UnaryOp::TruncSatS | UnaryOp::TruncSatU),
+                ty: from,
+                result,
+                ..
+            } => {
+                let sign = if *o == UnaryOp::TruncSatS { "s" } else { "u" };
+                write!(f, "{}.trunc_sat_{}_{}", ty(result), ty(from), sign)
+            }
+            Inst::Unary { op: o, ty: t, .. } => write!(f, "{}.{}", ty(t), op(o)),
+            Inst::Binary { op: o, ty: t, .. } => write!(f, "{}.{}", ty(t), op(o)),
+            Inst::Compare { op: o, ty: t, .. } => write!(f, "{}.{}", ty(t), op(o)),
+            Inst::Select { .. } => write!(f, "select"),
+            Inst::Drop { .. } => write!(f, "drop"),
+            Inst::Call { func, tail, .. } => {
+                let name = if *tail { "return_call" } else { "call" };
+                write!(f, "{} {}", name, func)
+            }
+            Inst::CallIndirect {
+                type_index,
+                table,
+                tail,
+                ..
+            } => {
+                let name = if *tail {
+                    "return_call_indirect"
+                } else {
+                    "call_indirect"
+                };
+                write!(f, "{} {} {}", name, table, type_index)
+            }
+            Inst::Label(label) => write!(f, "{:?}:", label),
+            Inst::Branch { target, cond, .. } => match cond {
+                Cond::Always => write!(f, "br {:?}", target),
+                Cond::NonZero(_) => write!(f, "br_if {:?}", target),
+                Cond::Zero(_) => write!(f, "br_unless {:?}", target),
+                Cond::Compare(o, ..) => write!(f, "br_if_{} {:?}", op(o), target),
+            },
+            Inst::Trap(trap) => write!(f, "trap {:?}", trap),
+        }
+    }
+}
+
+pub struct Function {
+    pub index: usize,
+    pub ty: FuncType,
+    /// Types of the parameters followed by the declared locals.
+    pub locals: Vec<ValType>,
+    pub body: Vec<Inst>,
+    /// Label placed at the end of the body, branching to which returns.
+    pub return_label: Label,
+    /// Number of labels used in the body.
+    pub labels: usize,
+    /// What the function is imported as, if it is, with no body.
+    pub import: Option<Import>,
+}
+
+impl Function {
+    /// Returns for each local whether it may be read before it is set, and so
+    /// has to start out zero as Wasm requires.
+    ///
+    /// Locals set on every path to an instruction are tracked through the body
+    /// in order. A label's forward branches all come before it, and a loop's
+    /// back edges can only add to what is set at its start, so one pass is
+    /// enough.
+    pub fn unset_reads(&self) -> Vec<bool> {
+        let num_params = self.ty.params().len();
+        let intersect = |set: &mut Vec<bool>, other: &[bool]| {
+            for (local, other) in set.iter_mut().zip(other) {
+                *local &= other;
+            }
+        };
+        let mut reads = vec![false; self.locals.len()];
+        // What is set where the code is reachable, and at the labels branched
+        // to so far.
+        let mut set: Option<Vec<bool>> =
+            Some((0..self.locals.len()).map(|l| l < num_params).collect());
+        let mut at_label: HashMap<Label, Vec<bool>> = HashMap::new();
+        for inst in &self.body {
+            match inst {
+                Inst::LocalGet { local, .. } => {
+                    if let Some(set) = &set {
+                        reads[*local] |= !set[*local];
+                    }
+                }
+                Inst::LocalSet { local, .. } | Inst::LocalTee { local, .. } => {
+                    if let Some(set) = &mut set {
+                        set[*local] = true;
+                    }
+                }
+                Inst::Branch { target, cond, .. } => {
+                    if let Some(current) = &set {
+                        at_label
+                            .entry(*target)
+                            .and_modify(|set| intersect(set, current))
+                            .or_insert_with(|| current.clone());
+                    }
+                    if *cond == Cond::Always {
+                        set = None;
+                    }
+                }
+                Inst::Trap(_)
+                | Inst::Call { tail: true, .. }
+                | Inst::CallIndirect { tail: true, .. } => set = None,
+                Inst::Label(label) => {
+                    if let Some(incoming) = at_label.remove(label) {
+                        match &mut set {
+                            Some(set) => intersect(set, &incoming),
+                            None => set = Some(incoming),
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        reads
+    }
+}
+
+/// Translates the body of function `index` from the operators of its Wasm
+/// code, resolving the operand stack into slots and structured control flow
+/// into labels and branches.
+pub fn build(module: &Module, index: usize) -> Result<Function> {
+    let def = &module.functions[index];
+    let mut locals = def.func_type.params().to_vec();
+    let body = match &def.code {
+        Code::Body(body) => body,
+        Code::Import(import) => {
+            return Ok(Function {
+                index,
+                ty: def.func_type.clone(),
+                locals,
+                body: vec![],
+                return_label: Label(0),
+                labels: 0,
+                import: Some(import.clone()),
+            });
+        }
+    };
+    for local in body.get_locals_reader().unwrap() {
+        let (amt, ty) = local.unwrap();
+        locals.extend(std::iter::repeat_n(ty, amt as usize));
+    }
+    let mut builder = Builder {
+        module,
+        body: vec![],
+        operands: vec![],
+        frames: vec![],
+        labels: 0,
+    };
+    // The function body is the outermost frame, branching to which returns.
+    let return_label = builder.label();
+    builder.frames.push(Frame {
+        label: return_label,
+        is_loop: false,
+        else_label: None,
+        height: 0,
+        params: vec![],
+        results: def.func_type.results().to_vec(),
+    });
+    // Number of blocks entered since the code became unreachable, if it is.
+    let mut dead: Option<usize> = None;
+    for op in body.get_operators_reader().unwrap() {
+        let op = op.unwrap();
+        if let Some(depth) = dead {
+            // Skip everything up to the end of the block the code became
+            // unreachable in.
+            match op {
+                Operator::Block { .. } | Operator::Loop { .. } | Operator::If { .. } => {
+                    dead = Some(depth + 1);
+                    continue;
+                }
+                Operator::End | Operator::Else if depth > 0 => {
+                    if let Operator::End = op {
+                        dead = Some(depth - 1);
+                    }
+                    continue;
+                }
+                Operator::End | Operator::Else => dead = None,
+                _ => continue,
+            }
+        }
+        let translated = builder
+            .translate(op, &locals)
+            .with_context(|| format!("in function {index}"))?;
+        if translated {
+            dead = Some(0);
+        }
+    }
+    Ok(Function {
+        index,
+        ty: def.func_type.clone(),
+        locals,
+        body: builder.body,
+        return_label,
+        labels: builder.labels,
+        import: None,
+    })
+}
+
+/// A block, loop or if being translated.
+struct Frame {
+    /// Label branched to: the start of a loop, or the end of anything else.
+    label: Label,
+    is_loop: bool,
+    /// Label of the else arm of an if, until it is placed.
+    else_label: Option<Label>,
+    /// Height of the operand stack below the parameters.
+    height: usize,
+    params: Vec<ValType>,
+    results: Vec<ValType>,
+}
+
+impl Frame {
+    /// Types of the values carried by a branch to this frame.
+    fn branch_types(&self) -> &[ValType] {
+        if self.is_loop {
+            &self.params
+        } else {
+            &self.results
+        }
+    }
+}
+
+struct Builder<'m, 'a> {
+    module: &'m Module<'a>,
+    body: Vec<Inst>,
+    /// Types of the values on the operand stack.
+    operands: Vec<ValType>,
+    frames: Vec<Frame>,
+    labels: usize,
+}
+
+impl Builder<'_, '_> {
+    fn label(&mut self) -> Label {
+        let label = Label(self.labels);
+        self.labels += 1;
+        label
+    }
+
+    fn pop(&mut self) -> (Slot, ValType) {
+        let ty = self.operands.pop().unwrap();
+        (Slot(self.operands.len()), ty)
+    }
+
+    fn push(&mut self, ty: ValType) -> Slot {
+        self.operands.push(ty);
+        Slot(self.operands.len() - 1)
+    }
+
+    fn top(&self) -> Slot {
+        Slot(self.operands.len() - 1)
+    }
+
+    fn frame(&self, relative_depth: u32) -> &Frame {
+        &self.frames[self.frames.len() - relative_depth as usize - 1]
+    }
+
+    /// Returns the operands to drop when branching to `frame`.
+    fn unwind(&self, frame: &Frame) -> Unwind {
+        let arity = frame.branch_types().len();
+        let top = self.operands.len() - arity;
+        Unwind {
+            keep: self.operands[top..].to_vec(),
+            discard: self.operands[frame.height..top].to_vec(),
+        }
+    }
+
+    fn control_frame(&self, blockty: BlockType, label: Label, is_loop: bool) -> Frame {
+        let (params, results) = match blockty {
+            BlockType::Empty => (vec![], vec![]),
+            BlockType::Type(ty) => (vec![], vec![ty]),
+            BlockType::FuncType(index) => {
+                let typ = &self.module.types[index as usize];
+                (typ.params().to_vec(), typ.results().to_vec())
+            }
+        };
+        Frame {
+            label,
+            is_loop,
+            else_label: None,
+            height: self.operands.len() - params.len(),
+            params,
+            results,
+        }
+    }
+
+    fn unary(&mut self, op: UnaryOp, result: ValType) {
+        let (src, ty) = self.pop();
+        let dst = self.push(result);
+        self.body.push(Inst::Unary {
+            op,
+            ty,
+            result,
+            dst,
+            src,
+        });
+    }
+
+    fn binary(&mut self, op: BinaryOp) {
+        let (rhs, ty) = self.pop();
+        let (lhs, _) = self.pop();
+        let dst = self.push(ty);
+        self.body.push(Inst::Binary {
+            op,
+            ty,
+            dst,
+            lhs,
+            rhs,
+        });
+    }
+
+    fn compare(&mut self, op: CompareOp) {
+        let (rhs, ty) = self.pop();
+        let (lhs, _) = self.pop();
+        let dst = self.push(ValType::I32);
+        self.body.push(Inst::Compare {
+            op,
+            ty,
+            dst,
+            lhs,
+            rhs,
+        });
+    }
+
+    /// Pops the condition of a branch taken when it is not zero, or when it
+    /// is zero if `zero`, folding the `eqz` and i32 comparison just before
+    /// that computed it into the branch.
+    fn condition(&mut self, mut zero: bool) -> Cond {
+        let (mut cond, _) = self.pop();
+        loop {
+            match self.body.last() {
+                Some(&Inst::Unary {
+                    op: UnaryOp::Eqz,
+                    ty: ValType::I32,
+                    dst,
+                    src,
+                    ..
+                }) if dst == cond => {
+                    zero = !zero;
+                    cond = src;
+                }
+                Some(&Inst::Compare {
+                    op,
+                    ty: ValType::I32,
+                    dst,
+                    lhs,
+                    rhs,
+                }) if dst == cond => {
+                    self.body.pop();
+                    let op = if zero { op.negate() } else { op };
+                    return Cond::Compare(op, lhs, rhs);
+                }
+                _ => break,
+            }
+            self.body.pop();
+        }
+        match zero {
+            true => Cond::Zero(cond),
+            false => Cond::NonZero(cond),
+        }
+    }
+
+    fn load(&mut self, ty: ValType, size: usize, signed: bool, offset: u64) {
+        let (addr, _) = self.pop();
+        let dst = self.push(ty);
+        self.body.push(Inst::Load {
+            ty,
+            size,
+            signed,
+            dst,
+            addr,
+            offset,
+        });
+    }
+
+    fn store(&mut self, size: usize, offset: u64) {
+        let (src, ty) = self.pop();
+        let (addr, _) = self.pop();
+        self.body.push(Inst::Store {
+            ty,
+            size,
+            addr,
+            src,
+            offset,
+        });
+    }
+
+    fn call(&mut self, func: u32, tail: bool) {
+        let ty = self.module.functions[func as usize].func_type.clone();
+        self.operands
+            .truncate(self.operands.len() - ty.params().len());
+        let args = Slot(self.operands.len());
+        self.operands.extend_from_slice(ty.results());
+        self.body.push(Inst::Call {
+            func,
+            ty,
+            args,
+            tail,
+        });
+    }
+
+    fn call_indirect(&mut self, type_index: u32, table: u32, tail: bool) {
+        let ty = self.module.types[type_index as usize].clone();
+        let (index, _) = self.pop();
+        self.operands
+            .truncate(self.operands.len() - ty.params().len());
+        let args = Slot(self.operands.len());
+        self.operands.extend_from_slice(ty.results());
+        self.body.push(Inst::CallIndirect {
+            type_index,
+            table,
+            ty,
+            args,
+            index,
+            tail,
+        });
+    }
+
+    /// Translates `op`, returning whether the code following it is
+    /// unreachable.
+    fn translate(&mut self, op: Operator, locals: &[ValType]) -> Result<bool> {
+        match op {
+            Operator::Nop => {}
+            Operator::Drop => {
+                let (src, ty) = self.pop();
+                self.body.push(Inst::Drop { ty, src });
+            }
+            Operator::LocalGet { local_index } => {
+                let local = local_index as usize;
+                let dst = self.push(locals[local]);
+                self.body.push(Inst::LocalGet { dst, local });
+            }
+            Operator::LocalSet { local_index } => {
+                let (src, _) = self.pop();
+                let local = local_index as usize;
+                self.body.push(Inst::LocalSet { src, local });
+            }
+            Operator::LocalTee { local_index } => {
+                let local = local_index as usize;
+                let src = self.top();
+                self.body.push(Inst::LocalTee { src, local });
+            }
+            Operator::GlobalGet { global_index } => {
+                let dst = self.push(ValType::I32);
+                self.body.push(Inst::GlobalGet {
+                    dst,
+                    global: global_index,
+                });
+            }
+            Operator::GlobalSet { global_index } => {
+                let (src, _) = self.pop();
+                self.body.push(Inst::GlobalSet {
+                    src,
+                    global: global_index,
+                });
+            }
+            Operator::I32Const { value } => {
+                let dst = self.push(ValType::I32);
+                self.body.push(Inst::Const {
+                    dst,
+                    ty: ValType::I32,
+                    value: value.into(),
+                });
+            }
+            Operator::I64Const { value } => {
+                let dst = self.push(ValType::I64);
+                self.body.push(Inst::Const {
+                    dst,
+                    ty: ValType::I64,
+                    value,
+                });
+            }
+            // Floats are only ever truncated, so their constants are carried
+            // as raw bits.
+            Operator::F32Const { value } => {
+                let dst = self.push(ValType::F32);
+                self.body.push(Inst::Const {
+                    dst,
+                    ty: ValType::F32,
+                    value: value.bits().into(),
+                });
+            }
+            Operator::F64Const { value } => {
+                let dst = self.push(ValType::F64);
+                self.body.push(Inst::Const {
+                    dst,
+                    ty: ValType::F64,
+                    value: value.bits() as i64,
+                });
+            }
+            Operator::I32Store8 { memarg } => self.store(1, memarg.offset),
+            Operator::I32Store { memarg } => self.store(4, memarg.offset),
+            Operator::I32Load { memarg } => self.load(ValType::I32, 4, false, memarg.offset),
+            Operator::I32Load8U { memarg } => self.load(ValType::I32, 1, false, memarg.offset),
+            Operator::I32Eqz => self.unary(UnaryOp::Eqz, ValType::I32),
+            Operator::I32Add => self.binary(BinaryOp::Add),
+            Operator::I32Sub => self.binary(BinaryOp::Sub),
+            Operator::I32And => self.binary(BinaryOp::And),
+            Operator::I32Or => self.binary(BinaryOp::Or),
+            Operator::I32Xor => self.binary(BinaryOp::Xor),
+            Operator::I32Mul => self.binary(BinaryOp::Mul),
+            Operator::I32DivS => self.binary(BinaryOp::DivS),
+            Operator::I32DivU => self.binary(BinaryOp::DivU),
+            Operator::I32RemS => self.binary(BinaryOp::RemS),
+            Operator::I32RemU => self.binary(BinaryOp::RemU),
+            Operator::I32Eq => self.compare(CompareOp::Eq),
+            Operator::I32Ne => self.compare(CompareOp::Ne),
+            Operator::I32LtS => self.compare(CompareOp::LtS),
+            Operator::I32LtU => self.compare(CompareOp::LtU),
+            Operator::I32GtS => self.compare(CompareOp::GtS),
+            Operator::I32GtU => self.compare(CompareOp::GtU),
+            Operator::I32LeS => self.compare(CompareOp::LeS),
+            Operator::I32LeU => self.compare(CompareOp::LeU),
+            Operator::I32GeS => self.compare(CompareOp::GeS),
+            Operator::I32GeU => self.compare(CompareOp::GeU),
+            Operator::I32Extend8S => self.unary(UnaryOp::Extend8S, ValType::I32),
+            Operator::I32Extend16S => self.unary(UnaryOp::Extend16S, ValType::I32),
+            Operator::I64Extend8S => self.unary(UnaryOp::Extend8S, ValType::I64),
+            Operator::I64Extend16S => self.unary(UnaryOp::Extend16S, ValType::I64),
+            Operator::I64Extend32S => self.unary(UnaryOp::Extend32S, ValType::I64),
+            Operator::I32Clz => self.unary(UnaryOp::Clz, ValType::I32),
+            Operator::I32Ctz => self.unary(UnaryOp::Ctz, ValType::I32),
+            Operator::I32Popcnt => self.unary(UnaryOp::Popcnt, ValType::I32),
+            Operator::I64Clz => self.unary(UnaryOp::Clz, ValType::I64),
+            Operator::I64Ctz => self.unary(UnaryOp::Ctz, ValType::I64),
+            Operator::I64Popcnt => self.unary(UnaryOp::Popcnt, ValType::I64),
+            Operator::I32TruncSatF32S | Operator::I32TruncSatF64S => {
+                self.unary(UnaryOp::TruncSatS, ValType::I32)
+            }
+            Operator::I32TruncSatF32U | Operator::I32TruncSatF64U => {
+                self.unary(UnaryOp::TruncSatU, ValType::I32)
+            }
+            Operator::I64TruncSatF32S | Operator::I64TruncSatF64S => {
+                self.unary(UnaryOp::TruncSatS, ValType::I64)
+            }
+            Operator::I64TruncSatF32U | Operator::I64TruncSatF64U => {
+                self.unary(UnaryOp::TruncSatU, ValType::I64)
+            }
+            Operator::Select => {
+                let (cond, _) = self.pop();
+                let (if_false, _) = self.pop();
+                let (if_true, ty) = self.pop();
+                if ty != ValType::I32 {
+                    bail!("select is only supported on i32, not {ty}");
+                }
+                let dst = self.push(ty);
+                self.body.push(Inst::Select {
+                    ty,
+                    dst,
+                    if_true,
+                    if_false,
+                    cond,
+                });
+            }
+            Operator::Unreachable => {
+                self.body.push(Inst::Trap(Trap::Unreachable));
+                return Ok(true);
+            }
+            Operator::Br { relative_depth } => {
+                let frame = self.frame(relative_depth);
+                let inst = Inst::Branch {
+                    target: frame.label,
+                    cond: Cond::Always,
+                    unwind: self.unwind(frame),
+                };
+                self.body.push(inst);
+                return Ok(true);
+            }
+            Operator::BrIf { relative_depth } => {
+                let cond = self.condition(false);
+                let frame = self.frame(relative_depth);
+                let inst = Inst::Branch {
+                    target: frame.label,
+                    cond,
+                    unwind: self.unwind(frame),
+                };
+                self.body.push(inst);
+            }
+            Operator::Loop { blockty } => {
+                let label = self.label();
+                let frame = self.control_frame(blockty, label, true);
+                self.frames.push(frame);
+                self.body.push(Inst::Label(label));
+            }
+            Operator::Block { blockty } => {
+                let label = self.label();
+                let frame = self.control_frame(blockty, label, false);
+                self.frames.push(frame);
+            }
+            Operator::If { blockty } => {
+                let cond = self.condition(true);
+                let label = self.label();
+                let else_label = self.label();
+                let mut frame = self.control_frame(blockty, label, false);
+                frame.else_label = Some(else_label);
+                self.frames.push(frame);
+                self.body.push(Inst::Branch {
+                    target: else_label,
+                    cond,
+                    unwind: Unwind::default(),
+                });
+            }
+            Operator::Else => {
+                let frame = self.frames.last_mut().unwrap();
+                let else_label = frame.else_label.take().unwrap();
+                let label = frame.label;
+                self.operands.truncate(frame.height);
+                self.operands.extend_from_slice(&frame.params);
+                self.body.push(Inst::Branch {
+                    target: label,
+                    cond: Cond::Always,
+                    unwind: Unwind::default(),
+                });
+                self.body.push(Inst::Label(else_label));
+            }
+            Operator::Call { function_index } => self.call(function_index, false),
+            Operator::CallIndirect {
+                type_index,
+                table_index,
+                ..
+            } => self.call_indirect(type_index, table_index, false),
+            Operator::ReturnCall { function_index } => {
+                self.call(function_index, true);
+                return Ok(true);
+            }
+            Operator::ReturnCallIndirect {
+                type_index,
+                table_index,
+            } => {
+                self.call_indirect(type_index, table_index, true);
+                return Ok(true);
+            }
+            Operator::Return => {
+                let frame = &self.frames[0];
+                let inst = Inst::Branch {
+                    target: frame.label,
+                    cond: Cond::Always,
+                    unwind: self.unwind(frame),
+                };
+                self.body.push(inst);
+                return Ok(true);
+            }
+            Operator::End => {
+                let frame = self.frames.pop().unwrap();
+                if let Some(else_label) = frame.else_label {
+                    self.body.push(Inst::Label(else_label));
+                }
+                if !frame.is_loop {
+                    self.body.push(Inst::Label(frame.label));
+                }
+                self.operands.truncate(frame.height);
+                self.operands.extend_from_slice(&frame.results);
+            }
+            // i64 arithmetic and float operators other than truncation
+            // have no code generation yet.
+            op => bail!("unsupported operator {op:?}"),
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile::{Config, OptLevel};
+    use crate::emu::{self, Machine};
+    use crate::loader;
+    use crate::runtime::SCRATCH_ADDR;
+    use crate::trap::TRAP_CODE_OFFSET;
+
+    /// Returns the body of the entry of a module running `body` with
+    /// parameters `a` and `b`.
+    fn entry(body: &str) -> Vec<Inst> {
+        let wasm = wat::parse_str(format!(
+            r#"(module (func (export "entry") (param $a i32) (param $b i32) (result i32)
+                {body}))"#
+        ))
+        .unwrap();
+        let module = loader::load(&wasm).unwrap();
+        build(&module, 0).unwrap().body
+    }
+
+    fn branch_conds(body: &[Inst]) -> Vec<Cond> {
+        body.iter()
+            .filter_map(|inst| match inst {
+                Inst::Branch { cond, .. } => Some(*cond),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn compares(body: &[Inst]) -> usize {
+        body.iter()
+            .filter(|inst| matches!(inst, Inst::Compare { .. }))
+            .count()
+    }
+
+    #[test]
+    fn branches_test_comparisons() {
+        let body =
+            entry("(br_if 0 (i32.const 1) (i32.lt_u (local.get $a) (local.get $b))) (i32.const 0)");
+        assert_eq!(compares(&body), 0);
+        assert!(matches!(
+            branch_conds(&body)[..],
+            [Cond::Compare(CompareOp::LtU, _, _), ..]
+        ));
+        // An if branches past its then arm when the condition fails.
+        let body = entry("(if (result i32) (i32.eqz (i32.gt_s (local.get $a) (local.get $b))) (then (i32.const 1)) (else (i32.const 2)))");
+        assert_eq!(compares(&body), 0);
+        assert!(matches!(
+            branch_conds(&body)[..],
+            [Cond::Compare(CompareOp::GtS, _, _), ..]
+        ));
+    }
+
+    #[test]
+    fn comparisons_kept_as_values_stay() {
+        let body = entry("(local.set $b (i32.lt_u (local.get $a) (local.get $b))) (br_if 0 (i32.const 1) (local.get $b)) (i32.const 0)");
+        assert_eq!(compares(&body), 1);
+        assert!(matches!(branch_conds(&body)[..], [Cond::NonZero(_), ..]));
+    }
+
+    #[test]
+    fn fused_branches_go_the_right_way() {
+        let wat = |a: i32, b: i32| {
+            format!(
+                r#"(module
+                    (func $f (param $a i32) (param $b i32) (result i32)
+                      (if (result i32) (i32.eqz (i32.gt_s (local.get $a) (local.get $b)))
+                        (then (i32.const 1))
+                        (else (br_if 0 (i32.const 2) (i32.lt_u (local.get $a) (local.get $b)))
+                          (drop) (i32.const 3))))
+                    (func (export "entry") (result i32)
+                      (call $f (i32.const {a}) (i32.const {b}))))"#
+            )
+        };
+        for (a, b, result) in [
+            (1, 2, 1),
+            (-1, 1, 1),
+            (2, 1, 3),
+            (1, -1, 2),
+            (0x10000, 5, 3),
+        ] {
+            let machine = emu::run_wat(&wat(a, b), Config::default());
+            assert_eq!(machine.top_i32(), result, "{a} {b}");
+        }
+    }
+
+    /// Runs `funcs` and an entry with `results` running `body`, unoptimized
+    /// and at -O2.
+    fn run(funcs: &str, results: &str, body: &str) -> [Machine; 2] {
+        let wat = format!(
+            r#"(module {funcs}
+                (func (export "entry") (result {results}) {body}))"#
+        );
+        [OptLevel::O0, OptLevel::O2].map(|level| {
+            let config = Config {
+                passes: level.passes(),
+                ..Config::default()
+            };
+            let machine = emu::run_wat(&wat, config);
+            assert_eq!(machine.byte(SCRATCH_ADDR + TRAP_CODE_OFFSET as u16), 0);
+            machine
+        })
+    }
+
+    #[test]
+    fn drops_take_whole_values() {
+        for body in [
+            "(drop (i64.const 0x1122334455667788)) (i32.const 7)",
+            "(i32.const 7) (i64.const -1) (drop)",
+            "(i32.const 7) (i32.const 8) (i64.const 9) (drop) (drop)",
+        ] {
+            for machine in run("", "i32", body) {
+                assert_eq!(machine.top_i32(), 7, "{body}");
+            }
+        }
+    }
+
+    #[test]
+    fn code_after_leaving_is_skipped() {
+        for body in [
+            "(block (br 0) (i32.const 1) (drop) (unreachable)) (i32.const 3)",
+            "(block (result i32) (br 0 (i32.const 3)) (i64.const 1) (unreachable))",
+            "(return (i32.const 3)) (unreachable)",
+            "(if (i32.const 1) (then (return (i32.const 3)) (drop (i32.const 1)))) (unreachable)",
+        ] {
+            for machine in run("", "i32", body) {
+                assert_eq!(machine.top_i32(), 3, "{body}");
+            }
+        }
+    }
+
+    #[test]
+    fn several_results_and_params_pass_on_the_stack() {
+        let pair = r#"(func $pair (param i32) (result i32 i32)
+            (i32.add (local.get 0) (i32.const 9)) (i32.const 3))"#;
+        for (body, result) in [
+            ("(call $pair (i32.const 1)) (i32.sub)", 7),
+            ("(call $pair (i32.const 1)) (drop)", 10),
+            (
+                "(i32.const 2) (block (param i32) (result i32) (i32.const 3) (i32.add))",
+                5,
+            ),
+            (
+                "(i32.const 50) (i32.const 10)
+                 (if (param i32 i32) (result i32) (i32.const 1)
+                    (then (i32.sub)) (else (i32.add)))",
+                40,
+            ),
+            (
+                "(i32.const 0)
+                 (loop (param i32) (result i32)
+                    (i32.const 1) (i32.add) (local.tee 0) (local.get 0)
+                    (i32.const 5) (i32.lt_u) (br_if 0))",
+                5,
+            ),
+            (
+                "(call $pair (i32.const 1))
+                 (block (param i32 i32) (result i32 i32) (i32.const 2) (i32.add))
+                 (i32.sub)",
+                5,
+            ),
+        ] {
+            let body = format!("(local i32) {body}");
+            for machine in run(pair, "i32", &body) {
+                assert_eq!(machine.top_i32(), result, "{body}");
+            }
+        }
+    }
+}
@@ -1,6 +1,10 @@
-use wasmparser::{Export, FuncType, FunctionBody, Payload, RecGroup, SectionLimited};
+use anyhow::{bail, Result};
+use wasmparser::{
+    ConstExpr, Data as DataDef, DataKind, Element, ElementItems, ElementKind, Export, FuncType,
+    FunctionBody, Import, Operator, Payload, RecGroup, SectionLimited, Table as TableDef, TypeRef,
+};
 
-use crate::compile::{Module, FunctionDef};
+use crate::compile::{self, Code, Data, FunctionDef, Module, Table};
 
 struct FunctionDecl {
     typ: FuncType,
@@ -11,7 +15,14 @@ struct ModuleBuilder<'a> {
     types: Vec<FuncType>,
     func_decls: Vec<FunctionDecl>,
     functions: Vec<FunctionDef<'a>>,
+    /// Number of functions imported, which come before the defined ones.
+    imported: usize,
+    tables: Vec<Table>,
+    globals: usize,
+    data: Vec<Data<'a>>,
     entry: Option<usize>,
+    start: Option<usize>,
+    exports: Vec<usize>,
 }
 
 impl<'a> ModuleBuilder<'a> {
@@ -26,6 +37,32 @@ impl<'a> ModuleBuilder<'a> {
             .unwrap();
     }
 
+    pub fn add_imports(&mut self, imports: SectionLimited<'_, Import<'_>>) -> Result<()> {
+        for import in imports {
+            let import = import.unwrap();
+            match import.ty {
+                TypeRef::Func(index) => {
+                    self.functions.push(FunctionDef {
+                        func_type: self.types[index as usize].clone(),
+                        code: Code::Import(compile::Import {
+                            module: import.module.to_string(),
+                            name: import.name.to_string(),
+                        }),
+                    });
+                    self.imported += 1;
+                }
+                // Memory is whatever the platform has.
+                TypeRef::Memory(_) => {}
+                ty => bail!(
+                    "{}.{} is imported as {ty:?}, which isn't supported",
+                    import.module,
+                    import.name
+                ),
+            }
+        }
+        Ok(())
+    }
+
     pub fn add_funcs(&mut self, funcs: SectionLimited<'_, u32>) {
         self.func_decls = funcs
             .into_iter()
@@ -39,33 +76,127 @@ impl<'a> ModuleBuilder<'a> {
     }
 
     pub fn add_code(&mut self, body: FunctionBody<'a>) {
-        let typ = self.func_decls[self.functions.len()].typ.clone();
-        self.functions.push(FunctionDef { func_type: typ, body });
+        let typ = self.func_decls[self.functions.len() - self.imported]
+            .typ
+            .clone();
+        self.functions.push(FunctionDef {
+            func_type: typ,
+            code: Code::Body(body),
+        });
     }
 
-    pub fn add_exports(&mut self, exports: SectionLimited<'_, Export<'_>>) {
-        self.entry = exports
+    pub fn add_tables(&mut self, tables: SectionLimited<'_, TableDef<'_>>) {
+        self.tables = tables
             .into_iter()
-            .filter_map(|exp| exp.ok())
-            .filter_map(|exp| {
-                if exp.kind == wasmparser::ExternalKind::Func && exp.name == "entry" {
-                    Some(exp.index as usize)
-                } else {
-                    None
-                }
+            .map(|table| {
+                table.map(|table| Table {
+                    elements: vec![None; table.ty.initial as usize],
+                })
             })
-            .next();
+            .collect::<Result<_, _>>()
+            .unwrap();
     }
 
-    pub fn build(self) -> Module<'a> {
-        Module {
-            entry: self.entry.unwrap(),
-            functions: self.functions,
+    pub fn add_elements(&mut self, elements: SectionLimited<'_, Element<'_>>) -> Result<()> {
+        for (index, element) in elements.into_iter().enumerate() {
+            let element = element.unwrap();
+            let ElementKind::Active {
+                table_index,
+                offset_expr,
+            } = element.kind
+            else {
+                continue;
+            };
+            let offset = eval_offset(&offset_expr)?;
+            let funcs: Vec<Option<u32>> = match element.items {
+                ElementItems::Functions(funcs) => funcs
+                    .into_iter()
+                    .map(|func| func.map(Some))
+                    .collect::<Result<_, _>>()
+                    .unwrap(),
+                ElementItems::Expressions(_, exprs) => exprs
+                    .into_iter()
+                    .map(|expr| eval_element(&expr.unwrap()))
+                    .collect::<Result<_>>()?,
+            };
+            let table_index = table_index.unwrap_or(0);
+            let table = &mut self.tables[table_index as usize];
+            let end = offset + funcs.len();
+            if end > table.elements.len() {
+                bail!(
+                    "element segment {index} ends at {end}, past the {} elements of table {table_index}",
+                    table.elements.len()
+                );
+            }
+            table.elements[offset..end].copy_from_slice(&funcs);
+        }
+        Ok(())
+    }
+
+    pub fn add_data(&mut self, data: SectionLimited<'a, DataDef<'a>>) -> Result<()> {
+        for (index, segment) in data.into_iter().enumerate() {
+            let segment = segment.unwrap();
+            // Passive segments are only copied by `memory.init`, which isn't
+            // supported.
+            let DataKind::Active { offset_expr, .. } = segment.kind else {
+                bail!("data segment {index} is passive, which isn't supported");
+            };
+            self.data.push(Data {
+                offset: eval_offset(&offset_expr)?,
+                bytes: segment.data,
+            });
         }
+        Ok(())
+    }
+
+    pub fn add_exports(&mut self, exports: SectionLimited<'_, Export<'_>>) {
+        for export in exports {
+            let export = export.unwrap();
+            if export.kind != wasmparser::ExternalKind::Func {
+                continue;
+            }
+            if export.name == "entry" {
+                self.entry = Some(export.index as usize);
+            }
+            self.exports.push(export.index as usize);
+        }
+    }
+
+    pub fn build(self) -> Result<Module<'a>> {
+        let Some(entry) = self.entry else {
+            bail!("the module exports no function named `entry` to start from");
+        };
+        Ok(Module {
+            entry,
+            start: self.start,
+            exports: self.exports,
+            types: self.types,
+            functions: self.functions,
+            tables: self.tables,
+            globals: self.globals,
+            data: self.data,
+        })
+    }
+}
+
+fn eval_offset(expr: &ConstExpr<'_>) -> Result<usize> {
+    match expr.get_operators_reader().read().unwrap() {
+        // Offsets are unsigned.
+        Operator::I32Const { value } => Ok(value as u32 as usize),
+        op => bail!("unsupported offset expression {op:?}"),
     }
 }
 
-pub fn load(data: &[u8]) -> Module {
+/// Returns the function an element expression refers to, if any.
+fn eval_element(expr: &ConstExpr<'_>) -> Result<Option<u32>> {
+    match expr.get_operators_reader().read().unwrap() {
+        Operator::RefFunc { function_index } => Ok(Some(function_index)),
+        Operator::RefNull { .. } => Ok(None),
+        op => bail!("unsupported element expression {op:?}"),
+    }
+}
+
+pub fn load(data: &[u8]) -> Result<Module<'_>> {
     let parser = wasmparser::Parser::new(0);
     let mut builder = ModuleBuilder::new();
     for payload in parser.parse_all(data) {
@@ -75,25 +206,70 @@ pub fn load(data: &[u8]) -> Module {
             Payload::TypeSection(types) => {
                 builder.add_types(types);
             }
+            Payload::ImportSection(imports) => builder.add_imports(imports)?,
             Payload::FunctionSection(funcs) => {
                 builder.add_funcs(funcs);
             }
+            Payload::TableSection(tables) => builder.add_tables(tables),
             Payload::ExportSection(exports) => builder.add_exports(exports),
+            Payload::StartSection { func, .. } => builder.start = Some(func as usize),
+            Payload::ElementSection(elements) => builder.add_elements(elements)?,
             Payload::CodeSectionEntry(body) => {
                 builder.add_code(body);
             }
-            Payload::DataSection(_data) => {
-                todo!();
-            }
+            Payload::GlobalSection(globals) => builder.globals = globals.count() as usize,
+            Payload::DataSection(data) => builder.add_data(data)?,
             Payload::CustomSection(_)
             | Payload::Version { .. }
             | Payload::MemorySection(_)
-            | Payload::GlobalSection(_)
+            | Payload::DataCountSection { .. }
             | Payload::CodeSectionStart { .. } => { /* ignore */ }
-            payload => {
-                panic!("{:?}", payload);
-            }
+            payload => bail!("unsupported section {payload:?}"),
         }
     }
     builder.build()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passive_data_is_rejected() {
+        let wasm = wat::parse_str(r#"(module (memory 1) (data "x") (func (export "entry")))"#);
+        assert!(load(&wasm.unwrap()).is_err());
+    }
+
+    #[test]
+    fn missing_entry_is_rejected() {
+        let wasm = wat::parse_str(r#"(module (func (export "add") (param i32 i32)))"#);
+        assert!(load(&wasm.unwrap()).is_err());
+    }
+
+    #[test]
+    fn elements_past_the_table_are_rejected() {
+        let module = |offset: i32| {
+            wat::parse_str(format!(
+                r#"(module (table 2 funcref) (elem (i32.const {offset}) $f $f)
+                    (func $f) (func (export "entry")))"#
+            ))
+            .unwrap()
+        };
+        assert!(load(&module(0)).is_ok());
+        assert!(load(&module(1)).is_err());
+        assert!(load(&module(-1)).is_err());
+    }
+
+    #[test]
+    fn unsupported_imports_and_sections_are_rejected() {
+        for wat in [
+            r#"(module (import "env" "g" (global i32)) (func (export "entry")))"#,
+            r#"(module (import "env" "t" (table 1 funcref)) (func (export "entry")))"#,
+            r#"(module (tag) (func (export "entry")))"#,
+            r#"(module (global i32 (i32.const 0))
+                (memory 1) (data (global.get 0) "x") (func (export "entry")))"#,
+        ] {
+            assert!(load(&wat::parse_str(wat).unwrap()).is_err(), "{wat}");
+        }
+    }
+}
@@ -1,22 +1,173 @@
 use std::{io::Write, path::PathBuf};
 
-use clap::Parser;
+use clap::{error::ErrorKind, CommandFactory, Parser, ValueEnum};
 
+mod agon;
+mod asm;
+mod callgraph;
 mod compile;
+mod countdown;
+mod cpm;
+#[cfg(test)]
+mod emu;
+mod encode;
+mod ez80;
+mod i8080;
+mod inline;
+mod ir;
 mod loader;
+mod msx;
+mod narrow;
+mod peephole;
+mod regalloc;
+mod relax;
+mod runtime;
+mod sm83;
+mod spectrum;
+mod trap;
+mod z180;
 
 #[derive(Parser)]
 struct Opts {
     //#[clap(short, long)]
     //output: PathBuf,
     wasm: PathBuf,
+    /// Label to jump to when the program traps, instead of halting
+    #[clap(long)]
+    trap_handler: Option<String>,
+    /// Use 256-byte lookup tables in runtime routines, for speed over size
+    #[clap(long)]
+    lookup_tables: bool,
+    /// Assembler syntax to emit
+    #[clap(long, value_enum, default_value_t)]
+    syntax: asm::Syntax,
+    /// Processor to generate code for
+    #[clap(long, value_enum, default_value_t)]
+    target: compile::Target,
+    /// System to run on
+    #[clap(long, value_enum, default_value_t)]
+    platform: compile::Platform,
+    /// Form of the output
+    #[clap(long, value_enum, default_value_t)]
+    format: compile::Format,
+    /// Index register to address stack frames with
+    #[clap(long, value_enum, default_value_t)]
+    frame_pointer: compile::FramePointer,
+    /// Optimization level: 0 to 3, or s for size
+    #[clap(short = 'O', value_enum, default_value_t)]
+    opt_level: compile::OptLevel,
+    /// Inline calls to functions costing at most this many instructions,
+    /// each parameter counting as 3, or 0 to never inline, instead of what
+    /// the optimization level does
+    #[clap(long)]
+    inline_threshold: Option<usize>,
+    /// Indices of functions to compile even if nothing calls them, like ones
+    /// called as `func_N` from hand-written assembly
+    #[clap(long, value_delimiter = ',')]
+    keep: Vec<u32>,
+    /// Z180 CBAR value: the 4 KiB pages common area 1 and the bank area
+    /// start at, in the high and low nibbles, instead of 0xFD
+    #[clap(long, value_parser = parse_byte)]
+    z180_cbar: Option<u8>,
+    /// Z180 CBR value: the page common area 1 is mapped at in physical
+    /// memory, less the one it starts at, instead of 0
+    #[clap(long, value_parser = parse_byte)]
+    z180_cbr: Option<u8>,
+    /// Print code size before peephole optimization and of the final code,
+    /// with jumps relaxed, to stderr
+    #[clap(long)]
+    stats: bool,
 }
 
-fn main() {
+fn main() -> anyhow::Result<()> {
     let opts = Opts::parse();
+    if !opts.platform.runs(opts.target) {
+        let message = format!(
+            "--platform {} doesn't run --target {}",
+            value_name(opts.platform),
+            value_name(opts.target)
+        );
+        Opts::command()
+            .error(ErrorKind::ArgumentConflict, message)
+            .exit();
+    }
+    if !opts.format.fits(opts.target, opts.platform) {
+        let message = format!(
+            "--format {} can't be made for --target {} on --platform {}",
+            value_name(opts.format),
+            value_name(opts.target),
+            value_name(opts.platform)
+        );
+        Opts::command()
+            .error(ErrorKind::ArgumentConflict, message)
+            .exit();
+    }
+    let mut mmu = z180::Mmu::default();
+    if opts.target == compile::Target::Z180 {
+        mmu.cbar = opts.z180_cbar.unwrap_or(mmu.cbar);
+        mmu.cbr = opts.z180_cbr.unwrap_or(mmu.cbr);
+        if let Err(message) = mmu.check() {
+            Opts::command()
+                .error(ErrorKind::InvalidValue, message)
+                .exit();
+        }
+    } else if opts.z180_cbar.is_some() || opts.z180_cbr.is_some() {
+        let message = format!(
+            "--z180-cbar and --z180-cbr don't apply to --target {}",
+            value_name(opts.target)
+        );
+        Opts::command()
+            .error(ErrorKind::ArgumentConflict, message)
+            .exit();
+    }
     let wasm = std::fs::read(opts.wasm).unwrap();
-    let module = loader::load(&wasm);
+    let module = loader::load(&wasm)?;
+    let functions = module.functions.len();
+    if let Some(func) = opts.keep.iter().find(|func| **func as usize >= functions) {
+        let message =
+            format!("--keep {func} is out of range, the module has {functions} functions");
+        Opts::command()
+            .error(ErrorKind::InvalidValue, message)
+            .exit();
+    }
+    let mut passes = opts.opt_level.passes();
+    if let Some(threshold) = opts.inline_threshold {
+        passes.inline_threshold = threshold;
+    }
+    let config = compile::Config {
+        trap_handler: opts.trap_handler,
+        lookup_tables: opts.lookup_tables,
+        syntax: opts.syntax,
+        target: opts.target,
+        platform: opts.platform,
+        format: opts.format,
+        frame_pointer: opts.frame_pointer,
+        passes,
+        keep: opts.keep,
+        mmu,
+    };
     let mut out = vec![];
-    module.compile(&mut out);
+    let stats = module.compile(&config, &mut out)?;
+    if opts.stats {
+        let saved = stats.before as i64 - stats.after as i64;
+        eprintln!(
+            "code size: {} bytes before peephole, {} final ({} saved)",
+            stats.before, stats.after, saved
+        );
+    }
     std::io::stdout().write_all(&out).unwrap();
+    Ok(())
+}
+
+/// Returns the name `value` is given by on the command line.
+fn value_name(value: impl ValueEnum) -> String {
+    value.to_possible_value().unwrap().get_name().to_string()
+}
+
+/// Parses a byte written in decimal, or in hexadecimal after `0x`.
+fn parse_byte(text: &str) -> Result<u8, std::num::ParseIntError> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => text.parse(),
+    }
 }
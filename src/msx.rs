@@ -0,0 +1,186 @@
+use anyhow::{bail, Result};
+use wasmparser::ValType;
+
+use crate::asm::{self, Inst};
+use crate::compile::{Provided, Target, GLOBALS_OFFSET};
+use crate::encode;
+
+/// Address of the cartridge ROM in page 1, where the BIOS looks for the
+/// header.
+const ROM_ADDR: u16 = 0x4000;
+
+/// Address the code is assembled at, after the header.
+pub const CODE_ADDR: u16 = ROM_ADDR + 0x10;
+
+/// Address of the data block in the RAM of page 3: the scratch buffer, the
+/// trap record and the globals.
+pub const DATA_ADDR: u16 = 0xC000;
+
+/// Number of globals there is room for in the data block.
+pub const MAX_GLOBALS: usize = 16;
+
+/// Size of the data block.
+const DATA_SIZE: u16 = (GLOBALS_OFFSET + 4 * MAX_GLOBALS) as u16;
+
+/// Start of the system work area, which the BIOS's stack grows down from.
+const SYSTEM_ADDR: u16 = 0xF380;
+
+/// Returns whether the `size` bytes of linear memory at `addr` are in the RAM
+/// left to the program, between the data block and the system work area.
+pub fn is_ram(addr: usize, size: usize) -> bool {
+    usize::from(DATA_ADDR + DATA_SIZE) <= addr && addr + size <= usize::from(SYSTEM_ADDR)
+}
+
+/// Emits the routine ending the program, which stays on screen since the
+/// BIOS isn't returned to.
+pub fn emit_routine(code: &mut Vec<Inst>) {
+    code.extend(asm::parse(EXIT));
+}
+
+/// Routines imported from `console`, calling the BIOS routines they stand
+/// for.
+pub const IMPORTS: &[Provided] = &[
+    Provided {
+        name: "putchar",
+        params: &[ValType::I32],
+        results: &[],
+        body: PUTCHAR,
+    },
+    Provided {
+        name: "getchar",
+        params: &[],
+        results: &[ValType::I32],
+        body: GETCHAR,
+    },
+];
+
+/// Returns a cartridge ROM image of `code` for `target`, 16 KiB if it fits
+/// and 32 KiB otherwise, starting with the header giving the address to
+/// initialize it at.
+///
+/// Only page 1 of a cartridge is selected when it is initialized, so a 32 KiB
+/// ROM starts by selecting page 2 as well. That code goes in front after the
+/// code is relaxed, which leaves the jumps in range since none cross it.
+pub fn rom(code: &[Inst], target: Target) -> Result<Vec<u8>> {
+    let header = usize::from(CODE_ADDR - ROM_ADDR);
+    let mut bytes = encode::assemble(code, CODE_ADDR, target);
+    let mut size = 0x4000;
+    if header + bytes.len() > size {
+        let (org, rest) = code.split_first().unwrap();
+        let mut code = vec![org.clone()];
+        code.extend(asm::parse(PAGE_2));
+        code.extend_from_slice(rest);
+        bytes = encode::assemble(&code, CODE_ADDR, target);
+        size = 0x8000;
+    }
+    let end = header + bytes.len();
+    if end > size {
+        bail!(
+            "the code takes {} bytes, which don't fit in a 32 KiB ROM",
+            bytes.len()
+        );
+    }
+    let mut rom = vec![0; size];
+    rom[header..end].copy_from_slice(&bytes);
+    rom[0..2].copy_from_slice(b"AB");
+    rom[2..4].copy_from_slice(&CODE_ADDR.to_le_bytes());
+    Ok(rom)
+}
+
+/// Stops for good, with interrupts off so that `HALT` isn't left.
+const EXIT: &str = "\
+msx_exit:
+  DI
+  HALT
+";
+
+/// Selects the slot page 1 is in for page 2 with `ENASLT`, which leaves
+/// interrupts off. The slot is read from the primary
+/// slot register with `RSLREG`, and if it is expanded, its secondary slot
+/// register from `SLTTBL`.
+const PAGE_2: &str = "\
+msx_page_2:
+  CALL 0x0138
+  RRCA
+  RRCA
+  AND 3
+  LD C,A
+  LD B,0
+  LD HL,0xFCC1
+  ADD HL,BC
+  LD A,(HL)
+  AND 0x80
+  OR C
+  LD C,A
+  INC HL
+  INC HL
+  INC HL
+  INC HL
+  LD A,(HL)
+  AND 0x0C
+  OR C
+  LD H,0x80
+  CALL 0x0024
+  EI
+";
+
+// The BIOS routines keep the registers they don't return in.
+
+/// Writes the character in the low byte of the argument with `CHPUT`.
+const PUTCHAR: &str = "
+  LD A,E
+  CALL 0x00A2
+";
+
+/// Reads a character with `CHGET`, which waits for a key.
+const GETCHAR: &str = "
+  CALL 0x009F
+";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile::{Config, Format, Platform};
+    use crate::loader;
+
+    /// Compiles a module adding to a global `adds` times.
+    fn compile(adds: usize, format: Format) -> Result<Vec<u8>> {
+        let wasm = wat::parse_str(format!(
+            r#"(module (global (mut i32) (i32.const 0)) (func (export "entry") {}))"#,
+            "(global.set 0 (i32.add (global.get 0) (i32.const 3)))".repeat(adds)
+        ))
+        .unwrap();
+        let config = Config {
+            platform: Platform::Msx,
+            format,
+            ..Config::default()
+        };
+        let mut out = vec![];
+        loader::load(&wasm)?.compile(&config, &mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn small_programs_fill_page_1() {
+        let rom = compile(1, Format::Rom).unwrap();
+        let code = compile(1, Format::Bin).unwrap();
+        assert_eq!(rom.len(), 0x4000);
+        assert_eq!(&rom[0..2], b"AB");
+        assert_eq!(&rom[2..4], CODE_ADDR.to_le_bytes());
+        assert!(rom[4..0x10].iter().all(|&byte| byte == 0));
+        assert_eq!(&rom[0x10..0x10 + code.len()], code);
+        assert!(rom[0x10 + code.len()..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn large_programs_select_page_2() {
+        let rom = compile(1000, Format::Rom).unwrap();
+        assert_eq!(rom.len(), 0x8000);
+        assert_eq!(&rom[0..2], b"AB");
+        assert_eq!(&rom[2..4], CODE_ADDR.to_le_bytes());
+        // RSLREG is called first, before anything runs from page 2.
+        assert_eq!(&rom[0x10..0x13], [0xCD, 0x38, 0x01]);
+        assert!(rom[0x4000..].iter().any(|&byte| byte != 0));
+        assert!(compile(1400, Format::Rom).is_err());
+    }
+}
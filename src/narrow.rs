@@ -0,0 +1,302 @@
+use wasmparser::ValType;
+
+use crate::ir::{self, BinaryOp, Cond, Inst, Slot, UnaryOp};
+
+/// What is known of the bytes an instruction reads and writes, counted from
+/// the least significant one of each i32.
+///
+/// Code for an instruction only has to compute the `demand` low bytes of its
+/// result, and may leave anything in the others, since no instruction reads
+/// them. An operand `width` of 2 means the upper 16 bits of every operand
+/// the instruction compares or tests are known to be zero, so 16-bit code
+/// gives the same answer as 32-bit code would, even for signed comparisons.
+#[derive(Clone, Copy, Debug)]
+pub struct Narrow {
+    pub demand: usize,
+    pub width: usize,
+}
+
+/// Bytes of a slot assumed when nothing narrower is known.
+const FULL: usize = 4;
+
+/// Per-slot facts, the slots not tracked counting as `FULL`.
+#[derive(Default)]
+struct Slots(Vec<usize>);
+
+impl Slots {
+    fn get(&self, slot: Slot) -> usize {
+        self.0.get(slot.0).copied().unwrap_or(FULL)
+    }
+
+    fn set(&mut self, slot: Slot, bytes: usize) {
+        if self.0.len() <= slot.0 {
+            self.0.resize(slot.0 + 1, FULL);
+        }
+        self.0[slot.0] = bytes;
+    }
+
+    /// Forgets what is known of `slot` and every slot above it.
+    fn reset_from(&mut self, slot: Slot) {
+        self.0.truncate(slot.0);
+    }
+}
+
+/// Rounds a byte count up to a size arithmetic is done in.
+fn round(bytes: usize) -> usize {
+    match bytes {
+        0 | 1 => 1,
+        2 => 2,
+        _ => FULL,
+    }
+}
+
+/// Width of an i32 constant.
+fn const_width(value: i64) -> usize {
+    match value as u32 {
+        0..=0xFF => 1,
+        0x100..=0xFFFF => 2,
+        _ => FULL,
+    }
+}
+
+/// Bytes of its operands that a comparison of operands of `width` bytes
+/// reads, comparing in 16 bits if they fit.
+fn compare_demand(width: usize) -> usize {
+    match width {
+        1 | 2 => 2,
+        _ => FULL,
+    }
+}
+
+/// Analyzes `func`, returning what is known for each instruction of its body,
+/// where linear memory is addressed with the low `address_size` bytes of an
+/// address.
+///
+/// Widths are propagated forward and demand backward through straight-line
+/// code. Values crossing a label or a branch may come from or go to
+/// elsewhere, so nothing is assumed of them.
+pub fn analyze(func: &ir::Function, address_size: usize) -> Vec<Narrow> {
+    let widths = widths(func);
+    let mut demand = Slots::default();
+    let mut narrow = vec![];
+    for (inst, width) in func.body.iter().zip(widths).rev() {
+        let mut result = FULL;
+        let mut define = |demand: &mut Slots, dst: Slot| {
+            result = demand.get(dst);
+            demand.set(dst, FULL);
+        };
+        match inst {
+            Inst::Const { dst, .. } | Inst::LocalGet { dst, .. } | Inst::GlobalGet { dst, .. } => {
+                define(&mut demand, *dst);
+            }
+            Inst::LocalSet { src, .. }
+            | Inst::LocalTee { src, .. }
+            | Inst::GlobalSet { src, .. } => {
+                demand.set(*src, FULL);
+            }
+            Inst::Load { dst, addr, .. } => {
+                define(&mut demand, *dst);
+                demand.set(*addr, round(address_size));
+            }
+            Inst::Store {
+                size, addr, src, ..
+            } => {
+                demand.set(*addr, round(address_size));
+                demand.set(*src, round(*size));
+            }
+            Inst::Unary { op, dst, src, .. } => {
+                define(&mut demand, *dst);
+                match op {
+                    UnaryOp::Eqz => demand.set(*src, round(width)),
+                    _ => demand.set(*src, FULL),
+                }
+            }
+            Inst::Binary {
+                op, dst, lhs, rhs, ..
+            } => {
+                define(&mut demand, *dst);
+                // Quotients and remainders depend on every byte of their
+                // operands, but the low bytes of the other results only
+                // depend on the low bytes of the operands, even of a product.
+                let operand = match op {
+                    BinaryOp::DivS | BinaryOp::DivU | BinaryOp::RemS | BinaryOp::RemU => FULL,
+                    _ => round(result),
+                };
+                demand.set(*lhs, operand);
+                demand.set(*rhs, operand);
+            }
+            Inst::Compare { dst, lhs, rhs, .. } => {
+                define(&mut demand, *dst);
+                demand.set(*lhs, compare_demand(width));
+                demand.set(*rhs, compare_demand(width));
+            }
+            Inst::Select {
+                dst,
+                if_true,
+                if_false,
+                cond,
+                ..
+            } => {
+                define(&mut demand, *dst);
+                demand.set(*if_true, round(result));
+                demand.set(*if_false, round(result));
+                demand.set(*cond, FULL);
+            }
+            Inst::Drop { src, .. } => demand.set(*src, 1),
+            Inst::Call { args, .. } | Inst::CallIndirect { args, .. } => demand.reset_from(*args),
+            Inst::Label(_) => demand = Slots::default(),
+            Inst::Branch { cond, .. } => {
+                demand = Slots::default();
+                match cond {
+                    Cond::Always => {}
+                    Cond::NonZero(slot) | Cond::Zero(slot) => demand.set(*slot, round(width)),
+                    Cond::Compare(_, lhs, rhs) => {
+                        demand.set(*lhs, compare_demand(width));
+                        demand.set(*rhs, compare_demand(width));
+                    }
+                }
+            }
+            Inst::Trap(_) => {}
+        }
+        narrow.push(Narrow {
+            demand: result,
+            width,
+        });
+    }
+    narrow.reverse();
+    narrow
+}
+
+/// Returns for each instruction the widest of the operands it compares or
+/// tests.
+fn widths(func: &ir::Function) -> Vec<usize> {
+    let mut widths = Slots::default();
+    let mut tested = vec![];
+    for inst in &func.body {
+        let mut width = FULL;
+        match inst {
+            Inst::Const { dst, value, .. } => widths.set(*dst, const_width(*value)),
+            Inst::Load {
+                ty: ValType::I32,
+                size,
+                signed: false,
+                dst,
+                ..
+            } => widths.set(*dst, round(*size)),
+            Inst::Unary { op, dst, src, .. } => {
+                if *op == UnaryOp::Eqz {
+                    width = widths.get(*src);
+                }
+                let result = match op {
+                    UnaryOp::Eqz => 1,
+                    _ => FULL,
+                };
+                widths.set(*dst, result);
+            }
+            Inst::Binary {
+                op, dst, lhs, rhs, ..
+            } => {
+                let (lhs, rhs) = (widths.get(*lhs), widths.get(*rhs));
+                let result = match op {
+                    BinaryOp::And => lhs.min(rhs),
+                    BinaryOp::Or | BinaryOp::Xor => lhs.max(rhs),
+                    // The sum may carry into the next byte.
+                    BinaryOp::Add => round(lhs.max(rhs) + 1),
+                    // The difference may be negative.
+                    BinaryOp::Sub => FULL,
+                    // The product may be as wide as both together.
+                    BinaryOp::Mul => round(lhs + rhs),
+                    // An unsigned quotient is no wider than the dividend, and
+                    // a remainder no wider than either operand.
+                    BinaryOp::DivU => lhs,
+                    BinaryOp::RemU => lhs.min(rhs),
+                    // A signed one may be negative.
+                    BinaryOp::DivS | BinaryOp::RemS => FULL,
+                };
+                widths.set(*dst, result);
+            }
+            Inst::Compare { dst, lhs, rhs, .. } => {
+                width = widths.get(*lhs).max(widths.get(*rhs));
+                widths.set(*dst, 1);
+            }
+            Inst::LocalGet { dst, .. }
+            | Inst::GlobalGet { dst, .. }
+            | Inst::Load { dst, .. }
+            | Inst::Select { dst, .. } => {
+                widths.set(*dst, FULL);
+            }
+            Inst::Call { args, .. } | Inst::CallIndirect { args, .. } => widths.reset_from(*args),
+            Inst::Label(_) => widths = Slots::default(),
+            Inst::Branch { cond, .. } => match cond {
+                Cond::Always => {}
+                Cond::NonZero(slot) | Cond::Zero(slot) => width = widths.get(*slot),
+                Cond::Compare(_, lhs, rhs) => width = widths.get(*lhs).max(widths.get(*rhs)),
+            },
+            Inst::LocalSet { .. }
+            | Inst::LocalTee { .. }
+            | Inst::GlobalSet { .. }
+            | Inst::Store { .. }
+            | Inst::Drop { .. }
+            | Inst::Trap(_) => {}
+        }
+        tested.push(width);
+    }
+    tested
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile::Config;
+    use crate::{emu, loader};
+
+    /// Adds 1 to the byte at 0x8000, storing the sum's low byte at 0x8010
+    /// and all of it at 0x8020.
+    const INCREMENT: &str = r#"(module (memory 1)
+        (data (i32.const 0x8000) "\ff")
+        (func (export "entry")
+          (i32.store8 (i32.const 0x8010) (i32.add (i32.load8_u (i32.const 0x8000)) (i32.const 1)))
+          (i32.store (i32.const 0x8020) (i32.add (i32.load8_u (i32.const 0x8000)) (i32.const 1)))))"#;
+
+    #[test]
+    fn only_stored_bytes_are_computed() {
+        let wasm = wat::parse_str(INCREMENT).unwrap();
+        let module = loader::load(&wasm).unwrap();
+        let func = ir::build(&module, 0).unwrap();
+        let demands: Vec<usize> = (func.body.iter())
+            .zip(analyze(&func, 2))
+            .filter(|(inst, _)| matches!(inst, Inst::Binary { .. }))
+            .map(|(_, narrow)| narrow.demand)
+            .collect();
+        assert_eq!(demands, [1, 4]);
+        let code = emu::function_asm(INCREMENT, Config::default(), 0).unwrap();
+        let (byte, word) = code.split_at(
+            code.iter()
+                .position(|line| line == "LD (0x8010),A")
+                .unwrap(),
+        );
+        assert!(!byte.iter().any(|line| line.starts_with("ADC")));
+        assert!(word.iter().any(|line| line.starts_with("ADC")));
+        let machine = emu::run_wat(INCREMENT, Config::default());
+        assert_eq!(machine.byte(0x8010), 0);
+        assert_eq!(machine.word(0x8020), 0x100);
+    }
+
+    #[test]
+    fn narrow_operands_compare_narrow() {
+        let wasm = wat::parse_str(
+            r#"(module (memory 1) (func (export "entry") (param i32)
+                (br_if 0 (i32.lt_s (i32.load8_u (local.get 0)) (i32.const 200)))
+                (br_if 0 (i32.lt_s (i32.load (local.get 0)) (i32.const 200)))))"#,
+        )
+        .unwrap();
+        let module = loader::load(&wasm).unwrap();
+        let func = ir::build(&module, 0).unwrap();
+        let widths: Vec<usize> = (func.body.iter())
+            .zip(analyze(&func, 2))
+            .filter(|(inst, _)| matches!(inst, Inst::Branch { .. }))
+            .map(|(_, narrow)| narrow.width)
+            .collect();
+        assert_eq!(widths, [1, FULL]);
+    }
+}
@@ -0,0 +1,522 @@
+use crate::{
+    asm::{Alu, BitOp, Inst, Operand, Plain, Reg16, Reg8, Shift},
+    regalloc,
+};
+
+/// Registers and other state an instruction reads or writes, as a bit set.
+type Effects = u16;
+
+const A: Effects = 1 << 0;
+const F: Effects = 1 << 1;
+const B: Effects = 1 << 2;
+const C: Effects = 1 << 3;
+const D: Effects = 1 << 4;
+const E: Effects = 1 << 5;
+const H: Effects = 1 << 6;
+const L: Effects = 1 << 7;
+const IX: Effects = 1 << 8;
+const IY: Effects = 1 << 9;
+const SP: Effects = 1 << 10;
+const MEM: Effects = 1 << 11;
+/// State shared by both register banks, which `EXX` doesn't switch.
+const SHARED: Effects = A | F | IX | IY | SP | MEM;
+
+fn reg8(reg: Reg8) -> Effects {
+    match reg {
+        Reg8::A => A,
+        Reg8::B => B,
+        Reg8::C => C,
+        Reg8::D => D,
+        Reg8::E => E,
+        Reg8::H => H,
+        Reg8::L => L,
+    }
+}
+
+fn reg16(reg: Reg16) -> Effects {
+    match reg {
+        Reg16::BC => B | C,
+        Reg16::DE => D | E,
+        Reg16::HL => H | L,
+        Reg16::SP => SP,
+        Reg16::AF => A | F,
+        Reg16::IX => IX,
+        Reg16::IY => IY,
+    }
+}
+
+/// Returns what reading `operand` reads.
+fn reads_operand(operand: &Operand) -> Effects {
+    match operand {
+        Operand::Reg8(reg) => reg8(*reg),
+        Operand::Reg16(reg) => reg16(*reg),
+        Operand::Imm(_) => 0,
+        Operand::Mem(_) => MEM,
+        Operand::Ind(reg) | Operand::Idx(reg, _) => reg16(*reg) | MEM,
+        Operand::HlInc | Operand::HlDec => H | L | MEM,
+        Operand::SpOffset(_) => SP,
+        Operand::Long(operand) => reads_operand(operand),
+    }
+}
+
+/// Returns what writing `operand` reads, for the address, and writes.
+fn writes_operand(operand: &Operand) -> (Effects, Effects) {
+    match operand {
+        Operand::Reg8(reg) => (0, reg8(*reg)),
+        Operand::Reg16(reg) => (0, reg16(*reg)),
+        Operand::Imm(_) | Operand::SpOffset(_) => unreachable!(),
+        Operand::Mem(_) => (0, MEM),
+        Operand::Ind(reg) | Operand::Idx(reg, _) => (reg16(*reg), MEM),
+        Operand::HlInc | Operand::HlDec => (H | L, H | L | MEM),
+        Operand::Long(operand) => writes_operand(operand),
+    }
+}
+
+/// Returns what `inst` reads and writes, or `None` if it transfers control or
+/// isn't code.
+fn effects(inst: &Inst) -> Option<(Effects, Effects)> {
+    const MAIN: Effects = B | C | D | E | H | L;
+    Some(match inst {
+        Inst::Ld(dst, src) => {
+            let (address, writes) = writes_operand(dst);
+            (reads_operand(src) | address, writes)
+        }
+        Inst::Push(reg) => (reg16(*reg) | SP, SP | MEM),
+        Inst::Pop(reg) => (SP | MEM, reg16(*reg) | SP),
+        Inst::Ex(a, b) => {
+            let both = reads_operand(a) | reads_operand(b);
+            (both, both & !SP)
+        }
+        Inst::Alu16(alu, dst, src) => {
+            let carry = if *alu == Alu::Add { 0 } else { F };
+            (reg16(*dst) | reg16(*src) | carry, reg16(*dst) | F)
+        }
+        Inst::Alu(alu, src) => {
+            let carry = if matches!(alu, Alu::Adc | Alu::Sbc) {
+                F
+            } else {
+                0
+            };
+            let result = if *alu == Alu::Cp { 0 } else { A };
+            (A | reads_operand(src) | carry, result | F)
+        }
+        Inst::Inc(operand) | Inst::Dec(operand) => {
+            let flags = match operand {
+                Operand::Reg16(_) => 0,
+                _ => F,
+            };
+            let (address, writes) = writes_operand(operand);
+            (reads_operand(operand) | address, writes | flags)
+        }
+        Inst::Shift(shift, operand) => {
+            let carry = if matches!(shift, Shift::Rl | Shift::Rr) {
+                F
+            } else {
+                0
+            };
+            let (address, writes) = writes_operand(operand);
+            (reads_operand(operand) | address | carry, writes | F)
+        }
+        Inst::Bit(BitOp::Bit, _, operand) => (reads_operand(operand), F),
+        Inst::Bit(_, _, operand) => {
+            let (address, writes) = writes_operand(operand);
+            (reads_operand(operand) | address, writes)
+        }
+        Inst::Plain(plain) => match plain {
+            Plain::Nop => (0, 0),
+            Plain::Exx => (MAIN, MAIN),
+            Plain::Rla | Plain::Rra => (A | F, A | F),
+            Plain::Rlca | Plain::Rrca | Plain::Cpl | Plain::Neg => (A, A | F),
+            Plain::Scf => (0, F),
+            Plain::Ccf => (F, F),
+            Plain::Ldi | Plain::Ldd | Plain::Ldir | Plain::Lddr => (MAIN | MEM, MAIN | MEM | F),
+            Plain::Halt | Plain::Di | Plain::Ei => return None,
+        },
+        Inst::Mlt(reg) => (reg16(*reg), reg16(*reg)),
+        Inst::Lea(dst, src, _) => (reg16(*src), reg16(*dst)),
+        Inst::Muluw(reg) => (H | L | reg16(*reg), D | E | H | L | F),
+        _ => return None,
+    })
+}
+
+/// Whether `inst` sets every flag without reading any.
+fn sets_all_flags(inst: &Inst) -> bool {
+    matches!(
+        inst,
+        Inst::Alu(
+            Alu::Add | Alu::Sub | Alu::And | Alu::Or | Alu::Xor | Alu::Cp,
+            _
+        )
+    )
+}
+
+fn is_and_a(inst: &Inst) -> bool {
+    *inst == Inst::Alu(Alu::And, Reg8::A.into())
+}
+
+fn is_conditional_jump(inst: &Inst) -> bool {
+    matches!(inst, Inst::Jp(Some(_), _) | Inst::Jr(Some(_), _))
+}
+
+/// What a pattern does with each instruction it matched.
+enum Action {
+    Keep,
+    Delete,
+    Replace(Vec<Inst>),
+}
+
+use Action::*;
+
+/// A pattern takes the instructions from some point on, comments left out,
+/// and returns what to do with the ones it matched.
+type Pattern = fn(&[&Inst]) -> Option<Vec<Action>>;
+
+const PATTERNS: [Pattern; 10] = [
+    push_pop,
+    cancelling_pair,
+    copy_back,
+    reload,
+    dead_load,
+    and_a_after_clear,
+    and_a_before_overwrite,
+    jump_to_next,
+    unreachable_code,
+    exx_swap,
+];
+
+/// How far patterns scanning for a later instruction look ahead.
+const WINDOW: usize = 16;
+
+/// Whether reading `operand` has no side effects and gives the same value
+/// until something writes what it reads.
+///
+/// Absolute and register-indirect memory can be the console, and `IX`
+/// addresses linear memory, which can be too, so only frame slots qualify.
+fn is_pure(operand: &Operand) -> bool {
+    match operand {
+        Operand::Reg8(_) | Operand::Reg16(_) | Operand::Imm(_) | Operand::SpOffset(_) => true,
+        Operand::Idx(reg, _) => *reg == Reg16::IY,
+        Operand::Mem(_) | Operand::Ind(_) | Operand::HlInc | Operand::HlDec | Operand::Long(_) => {
+            false
+        }
+    }
+}
+
+fn is_register(operand: &Operand) -> bool {
+    matches!(operand, Operand::Reg8(_) | Operand::Reg16(_)) && *operand != Reg16::SP.into()
+}
+
+/// `PUSH rr; POP rr` does nothing, and `PUSH rr; POP ss` is a move between
+/// pairs.
+fn push_pop(code: &[&Inst]) -> Option<Vec<Action>> {
+    let pairs = [Reg16::BC, Reg16::DE, Reg16::HL];
+    match code {
+        [Inst::Push(a), Inst::Pop(b), ..] if a == b => Some(vec![Delete, Delete]),
+        [Inst::Push(a), Inst::Pop(b), ..] if pairs.contains(a) && pairs.contains(b) => Some(vec![
+            Replace(vec![
+                Inst::Ld(regalloc::high(*b).into(), regalloc::high(*a).into()),
+                Inst::Ld(regalloc::low(*b).into(), regalloc::low(*a).into()),
+            ]),
+            Delete,
+        ]),
+        _ => None,
+    }
+}
+
+/// `EXX; EXX` and the same `EX` twice do nothing.
+fn cancelling_pair(code: &[&Inst]) -> Option<Vec<Action>> {
+    match code {
+        [Inst::Plain(Plain::Exx), Inst::Plain(Plain::Exx), ..] => Some(vec![Delete, Delete]),
+        [Inst::Ex(a, b), Inst::Ex(c, d), ..] if a == c && b == d => Some(vec![Delete, Delete]),
+        _ => None,
+    }
+}
+
+/// `LD x,y; LD y,x` copies back what's already there.
+fn copy_back(code: &[&Inst]) -> Option<Vec<Action>> {
+    match code {
+        [Inst::Ld(a, b), Inst::Ld(c, d), ..] if a == d && b == c && is_pure(a) && is_pure(b) => {
+            Some(vec![Keep, Delete])
+        }
+        _ => None,
+    }
+}
+
+/// A load into a register that already holds the value is redundant.
+fn reload(code: &[&Inst]) -> Option<Vec<Action>> {
+    let [Inst::Ld(dst, src), rest @ ..] = code else {
+        return None;
+    };
+    if !is_register(dst) || !is_pure(src) {
+        return None;
+    }
+    let target = writes_operand(dst).1;
+    let source = reads_operand(src);
+    if target & source != 0 {
+        return None;
+    }
+    for (i, inst) in rest.iter().take(WINDOW).enumerate() {
+        if let Inst::Ld(d, s) = inst {
+            if d == dst && s == src {
+                let mut actions: Vec<Action> = (0..=i).map(|_| Keep).collect();
+                actions.push(Delete);
+                return Some(actions);
+            }
+        }
+        let (_, writes) = effects(inst)?;
+        if writes & (target | source) != 0 {
+            return None;
+        }
+    }
+    None
+}
+
+/// A load into a register that's overwritten before being read is dead.
+fn dead_load(code: &[&Inst]) -> Option<Vec<Action>> {
+    let [Inst::Ld(dst, src), rest @ ..] = code else {
+        return None;
+    };
+    if !is_register(dst) || !is_pure(src) {
+        return None;
+    }
+    let target = writes_operand(dst).1;
+    for (i, inst) in rest.iter().take(WINDOW).enumerate() {
+        let (reads, writes) = effects(inst)?;
+        if reads & target != 0 {
+            return None;
+        }
+        if writes & target == target {
+            let mut actions = vec![Delete];
+            actions.extend((0..=i).map(|_| Keep));
+            return Some(actions);
+        }
+    }
+    None
+}
+
+/// `AND A` only sets the flags from `A`, clearing carry, which `AND`, `OR`
+/// and `XOR` have already done if neither changed since.
+fn and_a_after_clear(code: &[&Inst]) -> Option<Vec<Action>> {
+    let [Inst::Alu(Alu::And | Alu::Or | Alu::Xor, _), rest @ ..] = code else {
+        return None;
+    };
+    for (i, inst) in rest.iter().take(WINDOW).enumerate() {
+        if is_and_a(inst) {
+            let mut actions: Vec<Action> = (0..=i).map(|_| Keep).collect();
+            actions.push(Delete);
+            return Some(actions);
+        }
+        if is_conditional_jump(inst) {
+            continue;
+        }
+        let (_, writes) = effects(inst)?;
+        if writes & (A | F) != 0 {
+            return None;
+        }
+    }
+    None
+}
+
+/// `AND A` is useless if the flags it sets are overwritten before being
+/// read.
+fn and_a_before_overwrite(code: &[&Inst]) -> Option<Vec<Action>> {
+    let [and_a, rest @ ..] = code else {
+        return None;
+    };
+    if !is_and_a(and_a) {
+        return None;
+    }
+    for (i, inst) in rest.iter().take(WINDOW).enumerate() {
+        let (reads, _) = effects(inst)?;
+        if reads & F != 0 {
+            return None;
+        }
+        if sets_all_flags(inst) {
+            let mut actions = vec![Delete];
+            actions.extend((0..=i).map(|_| Keep));
+            return Some(actions);
+        }
+    }
+    None
+}
+
+/// A jump to the label right after it does nothing.
+fn jump_to_next(code: &[&Inst]) -> Option<Vec<Action>> {
+    let [Inst::Jp(_, target) | Inst::Jr(_, target), rest @ ..] = code else {
+        return None;
+    };
+    for (i, inst) in rest.iter().enumerate() {
+        match inst {
+            Inst::Label(label) if target.symbol.as_ref() == Some(label) && target.offset == 0 => {
+                let mut actions = vec![Delete];
+                actions.extend((0..=i).map(|_| Keep));
+                return Some(actions);
+            }
+            Inst::Label(_) => {}
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// Code after an unconditional jump or return is unreachable until the next
+/// label.
+fn unreachable_code(code: &[&Inst]) -> Option<Vec<Action>> {
+    let [Inst::Jp(None, _) | Inst::Jr(None, _) | Inst::JpInd(_) | Inst::Ret(None), rest @ ..] =
+        code
+    else {
+        return None;
+    };
+    let dead = rest
+        .iter()
+        .take_while(|inst| {
+            !matches!(
+                inst,
+                Inst::Label(_) | Inst::Equ(..) | Inst::Db(_) | Inst::Dw(_)
+            )
+        })
+        .count();
+    if dead == 0 {
+        return None;
+    }
+    let mut actions = vec![Keep];
+    actions.extend((0..dead).map(|_| Delete));
+    Some(actions)
+}
+
+/// `EXX; a; EXX; b; EXX` is `b; EXX; a` if `a` and `b` don't touch the same
+/// state shared by both banks.
+fn exx_swap(code: &[&Inst]) -> Option<Vec<Action>> {
+    let exx = Inst::Plain(Plain::Exx);
+    let [x, a, y, b, z, ..] = code else {
+        return None;
+    };
+    if **x != exx || **y != exx || **z != exx || **a == exx || **b == exx {
+        return None;
+    }
+    let (a_reads, a_writes) = effects(a)?;
+    let (b_reads, b_writes) = effects(b)?;
+    if (a_writes & (b_reads | b_writes) | b_writes & a_reads) & SHARED != 0 {
+        return None;
+    }
+    Some(vec![
+        Replace(vec![(*b).clone(), exx.clone()]),
+        Keep,
+        Delete,
+        Delete,
+        Delete,
+    ])
+}
+
+/// Applies the patterns over `code` until none matches.
+pub fn optimize(code: &mut Vec<Inst>) {
+    loop {
+        let positions: Vec<usize> = (0..code.len())
+            .filter(|&i| !matches!(code[i], Inst::Comment(_)))
+            .collect();
+        let insts: Vec<&Inst> = positions.iter().map(|&i| &code[i]).collect();
+        let mut edits = vec![];
+        let mut i = 0;
+        while i < insts.len() {
+            match PATTERNS.iter().find_map(|pattern| pattern(&insts[i..])) {
+                Some(actions) => {
+                    for (j, action) in actions.into_iter().enumerate() {
+                        edits.push((positions[i + j], action));
+                    }
+                    i = positions.partition_point(|&p| p <= edits.last().unwrap().0);
+                }
+                None => i += 1,
+            }
+        }
+        if edits.is_empty() {
+            return;
+        }
+        let mut edits = edits.into_iter().peekable();
+        let old = std::mem::take(code);
+        for (i, inst) in old.into_iter().enumerate() {
+            match edits.next_if(|(position, _)| *position == i) {
+                Some((_, Keep)) | None => code.push(inst),
+                Some((_, Delete)) => {}
+                Some((_, Replace(insts))) => code.extend(insts),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::compile::{Config, OptLevel, Passes};
+    use crate::loader;
+
+    fn optimized(text: &str) -> Vec<Inst> {
+        let mut code = asm::parse(text);
+        optimize(&mut code);
+        code
+    }
+
+    fn assert_optimizes(text: &str, expected: &str) {
+        assert_eq!(optimized(text), asm::parse(expected), "{text}");
+    }
+
+    fn assert_keeps(text: &str) {
+        assert_optimizes(text, text);
+    }
+
+    #[test]
+    fn push_pop_pairs_become_moves() {
+        assert_optimizes("  PUSH DE\n  POP DE\n", "");
+        assert_optimizes("  PUSH DE\n  POP HL\n", "  LD H,D\n  LD L,E\n");
+        assert_keeps("  PUSH IX\n  POP HL\n");
+        assert_keeps("  PUSH DE\n  INC SP\n  POP DE\n");
+    }
+
+    #[test]
+    fn dead_and_repeated_loads_go() {
+        assert_optimizes("  LD A,5\n  LD A,6\n", "  LD A,6\n");
+        assert_keeps("  LD A,5\n  LD (HL),A\n  LD A,6\n");
+        assert_optimizes(
+            "  LD A,(IY+4)\n  LD B,A\n  LD A,(IY+4)\n  LD C,A\n",
+            "  LD A,(IY+4)\n  LD B,A\n  LD C,A\n",
+        );
+        // Absolute memory can be the console, which reads differently each
+        // time.
+        assert_keeps("  LD A,(0xFFFE)\n  LD B,A\n  LD A,(0xFFFE)\n  LD C,A\n");
+    }
+
+    #[test]
+    fn flag_clears_go_where_the_flags_are_set_again() {
+        assert_optimizes("  AND A\n  LD HL,1\n  CP 3\n", "  LD HL,1\n  CP 3\n");
+        assert_optimizes(
+            "  OR L\n  AND A\n  JR Z,x\n  INC A\nx:\n",
+            "  OR L\n  JR Z,x\n  INC A\nx:\n",
+        );
+        assert_keeps("  AND A\n  SBC HL,DE\n");
+    }
+
+    #[test]
+    fn compiled_code_shrinks() {
+        let wasm = wat::parse_str(
+            r#"(module (memory 1)
+                (func (export "entry") (param i32) (result i32)
+                  (i32.store8 (local.get 0) (i32.const 65))
+                  (i32.add (local.get 0) (i32.const 1))))"#,
+        )
+        .unwrap();
+        let module = loader::load(&wasm).unwrap();
+        let stats = |peephole| {
+            let config = Config {
+                passes: Passes {
+                    peephole,
+                    ..OptLevel::O0.passes()
+                },
+                ..Config::default()
+            };
+            module.compile(&config, &mut vec![]).unwrap()
+        };
+        let (with, without) = (stats(true), stats(false));
+        assert_eq!(with.before, without.before);
+        assert!(with.after < without.after);
+    }
+}
@@ -0,0 +1,324 @@
+use crate::asm::{imm, ind, Inst, Operand, Plain, Reg16, Reg8};
+
+/// Register pairs the topmost operands are kept in, in order of preference.
+///
+/// An i32 is held with its low word in a pair and its high word in the same
+/// pair of the shadow bank, so that `EXX` switches between its halves and
+/// 32-bit arithmetic is the 16-bit instruction done once in each bank.
+const PAIRS: [Reg16; 3] = [Reg16::HL, Reg16::DE, Reg16::BC];
+
+pub fn low(pair: Reg16) -> Reg8 {
+    match pair {
+        Reg16::HL => Reg8::L,
+        Reg16::DE => Reg8::E,
+        Reg16::BC => Reg8::C,
+        _ => unreachable!(),
+    }
+}
+
+pub fn high(pair: Reg16) -> Reg8 {
+    match pair {
+        Reg16::HL => Reg8::H,
+        Reg16::DE => Reg8::D,
+        Reg16::BC => Reg8::B,
+        _ => unreachable!(),
+    }
+}
+
+/// Where a cached operand is: in a pair, or a constant not loaded into any
+/// register yet.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Cached {
+    Pair(Reg16),
+    Const(i32),
+}
+
+/// Tracks which of the topmost operands, all of them 4 bytes, are held in
+/// registers instead of on the stack.
+///
+/// Cached operands are always the top of the operand stack, so spilling the
+/// bottommost one or filling from the stack keeps the order intact.
+/// Constants are only loaded into a pair when an instruction needs them
+/// there, which lets the others use them as immediates or fold them.
+#[derive(Default)]
+pub struct Regs {
+    /// The topmost operands, bottom first.
+    cached: Vec<Cached>,
+    /// Whether `B` holds a loop count, which leaves `BC` out of the pairs.
+    counting: bool,
+}
+
+impl Regs {
+    pub fn is_empty(&self) -> bool {
+        self.cached.is_empty()
+    }
+
+    /// Returns the operand `depth` below the top, which must be cached.
+    pub fn get(&self, depth: usize) -> Cached {
+        self.cached[self.cached.len() - 1 - depth]
+    }
+
+    /// Returns the value of the operand `depth` below the top if it is a
+    /// cached constant.
+    pub fn constant(&self, depth: usize) -> Option<i32> {
+        let index = self.cached.len().checked_sub(depth + 1)?;
+        match self.cached[index] {
+            Cached::Const(value) => Some(value),
+            Cached::Pair(_) => None,
+        }
+    }
+
+    /// Sets whether `B` holds a loop count, which no operand may be cached
+    /// in `BC` for.
+    pub fn set_counting(&mut self, counting: bool) {
+        debug_assert!(!self.cached.contains(&Cached::Pair(Reg16::BC)));
+        self.counting = counting;
+    }
+
+    fn free_pair(&self) -> Option<Reg16> {
+        PAIRS
+            .into_iter()
+            .filter(|pair| !(self.counting && *pair == Reg16::BC))
+            .find(|pair| !self.cached.contains(&Cached::Pair(*pair)))
+    }
+
+    /// Returns a free pair, spilling the bottommost cached operands until
+    /// one is.
+    fn alloc(&mut self, code: &mut Vec<Inst>) -> Reg16 {
+        loop {
+            if let Some(pair) = self.free_pair() {
+                return pair;
+            }
+            self.spill_bottom(code);
+        }
+    }
+
+    /// Allocates a pair for a new operand on top, spilling the bottommost
+    /// cached operands if all pairs are in use.
+    pub fn push(&mut self, code: &mut Vec<Inst>) -> Reg16 {
+        let pair = self.alloc(code);
+        self.cached.push(Cached::Pair(pair));
+        pair
+    }
+
+    /// Pushes a constant on top without loading it anywhere.
+    pub fn push_const(&mut self, value: i32) {
+        self.cached.push(Cached::Const(value));
+    }
+
+    /// Removes the top operand from the cache, freeing its pair if it has
+    /// one.
+    pub fn pop(&mut self) -> Cached {
+        self.cached.pop().unwrap()
+    }
+
+    /// Returns the pair holding the operand `depth` below the top, loading
+    /// it first if it is a constant.
+    pub fn pair(&mut self, code: &mut Vec<Inst>, depth: usize) -> Reg16 {
+        let value = match self.get(depth) {
+            Cached::Pair(pair) => return pair,
+            Cached::Const(value) => value,
+        };
+        let pair = self.alloc(code);
+        emit_const(code, pair, value);
+        let index = self.cached.len() - 1 - depth;
+        self.cached[index] = Cached::Pair(pair);
+        pair
+    }
+
+    /// Swaps the two topmost operands, which must both be cached.
+    pub fn swap(&mut self) {
+        let len = self.cached.len();
+        self.cached.swap(len - 1, len - 2);
+    }
+
+    /// Makes sure the top `n` operands are cached, popping them from the
+    /// stack into pairs as needed.
+    pub fn fill(&mut self, code: &mut Vec<Inst>, n: usize) {
+        while self.cached.len() < n {
+            let pair = self.free_pair().unwrap();
+            emit_pop(code, pair);
+            self.cached.insert(0, Cached::Pair(pair));
+        }
+    }
+
+    fn spill_bottom(&mut self, code: &mut Vec<Inst>) {
+        match self.cached.remove(0) {
+            Cached::Pair(pair) => emit_push(code, pair),
+            // Without a free pair, HL is swapped with the word on the stack.
+            Cached::Const(value) => {
+                for word in [value as u16, (value >> 16) as u16] {
+                    let word = imm(i64::from(word));
+                    match self.free_pair() {
+                        Some(pair) => {
+                            code.push(Inst::Ld(pair.into(), word));
+                            code.push(Inst::Push(pair));
+                        }
+                        None => {
+                            code.push(Inst::Push(Reg16::HL));
+                            code.push(Inst::Ld(Reg16::HL.into(), word));
+                            code.push(Inst::Ex(ind(Reg16::SP), Reg16::HL.into()));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Spills all but the top `keep` cached operands to the stack.
+    pub fn spill(&mut self, code: &mut Vec<Inst>, keep: usize) {
+        while self.cached.len() > keep {
+            self.spill_bottom(code);
+        }
+    }
+
+    /// Spills every cached operand, leaving the whole operand stack in
+    /// memory as code outside the cache expects it.
+    pub fn flush(&mut self, code: &mut Vec<Inst>) {
+        self.spill(code, 0);
+    }
+
+    /// Moves the operand `depth` below the top into HL, and whatever HL held
+    /// into the pair it leaves.
+    pub fn move_to_hl(&mut self, code: &mut Vec<Inst>, depth: usize) {
+        let pair = self.pair(code, depth);
+        if pair == Reg16::HL {
+            return;
+        }
+        let hl_used = self.cached.contains(&Cached::Pair(Reg16::HL));
+        each_bank(code, |code, _| match (pair, hl_used) {
+            (Reg16::DE, _) => code.push(Inst::Ex(Reg16::DE.into(), Reg16::HL.into())),
+            (_, false) => {
+                code.push(Inst::Ld(Reg8::H.into(), high(pair).into()));
+                code.push(Inst::Ld(Reg8::L.into(), low(pair).into()));
+            }
+            (_, true) => {
+                code.push(Inst::Push(Reg16::HL));
+                code.push(Inst::Push(pair));
+                code.push(Inst::Pop(Reg16::HL));
+                code.push(Inst::Pop(pair));
+            }
+        });
+        for cached in &mut self.cached {
+            if *cached == Cached::Pair(Reg16::HL) {
+                *cached = Cached::Pair(pair);
+            } else if *cached == Cached::Pair(pair) {
+                *cached = Cached::Pair(Reg16::HL);
+            }
+        }
+    }
+}
+
+/// Emits code loading the i32 `value` into `pair`.
+pub fn emit_const(code: &mut Vec<Inst>, pair: Reg16, value: i32) {
+    each_bank(code, |code, bank| {
+        let word = (value >> (bank * 16)) as u16;
+        code.push(Inst::Ld(pair.into(), imm(i64::from(word))));
+    });
+}
+
+/// Emits code pushing the i32 in `pair` on the stack, low word first.
+fn emit_push(code: &mut Vec<Inst>, pair: Reg16) {
+    code.push(Inst::Push(pair));
+    code.push(Inst::Plain(Plain::Exx));
+    code.push(Inst::Push(pair));
+    code.push(Inst::Plain(Plain::Exx));
+}
+
+/// Emits code popping the i32 on top of the stack into `pair`.
+fn emit_pop(code: &mut Vec<Inst>, pair: Reg16) {
+    code.push(Inst::Plain(Plain::Exx));
+    code.push(Inst::Pop(pair));
+    code.push(Inst::Plain(Plain::Exx));
+    code.push(Inst::Pop(pair));
+}
+
+/// Emits `emit` once for the low word of a cached i32 and once for its high
+/// word, switching banks in between and back after.
+pub fn each_bank(code: &mut Vec<Inst>, mut emit: impl FnMut(&mut Vec<Inst>, usize)) {
+    emit(code, 0);
+    code.push(Inst::Plain(Plain::Exx));
+    emit(code, 1);
+    code.push(Inst::Plain(Plain::Exx));
+}
+
+/// Emits `emit` like `each_bank`, but only for the low word if the `size`
+/// low bytes of the i32 that are needed fit in it.
+pub fn each_needed_bank(
+    code: &mut Vec<Inst>,
+    size: usize,
+    mut emit: impl FnMut(&mut Vec<Inst>, usize),
+) {
+    if size <= 2 {
+        emit(code, 0);
+    } else {
+        each_bank(code, emit);
+    }
+}
+
+/// Emits code loading the `size` low bytes of the i32 in the four bytes from
+/// `addr`, least significant first, into `pair`.
+pub fn emit_load(code: &mut Vec<Inst>, pair: Reg16, size: usize, addr: impl Fn(usize) -> Operand) {
+    each_needed_bank(code, size, |code, bank| {
+        code.push(Inst::Ld(low(pair).into(), addr(bank * 2)));
+        if size > 1 {
+            code.push(Inst::Ld(high(pair).into(), addr(bank * 2 + 1)));
+        }
+    });
+}
+
+/// Emits code storing the i32 in `pair` into the four bytes from `addr`,
+/// least significant first.
+pub fn emit_store(code: &mut Vec<Inst>, pair: Reg16, addr: impl Fn(usize) -> Operand) {
+    each_bank(code, |code, bank| {
+        code.push(Inst::Ld(addr(bank * 2), low(pair).into()));
+        code.push(Inst::Ld(addr(bank * 2 + 1), high(pair).into()));
+    });
+}
+
+/// Emits code storing the i32 `value` into the four bytes from `addr`, least
+/// significant first.
+pub fn emit_store_const(code: &mut Vec<Inst>, value: i32, addr: impl Fn(usize) -> Operand) {
+    for byte in 0..4 {
+        let value = (value >> (byte * 8)) as u8;
+        code.push(Inst::Ld(addr(byte), imm(i64::from(value))));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compile::{Config, OptLevel, Passes};
+    use crate::emu;
+
+    const SUM: &str = r#"(module
+        (func $sum (param i32 i32) (result i32)
+          (i32.add (i32.add (local.get 0) (local.get 1)) (local.get 0)))
+        (func (export "entry") (result i32)
+          (call $sum (i32.const 0x12345) (i32.const 0x10001))))"#;
+
+    fn config(registers: bool) -> Config {
+        Config {
+            passes: Passes {
+                registers,
+                ..OptLevel::O1.passes()
+            },
+            ..Config::default()
+        }
+    }
+
+    /// Returns the operands `$sum` pops back off the stack.
+    fn pops(registers: bool) -> usize {
+        let code = emu::function_asm(SUM, config(registers), 0).unwrap();
+        code.iter().filter(|line| *line == "POP DE").count()
+    }
+
+    #[test]
+    fn operands_stay_in_registers() {
+        assert_eq!(pops(true), 0);
+        assert_eq!(pops(false), 4);
+        for registers in [true, false] {
+            let machine = emu::run_wat(SUM, config(registers));
+            assert_eq!(machine.top_i32(), 0x12345 * 2 + 0x10001);
+        }
+    }
+}
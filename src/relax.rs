@@ -0,0 +1,289 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::asm::{Alu, BitOp, Cond, Expr, Inst, Operand, Plain, Reg16, Reg8};
+
+/// Picks the shortest form of every jump to a label in `code`: `JR` where it
+/// can test the condition and reach the label, `JP` elsewhere, and `DJNZ` for
+/// `DEC B` followed by a jump taken unless B became zero.
+pub fn relax(code: &mut Vec<Inst>) {
+    fuse_djnz(code);
+    shorten_jumps(code);
+}
+
+/// Turns every `JP` to a label in `code` into `JR` where it can test the
+/// condition and reach the label, for processors without `DJNZ`. A `DJNZ`
+/// that can't reach its label becomes `DEC B` and `JP`.
+///
+/// Jumps start out short and are lengthened while any is out of range.
+/// Lengthening one only moves the others further apart, so this ends once
+/// none is.
+pub fn shorten_jumps(code: &mut Vec<Inst>) {
+    let labels = labels(code);
+    for inst in code.iter_mut() {
+        if let Inst::Jp(cond, target) = inst {
+            if short_cond(*cond) && labels.contains_key(target) {
+                *inst = Inst::Jr(*cond, target.clone());
+            }
+        }
+    }
+    while let Some(index) = out_of_range(code) {
+        let long = match &code[index] {
+            Inst::Jr(cond, target) => vec![Inst::Jp(*cond, target.clone())],
+            Inst::Djnz(target) => vec![
+                Inst::Dec(Reg8::B.into()),
+                Inst::Jp(Some(Cond::Nz), target.clone()),
+            ],
+            _ => unreachable!(),
+        };
+        code.splice(index..=index, long);
+    }
+}
+
+/// Conditions `JR` can test.
+fn short_cond(cond: Option<Cond>) -> bool {
+    matches!(cond, None | Some(Cond::Z | Cond::Nz | Cond::C | Cond::Nc))
+}
+
+/// Returns the index of each label in `code`, by the expression jumping to
+/// it.
+fn labels(code: &[Inst]) -> HashMap<Expr, usize> {
+    let labels = code
+        .iter()
+        .enumerate()
+        .filter_map(|(index, inst)| match inst {
+            Inst::Label(label) => Some((Expr::sym(label.clone()), index)),
+            _ => None,
+        });
+    labels.collect()
+}
+
+/// Returns the index of the first relative jump in `code` that can't reach
+/// its target.
+fn out_of_range(code: &[Inst]) -> Option<usize> {
+    let mut addresses = Vec::with_capacity(code.len());
+    let mut address = 0;
+    for inst in code {
+        addresses.push(address);
+        address += inst.size() as i64;
+    }
+    let labels = labels(code);
+    code.iter().enumerate().position(|(index, inst)| {
+        let (Inst::Jr(_, target) | Inst::Djnz(target)) = inst else {
+            return false;
+        };
+        // Jumps to symbols elsewhere are left as they are.
+        let Some(&label) = labels.get(target) else {
+            return false;
+        };
+        // The displacement counts from the end of the jump.
+        let displacement = addresses[label] - (addresses[index] + 2);
+        i8::try_from(displacement).is_err()
+    })
+}
+
+/// Turns `DEC B` followed by a jump on NZ into `DJNZ`, where the flags the
+/// decrement sets are dead both after the jump and at its target.
+fn fuse_djnz(code: &mut Vec<Inst>) {
+    let labels = labels(code);
+    let fused: Vec<usize> = (0..code.len().saturating_sub(1))
+        .filter(|&index| {
+            let (
+                Inst::Dec(dec),
+                Inst::Jp(Some(Cond::Nz), target) | Inst::Jr(Some(Cond::Nz), target),
+            ) = (&code[index], &code[index + 1])
+            else {
+                return false;
+            };
+            let Some(&label) = labels.get(target) else {
+                return false;
+            };
+            *dec == Reg8::B.into()
+                && flags_dead(code, &labels, index + 2)
+                && flags_dead(code, &labels, label)
+        })
+        .collect();
+    // Later pairs first, so the earlier ones keep their indices.
+    for index in fused.into_iter().rev() {
+        let (Inst::Jp(_, target) | Inst::Jr(_, target)) = code.remove(index + 1) else {
+            unreachable!();
+        };
+        code[index] = Inst::Djnz(target);
+    }
+}
+
+/// What an instruction does with the flags `DEC B` sets and `DJNZ` leaves
+/// as they are: all of them but the carry.
+enum FlagUse {
+    /// Reads one of them, or might.
+    Read,
+    /// Sets them all, or leaves for another function or a caller, which
+    /// never take flags.
+    Set,
+    /// Leaves them alone, or only sets some of them.
+    Pass,
+}
+
+fn flag_use(inst: &Inst) -> FlagUse {
+    let carry_only = |cond: &Cond| matches!(cond, Cond::C | Cond::Nc);
+    match inst {
+        Inst::Jp(Some(cond), _)
+        | Inst::Jr(Some(cond), _)
+        | Inst::Call(Some(cond), _)
+        | Inst::Ret(Some(cond))
+            if !carry_only(cond) =>
+        {
+            FlagUse::Read
+        }
+        Inst::Push(Reg16::AF) => FlagUse::Read,
+        Inst::Ex(Operand::Reg16(Reg16::AF), _) => FlagUse::Read,
+        Inst::Pop(Reg16::AF)
+        | Inst::Alu(..)
+        | Inst::Alu16(Alu::Adc | Alu::Sbc, ..)
+        | Inst::Shift(..)
+        | Inst::Bit(BitOp::Bit, ..)
+        | Inst::Plain(Plain::Neg | Plain::Halt)
+        | Inst::Ret(None)
+        | Inst::JpInd(_) => FlagUse::Set,
+        Inst::Inc(operand) | Inst::Dec(operand) => match operand {
+            Operand::Reg16(_) => FlagUse::Pass,
+            _ => FlagUse::Set,
+        },
+        Inst::Label(_)
+        | Inst::Comment(_)
+        | Inst::Ld(..)
+        | Inst::Push(_)
+        | Inst::Pop(_)
+        | Inst::Ex(..)
+        | Inst::Alu16(..)
+        | Inst::Bit(..)
+        | Inst::Plain(_)
+        | Inst::Ret(Some(_))
+        | Inst::Mlt(_)
+        | Inst::Lea(..) => FlagUse::Pass,
+        _ => FlagUse::Read,
+    }
+}
+
+/// Returns whether the flags `DEC B` sets besides the carry are dead at
+/// index `start` of `code`: set again on every path before anything reads
+/// them.
+fn flags_dead(code: &[Inst], labels: &HashMap<Expr, usize>, start: usize) -> bool {
+    let mut visited = HashSet::new();
+    let mut pending = vec![start];
+    while let Some(mut index) = pending.pop() {
+        while visited.insert(index) {
+            let Some(inst) = code.get(index) else {
+                return false;
+            };
+            match inst {
+                Inst::Jp(cond, target) | Inst::Jr(cond, target)
+                    if cond
+                        .as_ref()
+                        .is_none_or(|cond| matches!(cond, Cond::C | Cond::Nc)) =>
+                {
+                    let Some(&label) = labels.get(target) else {
+                        return false;
+                    };
+                    pending.push(label);
+                    if cond.is_none() {
+                        break;
+                    }
+                }
+                Inst::Djnz(target) => {
+                    let Some(&label) = labels.get(target) else {
+                        return false;
+                    };
+                    pending.push(label);
+                }
+                _ => match flag_use(inst) {
+                    FlagUse::Read => return false,
+                    FlagUse::Set => break,
+                    FlagUse::Pass => {}
+                },
+            }
+            index += 1;
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pad(size: usize) -> Inst {
+        Inst::Db(vec![0.into(); size])
+    }
+
+    fn label() -> Inst {
+        Inst::Label("x".into())
+    }
+
+    fn jump(cond: Option<Cond>) -> Inst {
+        Inst::Jp(cond, "x".into())
+    }
+
+    /// Returns the jumps and decrements in `code` once relaxed.
+    fn relaxed(mut code: Vec<Inst>) -> Vec<Inst> {
+        relax(&mut code);
+        code.retain(|inst| {
+            matches!(
+                inst,
+                Inst::Jp(..) | Inst::Jr(..) | Inst::Djnz(_) | Inst::Dec(_)
+            )
+        });
+        code
+    }
+
+    #[test]
+    fn jumps_in_reach_are_short() {
+        let short = || Inst::Jr(None, "x".into());
+        // Displacements of 127 and -128.
+        assert_eq!(relaxed(vec![jump(None), pad(127), label()]), [short()]);
+        assert_eq!(relaxed(vec![label(), pad(126), jump(None)]), [short()]);
+        // Displacements of 128 and -129.
+        assert_eq!(relaxed(vec![jump(None), pad(128), label()]), [jump(None)]);
+        assert_eq!(relaxed(vec![label(), pad(127), jump(None)]), [jump(None)]);
+    }
+
+    #[test]
+    fn other_jumps_stay_long() {
+        let cond = Some(Cond::Po);
+        assert_eq!(relaxed(vec![jump(cond), label()]), [jump(cond)]);
+        let elsewhere = Inst::Jp(None, "func_0".into());
+        assert_eq!(relaxed(vec![elsewhere.clone()]), [elsewhere]);
+    }
+
+    #[test]
+    fn decrements_fuse_into_djnz() {
+        let dec = Inst::Dec(Reg8::B.into());
+        let set = Inst::Alu(Alu::Or, Reg8::A.into());
+        let read = Inst::Jp(Some(Cond::Z), "x".into());
+        let body = |size, after: Inst| {
+            vec![
+                label(),
+                set.clone(),
+                pad(size),
+                dec.clone(),
+                jump(Some(Cond::Nz)),
+                after,
+            ]
+        };
+        let djnz = Inst::Djnz("x".into());
+        assert_eq!(relaxed(body(10, set.clone())), [djnz]);
+        // Out of reach, DJNZ is taken apart again.
+        assert_eq!(
+            relaxed(body(200, set.clone())),
+            [dec.clone(), jump(Some(Cond::Nz))]
+        );
+        // The flags are read after the loop.
+        assert_eq!(
+            relaxed(body(10, read)),
+            [
+                dec,
+                Inst::Jr(Some(Cond::Nz), "x".into()),
+                Inst::Jr(Some(Cond::Z), "x".into())
+            ]
+        );
+    }
+}
@@ -0,0 +1,993 @@
+use std::collections::BTreeSet;
+
+use crate::asm::{self, imm, mem, Expr, Inst, Reg16::*, Reg8::*};
+use crate::compile::Target;
+
+/// Address of the 8-byte scratch buffer helper routines take their operands
+/// in and leave their results in, least significant byte first, on a system
+/// leaving the top of memory to the program.
+pub const SCRATCH_ADDR: u16 = 0xFFC0;
+
+/// Bytes below the scratch buffer left to the stack on such a system, which
+/// data must stay out of.
+pub const STACK_SIZE: u16 = 0x400;
+
+/// Number of globals there is room for after the trap record on such a
+/// system, below the console at 0xFFFD.
+pub const MAX_GLOBALS: usize = 12;
+
+/// Helper routines called from compiled code.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Helper {
+    TruncSat,
+    Clz,
+    Ctz,
+    Popcnt,
+    Mul,
+    Div,
+    MemCopy,
+}
+
+impl Helper {
+    fn label(self) -> &'static str {
+        match self {
+            Helper::TruncSat => "trunc_sat",
+            Helper::Clz => "clz",
+            Helper::Ctz => "ctz",
+            Helper::Popcnt => "popcnt",
+            Helper::Mul => "mul",
+            Helper::Div => "div",
+            Helper::MemCopy => "mem_copy",
+        }
+    }
+
+    fn text(self, lookup_tables: bool, target: Target) -> &'static str {
+        match (self, lookup_tables) {
+            (Helper::Mul, _) if matches!(target, Target::Ez80 | Target::Z180) => MUL_MLT,
+            (Helper::Mul, _) if target == Target::R800 => MUL_MULUW,
+            (Helper::Mul, _) => MUL_LOOP,
+            (Helper::Div, _) => DIV,
+            (Helper::MemCopy, _) if matches!(target, Target::I8080 | Target::Sm83) => MEM_COPY_LOOP,
+            (Helper::MemCopy, _) if target == Target::Ez80 => MEM_COPY_LONG,
+            (Helper::MemCopy, _) => MEM_COPY_LDIR,
+            (Helper::TruncSat, _) => TRUNC_SAT,
+            (Helper::Clz, false) => CLZ_LOOP,
+            (Helper::Clz, true) => CLZ_TABLE,
+            (Helper::Ctz, false) => CTZ_LOOP,
+            (Helper::Ctz, true) => CTZ_TABLE,
+            (Helper::Popcnt, false) => POPCNT_LOOP,
+            (Helper::Popcnt, true) => POPCNT_TABLE,
+        }
+    }
+
+    /// Emits the table the routine looks counts up in, if it has one.
+    fn emit_table(self, code: &mut Vec<Inst>) {
+        let count: fn(u8) -> u32 = match self {
+            Helper::TruncSat | Helper::Mul | Helper::Div | Helper::MemCopy => return,
+            Helper::Clz => u8::leading_zeros,
+            Helper::Ctz => u8::trailing_zeros,
+            Helper::Popcnt => u8::count_ones,
+        };
+        code.push(Inst::Label(format!("{}_table", self.label())));
+        for row in 0..16 {
+            let entries = (0..16)
+                .map(|col| i64::from(count(row * 16 + col)).into())
+                .collect();
+            code.push(Inst::Db(entries));
+        }
+    }
+}
+
+/// Flags passed in `A` to `trunc_sat`.
+pub const TRUNC_SAT_F64: u8 = 1;
+pub const TRUNC_SAT_I64: u8 = 2;
+pub const TRUNC_SAT_SIGNED: u8 = 4;
+
+/// Flags passed in `A` to `div`.
+pub const DIV_SIGNED: u8 = 1;
+pub const DIV_REM: u8 = 2;
+
+/// Collects the helper routines used by the compiled functions.
+pub struct Runtime {
+    /// Routines called.
+    called: BTreeSet<Helper>,
+    /// Routines expanded where they are used, which only need their tables.
+    expanded: BTreeSet<Helper>,
+    /// Whether the routines look results up in 256-byte tables instead of
+    /// looping over bits.
+    lookup_tables: bool,
+    /// Whether the bit counting routines are expanded instead of called.
+    inline: bool,
+    /// Processor the routines are for.
+    target: Target,
+    /// Number of expansions so far, which number their labels.
+    expansions: usize,
+}
+
+impl Runtime {
+    pub fn new(lookup_tables: bool, inline: bool, target: Target) -> Self {
+        Self {
+            called: BTreeSet::new(),
+            expanded: BTreeSet::new(),
+            lookup_tables,
+            inline,
+            target,
+            expansions: 0,
+        }
+    }
+
+    /// Emits a call to `helper`, or its body if it is expanded. Routines
+    /// calling subroutines of their own or returning conditionally,
+    /// `trunc_sat` and `mul` with `MLT`, are always called, since only a plain
+    /// `RET` of the routine itself can be turned into a jump past its end.
+    pub fn call(&mut self, code: &mut Vec<Inst>, helper: Helper) {
+        let body = asm::parse(helper.text(self.lookup_tables, self.target));
+        let expandable = !body
+            .iter()
+            .any(|inst| matches!(inst, Inst::Call(..) | Inst::Ret(Some(_))));
+        if self.inline && expandable {
+            self.expand(code, helper, body);
+            return;
+        }
+        self.called.insert(helper);
+        code.push(Inst::Call(None, helper.label().into()));
+    }
+
+    /// Emits `body`, that of `helper`, in place of a call, its labels
+    /// numbered apart from other expansions and its returns jumping past its
+    /// end.
+    fn expand(&mut self, code: &mut Vec<Inst>, helper: Helper, body: Vec<Inst>) {
+        self.expanded.insert(helper);
+        let labels: BTreeSet<String> = body
+            .iter()
+            .filter_map(|inst| match inst {
+                Inst::Label(label) => Some(label.clone()),
+                _ => None,
+            })
+            .collect();
+        self.expansions += 1;
+        let suffix = self.expansions;
+        let rename = |target: Expr| match &target.symbol {
+            Some(symbol) if labels.contains(symbol) => Expr::sym(format!("{symbol}_{suffix}")),
+            _ => target,
+        };
+        let end = format!("{}_end_{suffix}", helper.label());
+        for inst in body {
+            code.push(match inst {
+                Inst::Label(label) => Inst::Label(format!("{label}_{suffix}")),
+                Inst::Ret(None) => Inst::Jp(None, Expr::sym(end.clone())),
+                Inst::Jp(cond, target) => Inst::Jp(cond, rename(target)),
+                Inst::Jr(cond, target) => Inst::Jr(cond, rename(target)),
+                Inst::Djnz(target) => Inst::Djnz(rename(target)),
+                inst => inst,
+            });
+        }
+        code.push(Inst::Label(end));
+    }
+
+    /// Emits the routines called so far, and the tables of those expanded.
+    pub fn emit(&self, code: &mut Vec<Inst>) {
+        for helper in &self.called {
+            code.extend(asm::parse(helper.text(self.lookup_tables, self.target)));
+            if self.lookup_tables {
+                helper.emit_table(code);
+            }
+        }
+        if self.lookup_tables {
+            for helper in self.expanded.difference(&self.called) {
+                helper.emit_table(code);
+            }
+        }
+    }
+}
+
+pub fn emit_symbols(code: &mut Vec<Inst>, scratch: &Expr) {
+    code.push(Inst::Equ("rt_buf".into(), scratch.clone()));
+}
+
+fn scratch(offset: usize) -> Expr {
+    Expr::sym("rt_buf").plus(offset as i64)
+}
+
+/// Emits code moving the `size`-byte value on top of the operand stack into
+/// the scratch buffer.
+pub fn emit_pop_to_scratch(code: &mut Vec<Inst>, size: usize) {
+    for offset in (0..size).step_by(2).rev() {
+        code.push(Inst::Pop(HL));
+        code.push(Inst::Ld(mem(scratch(offset)), HL.into()));
+    }
+}
+
+/// Emits code pushing the first `size` bytes of the scratch buffer as a value
+/// on the operand stack.
+pub fn emit_push_from_scratch(code: &mut Vec<Inst>, size: usize) {
+    for offset in (0..size).step_by(2) {
+        code.push(Inst::Ld(HL.into(), mem(scratch(offset))));
+        code.push(Inst::Push(HL));
+    }
+}
+
+/// Emits code pushing the count in `A` as a `size`-byte value on the operand
+/// stack.
+pub fn emit_push_a(code: &mut Vec<Inst>, size: usize) {
+    code.push(Inst::Ld(L.into(), A.into()));
+    code.push(Inst::Ld(H.into(), imm(0)));
+    code.push(Inst::Push(HL));
+    code.push(Inst::Ld(L.into(), H.into()));
+    for _ in 1..size / 2 {
+        code.push(Inst::Push(HL));
+    }
+}
+
+/// Bit counting routines over the `B`-byte value in the scratch buffer,
+/// returning the count in `A`. Each scans the value a byte at a time, and
+/// counts bits within a byte either with a loop or a table lookup.
+const CLZ_LOOP: &str = "\
+clz:
+  LD E,B
+  LD D,0
+  LD HL,rt_buf-1
+  ADD HL,DE
+  LD C,D
+clz_byte:
+  LD A,(HL)
+  OR A
+  JR NZ,clz_bit
+  LD A,C
+  ADD A,8
+  LD C,A
+  DEC HL
+  DJNZ clz_byte
+  RET
+clz_bit:
+  RLA
+  JR C,clz_done
+  INC C
+  JR clz_bit
+clz_done:
+  LD A,C
+  RET
+";
+
+const CLZ_TABLE: &str = "\
+clz:
+  LD E,B
+  LD D,0
+  LD HL,rt_buf-1
+  ADD HL,DE
+  LD C,D
+clz_byte:
+  LD A,(HL)
+  OR A
+  JR NZ,clz_lookup
+  LD A,C
+  ADD A,8
+  LD C,A
+  DEC HL
+  DJNZ clz_byte
+  RET
+clz_lookup:
+  LD E,A
+  LD HL,clz_table
+  ADD HL,DE
+  LD A,(HL)
+  ADD A,C
+  RET
+";
+
+const CTZ_LOOP: &str = "\
+ctz:
+  LD HL,rt_buf
+  LD C,0
+ctz_byte:
+  LD A,(HL)
+  OR A
+  JR NZ,ctz_bit
+  LD A,C
+  ADD A,8
+  LD C,A
+  INC HL
+  DJNZ ctz_byte
+  RET
+ctz_bit:
+  RRA
+  JR C,ctz_done
+  INC C
+  JR ctz_bit
+ctz_done:
+  LD A,C
+  RET
+";
+
+const CTZ_TABLE: &str = "\
+ctz:
+  LD HL,rt_buf
+  LD C,0
+ctz_byte:
+  LD A,(HL)
+  OR A
+  JR NZ,ctz_lookup
+  LD A,C
+  ADD A,8
+  LD C,A
+  INC HL
+  DJNZ ctz_byte
+  RET
+ctz_lookup:
+  LD E,A
+  LD D,0
+  LD HL,ctz_table
+  ADD HL,DE
+  LD A,(HL)
+  ADD A,C
+  RET
+";
+
+const POPCNT_LOOP: &str = "\
+popcnt:
+  LD HL,rt_buf
+  LD C,0
+popcnt_byte:
+  LD A,(HL)
+popcnt_bit:
+  OR A
+  JR Z,popcnt_next
+  SRL A
+  JR NC,popcnt_bit
+  INC C
+  JR popcnt_bit
+popcnt_next:
+  INC HL
+  DJNZ popcnt_byte
+  LD A,C
+  RET
+";
+
+const POPCNT_TABLE: &str = "\
+popcnt:
+  LD HL,rt_buf
+  LD C,0
+  LD D,C
+popcnt_byte:
+  LD E,(HL)
+  PUSH HL
+  LD HL,popcnt_table
+  ADD HL,DE
+  LD A,(HL)
+  ADD A,C
+  LD C,A
+  POP HL
+  INC HL
+  DJNZ popcnt_byte
+  LD A,C
+  RET
+";
+
+/// Multiplication of the i32 in the first four bytes of the scratch buffer by
+/// the one in the next four, leaving the product in the first four.
+///
+/// The multiplier is shifted out a bit at a time from the top, doubling the
+/// product in `HL'HL` and adding the multiplicand in `DE'DE` for each one
+/// bit.
+const MUL_LOOP: &str = "\
+mul:
+  EXX
+  LD HL,0
+  LD DE,(rt_buf+2)
+  LD BC,rt_buf+8
+  EXX
+  LD HL,0
+  LD DE,(rt_buf)
+  LD C,4
+mul_byte:
+  EXX
+  DEC BC
+  LD A,(BC)
+  EXX
+  LD B,8
+mul_bit:
+  ADD HL,HL
+  EXX
+  ADC HL,HL
+  EXX
+  RLA
+  JR NC,mul_next
+  ADD HL,DE
+  EXX
+  ADC HL,DE
+  EXX
+mul_next:
+  DJNZ mul_bit
+  DEC C
+  JR NZ,mul_byte
+  LD (rt_buf),HL
+  EXX
+  LD (rt_buf+2),HL
+  EXX
+  RET
+";
+
+/// Multiplication like `MUL_LOOP`, from 16-bit products of the low and high
+/// words with `MLT` doing each 8-bit one.
+///
+/// Only the low word of the product of the high word of one operand and the
+/// low word of the other is needed, which `mul_low` computes in three `MLT`s,
+/// returning it in `HL`. `mul_wide` computes the full product of the low
+/// words in four, returning it in `HL` and `DE`, high word first.
+const MUL_MLT: &str = "\
+mul:
+  LD BC,(rt_buf)
+  LD DE,(rt_buf+6)
+  CALL mul_low
+  PUSH HL
+  LD BC,(rt_buf+2)
+  LD DE,(rt_buf+4)
+  CALL mul_low
+  PUSH HL
+  LD BC,(rt_buf)
+  LD DE,(rt_buf+4)
+  CALL mul_wide
+  LD (rt_buf),DE
+  POP BC
+  ADD HL,BC
+  POP BC
+  ADD HL,BC
+  LD (rt_buf+2),HL
+  RET
+mul_low:
+  LD H,C
+  LD L,E
+  MLT HL
+  LD A,C
+  LD C,E
+  MLT BC
+  LD E,A
+  MLT DE
+  LD A,H
+  ADD A,C
+  ADD A,E
+  LD H,A
+  RET
+mul_wide:
+  LD H,C
+  LD L,E
+  MLT HL
+  PUSH HL
+  LD H,B
+  LD L,E
+  MLT HL
+  LD E,C
+  LD C,D
+  MLT BC
+  MLT DE
+  ADD HL,DE
+  LD E,H
+  LD D,0
+  RL D
+  EX DE,HL
+  ADD HL,BC
+  LD A,E
+  POP DE
+  ADD A,D
+  LD D,A
+  RET NC
+  INC HL
+  RET
+";
+
+/// Multiplication like `MUL_MLT`, with `MULUW` computing the full product of
+/// the low words and the two cross products of which only the low word is
+/// needed.
+const MUL_MULUW: &str = "\
+mul:
+  LD HL,(rt_buf)
+  LD BC,(rt_buf+6)
+  MULUW HL,BC
+  PUSH HL
+  LD HL,(rt_buf+2)
+  LD BC,(rt_buf+4)
+  MULUW HL,BC
+  PUSH HL
+  LD HL,(rt_buf)
+  MULUW HL,BC
+  LD (rt_buf),HL
+  POP HL
+  ADD HL,DE
+  POP DE
+  ADD HL,DE
+  LD (rt_buf+2),HL
+  RET
+";
+
+/// Division of the i32 in the first four bytes of the scratch buffer by the
+/// one in the next four, as selected by the `DIV_*` flags in `A`, leaving
+/// the quotient or the remainder in the first four. Returns 0 in `A`, or
+/// the code of the trap the division raises.
+///
+/// Signed operands are divided as magnitudes, the result being negated if
+/// the dividend's sign, and for a quotient the divisor's, says so. The
+/// dividend is shifted out a bit at a time from the top of `BC'BC` into the
+/// remainder in `HL'HL`, from which the divisor in `DE'DE` is subtracted
+/// whenever it goes, shifting a one into the quotient in its place. A
+/// remainder that carries out of the top is past any divisor.
+const DIV: &str = "\
+div:
+  LD C,A
+  LD HL,(rt_buf+4)
+  LD A,H
+  OR L
+  LD HL,(rt_buf+6)
+  OR H
+  OR L
+  LD A,TRAP_INTEGER_DIVIDE_BY_ZERO
+  RET Z
+  BIT 0,C
+  JR Z,div_start
+  LD A,(rt_buf+3)
+  RLA
+  JR NC,div_divisor
+  LD HL,rt_buf
+  CALL div_neg
+  SET 2,C
+div_divisor:
+  LD A,(rt_buf+7)
+  RLA
+  JR NC,div_start
+  LD HL,rt_buf+4
+  CALL div_neg
+  BIT 1,C
+  JR NZ,div_start
+  LD A,C
+  XOR 4
+  LD C,A
+div_start:
+  LD A,C
+  PUSH AF
+  LD BC,(rt_buf)
+  LD DE,(rt_buf+4)
+  LD HL,0
+  EXX
+  LD BC,(rt_buf+2)
+  LD DE,(rt_buf+6)
+  LD HL,0
+  EXX
+  LD A,32
+div_step:
+  SLA C
+  RL B
+  EXX
+  RL C
+  RL B
+  EXX
+  ADC HL,HL
+  EXX
+  ADC HL,HL
+  EXX
+  JR C,div_over
+  SBC HL,DE
+  EXX
+  SBC HL,DE
+  EXX
+  JR NC,div_one
+  ADD HL,DE
+  EXX
+  ADC HL,DE
+  EXX
+  JR div_next
+div_over:
+  OR A
+  SBC HL,DE
+  EXX
+  SBC HL,DE
+  EXX
+div_one:
+  INC C
+div_next:
+  DEC A
+  JR NZ,div_step
+  POP AF
+  BIT 1,A
+  JR NZ,div_rem
+  LD (rt_buf),BC
+  EXX
+  LD (rt_buf+2),BC
+  EXX
+  JR div_sign
+div_rem:
+  LD (rt_buf),HL
+  EXX
+  LD (rt_buf+2),HL
+  EXX
+div_sign:
+  LD C,A
+  BIT 2,C
+  JR Z,div_check
+  LD HL,rt_buf
+  CALL div_neg
+  XOR A
+  RET
+div_check:
+  AND 3
+  CP 1
+  JR NZ,div_ok
+  LD A,(rt_buf+3)
+  RLA
+  LD A,TRAP_INTEGER_OVERFLOW
+  RET C
+div_ok:
+  XOR A
+  RET
+div_neg:
+  LD B,4
+  XOR A
+div_neg_byte:
+  LD A,0
+  SBC A,(HL)
+  LD (HL),A
+  INC HL
+  DJNZ div_neg_byte
+  RET
+";
+
+/// Copies `BC` bytes, at least one, from `HL` to `DE`, as data segments are
+/// copied into linear memory.
+const MEM_COPY_LDIR: &str = "\
+mem_copy:
+  LDIR
+  RET
+";
+
+/// The same for processors without `LDIR`, a byte at a time.
+const MEM_COPY_LOOP: &str = "\
+mem_copy:
+  LD A,(HL)
+  LD (DE),A
+  INC HL
+  INC DE
+  DEC BC
+  LD A,B
+  OR C
+  JR NZ,mem_copy
+  RET
+";
+
+/// The same for the eZ80, to the 24-bit address in the scratch buffer
+/// instead of `DE`, as linear memory can be outside the program's segment.
+/// Only the low 16 bits of `IX` count up, so the bytes must not cross a
+/// 64 KiB boundary.
+const MEM_COPY_LONG: &str = "\
+mem_copy:
+  LD.LIS IX,(rt_buf)
+mem_copy_byte:
+  LD A,(HL)
+  LD.LIS (IX+0),A
+  INC HL
+  INC IX
+  DEC BC
+  LD A,B
+  OR C
+  JR NZ,mem_copy_byte
+  RET
+";
+
+/// Saturating float to integer conversion of the f32 or f64 in the scratch
+/// buffer, as selected by the `TRUNC_SAT_*` flags in `A`.
+///
+/// The float is unpacked into its exponent and a 64-bit mantissa, which is
+/// then shifted into place. NaN converts to zero, and out-of-range values to
+/// the minimum or maximum of the result type.
+const TRUNC_SAT: &str = "\
+trunc_sat:
+  LD C,A
+  BIT 0,C
+  JR NZ,trunc_sat_f64
+  LD HL,rt_buf+3
+  LD A,(HL)
+  AND 0x80
+  OR C
+  LD C,A
+  DEC HL
+  LD A,(HL)
+  RLA
+  INC HL
+  LD A,(HL)
+  RLA
+  LD E,A
+  LD D,0
+  XOR A
+  LD (HL),A
+  LD HL,0
+  LD (rt_buf+4),HL
+  LD (rt_buf+6),HL
+  LD HL,rt_buf+2
+  LD A,(HL)
+  AND 0x7F
+  LD (HL),A
+  DEC HL
+  OR (HL)
+  DEC HL
+  OR (HL)
+  LD B,A
+  LD HL,rt_buf+2
+  SET 7,(HL)
+  LD A,E
+  CP 0xFF
+  JR Z,trunc_sat_special
+  LD HL,-127
+  ADD HL,DE
+  LD A,23
+  JR trunc_sat_common
+trunc_sat_f64:
+  LD HL,rt_buf+7
+  LD A,(HL)
+  AND 0x80
+  OR C
+  LD C,A
+  LD A,(HL)
+  AND 0x7F
+  LD D,A
+  LD (HL),0
+  DEC HL
+  LD E,(HL)
+  LD A,(HL)
+  AND 0x0F
+  LD (HL),A
+  SRL D
+  RR E
+  SRL D
+  RR E
+  SRL D
+  RR E
+  SRL D
+  RR E
+  LD HL,rt_buf
+  LD B,6
+trunc_sat_f64_nan:
+  OR (HL)
+  INC HL
+  DJNZ trunc_sat_f64_nan
+  LD B,A
+  SET 4,(HL)
+  LD A,D
+  CP 7
+  JR NZ,trunc_sat_f64_finite
+  LD A,E
+  CP 0xFF
+  JR Z,trunc_sat_special
+trunc_sat_f64_finite:
+  LD HL,-1023
+  ADD HL,DE
+  LD A,52
+trunc_sat_common:
+  BIT 7,H
+  JR NZ,trunc_sat_zero
+  LD E,A
+  LD A,32
+  BIT 1,C
+  JR Z,trunc_sat_width
+  LD A,64
+trunc_sat_width:
+  BIT 2,C
+  JR Z,trunc_sat_limit
+  DEC A
+trunc_sat_limit:
+  LD D,A
+  LD A,H
+  OR A
+  JR NZ,trunc_sat_saturate
+  LD A,L
+  CP D
+  JR NC,trunc_sat_saturate
+  BIT 2,C
+  JR NZ,trunc_sat_shift
+  BIT 7,C
+  JR NZ,trunc_sat_zero
+trunc_sat_shift:
+  LD A,L
+  SUB E
+  JR Z,trunc_sat_sign
+  JR C,trunc_sat_shift_right
+  LD B,A
+trunc_sat_shift_left:
+  PUSH BC
+  LD HL,rt_buf
+  AND A
+  LD B,8
+trunc_sat_shift_left_byte:
+  RL (HL)
+  INC HL
+  DJNZ trunc_sat_shift_left_byte
+  POP BC
+  DJNZ trunc_sat_shift_left
+  JR trunc_sat_sign
+trunc_sat_shift_right:
+  NEG
+  LD B,A
+trunc_sat_shift_right_bit:
+  PUSH BC
+  LD HL,rt_buf+7
+  AND A
+  LD B,8
+trunc_sat_shift_right_byte:
+  RR (HL)
+  DEC HL
+  DJNZ trunc_sat_shift_right_byte
+  POP BC
+  DJNZ trunc_sat_shift_right_bit
+trunc_sat_sign:
+  BIT 7,C
+  RET Z
+  LD HL,rt_buf
+  LD B,8
+  AND A
+trunc_sat_negate:
+  LD A,0
+  SBC A,(HL)
+  LD (HL),A
+  INC HL
+  DJNZ trunc_sat_negate
+  RET
+trunc_sat_special:
+  LD A,B
+  OR A
+  JR NZ,trunc_sat_zero
+trunc_sat_saturate:
+  LD A,C
+  RLA
+  SBC A,A
+  CPL
+  CALL trunc_sat_fill
+  BIT 2,C
+  RET Z
+  LD HL,rt_buf+3
+  BIT 1,C
+  JR Z,trunc_sat_flip
+  LD HL,rt_buf+7
+trunc_sat_flip:
+  LD A,(HL)
+  XOR 0x80
+  LD (HL),A
+  RET
+trunc_sat_zero:
+  XOR A
+trunc_sat_fill:
+  LD HL,rt_buf
+  LD B,8
+trunc_sat_fill_byte:
+  LD (HL),A
+  INC HL
+  DJNZ trunc_sat_fill_byte
+  RET
+";
+
+#[cfg(test)]
+mod tests {
+    use crate::compile::{Config, OptLevel};
+    use crate::emu::{self, Machine};
+    use crate::trap::{Trap, TRAP_CODE_OFFSET};
+
+    /// Runs `op` on `lhs` and `rhs` passed to a function, so that it isn't
+    /// folded.
+    fn divide(op: &str, lhs: i32, rhs: i32) -> Machine {
+        emu::run_wat(
+            &format!(
+                r#"(module
+                    (func $op (param i32 i32) (result i32)
+                      (i32.{op} (local.get 0) (local.get 1)))
+                    (func (export "entry") (result i32)
+                      (call $op (i32.const {lhs}) (i32.const {rhs}))))"#
+            ),
+            Config::default(),
+        )
+    }
+
+    fn trap_code(machine: &Machine) -> u8 {
+        machine.byte(super::SCRATCH_ADDR + TRAP_CODE_OFFSET as u16)
+    }
+
+    #[test]
+    fn division_computes_quotients_and_remainders() {
+        for (op, lhs, rhs, result) in [
+            ("div_s", 100_000, 7, 14_285),
+            ("div_s", -100_000, 7, -14_285),
+            ("div_s", 7, -2, -3),
+            ("div_u", -1, 16, 0x0FFF_FFFF),
+            ("rem_s", -7, 2, -1),
+            ("rem_s", 7, -2, 1),
+            ("rem_s", i32::MIN, -1, 0),
+            ("rem_u", -1, 10, 5),
+        ] {
+            let machine = divide(op, lhs, rhs);
+            assert_eq!(trap_code(&machine), 0, "{op} {lhs} {rhs}");
+            assert_eq!(machine.top_i32(), result, "{op} {lhs} {rhs}");
+        }
+    }
+
+    #[test]
+    fn division_by_zero_traps() {
+        for op in ["div_s", "div_u", "rem_s", "rem_u"] {
+            let machine = divide(op, 5, 0);
+            assert_eq!(
+                trap_code(&machine),
+                Trap::IntegerDivideByZero.code(),
+                "{op}"
+            );
+        }
+    }
+
+    #[test]
+    fn signed_overflow_traps() {
+        let machine = divide("div_s", i32::MIN, -1);
+        assert_eq!(trap_code(&machine), Trap::IntegerOverflow.code());
+    }
+
+    /// Runs `op` of type `ty` on `value` passed to a function, with and
+    /// without lookup tables and at -O3.
+    fn count(ty: &str, op: &str, value: i64) -> Vec<Machine> {
+        let wat = format!(
+            r#"(module
+                (func $op (param {ty}) (result {ty})
+                  ({ty}.{op} (local.get 0)))
+                (func (export "entry") (result {ty})
+                  (call $op ({ty}.const {value}))))"#
+        );
+        let configs = [
+            Config::default(),
+            Config {
+                lookup_tables: true,
+                ..Config::default()
+            },
+            Config {
+                passes: OptLevel::O3.passes(),
+                ..Config::default()
+            },
+            Config {
+                lookup_tables: true,
+                passes: OptLevel::O3.passes(),
+                ..Config::default()
+            },
+        ];
+        configs
+            .into_iter()
+            .map(|config| emu::run_wat(&wat, config))
+            .collect()
+    }
+
+    #[test]
+    fn bits_are_counted() {
+        for (op, value, result) in [
+            ("clz", 0x00F0_0000, 8),
+            ("clz", 1, 31),
+            ("clz", -1, 0),
+            ("clz", 0, 32),
+            ("ctz", 0x100, 8),
+            ("ctz", 0x8000_0000, 31),
+            ("ctz", 0, 32),
+            ("popcnt", 0xF0F0_0001, 9),
+            ("popcnt", -1, 32),
+            ("popcnt", 0, 0),
+        ] {
+            for machine in count("i32", op, value) {
+                assert_eq!(trap_code(&machine), 0, "i32.{op} {value}");
+                assert_eq!(machine.top_i32(), result, "i32.{op} {value}");
+            }
+        }
+        for (op, value, result) in [
+            ("clz", 1, 63),
+            ("clz", 0x1_0000_0000, 31),
+            ("clz", -1, 0),
+            ("clz", 0, 64),
+            ("ctz", 0x1_0000_0000, 32),
+            ("ctz", i64::MIN, 63),
+            ("ctz", 0, 64),
+            ("popcnt", 0x0F00_0000_0000_00F1, 9),
+            ("popcnt", -1, 64),
+            ("popcnt", 0, 0),
+        ] {
+            for machine in count("i64", op, value) {
+                assert_eq!(trap_code(&machine), 0, "i64.{op} {value}");
+                assert_eq!(machine.top_i64(), result, "i64.{op} {value}");
+            }
+        }
+    }
+}
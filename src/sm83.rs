@@ -0,0 +1,611 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+
+use crate::asm::{
+    self, imm, ind, mem, Alu, BitOp, Cond, Expr, Inst, Operand, Plain, Reg16, Reg16::*, Reg8,
+    Reg8::*,
+};
+use crate::compile::Target;
+use crate::encode;
+use crate::i8080::{self, index_name, index_var};
+use crate::regalloc::{high, low};
+use crate::runtime;
+
+/// Address of the bytes holding what the SM83 lacks in registers, laid out
+/// as for the 8080, in high RAM next to the scratch buffer.
+pub const STATE_ADDR: u16 = runtime::SCRATCH_ADDR - 16;
+
+/// Top of the stack, at the end of work RAM, as the high RAM below the state
+/// is too small for it and ends in the I/O registers.
+pub const STACK_TOP: u16 = 0xE000;
+
+pub fn emit_symbols(code: &mut Vec<Inst>) {
+    let symbols = [
+        ("rt_ix", 0),
+        ("rt_iy", 2),
+        ("rt_shadow_bc", 4),
+        ("rt_shadow_de", 6),
+        ("rt_shadow_hl", 8),
+        ("rt_a", 10),
+        ("rt_hl", 12),
+        ("rt_overflow", 14),
+    ];
+    for (name, offset) in symbols {
+        let addr = i64::from(STATE_ADDR) + offset;
+        code.push(Inst::Equ(name.into(), addr.into()));
+    }
+}
+
+/// Routines the lowered code calls in place of Z80 instructions.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Routine {
+    Exx,
+    ExSp,
+    Carry16,
+    Lddr,
+    Halt,
+    Index(Reg16),
+}
+
+impl Routine {
+    fn text(self) -> String {
+        match self {
+            Routine::Exx => EXX.to_string(),
+            Routine::ExSp => EX_SP.to_string(),
+            Routine::Carry16 => CARRY16.to_string(),
+            Routine::Lddr => i8080::LDDR.to_string(),
+            Routine::Halt => HALT.to_string(),
+            Routine::Index(reg) => INDEX.replace("{reg}", &index_name(reg)),
+        }
+    }
+}
+
+/// Rewrites `code` into instructions the SM83 has, appending the routines
+/// the rewritten code calls, then accesses the high page with `LDH`.
+///
+/// Like for the 8080, Z80 instructions are replaced one at a time by
+/// sequences leaving the other registers and, unless noted, the flags as the
+/// instruction would, with the index registers and the shadow bank in
+/// memory. The SM83 lacks the 8080's `EX` and `HL` loads and stores too, but
+/// keeps the Z80's relative jumps, shifts and bit instructions. Code testing
+/// the sign or parity flag, which the SM83 lacks, other than for the
+/// overflow of `ADC HL` and `SBC HL`, is rejected, as are the block
+/// instructions other than `LDDR`.
+pub fn lower(code: &mut Vec<Inst>) -> Result<()> {
+    let mut routines = vec![];
+    let mut lowered = Vec::with_capacity(code.len());
+    for inst in code.drain(..) {
+        lower_inst(&mut lowered, &mut routines, inst)?;
+    }
+    for routine in routines {
+        lowered.extend(asm::parse(&routine.text()));
+    }
+    use_ldh(&mut lowered);
+    *code = lowered;
+    Ok(())
+}
+
+fn lower_inst(code: &mut Vec<Inst>, routines: &mut Vec<Routine>, inst: Inst) -> Result<()> {
+    if let Some((reg, offset)) = i8080::indexed(&inst) {
+        return lower_indexed(code, routines, inst, reg, offset);
+    }
+    match inst {
+        Inst::Djnz(target) => {
+            code.push(Inst::Dec(B.into()));
+            code.push(Inst::Jp(Some(Cond::Nz), target));
+        }
+        // The overflow is only tested after `ADC HL` or `SBC HL`, whose
+        // routine keeps it in memory. Only the carry survives.
+        Inst::Jp(Some(cond @ (Cond::Po | Cond::Pe)), target) => {
+            code.push(Inst::Push(HL));
+            code.push(Inst::Ld(HL.into(), imm("rt_overflow")));
+            code.push(Inst::Bit(BitOp::Bit, 7, ind(HL)));
+            code.push(Inst::Pop(HL));
+            let cond = if cond == Cond::Po { Cond::Z } else { Cond::Nz };
+            code.push(Inst::Jp(Some(cond), target));
+        }
+        Inst::Plain(Plain::Exx) => call(code, routines, Routine::Exx, "rt_exx"),
+        Inst::Plain(Plain::Lddr) => call(code, routines, Routine::Lddr, "rt_lddr"),
+        Inst::Plain(Plain::Halt) => {
+            add_routine(routines, Routine::Halt);
+            code.push(Inst::Jp(None, "rt_halt".into()));
+        }
+        Inst::Plain(Plain::Neg) => {
+            code.push(Inst::Push(HL));
+            code.push(Inst::Ld(HL.into(), imm("rt_a")));
+            code.push(Inst::Ld(ind(HL), A.into()));
+            code.push(Inst::Alu(Alu::Xor, A.into()));
+            code.push(Inst::Alu(Alu::Sub, ind(HL)));
+            code.push(Inst::Pop(HL));
+        }
+        // The routines take the operand in `DE`.
+        Inst::Alu16(alu @ (Alu::Adc | Alu::Sbc), HL, src) => {
+            let label = match alu {
+                Alu::Adc => "rt_adc",
+                _ => "rt_sbc",
+            };
+            if src == DE {
+                call(code, routines, Routine::Carry16, label);
+                return Ok(());
+            }
+            code.push(Inst::Push(DE));
+            code.push(Inst::Ld(D.into(), high(src).into()));
+            code.push(Inst::Ld(E.into(), low(src).into()));
+            call(code, routines, Routine::Carry16, label);
+            code.push(Inst::Pop(DE));
+        }
+        // Leaves the flags.
+        Inst::Alu16(Alu::Add, reg @ (IX | IY), SP) => {
+            code.push(Inst::Push(AF));
+            code.push(Inst::Push(DE));
+            code.push(Inst::Push(HL));
+            code.push(Inst::Ld(HL.into(), Operand::SpOffset(6)));
+            code.push(Inst::Ld(D.into(), H.into()));
+            code.push(Inst::Ld(E.into(), L.into()));
+            add_to_var(code, reg, DE);
+            code.push(Inst::Pop(HL));
+            code.push(Inst::Pop(DE));
+            code.push(Inst::Pop(AF));
+        }
+        Inst::Alu16(Alu::Add, reg @ (IX | IY), src @ (BC | DE)) => {
+            code.push(Inst::Push(AF));
+            code.push(Inst::Push(HL));
+            add_to_var(code, reg, src);
+            code.push(Inst::Pop(HL));
+            code.push(Inst::Pop(AF));
+        }
+        // The pushed `HL` is overwritten with the index register.
+        Inst::Push(reg @ (IX | IY)) => {
+            code.push(Inst::Push(HL));
+            code.push(Inst::Push(HL));
+            code.push(Inst::Push(AF));
+            code.push(Inst::Ld(HL.into(), Operand::SpOffset(4)));
+            code.push(Inst::Ld(A.into(), index_var(reg)));
+            code.push(Inst::Ld(Operand::HlInc, A.into()));
+            code.push(Inst::Ld(A.into(), index_byte(reg, 1)));
+            code.push(Inst::Ld(ind(HL), A.into()));
+            code.push(Inst::Pop(AF));
+            code.push(Inst::Pop(HL));
+        }
+        Inst::Pop(reg @ (IX | IY)) => {
+            code.push(Inst::Push(AF));
+            code.push(Inst::Push(HL));
+            code.push(Inst::Ld(HL.into(), Operand::SpOffset(4)));
+            code.push(Inst::Ld(A.into(), Operand::HlInc));
+            code.push(Inst::Ld(index_var(reg), A.into()));
+            code.push(Inst::Ld(A.into(), ind(HL)));
+            code.push(Inst::Ld(index_byte(reg, 1), A.into()));
+            code.push(Inst::Pop(HL));
+            code.push(Inst::Pop(AF));
+            code.push(Inst::Inc(SP.into()));
+            code.push(Inst::Inc(SP.into()));
+        }
+        Inst::Ld(Operand::Reg16(reg @ (IX | IY)), Operand::Imm(value)) => {
+            code.push(Inst::Push(HL));
+            code.push(Inst::Ld(HL.into(), Operand::Imm(value)));
+            store_pair(code, index_var(reg), HL);
+            code.push(Inst::Pop(HL));
+        }
+        // Whatever is below the new stack pointer is free, so `AF` can be
+        // saved there.
+        Inst::Ld(Operand::Reg16(SP), Operand::Reg16(reg @ (IX | IY))) => {
+            store_pair(code, mem("rt_hl"), HL);
+            load_pair(code, HL, index_var(reg));
+            code.push(Inst::Ld(SP.into(), HL.into()));
+            load_pair(code, HL, mem("rt_hl"));
+        }
+        Inst::Ld(Operand::Reg16(pair @ (BC | DE | HL)), src @ Operand::Mem(_)) => {
+            load_pair(code, pair, src);
+        }
+        Inst::Ld(dst @ Operand::Mem(_), Operand::Reg16(pair @ (BC | DE | HL))) => {
+            store_pair(code, dst, pair);
+        }
+        Inst::Ex(Operand::Reg16(DE), Operand::Reg16(HL)) => {
+            code.push(Inst::Push(DE));
+            code.push(Inst::Ld(D.into(), H.into()));
+            code.push(Inst::Ld(E.into(), L.into()));
+            code.push(Inst::Pop(HL));
+        }
+        Inst::Ex(Operand::Ind(SP), Operand::Reg16(HL)) => {
+            call(code, routines, Routine::ExSp, "rt_ex_sp_hl");
+        }
+        inst @ (Inst::Jp(Some(Cond::P | Cond::M), _)
+        | Inst::Call(Some(Cond::Po | Cond::Pe | Cond::P | Cond::M), _)
+        | Inst::Ret(Some(Cond::Po | Cond::Pe | Cond::P | Cond::M))) => {
+            bail!("{inst:?} tests a flag the SM83 lacks");
+        }
+        inst @ (Inst::Plain(Plain::Ldi | Plain::Ldd | Plain::Ldir)
+        | Inst::Alu16(..)
+        | Inst::Ex(..)
+        | Inst::JpInd(IX | IY)
+        | Inst::Ld(Operand::Reg16(IX | IY | SP), _)
+        | Inst::Ld(_, Operand::Reg16(IX | IY | SP))) => {
+            if !is_sm83(&inst) {
+                bail!("{inst:?} has no SM83 equivalent");
+            }
+            code.push(inst);
+        }
+        inst => code.push(inst),
+    }
+    Ok(())
+}
+
+fn add_routine(routines: &mut Vec<Routine>, routine: Routine) {
+    if !routines.contains(&routine) {
+        routines.push(routine);
+    }
+}
+
+fn call(code: &mut Vec<Inst>, routines: &mut Vec<Routine>, routine: Routine, label: &str) {
+    add_routine(routines, routine);
+    code.push(Inst::Call(None, label.into()));
+}
+
+/// Whether `inst`, among the forms `lower_inst` doesn't rewrite, exists on
+/// the SM83.
+fn is_sm83(inst: &Inst) -> bool {
+    matches!(
+        inst,
+        Inst::Alu16(Alu::Add, HL, _)
+            | Inst::Ld(Operand::Reg16(SP), Operand::Reg16(HL) | Operand::Imm(_))
+    )
+}
+
+/// Byte `offset` of the variable holding index register `reg`.
+fn index_byte(reg: Reg16, offset: i64) -> Operand {
+    let Operand::Mem(addr) = index_var(reg) else {
+        unreachable!();
+    };
+    Operand::Mem(addr.plus(offset))
+}
+
+/// Emits code adding `pair` to the variable holding index register `reg`,
+/// through `A` and `HL`.
+fn add_to_var(code: &mut Vec<Inst>, reg: Reg16, pair: Reg16) {
+    let Operand::Mem(addr) = index_var(reg) else {
+        unreachable!();
+    };
+    code.push(Inst::Ld(HL.into(), Operand::Imm(addr)));
+    code.push(Inst::Ld(A.into(), ind(HL)));
+    code.push(Inst::Alu(Alu::Add, low(pair).into()));
+    code.push(Inst::Ld(Operand::HlInc, A.into()));
+    code.push(Inst::Ld(A.into(), ind(HL)));
+    code.push(Inst::Alu(Alu::Adc, high(pair).into()));
+    code.push(Inst::Ld(ind(HL), A.into()));
+}
+
+/// Emits code loading `pair` from memory at `src` a byte at a time through
+/// `A`, which is saved on the stack meanwhile.
+fn load_pair(code: &mut Vec<Inst>, pair: Reg16, src: Operand) {
+    let Operand::Mem(addr) = src else {
+        unreachable!();
+    };
+    code.push(Inst::Push(AF));
+    code.push(Inst::Ld(A.into(), Operand::Mem(addr.clone())));
+    code.push(Inst::Ld(low(pair).into(), A.into()));
+    code.push(Inst::Ld(A.into(), Operand::Mem(addr.plus(1))));
+    code.push(Inst::Ld(high(pair).into(), A.into()));
+    code.push(Inst::Pop(AF));
+}
+
+/// Emits code storing `pair` to memory at `dst` a byte at a time through
+/// `A`, which is saved on the stack meanwhile.
+fn store_pair(code: &mut Vec<Inst>, dst: Operand, pair: Reg16) {
+    let Operand::Mem(addr) = dst else {
+        unreachable!();
+    };
+    code.push(Inst::Push(AF));
+    code.push(Inst::Ld(A.into(), low(pair).into()));
+    code.push(Inst::Ld(Operand::Mem(addr.clone()), A.into()));
+    code.push(Inst::Ld(A.into(), high(pair).into()));
+    code.push(Inst::Ld(Operand::Mem(addr.plus(1)), A.into()));
+    code.push(Inst::Pop(AF));
+}
+
+/// Lowers `inst` accessing memory at index register `reg` plus `offset` to
+/// access it through `HL` instead, as for the 8080, but copying `HL` into
+/// `DE` where the 8080 swaps them.
+fn lower_indexed(
+    code: &mut Vec<Inst>,
+    routines: &mut Vec<Routine>,
+    inst: Inst,
+    reg: Reg16,
+    offset: i16,
+) -> Result<()> {
+    if !(0..=255).contains(&offset) {
+        bail!("displacement {offset} out of range");
+    }
+    let uses_hl = i8080::operands(&inst)
+        .iter()
+        .any(|operand| matches!(operand, Operand::Reg8(H | L)));
+    let inst = i8080::map_operands(inst, |operand| match operand {
+        Operand::Idx(..) => ind(HL),
+        Operand::Reg8(H) => D.into(),
+        Operand::Reg8(L) => E.into(),
+        operand => operand,
+    });
+    if uses_hl {
+        code.push(Inst::Push(DE));
+        code.push(Inst::Ld(D.into(), H.into()));
+        code.push(Inst::Ld(E.into(), L.into()));
+    } else {
+        code.push(Inst::Push(HL));
+    }
+    let label = format!("rt_{}_index", index_name(reg));
+    call(code, routines, Routine::Index(reg), &label);
+    code.push(Inst::Db(vec![i64::from(offset).into()]));
+    lower_inst(code, routines, inst)?;
+    if uses_hl {
+        code.push(Inst::Ld(H.into(), D.into()));
+        code.push(Inst::Ld(L.into(), E.into()));
+        code.push(Inst::Pop(DE));
+    } else {
+        code.push(Inst::Pop(HL));
+    }
+    Ok(())
+}
+
+/// Turns loads of `A` from and stores to constant addresses in the high page
+/// into `LDH`, which is a byte shorter.
+fn use_ldh(code: &mut [Inst]) {
+    let mut symbols = HashMap::new();
+    for inst in code.iter() {
+        if let Inst::Equ(name, expr) = inst {
+            if let Some(value) = expr.value() {
+                symbols.insert(name.clone(), value);
+            }
+        }
+    }
+    let high_page = |addr: &Expr| {
+        let value = match &addr.symbol {
+            Some(symbol) => symbols.get(symbol).map(|value| value + addr.offset),
+            None => Some(addr.offset),
+        };
+        value.is_some_and(|value| (0xFF00..=0xFFFF).contains(&value))
+    };
+    for inst in code.iter_mut() {
+        match inst {
+            Inst::Ld(Operand::Reg8(Reg8::A), Operand::Mem(addr))
+            | Inst::Ld(Operand::Mem(addr), Operand::Reg8(Reg8::A))
+                if high_page(addr) =>
+            {
+                let Inst::Ld(dst, src) = inst.clone() else {
+                    unreachable!();
+                };
+                *inst = Inst::Ldh(dst, src);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Address the code starts at in a ROM image, after the header.
+const ROM_CODE: u16 = 0x150;
+
+/// Size of a ROM image without a memory bank controller.
+const ROM_SIZE: usize = 0x8000;
+
+/// The logo the boot ROM checks the header for.
+const LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+/// Assembles `code` into a 32 KiB Game Boy ROM image, with the code after
+/// the header and the entry point jumping to it.
+///
+/// The header declares a cartridge without a memory bank controller or RAM
+/// and carries the logo and both checksums, so the boot ROM accepts it.
+pub fn rom(code: &[Inst]) -> Result<Vec<u8>> {
+    let bytes = encode::assemble(code, ROM_CODE, Target::Sm83);
+    let end = usize::from(ROM_CODE) + bytes.len();
+    if end > ROM_SIZE {
+        bail!(
+            "the code takes {} bytes, which don't fit in a 32 KiB ROM",
+            bytes.len()
+        );
+    }
+    let mut rom = vec![0; ROM_SIZE];
+    rom[usize::from(ROM_CODE)..end].copy_from_slice(&bytes);
+    // NOP; JP 0x150
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+    rom[0x104..0x134].copy_from_slice(&LOGO);
+    rom[0x134..0x13C].copy_from_slice(b"WASM2Z80");
+    // Outside Japan.
+    rom[0x14A] = 0x01;
+    rom[0x14D] = rom[0x134..0x14D]
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_sub(*byte).wrapping_sub(1));
+    let sum = rom
+        .iter()
+        .fold(0u16, |sum, byte| sum.wrapping_add(u16::from(*byte)));
+    rom[0x14E..0x150].copy_from_slice(&sum.to_be_bytes());
+    Ok(rom)
+}
+
+/// Swaps `BC`, `DE` and `HL` with the shadow bank in memory, like `EXX`.
+/// They are pushed in the order of the shadow bank and swapped with it a
+/// byte at a time.
+const EXX: &str = "\
+rt_exx:
+  PUSH HL
+  PUSH DE
+  PUSH BC
+  PUSH AF
+  LD HL,SP+2
+  LD DE,rt_shadow_bc
+  LD B,6
+rt_exx_byte:
+  LD A,(DE)
+  LD C,(HL)
+  LD (HL+),A
+  LD A,C
+  LD (DE),A
+  INC DE
+  DEC B
+  JR NZ,rt_exx_byte
+  POP AF
+  POP BC
+  POP DE
+  POP HL
+  RET
+";
+
+/// Swaps `HL` with the word on the stack under the return address, like
+/// `EX (SP),HL`.
+const EX_SP: &str = "\
+rt_ex_sp_hl:
+  PUSH AF
+  PUSH DE
+  LD D,H
+  LD E,L
+  LD HL,SP+6
+  LD A,(HL)
+  LD (HL),E
+  LD E,A
+  INC HL
+  LD A,(HL)
+  LD (HL),D
+  LD H,A
+  LD L,E
+  POP DE
+  POP AF
+  RET
+";
+
+/// `ADC HL,DE` and `SBC HL,DE`, setting the zero and carry flags, with the
+/// overflow in bit 7 of `rt_overflow` as the SM83 has no flag for it. The
+/// flags are written over the caller's `F` on the stack.
+const CARRY16: &str = "\
+rt_adc:
+  PUSH AF
+  PUSH BC
+  LD A,L
+  ADC A,E
+  LD L,A
+  LD C,H
+  LD A,H
+  ADC A,D
+  LD H,A
+  LD A,D
+  CPL
+  LD B,A
+  JR rt_carry16_flags
+rt_sbc:
+  PUSH AF
+  PUSH BC
+  LD A,L
+  SBC A,E
+  LD L,A
+  LD C,H
+  LD A,H
+  SBC A,D
+  LD H,A
+  LD B,D
+rt_carry16_flags:
+  PUSH AF
+  LD A,C
+  XOR B
+  LD B,A
+  LD A,C
+  XOR H
+  AND B
+  LD (rt_overflow),A
+  POP AF
+  SBC A,A
+  AND 0x10
+  LD B,A
+  LD A,H
+  OR L
+  LD A,B
+  JR NZ,rt_carry16_nonzero
+  OR 0x80
+rt_carry16_nonzero:
+  PUSH HL
+  LD HL,SP+4
+  LD (HL),A
+  POP HL
+  POP BC
+  POP AF
+  RET
+";
+
+/// Halts for good. The `NOP` takes the byte the SM83 reads twice when an
+/// interrupt is pending with interrupts disabled.
+const HALT: &str = "\
+rt_halt:
+  HALT
+  NOP
+  JR rt_halt
+";
+
+/// Points `HL` at the index register's value plus the displacement byte
+/// following the call, which it returns past, keeping the other registers
+/// and the flags.
+const INDEX: &str = "\
+rt_{reg}_index:
+  PUSH AF
+  PUSH DE
+  LD HL,SP+4
+  LD E,(HL)
+  INC HL
+  LD D,(HL)
+  LD A,(DE)
+  INC DE
+  LD (HL),D
+  DEC HL
+  LD (HL),E
+  LD HL,rt_{reg}
+  ADD A,(HL)
+  INC HL
+  LD H,(HL)
+  LD L,A
+  JR NC,rt_{reg}_index_done
+  INC H
+rt_{reg}_index_done:
+  POP DE
+  POP AF
+  RET
+";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile::{Config, Format};
+    use crate::loader;
+
+    #[test]
+    fn rom_header_is_checked() {
+        let wasm = wat::parse_str(r#"(module (func (export "entry")))"#).unwrap();
+        let config = Config {
+            target: Target::Sm83,
+            format: Format::Gb,
+            ..Config::default()
+        };
+        let mut rom = vec![];
+        loader::load(&wasm)
+            .unwrap()
+            .compile(&config, &mut rom)
+            .unwrap();
+        assert_eq!(rom.len(), ROM_SIZE);
+        assert_eq!(&rom[0x100..0x104], [0x00, 0xC3, 0x50, 0x01]);
+        assert_eq!(&rom[0x104..0x134], LOGO);
+        assert!(rom[usize::from(ROM_CODE)..].iter().any(|&byte| byte != 0));
+        // What the boot ROM checks, and what emulators report: the header
+        // bytes and the checksum add up to -0x19.
+        let header = rom[0x134..0x14D]
+            .iter()
+            .fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        let header = 0u8.wrapping_sub(header).wrapping_sub(0x19);
+        assert_eq!(rom[0x14D], header);
+        let global = rom
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !(0x14E..0x150).contains(i))
+            .map(|(_, byte)| u32::from(*byte))
+            .sum::<u32>();
+        assert_eq!(u16::from_be_bytes([rom[0x14E], rom[0x14F]]), global as u16);
+    }
+}
@@ -0,0 +1,188 @@
+use anyhow::{bail, Result};
+use wasmparser::ValType;
+
+use crate::asm::{self, imm, Expr, Inst, Plain, Reg16::*, Reg8::*};
+use crate::compile::{Provided, Target, GLOBALS_OFFSET};
+use crate::encode;
+
+/// Address the code is loaded at, above the screen, the system variables and
+/// the BASIC loader, whose `CLEAR` puts the BASIC stacks below it.
+pub const ORIGIN: u16 = 0x8000;
+
+/// Number of globals there is room for in the data block.
+pub const MAX_GLOBALS: usize = 16;
+
+/// Size of the data block: the scratch buffer, the trap record and the
+/// globals.
+const DATA_SIZE: usize = GLOBALS_OFFSET + 4 * MAX_GLOBALS;
+
+/// ROM routine opening the channel of the stream in `A`.
+const CHAN_OPEN: i64 = 0x1601;
+
+/// Line number of the BASIC loader.
+const LOADER_LINE: u16 = 10;
+
+/// Emits code saving what BASIC needs back, the stack pointer and `HL'`,
+/// and opening the upper screen for printing. The stack stays where BASIC
+/// has it, below the code.
+pub fn emit_setup(code: &mut Vec<Inst>) {
+    code.push(Inst::Plain(Plain::Exx));
+    code.push(Inst::Push(HL));
+    code.push(Inst::Plain(Plain::Exx));
+    code.push(Inst::Ld(asm::mem(Expr::sym("spectrum_sp")), SP.into()));
+    code.push(Inst::Ld(A.into(), imm(2)));
+    code.push(Inst::Call(None, CHAN_OPEN.into()));
+}
+
+/// Emits the data block, with the saved stack pointer in front, and the
+/// routine returning to BASIC.
+pub fn emit_data(code: &mut Vec<Inst>) {
+    code.extend(asm::parse(EXIT));
+    code.push(Inst::Label("spectrum_sp".into()));
+    code.push(Inst::Dw(vec![0.into()]));
+    code.push(Inst::Label("rt_data".into()));
+    for row in (0..DATA_SIZE).step_by(16) {
+        code.push(Inst::Db(vec![0.into(); (DATA_SIZE - row).min(16)]));
+    }
+}
+
+/// Routines imported from `console`, calling the ROM routines they stand
+/// for.
+pub const IMPORTS: &[Provided] = &[Provided {
+    name: "putchar",
+    params: &[ValType::I32],
+    results: &[],
+    body: PUTCHAR,
+}];
+
+/// Returns a tape image of `code`: a BASIC program loading it and calling
+/// it, followed by the code itself.
+pub fn tap(code: &[Inst]) -> Result<Vec<u8>> {
+    let bytes = encode::assemble(code, ORIGIN, Target::Z80);
+    if usize::from(ORIGIN) + bytes.len() > 0x10000 {
+        bail!(
+            "the code takes {} bytes, which don't fit above 0x8000",
+            bytes.len()
+        );
+    }
+    // CLEAR origin-1: LOAD "" CODE : RANDOMIZE USR origin
+    let mut line = vec![0xFD];
+    push_number(&mut line, ORIGIN - 1);
+    line.extend([b':', 0xEF, b'"', b'"', 0xAF, b':', 0xF9, 0xC0]);
+    push_number(&mut line, ORIGIN);
+    line.push(0x0D);
+    let mut program = LOADER_LINE.to_be_bytes().to_vec();
+    program.extend((line.len() as u16).to_le_bytes());
+    program.extend(line);
+    let mut tap = vec![];
+    push_file(&mut tap, 0, &program, LOADER_LINE, program.len() as u16);
+    push_file(&mut tap, 3, &bytes, ORIGIN, 0x8000);
+    Ok(tap)
+}
+
+/// Appends `value` the way BASIC stores numbers in program lines: its digits
+/// followed by its five byte form, here the one for small integers.
+fn push_number(line: &mut Vec<u8>, value: u16) {
+    line.extend(value.to_string().bytes());
+    line.extend([0x0E, 0x00, 0x00]);
+    line.extend(value.to_le_bytes());
+    line.push(0x00);
+}
+
+/// Appends a header block and a data block holding `data` as a file of
+/// `kind`, with the meaning of the two parameters depending on it.
+fn push_file(tap: &mut Vec<u8>, kind: u8, data: &[u8], param1: u16, param2: u16) {
+    let mut header = vec![kind];
+    header.extend(b"wasm      ");
+    header.extend((data.len() as u16).to_le_bytes());
+    header.extend(param1.to_le_bytes());
+    header.extend(param2.to_le_bytes());
+    push_block(tap, 0x00, &header);
+    push_block(tap, 0xFF, data);
+}
+
+/// Appends a block of `data` behind `flag`, with its length in front and
+/// its checksum behind.
+fn push_block(tap: &mut Vec<u8>, flag: u8, data: &[u8]) {
+    tap.extend((data.len() as u16 + 2).to_le_bytes());
+    tap.push(flag);
+    tap.extend(data);
+    tap.push(data.iter().fold(flag, |sum, byte| sum ^ byte));
+}
+
+/// Returns to BASIC from anywhere, with the stack pointer and `HL'` as they
+/// were when the program was called.
+const EXIT: &str = "\
+spectrum_exit:
+  LD SP,(spectrum_sp)
+  EXX
+  POP HL
+  EXX
+  RET
+";
+
+// The ROM expects IY to point at the system variables, which it always does
+// since the frame pointer is IX, saved around the ROM here.
+
+/// Prints the character in the low byte of the argument with `RST 0x10`,
+/// which takes 13 for a new line. The scroll count is kept from running
+/// out so that the ROM never stops to ask whether to scroll.
+const PUTCHAR: &str = "
+  PUSH IX
+  LD A,0xFF
+  LD (0x5C8C),A
+  LD A,E
+  RST 0x10
+  POP IX
+";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile::{Config, Format, Platform};
+    use crate::loader;
+
+    fn compile(format: Format) -> Vec<u8> {
+        let wasm = wat::parse_str(r#"(module (func (export "entry")))"#).unwrap();
+        let config = Config {
+            platform: Platform::Spectrum,
+            format,
+            ..Config::default()
+        };
+        let mut out = vec![];
+        loader::load(&wasm)
+            .unwrap()
+            .compile(&config, &mut out)
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn tape_holds_the_loader_and_the_code() {
+        let tap = compile(Format::Tap);
+        let mut blocks = vec![];
+        let mut rest = &tap[..];
+        while !rest.is_empty() {
+            let len = usize::from(u16::from_le_bytes([rest[0], rest[1]]));
+            let block = &rest[2..2 + len];
+            assert_eq!(block.iter().fold(0, |sum, byte| sum ^ byte), 0);
+            blocks.push(block);
+            rest = &rest[2 + len..];
+        }
+        let flags: Vec<u8> = blocks.iter().map(|block| block[0]).collect();
+        assert_eq!(flags, [0x00, 0xFF, 0x00, 0xFF]);
+        for (header, kind, param1) in [(blocks[0], 0, LOADER_LINE), (blocks[2], 3, ORIGIN)] {
+            assert_eq!(header.len(), 19);
+            assert_eq!(header[1], kind);
+            assert_eq!(&header[2..12], b"wasm      ");
+            assert_eq!(&header[14..16], param1.to_le_bytes());
+        }
+        for (header, data) in [(blocks[0], blocks[1]), (blocks[2], blocks[3])] {
+            assert_eq!(
+                usize::from(u16::from_le_bytes([header[12], header[13]])),
+                data.len() - 2
+            );
+        }
+        assert_eq!(&blocks[3][1..blocks[3].len() - 1], compile(Format::Bin));
+    }
+}
@@ -0,0 +1,189 @@
+use crate::asm::{imm, mem, Alu, Expr, Inst, Reg16::*, Reg8::*};
+
+/// Offset from the scratch buffer of the byte the trap routine stores the
+/// trap code into.
+pub const TRAP_CODE_OFFSET: i64 = 8;
+/// Offset from the scratch buffer of the word the trap routine stores the
+/// faulting function index into.
+pub const TRAP_FUNC_OFFSET: i64 = 10;
+
+/// Reasons for which compiled code can trap.
+///
+/// The discriminant is the code stored at [`TRAP_CODE_OFFSET`].
+///
+/// A host learns how the program ended from the trap record: the startup
+/// code clears the trap code, and a trap stores its code and the index of
+/// the faulting function, as a little-endian word at [`TRAP_FUNC_OFFSET`],
+/// before ending the program like returning from the entry does. A trap
+/// code still 0 once the program has ended thus means that it didn't trap.
+/// The assembly defines `trap_code` and `trap_func` to the addresses of the
+/// record, which without a platform are 0xFFC8 and 0xFFCA, and a `TRAP_*`
+/// symbol to each code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trap {
+    Unreachable = 1,
+    IntegerDivideByZero = 2,
+    IntegerOverflow = 3,
+    InvalidConversionToInteger = 4,
+    UndefinedElement = 5,
+    IndirectCallTypeMismatch = 6,
+}
+
+impl Trap {
+    pub const ALL: [Trap; 6] = [
+        Trap::Unreachable,
+        Trap::IntegerDivideByZero,
+        Trap::IntegerOverflow,
+        Trap::InvalidConversionToInteger,
+        Trap::UndefinedElement,
+        Trap::IndirectCallTypeMismatch,
+    ];
+
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+
+    /// Name of the assembler symbol defined to this trap's code.
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Trap::Unreachable => "TRAP_UNREACHABLE",
+            Trap::IntegerDivideByZero => "TRAP_INTEGER_DIVIDE_BY_ZERO",
+            Trap::IntegerOverflow => "TRAP_INTEGER_OVERFLOW",
+            Trap::InvalidConversionToInteger => "TRAP_INVALID_CONVERSION_TO_INTEGER",
+            Trap::UndefinedElement => "TRAP_UNDEFINED_ELEMENT",
+            Trap::IndirectCallTypeMismatch => "TRAP_INDIRECT_CALL_TYPE_MISMATCH",
+        }
+    }
+}
+
+/// Emits the symbols describing the trap record, so that a debugger or test
+/// harness reading the assembly can decode it. The record follows the
+/// scratch buffer at `scratch`.
+pub fn emit_symbols(code: &mut Vec<Inst>, scratch: &Expr) {
+    let trap_code = scratch.clone().plus(TRAP_CODE_OFFSET);
+    let trap_func = scratch.clone().plus(TRAP_FUNC_OFFSET);
+    code.push(Inst::Equ("trap_code".into(), trap_code));
+    code.push(Inst::Equ("trap_func".into(), trap_func));
+    for trap in Trap::ALL {
+        let value = i64::from(trap.code()).into();
+        code.push(Inst::Equ(trap.symbol().into(), value));
+    }
+}
+
+/// Emits code clearing the trap code, for the program's startup.
+pub fn emit_clear(code: &mut Vec<Inst>) {
+    code.push(Inst::Alu(Alu::Xor, A.into()));
+    code.push(Inst::Ld(mem("trap_code"), A.into()));
+}
+
+/// Emits the shared trap routine.
+///
+/// It is entered by `CALL trap` with the trap code in `A` and the faulting
+/// function index in `HL`, so the faulting address is left on top of the
+/// stack. Both values are recorded in the trap record, then the routine either
+/// ends the program with `exit` or jumps to `handler` with the registers
+/// intact.
+pub fn emit_routine(code: &mut Vec<Inst>, handler: Option<&str>, exit: Inst) {
+    code.push(Inst::Label("trap".into()));
+    code.push(Inst::Ld(mem("trap_code"), A.into()));
+    code.push(Inst::Ld(mem("trap_func"), HL.into()));
+    match handler {
+        Some(handler) => code.push(Inst::Jp(None, handler.into())),
+        None => code.push(exit),
+    }
+}
+
+/// Emits a trap site raising `trap` from function `func_index`.
+pub fn emit_raise(code: &mut Vec<Inst>, trap: Trap, func_index: usize) {
+    code.push(Inst::Ld(HL.into(), imm(func_index as i64)));
+    code.push(Inst::Ld(A.into(), imm(Expr::sym(trap.symbol()))));
+    code.push(Inst::Call(None, "trap".into()));
+}
+
+/// Emits a trap site raising the trap whose code a routine left in `A`,
+/// from function `func_index`.
+pub fn emit_raise_in_a(code: &mut Vec<Inst>, func_index: usize) {
+    code.push(Inst::Ld(HL.into(), imm(func_index as i64)));
+    code.push(Inst::Call(None, "trap".into()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile::Config;
+    use crate::emu::{self, Machine};
+    use crate::runtime::SCRATCH_ADDR;
+
+    /// Returns the trap code and function index in the trap record.
+    fn record(machine: &Machine) -> (u8, u16) {
+        let code = machine.byte(SCRATCH_ADDR + TRAP_CODE_OFFSET as u16);
+        let func = machine.word(SCRATCH_ADDR + TRAP_FUNC_OFFSET as u16);
+        (code, func)
+    }
+
+    #[test]
+    fn returning_leaves_no_trap() {
+        let machine = emu::run_wat(
+            r#"(module (func (export "entry") (result i32) (i32.const 7)))"#,
+            Config::default(),
+        );
+        assert_eq!(record(&machine).0, 0);
+        assert_eq!(machine.top_i32(), 7);
+    }
+
+    #[test]
+    fn unreachable_is_recorded() {
+        let machine = emu::run_wat(
+            r#"(module
+                (func $fail unreachable)
+                (func (export "entry") (call $fail)))"#,
+            Config::default(),
+        );
+        assert_eq!(record(&machine), (Trap::Unreachable.code(), 0));
+    }
+
+    #[test]
+    fn signature_mismatch_is_recorded() {
+        let machine = emu::run_wat(
+            r#"(module
+                (type $unary (func (param i32) (result i32)))
+                (table 1 funcref)
+                (elem (i32.const 0) $nullary)
+                (func $nullary (result i32) (i32.const 1))
+                (func (export "entry") (result i32)
+                  (call_indirect (type $unary) (i32.const 5) (i32.const 0))))"#,
+            Config::default(),
+        );
+        assert_eq!(record(&machine), (Trap::IndirectCallTypeMismatch.code(), 1));
+    }
+
+    #[test]
+    fn tail_call_signature_mismatch_is_recorded() {
+        let machine = emu::run_wat(
+            r#"(module
+                (type $unary (func (param i32) (result i32)))
+                (table 1 funcref)
+                (elem (i32.const 0) $nullary)
+                (func $nullary (result i32) (i32.const 1))
+                (func (export "entry") (result i32)
+                  (return_call_indirect (type $unary) (i32.const 5) (i32.const 0))))"#,
+            Config::default(),
+        );
+        assert_eq!(record(&machine), (Trap::IndirectCallTypeMismatch.code(), 1));
+    }
+
+    #[test]
+    fn missing_element_is_recorded() {
+        let machine = emu::run_wat(
+            r#"(module
+                (type $nullary (func (result i32)))
+                (table 2 funcref)
+                (elem (i32.const 0) $one)
+                (func $one (result i32) (i32.const 1))
+                (func (export "entry") (result i32)
+                  (call_indirect (type $nullary) (i32.const 1))))"#,
+            Config::default(),
+        );
+        assert_eq!(record(&machine), (Trap::UndefinedElement.code(), 1));
+    }
+}
@@ -0,0 +1,162 @@
+use crate::asm::{self, imm, Alu, Expr, Inst, Plain, Reg16, Reg16::*, Reg8::*};
+use crate::compile;
+use crate::regalloc::low;
+
+/// Internal I/O ports of the MMU: the common base, bank base and
+/// common/bank area registers.
+const CBR: u8 = 0x38;
+const BBR: u8 = 0x39;
+const CBAR: u8 = 0x3A;
+
+/// How the MMU divides the 64 KiB the processor addresses, set in CBAR, and
+/// where common area 1 is in physical memory, set in CBR.
+///
+/// Code is in common area 0, from 0 to the bank area, the window linear
+/// memory is accessed through, and must end below it. Common area 1, from
+/// the top of the bank area, holds the stack, the scratch buffer and the
+/// globals, and the stack must not grow below it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mmu {
+    /// Pages of 4 KiB common area 1 and the bank area start at, in the high
+    /// and low nibbles.
+    pub cbar: u8,
+    /// Page common area 1 is mapped at in physical memory, less the page it
+    /// starts at.
+    pub cbr: u8,
+}
+
+impl Default for Mmu {
+    /// An 8 KiB bank area at 0xD000 and common area 1 from 0xF000, mapped to
+    /// the same physical addresses.
+    fn default() -> Self {
+        Self { cbar: 0xFD, cbr: 0 }
+    }
+}
+
+impl Mmu {
+    /// Start of the bank area.
+    pub fn bank_addr(self) -> u16 {
+        u16::from(self.cbar & 0x0F) << 12
+    }
+
+    /// Start of common area 1.
+    fn common_addr(self) -> u32 {
+        u32::from(self.cbar >> 4) << 12
+    }
+
+    /// Checks that the layout leaves room for code and for the window,
+    /// describing what is wrong otherwise.
+    pub fn check(self) -> Result<(), String> {
+        if self.bank_addr() == 0 {
+            return Err(format!("CBAR 0x{:02X} leaves no room for code", self.cbar));
+        }
+        // The window is two pages long.
+        if self.common_addr() < u32::from(self.bank_addr()) + 0x2000 {
+            return Err(format!(
+                "CBAR 0x{:02X} leaves less than 8 KiB of bank area",
+                self.cbar
+            ));
+        }
+        if self.cbr > 0xF0 {
+            return Err(format!(
+                "CBR 0x{:02X} maps common area 1 past the end of physical memory",
+                self.cbr
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns whether the `size` bytes of linear memory at `addr` are in
+    /// the common areas at the same physical addresses, where they can be
+    /// accessed directly.
+    pub fn is_common(self, addr: i64, size: usize) -> bool {
+        let end = addr + size as i64;
+        end <= i64::from(self.bank_addr())
+            || (self.cbr == 0 && i64::from(self.common_addr()) <= addr && end <= 0x10000)
+    }
+}
+
+/// Emits code configuring the MMU as `mmu` says, with common area 0 mapped
+/// to the same physical addresses as its logical ones.
+pub fn emit_setup(code: &mut Vec<Inst>, mmu: Mmu) {
+    code.push(Inst::Ld(A.into(), imm(i64::from(mmu.cbar))));
+    code.push(Inst::Out0(CBAR, A));
+    match mmu.cbr {
+        0 => code.push(Inst::Alu(Alu::Xor, A.into())),
+        cbr => code.push(Inst::Ld(A.into(), imm(i64::from(cbr)))),
+    }
+    code.push(Inst::Out0(CBR, A));
+}
+
+/// Emits code mapping the bank window over the address in the low three
+/// bytes of the i32 in `pair` plus `offset` and pointing IX at it there,
+/// returning the displacement the `size` bytes there are accessed with.
+///
+/// The address is put together in `IX` and `A`, which `z180_bank` maps.
+/// The window is two pages long, so the page the address is in is followed
+/// by the one a displacement or the access itself runs into.
+pub fn emit_index(code: &mut Vec<Inst>, pair: Reg16, offset: u64, size: usize) -> usize {
+    let disp = compile::emit_index(code, pair, offset, size);
+    code.push(Inst::Plain(Plain::Exx));
+    code.push(Inst::Ld(A.into(), low(pair).into()));
+    code.push(Inst::Plain(Plain::Exx));
+    if !compile::in_reach(offset, size) {
+        code.push(Inst::Alu(Alu::Adc, imm(((offset >> 16) & 0xFF) as i64)));
+    }
+    code.push(Inst::Call(None, Expr::sym("z180_bank")));
+    disp
+}
+
+/// Emits code mapping the bank window over the linear memory address `addr`
+/// and pointing `DE` at it there, where up to the rest of its page can be
+/// accessed.
+pub fn emit_map(code: &mut Vec<Inst>, addr: usize) {
+    code.push(Inst::Ld(IX.into(), imm((addr & 0xFFFF) as i64)));
+    code.push(Inst::Ld(A.into(), imm((addr >> 16) as i64)));
+    code.push(Inst::Call(None, Expr::sym("z180_bank")));
+    code.push(Inst::Push(IX));
+    code.push(Inst::Pop(DE));
+}
+
+/// Appends `z180_bank` if `code` calls it, for the bank area of `mmu`.
+pub fn emit_routine(code: &mut Vec<Inst>, mmu: Mmu) {
+    let called = code.iter().any(|inst| match inst {
+        Inst::Call(None, target) => target.symbol.as_deref() == Some("z180_bank"),
+        _ => false,
+    });
+    if called {
+        let text = BANK
+            .replace("{bbr}", &BBR.to_string())
+            .replace("{page}", &(mmu.bank_addr() >> 12).to_string())
+            .replace("{window}", &(mmu.bank_addr() >> 8).to_string());
+        code.extend(asm::parse(&text));
+    }
+}
+
+/// Maps the bank window over the address in `A` and `IX`, bits 16 to 23
+/// and 0 to 15, and points `IX` at it there.
+///
+/// The bank base is the page the address is in less the window's, so that
+/// it is translated back to the address. Only `A` and the flags are changed
+/// besides.
+const BANK: &str = "\
+z180_bank:
+  LD (rt_buf),IX
+  LD (rt_buf+2),A
+  PUSH HL
+  LD HL,(rt_buf+1)
+  ADD HL,HL
+  ADD HL,HL
+  ADD HL,HL
+  ADD HL,HL
+  LD A,H
+  SUB {page}
+  OUT0 ({bbr}),A
+  LD A,(rt_buf+1)
+  AND 0x0F
+  OR {window}
+  LD (rt_buf+1),A
+  POP HL
+  LD IX,(rt_buf)
+  RET
+";